@@ -5,8 +5,10 @@ use http::Request;
 use http_body_util::Full;
 use std::collections::HashMap;
 
-use requests;
-use results::{DomainResult, ResponseJson, UpdateIpResults};
+use conditions::Condition;
+use results::{
+    Address, ComparePrefixLen, DomainOutcome, DomainResult, PlannedUpdate, ResponseJson,
+};
 
 // following types are based on:
 // https://developers.cloudflare.com/api/operations/dns-records-for-a-zone-update-dns-record
@@ -23,10 +25,22 @@ pub struct Cloudflare {
     pub comment: Option<String>,
     pub tags: Option<Vec<String>>,
     pub ttl: Option<usize>,
+    pub condition: Option<Condition>,
+    pub priority: Option<u32>,
+    #[serde(default, deserialize_with = "durations::deserialize_optional_ms")]
+    pub min_update_interval_ms: Option<u64>,
+    pub compare_prefix_len: Option<ComparePrefixLen>,
+    pub check_response_shape: Option<bool>,
+    pub success_predicate: Option<String>,
 }
 
 pub type CloudflareDomains = Vec<Cloudflare>;
 
+const SERVICE_NAME: &str = "cloudflare";
+pub const API_URL: &str = "https://api.cloudflare.com/client/v4/";
+// the cloudflare api reports errors in the body of some 2xx responses
+const DEFAULT_SUCCESS_PREDICATE: &str = "/success == true";
+
 #[derive(Clone, Serialize, Debug)]
 pub struct CloudflareRequestBody {
     pub content: String,
@@ -42,6 +56,33 @@ pub struct CloudflareRequestBody {
     pub ttl: Option<usize>,
 }
 
+pub fn validate(domain: &Cloudflare) -> Vec<String> {
+    let mut errors = Vec::<String>::new();
+
+    let required = [
+        ("name", &domain.name),
+        ("zone_id", &domain.zone_id),
+        ("dns_record_id", &domain.dns_record_id),
+        ("api_token", &domain.api_token),
+        ("type", &domain.r#type),
+    ];
+    for (field, value) in required {
+        if value.is_empty() {
+            errors.push(field.to_string() + ": must not be empty");
+        }
+    }
+    if let Some(success_predicate) = &domain.success_predicate {
+        if let Err(e) = requests::validate_json_predicate(success_predicate) {
+            errors.push("success_predicate: ".to_string() + &e);
+        }
+    }
+    if let Some(condition) = &domain.condition {
+        errors.append(&mut conditions::validate(condition));
+    }
+
+    errors
+}
+
 /*
 https://developers.cloudflare.com/api/operations/dns-records-for-a-zone-patch-dns-record
 
@@ -51,41 +92,32 @@ Only update changed parameters
 
 pub async fn update_domains(
     domain_results: &mut HashMap<String, DomainResult>,
-    prev_results: &Option<UpdateIpResults>,
-    ip_address: &str,
-    cloudflare_domains: &CloudflareDomains,
+    planned_updates: &[PlannedUpdate<'_, Cloudflare>],
+    timeout_ms: Option<u64>,
 ) {
-    for domain in cloudflare_domains {
-        let mut domain_result = match prev_results {
-            Some(results) => match results.domain_service_results.get(&domain.name) {
-                Some(domain) => domain.clone(),
-                _ => DomainResult::new(&domain.name),
-            },
-            _ => DomainResult::new(&domain.name),
-        };
-
-        let hostname = domain.name.clone();
+    for planned_update in planned_updates {
+        let domain_result = build_domain_result(planned_update, timeout_ms).await;
 
-        if let Some(domain_ip) = &domain_result.ip_address {
-            if domain_ip == ip_address {
-                domain_results.insert(hostname, domain_result);
-                continue;
-            }
-        }
-
-        // build domain result
-        domain_result = build_domain_result(&domain, ip_address).await;
         // write over previous entry
-        domain_results.insert(hostname, domain_result);
+        domain_results.insert(planned_update.key.clone(), domain_result);
     }
 }
 
-async fn build_domain_result(domain: &Cloudflare, ip_address: &str) -> DomainResult {
+async fn build_domain_result(
+    planned_update: &PlannedUpdate<'_, Cloudflare>,
+    timeout_ms: Option<u64>,
+) -> DomainResult {
+    let domain = planned_update.domain;
+    let ip_address = &planned_update.ip_address;
+
     let mut domain_result = DomainResult::new(&domain.name);
+    domain_result.service = Some(SERVICE_NAME.to_string());
+    domain_result.reason = Some(planned_update.reason);
 
-    let request = match get_cloudflare_req(&domain, &ip_address) {
+    let request = match get_cloudflare_req(domain, ip_address) {
         Ok(s) => s,
         Err(e) => {
+            domain_result.outcome = Some(DomainOutcome::Failed);
             domain_result.errors.push(e);
             return domain_result;
         }
@@ -94,38 +126,260 @@ async fn build_domain_result(domain: &Cloudflare, ip_address: &str) -> DomainRes
     // update domain service
     // create json-able struct from response
     // add to domain result
-    match requests::boxed_request_http1_tls_response(request).await {
+    let response = requests::boxed_request_http1_tls_response(request);
+    match requests::with_timeout(timeout_ms, response).await {
         Ok(r) => {
-            if verify_resposne(&r) {
-                domain_result.ip_address = Some(ip_address.to_string());
+            if let Some(warning) = requests::get_clock_skew_warning(&r) {
+                domain_result.warnings.push(warning);
             }
+            match verify_resposne(domain, &r) {
+                Ok(()) => {
+                    domain_result.outcome = Some(DomainOutcome::Updated);
+                    domain_result.ip_address = Some(*ip_address);
+                    domain_result.response_shape = Some(results::get_response_shape(&r));
+                }
+                Err(e) => {
+                    // failed updates, ie: an expired token, are retried next run
+                    domain_result.outcome = Some(DomainOutcome::Failed);
+                    domain_result.errors.push(e);
+                    domain_result.errors.append(&mut get_api_errors(&r));
+                }
+            }
+        }
+        Err(e) => {
+            domain_result.outcome = Some(DomainOutcome::Failed);
+            domain_result.errors.push(e);
         }
-        Err(e) => domain_result.errors.push(e),
     }
 
     domain_result
 }
 
-fn verify_resposne(res: &ResponseJson) -> bool {
-    res.status_code >= 200 && res.status_code < 300
+/*
+    Verified updates are read back from the cloudflare api. A record that
+    does not hold the sent address is updated and read back once more
+    before the domain fails.
+*/
+pub async fn verify_domains(
+    domain_results: &mut HashMap<String, DomainResult>,
+    planned_updates: &[PlannedUpdate<'_, Cloudflare>],
+    timeout_ms: Option<u64>,
+) {
+    for planned_update in planned_updates {
+        let updated = match domain_results.get(&planned_update.key) {
+            Some(domain_result) => domain_result.outcome == Some(DomainOutcome::Updated),
+            _ => false,
+        };
+        if !updated {
+            continue;
+        }
+
+        let mut verified = verify_record(planned_update, timeout_ms).await;
+        if verified.is_err() {
+            let domain_result = build_domain_result(planned_update, timeout_ms).await;
+            if domain_result.outcome == Some(DomainOutcome::Updated) {
+                verified = verify_record(planned_update, timeout_ms).await;
+            }
+            domain_results.insert(planned_update.key.clone(), domain_result);
+        }
+
+        // a failed verify is retried next run
+        if let (Err(e), Some(domain_result)) =
+            (verified, domain_results.get_mut(&planned_update.key))
+        {
+            domain_result.outcome = Some(DomainOutcome::Failed);
+            domain_result.ip_address = None;
+            domain_result.errors.push(e);
+        }
+    }
+}
+
+async fn verify_record(
+    planned_update: &PlannedUpdate<'_, Cloudflare>,
+    timeout_ms: Option<u64>,
+) -> Result<(), String> {
+    let content = read_record_content(planned_update.domain, timeout_ms).await?;
+
+    match content.parse::<Address>() {
+        Ok(address) if address == planned_update.ip_address => Ok(()),
+        _ => Err("verify failed: sent ".to_string()
+            + &planned_update.ip_address.to_string()
+            + " but the record holds "
+            + &content),
+    }
+}
+
+/*
+https://developers.cloudflare.com/api/operations/dns-records-for-a-zone-dns-record-details
+
+GET Request
+Reads the content of a dns record without changing it
+*/
+pub async fn read_record_content(
+    domain: &Cloudflare,
+    timeout_ms: Option<u64>,
+) -> Result<String, String> {
+    let request = get_cloudflare_read_req(domain)?;
+
+    let response = requests::boxed_request_http1_tls_response(request);
+    let res = requests::with_timeout(timeout_ms, response).await?;
+    verify_resposne(domain, &res)?;
+
+    let body: serde_json::Value = match serde_json::from_str(&res.body) {
+        Ok(body) => body,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    match body
+        .pointer("/result/content")
+        .and_then(|content| content.as_str())
+    {
+        Some(content) => Ok(content.to_string()),
+        _ => Err("record content not found in response".to_string()),
+    }
+}
+
+/*
+https://developers.cloudflare.com/api/operations/zones-get
+https://developers.cloudflare.com/api/operations/dns-records-for-a-zone-list-dns-records
+
+GET Requests
+Looks up the zone_id of a zone and the dns_record_id of a record by name
+
+Only a ddclient import looks records up, once per imported host. Updates
+address records by the zone_id and dns_record_id of their config.
+*/
+pub async fn lookup_record_ids(
+    domain: &Cloudflare,
+    zone: &str,
+    timeout_ms: Option<u64>,
+) -> Result<(String, String), String> {
+    let zones_uri =
+        requests::append_query_params(&(API_URL.to_string() + "zones"), &[("name", zone)])?;
+    let zone_id = match request_first_result_id(domain, &zones_uri, timeout_ms).await? {
+        Some(zone_id) => zone_id,
+        _ => return Err("zone ".to_string() + zone + " not found"),
+    };
+
+    let records_uri = requests::append_query_params(
+        &(API_URL.to_string() + "zones/" + &zone_id + "/dns_records"),
+        &[("type", &domain.r#type), ("name", &domain.name)],
+    )?;
+    match request_first_result_id(domain, &records_uri, timeout_ms).await? {
+        Some(dns_record_id) => Ok((zone_id, dns_record_id)),
+        _ => Err(domain.r#type.clone() + " record " + &domain.name + " not found in zone " + zone),
+    }
+}
+
+// list responses hold matches in result, ie: {"result": [{"id": "023e105f4ecef8ad9ca31a8372d0c353"}]}
+async fn request_first_result_id(
+    domain: &Cloudflare,
+    uri: &str,
+    timeout_ms: Option<u64>,
+) -> Result<Option<String>, String> {
+    let request = get_cloudflare_get_req(domain, uri)?;
+
+    let response = requests::boxed_request_http1_tls_response(request);
+    let res = requests::with_timeout(timeout_ms, response).await?;
+    if let Err(e) = verify_resposne(domain, &res) {
+        let api_errors = get_api_errors(&res);
+        return match api_errors.is_empty() {
+            true => Err(e),
+            false => Err(api_errors.join(", ")),
+        };
+    }
+
+    let body: serde_json::Value = match serde_json::from_str(&res.body) {
+        Ok(body) => body,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    Ok(body
+        .pointer("/result/0/id")
+        .and_then(|id| id.as_str())
+        .map(|id| id.to_string()))
+}
+
+fn verify_resposne(domain: &Cloudflare, res: &ResponseJson) -> Result<(), String> {
+    if res.status_code < 200 || res.status_code >= 300 {
+        return Err("response status ".to_string() + &res.status_code.to_string());
+    }
+
+    let success_predicate = match &domain.success_predicate {
+        Some(predicate) => predicate.as_str(),
+        _ => DEFAULT_SUCCESS_PREDICATE,
+    };
+
+    requests::check_json_predicate(&res.body, success_predicate)
+}
+
+// the cloudflare api lists why a request failed, ie: {"errors": [{"code": 9109, "message": "Invalid access token"}]}
+fn get_api_errors(res: &ResponseJson) -> Vec<String> {
+    let body: serde_json::Value = match serde_json::from_str(&res.body) {
+        Ok(body) => body,
+        _ => return Vec::new(),
+    };
+    let api_errors = match body.get("errors").and_then(|errors| errors.as_array()) {
+        Some(api_errors) => api_errors,
+        _ => return Vec::new(),
+    };
+
+    let mut errors = Vec::<String>::new();
+    for api_error in api_errors {
+        let code = match api_error.get("code") {
+            Some(code) => code.to_string(),
+            _ => "unknown".to_string(),
+        };
+        let message = api_error
+            .get("message")
+            .and_then(|message| message.as_str())
+            .unwrap_or("no message");
+        errors.push("cloudflare error ".to_string() + &code + ": " + message);
+    }
+
+    errors
+}
+
+fn get_record_uri(domain: &Cloudflare) -> String {
+    API_URL.to_string() + "zones/" + &domain.zone_id + "/dns_records/" + &domain.dns_record_id
+}
+
+fn get_cloudflare_read_req(domain: &Cloudflare) -> Result<Request<Full<Bytes>>, String> {
+    get_cloudflare_get_req(domain, &get_record_uri(domain))
+}
+
+fn get_cloudflare_get_req(domain: &Cloudflare, uri: &str) -> Result<Request<Full<Bytes>>, String> {
+    let auth_value = "Bearer ".to_string() + &domain.api_token;
+
+    match Request::builder()
+        .method("GET")
+        .uri(uri)
+        .header(hyper::header::HOST, "api.cloudflare.com")
+        .header("X-Auth-Email", &domain.email)
+        .header(hyper::header::AUTHORIZATION, auth_value)
+        .body(Full::new(Bytes::new()))
+    {
+        Ok(req) => Ok(req),
+        Err(e) => Err(e.to_string()),
+    }
 }
 
-fn get_cloudflare_req(domain: &Cloudflare, ip_addr: &str) -> Result<Request<Full<Bytes>>, String> {
-    let uri_str = "https://api.cloudflare.com/client/v4/zones/".to_string()
-        + &domain.zone_id
-        + "/dns_records/"
-        + &domain.dns_record_id;
+fn get_cloudflare_req(
+    domain: &Cloudflare,
+    ip_addr: &Address,
+) -> Result<Request<Full<Bytes>>, String> {
+    let uri_str = get_record_uri(domain);
 
     let auth_value = "Bearer ".to_string() + &domain.api_token;
 
     let body = CloudflareRequestBody {
         content: ip_addr.to_string(),
         name: domain.name.clone(),
-        proxied: domain.proxied.clone(),
+        proxied: domain.proxied,
         r#type: domain.r#type.clone(),
         comment: domain.comment.clone(),
         tags: domain.tags.clone(),
-        ttl: domain.ttl.clone(),
+        ttl: domain.ttl,
     };
 
     let body_str = match serde_json::to_string(&body) {
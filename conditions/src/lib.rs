@@ -0,0 +1,215 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time;
+
+/*
+    Conditions gate a domain update on the state of the local network.
+    Every property of a condition must hold for the domain to be updated.
+
+    Conditions are evaluated once per run and shared between domains.
+*/
+
+const DEFAULT_REACHABLE_TIMEOUT_MS: u64 = 3000;
+
+#[derive(Clone, Serialize, Deserialize, Debug, Hash, PartialEq, Eq)]
+pub struct Condition {
+    pub interface_up: Option<String>,
+    pub interface_down: Option<String>,
+    pub reachable: Option<String>,
+    #[serde(default, deserialize_with = "durations::deserialize_optional_ms")]
+    pub reachable_timeout_ms: Option<u64>,
+}
+
+// an Err describes why a condition was not met
+pub type ConditionResults = HashMap<Condition, Result<(), String>>;
+
+pub fn validate(condition: &Condition) -> Vec<String> {
+    let mut errors = Vec::<String>::new();
+
+    if let Some(address) = &condition.reachable {
+        let valid_port = match address.rsplit_once(':') {
+            Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok(),
+            _ => false,
+        };
+        if !valid_port {
+            errors.push("condition.reachable: ".to_string() + address + " is not host:port");
+        }
+    }
+
+    if condition.reachable_timeout_ms == Some(0) {
+        errors.push("condition.reachable_timeout_ms: must be greater than 0".to_string());
+    }
+
+    errors
+}
+
+pub async fn evaluate_conditions(conditions: Vec<&Condition>) -> ConditionResults {
+    let mut condition_results = ConditionResults::new();
+
+    for condition in conditions {
+        if condition_results.contains_key(condition) {
+            continue;
+        }
+
+        let result = evaluate_condition(condition).await;
+        condition_results.insert(condition.clone(), result);
+    }
+
+    condition_results
+}
+
+// returns the reason a domain's condition was not met
+pub fn condition_not_met(
+    condition_results: &ConditionResults,
+    condition: &Option<Condition>,
+) -> Option<String> {
+    let condition = match condition {
+        Some(c) => c,
+        _ => return None,
+    };
+
+    match condition_results.get(condition) {
+        Some(Ok(())) => None,
+        Some(Err(e)) => Some(e.clone()),
+        _ => Some("condition was not evaluated".to_string()),
+    }
+}
+
+async fn evaluate_condition(condition: &Condition) -> Result<(), String> {
+    if let Some(name) = &condition.interface_up {
+        if !interface_is_up(name)? {
+            return Err("condition not met: interface ".to_string() + name + " is down");
+        }
+    }
+
+    if let Some(name) = &condition.interface_down {
+        if interface_is_up(name)? {
+            return Err("condition not met: interface ".to_string() + name + " is up");
+        }
+    }
+
+    if let Some(address) = &condition.reachable {
+        let timeout_ms = match condition.reachable_timeout_ms {
+            Some(ms) => ms,
+            _ => DEFAULT_REACHABLE_TIMEOUT_MS,
+        };
+
+        if let Err(e) = probe_address(address, timeout_ms).await {
+            return Err("condition not met: ".to_string() + address + " is unreachable, " + &e);
+        }
+    }
+
+    Ok(())
+}
+
+// tcp connect probe
+async fn probe_address(address: &str, timeout_ms: u64) -> Result<(), String> {
+    match time::timeout(
+        Duration::from_millis(timeout_ms),
+        TcpStream::connect(address),
+    )
+    .await
+    {
+        Ok(Ok(_stream)) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("timed out after ".to_string() + &timeout_ms.to_string() + "ms"),
+    }
+}
+
+// an interface that does not exist is considered down
+#[cfg(unix)]
+fn interface_is_up(name: &str) -> Result<bool, String> {
+    use std::ffi::CStr;
+
+    let up_flags = (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_uint;
+
+    let mut addrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut addrs) } != 0 {
+        return Err(std::io::Error::last_os_error().to_string());
+    }
+
+    let mut is_up = false;
+    let mut cursor = addrs;
+    while !cursor.is_null() {
+        // getifaddrs returns a linked list that is valid until freeifaddrs
+        let ifaddr = unsafe { &*cursor };
+        let ifaddr_name = unsafe { CStr::from_ptr(ifaddr.ifa_name) };
+        if ifaddr_name.to_bytes() == name.as_bytes() && ifaddr.ifa_flags & up_flags == up_flags {
+            is_up = true;
+            break;
+        }
+        cursor = ifaddr.ifa_next;
+    }
+
+    unsafe { libc::freeifaddrs(addrs) };
+
+    Ok(is_up)
+}
+
+#[cfg(not(unix))]
+fn interface_is_up(_name: &str) -> Result<bool, String> {
+    Err("interface conditions are not supported on this platform".to_string())
+}
+
+// addresses assigned to local network interfaces
+pub fn get_interface_addresses() -> Result<Vec<IpAddr>, String> {
+    read_interface_addresses(None)
+}
+
+// addresses assigned to a single network interface, ie: eth0
+pub fn get_addresses_of_interface(name: &str) -> Result<Vec<IpAddr>, String> {
+    read_interface_addresses(Some(name))
+}
+
+#[cfg(unix)]
+fn read_interface_addresses(name: Option<&str>) -> Result<Vec<IpAddr>, String> {
+    use std::ffi::CStr;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    let mut addrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut addrs) } != 0 {
+        return Err(std::io::Error::last_os_error().to_string());
+    }
+
+    let mut interface_addresses = Vec::<IpAddr>::new();
+    let mut cursor = addrs;
+    while !cursor.is_null() {
+        let ifaddr = unsafe { &*cursor };
+        cursor = ifaddr.ifa_next;
+        if ifaddr.ifa_addr.is_null() {
+            continue;
+        }
+        if let Some(name) = name {
+            let ifaddr_name = unsafe { CStr::from_ptr(ifaddr.ifa_name) };
+            if ifaddr_name.to_bytes() != name.as_bytes() {
+                continue;
+            }
+        }
+
+        // the family of a sockaddr decides how it is read
+        match unsafe { (*ifaddr.ifa_addr).sa_family } as libc::c_int {
+            libc::AF_INET => {
+                let sockaddr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in) };
+                let octets = sockaddr.sin_addr.s_addr.to_ne_bytes();
+                interface_addresses.push(IpAddr::V4(Ipv4Addr::from(octets)));
+            }
+            libc::AF_INET6 => {
+                let sockaddr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in6) };
+                interface_addresses.push(IpAddr::V6(Ipv6Addr::from(sockaddr.sin6_addr.s6_addr)));
+            }
+            _ => {}
+        }
+    }
+
+    unsafe { libc::freeifaddrs(addrs) };
+
+    Ok(interface_addresses)
+}
+
+#[cfg(not(unix))]
+fn read_interface_addresses(_name: Option<&str>) -> Result<Vec<IpAddr>, String> {
+    Err("interface addresses are not supported on this platform".to_string())
+}
@@ -0,0 +1,158 @@
+/*
+    Internationalized hostnames are converted to punycode labels before they
+    are validated and sent to a dns service, ie: bücher.example becomes
+    xn--bcher-kva.example (RFC 3492).
+
+    Labels that are not ascii are lowercased. Unicode normalization and the
+    rest of the UTS 46 mapping are not applied, so hostnames should be
+    written in their normalized form.
+*/
+
+const ACE_PREFIX: &str = "xn--";
+
+const BASE: u32 = 36;
+const T_MIN: u32 = 1;
+const T_MAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+const INITIAL_BIAS: u32 = 72;
+const INITIAL_N: u32 = 128;
+
+pub fn hostname_to_ascii(hostname: &str) -> Result<String, String> {
+    let mut labels = Vec::<String>::new();
+    for label in hostname.split('.') {
+        if label.is_ascii() {
+            labels.push(label.to_string());
+            continue;
+        }
+
+        match encode_punycode(&label.to_lowercase()) {
+            Some(encoded) => labels.push(ACE_PREFIX.to_string() + &encoded),
+            _ => return Err("could not be converted to punycode: ".to_string() + label),
+        }
+    }
+
+    Ok(labels.join("."))
+}
+
+// none when a label is too long to encode
+fn encode_punycode(label: &str) -> Option<String> {
+    let code_points: Vec<u32> = label.chars().map(|c| c as u32).collect();
+
+    let mut output: String = label.chars().filter(|c| c.is_ascii()).collect();
+    let basic_len = output.len() as u32;
+    if basic_len > 0 {
+        output.push('-');
+    }
+
+    let mut n = INITIAL_N;
+    let mut delta: u32 = 0;
+    let mut bias = INITIAL_BIAS;
+    let mut handled = basic_len;
+    while (handled as usize) < code_points.len() {
+        let m = *code_points.iter().filter(|&&c| c >= n).min()?;
+        delta = delta.checked_add((m - n).checked_mul(handled + 1)?)?;
+        n = m;
+
+        for &c in &code_points {
+            if c < n {
+                delta = delta.checked_add(1)?;
+            }
+            if c != n {
+                continue;
+            }
+
+            let mut q = delta;
+            let mut k = BASE;
+            loop {
+                let t = match k {
+                    k if k <= bias => T_MIN,
+                    k if k >= bias + T_MAX => T_MAX,
+                    k => k - bias,
+                };
+                if q < t {
+                    break;
+                }
+                output.push(encode_digit(t + (q - t) % (BASE - t)));
+                q = (q - t) / (BASE - t);
+                k += BASE;
+            }
+            output.push(encode_digit(q));
+
+            bias = adapt(delta, handled + 1, handled == basic_len);
+            delta = 0;
+            handled += 1;
+        }
+
+        delta = delta.checked_add(1)?;
+        n += 1;
+    }
+
+    Some(output)
+}
+
+fn adapt(delta: u32, num_points: u32, first_time: bool) -> u32 {
+    let mut delta = match first_time {
+        true => delta / DAMP,
+        false => delta / 2,
+    };
+    delta += delta / num_points;
+
+    let mut k = 0;
+    while delta > ((BASE - T_MIN) * T_MAX) / 2 {
+        delta /= BASE - T_MIN;
+        k += BASE;
+    }
+
+    k + (BASE - T_MIN + 1) * delta / (delta + SKEW)
+}
+
+// 0 to 25 are a to z, 26 to 35 are 0 to 9
+fn encode_digit(digit: u32) -> char {
+    match digit {
+        0..=25 => (b'a' + digit as u8) as char,
+        _ => (b'0' + (digit - 26) as u8) as char,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii_hostnames_are_unchanged() {
+        for hostname in [
+            "example.com",
+            "Example.COM.",
+            "_acme-challenge.example.com",
+            "*.a.b",
+        ] {
+            assert_eq!(hostname_to_ascii(hostname).unwrap(), hostname);
+        }
+    }
+
+    #[test]
+    fn labels_are_converted_to_punycode() {
+        assert_eq!(
+            hostname_to_ascii("bücher.example").unwrap(),
+            "xn--bcher-kva.example"
+        );
+        assert_eq!(
+            hostname_to_ascii("münchen.de").unwrap(),
+            "xn--mnchen-3ya.de"
+        );
+        assert_eq!(hostname_to_ascii("日本語.jp").unwrap(), "xn--wgv71a119e.jp");
+        assert_eq!(
+            hostname_to_ascii("ドメイン名例.jp").unwrap(),
+            "xn--eckwd4c7cu47r2wf.jp"
+        );
+    }
+
+    #[test]
+    fn labels_are_lowercased_before_conversion() {
+        assert_eq!(
+            hostname_to_ascii("BÜCHER.example").unwrap(),
+            "xn--bcher-kva.example"
+        );
+    }
+}
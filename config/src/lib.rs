@@ -1,33 +1,154 @@
 use serde::{Deserialize, Serialize};
-use serde_json;
 use std::fmt;
 use std::path;
 use tokio::fs;
 
 use ip_services::IpServices;
+use results::ConfigFingerprint;
 
-// ddns services
-#[cfg(feature = "cloudflare")]
-use cloudflare::Cloudflare;
-#[cfg(feature = "dyndns2")]
-use dyndns2::Dyndns2;
+mod idna;
+mod services;
+mod validation;
+mod windows;
 
-// add domain services here
-// beware of hydra
+pub use idna::hostname_to_ascii;
+pub use services::{get_service_names, DomainService};
+pub use windows::AllowedWindow;
+
+const DEFAULT_STATE_MAX_AGE_MS: u64 = 7 * 24 * 60 * 60 * 1000;
+const DEFAULT_RESULTS_BACKUPS: usize = 2;
+
+// fields that are hashed in config fingerprints, freedns update_url and query_get params carry tokens
+const SECRET_KEYS: [&str; 7] = [
+    "password",
+    "api_token",
+    "email",
+    "auth_header",
+    "services_from_url_auth_header",
+    "update_url",
+    "params",
+];
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnInvalidEntry {
+    #[default]
+    Fail,
+    Skip,
+}
+
+// an ip service or domain removed from the config by validation
+#[derive(Clone, Debug)]
+pub struct InvalidEntry {
+    pub entry: String,
+    pub hostname: Option<String>,
+    pub errors: Vec<String>,
+}
+
+// the user and group a run drops root privileges to
 #[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct Config {
-    pub results_filepath: path::PathBuf,
-    pub ip_services: IpServices,
-    #[cfg(feature = "dyndns2")]
-    pub dyndns2: Vec<Dyndns2>,
-    #[cfg(feature = "cloudflare")]
-    pub cloudflare: Vec<Cloudflare>,
+pub struct RunAs {
+    pub user: String,
+    pub group: Option<String>,
 }
 
+// the domains of every enabled dns service in the registry are properties of a config
+macro_rules! add_service_properties {
+    (
+        [$(($feature:literal, $property:ident, $domain:ty)),* $(,)?]
+        $(#[$meta:meta])*
+        pub struct $name:ident { $($properties:tt)* }
+    ) => {
+        $(#[$meta])*
+        pub struct $name {
+            $($properties)*
+            $(
+                #[cfg(feature = $feature)]
+                pub $property: Vec<$domain>,
+            )*
+        }
+    };
+}
+
+// beware of hydra
+for_each_service!(
+    add_service_properties,
+    #[derive(Clone, Serialize, Deserialize, Debug)]
+    pub struct Config {
+        pub results_filepath: path::PathBuf,
+        pub results_socket: Option<path::PathBuf>,
+        #[serde(default = "default_results_backups")]
+        pub results_backups: usize,
+        // ip_services_v4 names the ipv4 list of a dual-stack config
+        #[serde(default, alias = "ip_services_v4")]
+        pub ip_services: IpServices,
+        #[serde(default)]
+        pub ip_services_v6: IpServices,
+        pub services_from_url: Option<String>,
+        pub services_from_url_auth_header: Option<String>,
+        pub netns: Option<String>,
+        #[serde(default, deserialize_with = "durations::deserialize_optional_ms")]
+        pub ip_service_probe_timeout_ms: Option<u64>,
+        #[serde(
+            default = "default_ip_service_timeout_ms",
+            deserialize_with = "durations::deserialize_ms"
+        )]
+        pub ip_service_timeout_ms: u64,
+        #[serde(default, deserialize_with = "durations::deserialize_optional_ms")]
+        pub dns_update_timeout_ms: Option<u64>,
+        pub statsd_addr: Option<String>,
+        pub run_id_header: Option<String>,
+        pub allowed_hosts: Option<Vec<String>>,
+        #[serde(default)]
+        pub skip_updates_on_results_error: bool,
+        #[serde(
+            default = "default_state_max_age_ms",
+            deserialize_with = "durations::deserialize_ms"
+        )]
+        pub state_max_age_ms: u64,
+        #[serde(default, deserialize_with = "durations::deserialize_optional_ms")]
+        pub address_max_staleness_ms: Option<u64>,
+        #[serde(default)]
+        pub push_on_first_run: bool,
+        #[serde(default)]
+        pub suppress_updates_behind_cgnat: bool,
+        #[serde(default)]
+        pub block_updates_on_conflict: bool,
+        #[serde(default)]
+        pub treat_loss_as_change: bool,
+        #[serde(default)]
+        pub blocklist: Vec<String>,
+        pub run_as: Option<RunAs>,
+        pub fast_path: Option<String>,
+        #[serde(default)]
+        pub require_tls13: bool,
+        #[serde(default)]
+        pub verify_updates: bool,
+        #[serde(default)]
+        pub preflight_endpoints: bool,
+        pub allowed_windows: Option<Vec<AllowedWindow>>,
+        #[serde(default)]
+        pub on_invalid_entry: OnInvalidEntry,
+        #[serde(skip)]
+        pub invalid_entries: Vec<InvalidEntry>,
+    }
+);
+
 pub enum ConfigError<'a> {
     IoError(std::io::Error),
     JsonError(serde_json::Error),
     GenericError(&'a str),
+    ValidationError(Vec<String>),
+}
+
+impl fmt::Display for InvalidEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let entry = match &self.hostname {
+            Some(hostname) => self.entry.clone() + " (" + hostname + ")",
+            _ => self.entry.clone(),
+        };
+        write!(f, "{}: {}", entry, self.errors.join(", "))
+    }
 }
 
 impl fmt::Display for ConfigError<'_> {
@@ -36,11 +157,24 @@ impl fmt::Display for ConfigError<'_> {
             ConfigError::IoError(io_error) => write!(f, "{}", io_error),
             ConfigError::JsonError(json_error) => write!(f, "{}", json_error),
             ConfigError::GenericError(generic_error) => write!(f, "{}", generic_error,),
+            ConfigError::ValidationError(errors) => write!(f, "{}", errors.join("\n")),
         }
     }
 }
 
-pub async fn from_path(file_path: &path::Path) -> Result<Config, ConfigError> {
+fn default_state_max_age_ms() -> u64 {
+    DEFAULT_STATE_MAX_AGE_MS
+}
+
+fn default_ip_service_timeout_ms() -> u64 {
+    ip_services::DEFAULT_TIMEOUT_MS
+}
+
+fn default_results_backups() -> usize {
+    DEFAULT_RESULTS_BACKUPS
+}
+
+pub async fn from_path(file_path: &path::Path) -> Result<Config, ConfigError<'_>> {
     // get position relative to working directory
     let config_path = match path::absolute(file_path) {
         Ok(pb) => pb,
@@ -68,6 +202,23 @@ pub async fn from_path(file_path: &path::Path) -> Result<Config, ConfigError> {
 
     // find a way to verify the parent directory exists
     config.results_filepath = parent_dir.join(&config.results_filepath);
+    if let Some(results_socket) = &config.results_socket {
+        config.results_socket = Some(parent_dir.join(results_socket));
+    }
+
+    if let Err(errors) = validation::validate_config(&mut config) {
+        return Err(ConfigError::ValidationError(errors));
+    }
 
     Ok(config)
 }
+
+// a fingerprint of the effective config keyed with the fingerprint key
+pub fn get_config_fingerprint(config: &Config, key: &[u8]) -> Result<ConfigFingerprint, String> {
+    let config_value = match serde_json::to_value(config) {
+        Ok(v) => v,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    results::create_config_fingerprint(&config_value, &SECRET_KEYS, key)
+}
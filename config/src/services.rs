@@ -0,0 +1,319 @@
+use conditions::Condition;
+use results::ComparePrefixLen;
+
+/*
+    The registry of dns services.
+
+    Every list of dns services is built from the registry: the properties
+    of a config, their validation, and the planned updates of
+    domain_services. A service is its feature and config property, its
+    crate, and the domain type of its crate. Domain types implement
+    DomainService below.
+
+    A callback is called with the registry and any tokens that follow it,
+    ie: for_each_service!(callback, tokens) calls
+    callback!([("dyndns2", dyndns2, dyndns2::Dyndns2), ...] tokens)
+
+    Each service is also a feature of config and domain_services that
+    enables their "services" feature.
+*/
+#[macro_export]
+macro_rules! for_each_service {
+    ($callback:ident $(, $($tokens:tt)*)?) => {
+        $callback! {
+            [
+                // add more services here
+                ("dyndns2", dyndns2, dyndns2::Dyndns2),
+                ("cloudflare", cloudflare, cloudflare::Cloudflare),
+                ("query_get", query_get, query_get::QueryGet),
+                ("freedns", freedns, freedns::FreeDns),
+            ]
+            $($($tokens)*)?
+        }
+    };
+}
+
+// the properties of a domain every dns service shares
+pub trait DomainService {
+    // the config property of the hostname in validation errors
+    const HOSTNAME_FIELD: &'static str = "hostname";
+
+    fn hostname(&self) -> &String;
+    fn hostname_mut(&mut self) -> &mut String;
+    fn record_type(&self) -> Option<&str>;
+    fn condition(&self) -> &Option<Condition>;
+    fn priority(&self) -> Option<u32>;
+    fn min_update_interval_ms(&self) -> Option<u64>;
+    fn compare_prefix_len(&self) -> &Option<ComparePrefixLen>;
+    fn check_response_shape(&self) -> bool;
+    // the url preflighted before an update
+    fn endpoint(&self) -> &str;
+    // errors of the properties only this dns service has
+    fn validate(&self) -> Vec<String>;
+
+    // underscore names like _acme-challenge are only valid for records without an address
+    fn allows_underscore(&self) -> bool {
+        false
+    }
+}
+
+// properties of the same name in every domain type
+#[cfg(feature = "services")]
+macro_rules! shared_properties {
+    () => {
+        fn condition(&self) -> &Option<Condition> {
+            &self.condition
+        }
+
+        fn priority(&self) -> Option<u32> {
+            self.priority
+        }
+
+        fn min_update_interval_ms(&self) -> Option<u64> {
+            self.min_update_interval_ms
+        }
+
+        fn compare_prefix_len(&self) -> &Option<ComparePrefixLen> {
+            &self.compare_prefix_len
+        }
+
+        fn check_response_shape(&self) -> bool {
+            self.check_response_shape != Some(false)
+        }
+    };
+}
+
+// add more services here
+#[cfg(feature = "dyndns2")]
+impl DomainService for dyndns2::Dyndns2 {
+    shared_properties!();
+
+    fn hostname(&self) -> &String {
+        &self.hostname
+    }
+
+    fn hostname_mut(&mut self) -> &mut String {
+        &mut self.hostname
+    }
+
+    fn record_type(&self) -> Option<&str> {
+        self.record_type.as_deref()
+    }
+
+    fn endpoint(&self) -> &str {
+        &self.service_uri
+    }
+
+    fn validate(&self) -> Vec<String> {
+        dyndns2::validate(self)
+    }
+}
+
+#[cfg(feature = "cloudflare")]
+impl DomainService for cloudflare::Cloudflare {
+    const HOSTNAME_FIELD: &'static str = "name";
+
+    shared_properties!();
+
+    fn hostname(&self) -> &String {
+        &self.name
+    }
+
+    fn hostname_mut(&mut self) -> &mut String {
+        &mut self.name
+    }
+
+    fn record_type(&self) -> Option<&str> {
+        Some(&self.r#type)
+    }
+
+    fn endpoint(&self) -> &str {
+        cloudflare::API_URL
+    }
+
+    fn validate(&self) -> Vec<String> {
+        cloudflare::validate(self)
+    }
+
+    fn allows_underscore(&self) -> bool {
+        self.r#type != "A" && self.r#type != "AAAA"
+    }
+}
+
+#[cfg(feature = "query_get")]
+impl DomainService for query_get::QueryGet {
+    shared_properties!();
+
+    fn hostname(&self) -> &String {
+        &self.hostname
+    }
+
+    fn hostname_mut(&mut self) -> &mut String {
+        &mut self.hostname
+    }
+
+    fn record_type(&self) -> Option<&str> {
+        self.record_type.as_deref()
+    }
+
+    fn endpoint(&self) -> &str {
+        &self.service_uri
+    }
+
+    fn validate(&self) -> Vec<String> {
+        query_get::validate(self)
+    }
+}
+
+#[cfg(feature = "freedns")]
+impl DomainService for freedns::FreeDns {
+    shared_properties!();
+
+    fn hostname(&self) -> &String {
+        &self.hostname
+    }
+
+    fn hostname_mut(&mut self) -> &mut String {
+        &mut self.hostname
+    }
+
+    fn record_type(&self) -> Option<&str> {
+        self.record_type.as_deref()
+    }
+
+    fn endpoint(&self) -> &str {
+        &self.update_url
+    }
+
+    fn validate(&self) -> Vec<String> {
+        freedns::validate(self)
+    }
+}
+
+// the config properties of the enabled dns services
+macro_rules! enabled_services {
+    ([$(($feature:literal, $property:ident, $domain:ty)),* $(,)?]) => {
+        vec![$(
+            #[cfg(feature = $feature)]
+            stringify!($property),
+        )*]
+    };
+}
+
+pub fn get_service_names() -> Vec<&'static str> {
+    for_each_service!(enabled_services)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    // the features and properties of every service in the registry, enabled or not
+    macro_rules! registry {
+        ([$(($feature:literal, $property:ident, $domain:ty)),* $(,)?]) => {
+            vec![$(($feature, stringify!($property))),*]
+        };
+    }
+
+    // the features of a manifest other than default and services
+    fn get_service_features(manifest: &str) -> Vec<&str> {
+        manifest
+            .split("[features]")
+            .nth(1)
+            .unwrap_or("")
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(feature, _)| feature.trim())
+            .filter(|feature| *feature != "default" && *feature != "services")
+            .collect()
+    }
+
+    #[test]
+    fn features_are_the_properties_of_their_service() {
+        for (feature, property) in for_each_service!(registry) {
+            assert_eq!(feature, property);
+        }
+    }
+
+    #[test]
+    fn features_of_every_crate_are_in_the_registry() {
+        let mut registered: Vec<&str> = for_each_service!(registry)
+            .into_iter()
+            .map(|(feature, _)| feature)
+            .collect();
+        registered.sort();
+
+        let manifests = [
+            include_str!("../Cargo.toml"),
+            include_str!("../../domain_services/Cargo.toml"),
+        ];
+        for manifest in manifests {
+            let mut features = get_service_features(manifest);
+            features.sort();
+            assert_eq!(features, registered);
+        }
+    }
+
+    #[test]
+    fn serde_tags_are_the_enabled_services() {
+        let mut config_value = serde_json::json!({
+            "results_filepath": "results.json",
+            "ip_services": [],
+        });
+        for name in get_service_names() {
+            config_value[name] = serde_json::json!([]);
+        }
+        let config: Config = serde_json::from_value(config_value).unwrap();
+
+        // every domain list of a config is a service of the registry
+        let serialized = serde_json::to_value(&config).unwrap();
+        let mut domain_lists: Vec<&str> = serialized
+            .as_object()
+            .unwrap()
+            .iter()
+            .filter(|(_, value)| value.is_array())
+            .map(|(key, _)| key.as_str())
+            .filter(|key| !["ip_services", "ip_services_v6", "blocklist"].contains(key))
+            .collect();
+        domain_lists.sort();
+
+        let mut names = get_service_names();
+        names.sort();
+        assert_eq!(domain_lists, names);
+    }
+
+    #[cfg(feature = "services")]
+    #[test]
+    fn enabled_services_are_required_properties() {
+        for name in get_service_names() {
+            let mut config_value = serde_json::json!({
+                "results_filepath": "results.json",
+                "ip_services": [],
+            });
+            for other in get_service_names() {
+                if other != name {
+                    config_value[other] = serde_json::json!([]);
+                }
+            }
+
+            let e = serde_json::from_value::<Config>(config_value).unwrap_err();
+            assert_eq!(e.to_string(), "missing field `".to_string() + name + "`");
+        }
+    }
+
+    #[cfg(all(
+        feature = "dyndns2",
+        feature = "cloudflare",
+        feature = "query_get",
+        feature = "freedns"
+    ))]
+    #[test]
+    fn every_service_is_enabled_with_every_feature() {
+        let registered: Vec<&str> = for_each_service!(registry)
+            .into_iter()
+            .map(|(feature, _)| feature)
+            .collect();
+        assert_eq!(get_service_names(), registered);
+    }
+}
@@ -0,0 +1,510 @@
+use crate::{idna, Config, DomainService, InvalidEntry, OnInvalidEntry};
+use results::{AddressRange, ComparePrefixLen};
+
+/*
+    Entry errors only invalidate their own ip service or domain entry.
+    Global errors invalidate the entire config.
+
+    Invalid entries are removed from the config and kept in
+    invalid_entries so they can be skipped and reported.
+*/
+
+// takes the invalid domains of every dns service in the registry
+macro_rules! take_invalid_domains {
+    ([$(($feature:literal, $property:ident, $domain:ty)),* $(,)?] $config:ident) => {{
+        let mut invalid_entries = Vec::<InvalidEntry>::new();
+        $(
+            #[cfg(feature = $feature)]
+            {
+                invalid_entries.append(&mut take_invalid_entries(
+                    &mut $config.$property,
+                    stringify!($property),
+                    validate_domain,
+                    |domain| Some(domain.hostname().clone()),
+                ));
+                convert_hostnames(&mut $config.$property);
+            }
+        )*
+        invalid_entries
+    }};
+}
+
+pub fn validate_config(config: &mut Config) -> Result<(), Vec<String>> {
+    let mut invalid_entries = take_invalid_entries(
+        &mut config.ip_services,
+        "ip_services",
+        ip_services::validate,
+        |_| None,
+    );
+    invalid_entries.append(&mut take_invalid_entries(
+        &mut config.ip_services_v6,
+        "ip_services_v6",
+        ip_services::validate,
+        |_| None,
+    ));
+
+    invalid_entries.append(&mut crate::for_each_service!(take_invalid_domains, config));
+
+    let mut errors = Vec::<String>::new();
+    if config.on_invalid_entry == OnInvalidEntry::Fail {
+        for invalid_entry in &invalid_entries {
+            errors.push(invalid_entry.to_string());
+        }
+    }
+
+    errors.append(&mut get_global_errors(config));
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    config.invalid_entries = invalid_entries;
+
+    Ok(())
+}
+
+#[cfg_attr(not(feature = "services"), allow(dead_code))]
+fn validate_domain<T: DomainService>(domain: &T) -> Vec<String> {
+    let mut errors = domain.validate();
+    errors.append(&mut validate_hostname(
+        T::HOSTNAME_FIELD,
+        domain.hostname(),
+        domain.allows_underscore(),
+    ));
+    errors.append(&mut validate_compare_prefix_len(
+        domain.compare_prefix_len(),
+    ));
+    errors
+}
+
+fn get_global_errors(config: &Config) -> Vec<String> {
+    let mut errors = Vec::<String>::new();
+
+    // a config with only ip_services_v6 detects only ipv6
+    if config.ip_services.is_empty()
+        && config.ip_services_v6.is_empty()
+        && config.services_from_url.is_none()
+    {
+        errors.push("ip_services: no valid ip services".to_string());
+    }
+
+    if let Some(services_from_url) = &config.services_from_url {
+        if let Err(e) = requests::validate_url(services_from_url) {
+            errors.push("services_from_url: ".to_string() + &e);
+        }
+    }
+
+    if let Some(netns) = &config.netns {
+        if netns.is_empty() || netns.contains('/') || netns == "." || netns == ".." {
+            errors.push("netns: ".to_string() + netns + " is not a network namespace name");
+        }
+    }
+
+    if config.state_max_age_ms == 0 {
+        errors.push("state_max_age_ms: must be greater than 0".to_string());
+    }
+
+    if config.ip_service_probe_timeout_ms == Some(0) {
+        errors.push("ip_service_probe_timeout_ms: must be greater than 0".to_string());
+    }
+
+    if config.ip_service_timeout_ms == 0 {
+        errors.push("ip_service_timeout_ms: must be greater than 0".to_string());
+    }
+
+    if config.dns_update_timeout_ms == Some(0) {
+        errors.push("dns_update_timeout_ms: must be greater than 0".to_string());
+    }
+
+    if let Some(run_as) = &config.run_as {
+        if run_as.user.is_empty() {
+            errors.push("run_as.user: must not be empty".to_string());
+        }
+        if run_as.group.as_deref() == Some("") {
+            errors.push("run_as.group: must not be empty".to_string());
+        }
+        // ip services enter netns after privileges are dropped
+        if config.netns.is_some() {
+            errors.push(
+                "run_as: can not be used with netns, entering a namespace requires CAP_SYS_ADMIN"
+                    .to_string(),
+            );
+        }
+    }
+
+    if let Some(allowed_windows) = &config.allowed_windows {
+        if allowed_windows.is_empty() {
+            errors.push("allowed_windows: must not be empty".to_string());
+        }
+        for allowed_window in allowed_windows {
+            errors.append(&mut allowed_window.validate());
+        }
+    }
+
+    for range in &config.blocklist {
+        if let Err(e) = range.parse::<AddressRange>() {
+            errors.push("blocklist: ".to_string() + range + " " + &e);
+        }
+    }
+
+    if let Some(allowed_hosts) = &config.allowed_hosts {
+        for allowed_host in allowed_hosts {
+            let host = allowed_host.strip_prefix("*.").unwrap_or(allowed_host);
+            if host.is_empty() || host.contains('*') || host.contains('/') || host.contains(':') {
+                errors.push("allowed_hosts: ".to_string() + allowed_host + " is not a host");
+            }
+        }
+    }
+
+    if let Some(run_id_header) = &config.run_id_header {
+        let valid_header = !run_id_header.is_empty()
+            && run_id_header
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid_header {
+            errors.push("run_id_header: ".to_string() + run_id_header + " is not a header name");
+        }
+    }
+
+    if let Some(statsd_addr) = &config.statsd_addr {
+        let valid_port = match statsd_addr.rsplit_once(':') {
+            Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok(),
+            _ => false,
+        };
+        if !valid_port {
+            errors.push("statsd_addr: ".to_string() + statsd_addr + " is not host:port");
+        }
+    }
+
+    errors
+}
+
+// valid domains are sent to dns services in punycode
+#[cfg_attr(not(feature = "services"), allow(dead_code))]
+fn convert_hostnames<T: DomainService>(domains: &mut [T]) {
+    for domain in domains {
+        if let Ok(ascii_hostname) = idna::hostname_to_ascii(domain.hostname()) {
+            *domain.hostname_mut() = ascii_hostname;
+        }
+    }
+}
+
+/*
+    Hostnames are checked before they reach a dns service:
+    - at most 253 octets, an optional trailing dot is not counted
+    - labels of 1 to 63 octets
+    - letters, digits, and hyphens, labels do not start or end with a hyphen
+    - a wildcard "*" is only allowed as the first label
+
+    Internationalized hostnames are checked after their conversion to punycode,
+    ie: bücher.example is checked as xn--bcher-kva.example.
+*/
+#[cfg_attr(not(feature = "services"), allow(dead_code))]
+fn validate_hostname(field: &str, hostname: &str, allow_underscore: bool) -> Vec<String> {
+    let mut errors = Vec::<String>::new();
+
+    // empty hostnames are reported by dns services
+    if hostname.is_empty() {
+        return errors;
+    }
+
+    let ascii_hostname = match idna::hostname_to_ascii(hostname) {
+        Ok(ascii_hostname) => ascii_hostname,
+        Err(e) => {
+            errors.push(field.to_string() + ": " + hostname + " " + &e);
+            return errors;
+        }
+    };
+
+    // converted hostnames are shown in both forms, ie: bücher.example (xn--bcher-kva.example)
+    let shown_hostname = match ascii_hostname == hostname {
+        true => hostname.to_string(),
+        false => hostname.to_string() + " (" + &ascii_hostname + ")",
+    };
+
+    let name = ascii_hostname.strip_suffix('.').unwrap_or(&ascii_hostname);
+    if name.len() > 253 {
+        errors.push(field.to_string() + ": " + &shown_hostname + " is longer than 253 octets");
+    }
+
+    for (index, label) in name.split('.').enumerate() {
+        if let Err(e) = validate_label(label, index, allow_underscore) {
+            errors.push(field.to_string() + ": " + &shown_hostname + " " + &e);
+        }
+    }
+
+    errors
+}
+
+#[cfg_attr(not(feature = "services"), allow(dead_code))]
+fn validate_label(label: &str, index: usize, allow_underscore: bool) -> Result<(), String> {
+    if label.is_empty() {
+        return Err("has an empty label".to_string());
+    }
+    if label.len() > 63 {
+        return Err("has a label longer than 63 octets: ".to_string() + label);
+    }
+    if label == "*" && index == 0 {
+        return Ok(());
+    }
+
+    let label_chars = match label.strip_prefix('_') {
+        Some(rest) if allow_underscore => rest,
+        _ => label,
+    };
+    if !label_chars
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return Err("has a label with invalid characters: ".to_string() + label);
+    }
+    if label_chars.starts_with('-') || label.ends_with('-') {
+        return Err("has a label that starts or ends with a hyphen: ".to_string() + label);
+    }
+
+    Ok(())
+}
+
+#[cfg_attr(not(feature = "services"), allow(dead_code))]
+fn validate_compare_prefix_len(compare_prefix_len: &Option<ComparePrefixLen>) -> Vec<String> {
+    let mut errors = Vec::<String>::new();
+
+    if let Some(compare_prefix_len) = compare_prefix_len {
+        if compare_prefix_len.v4 > Some(32) {
+            errors.push("compare_prefix_len.v4: must be at most 32".to_string());
+        }
+        if compare_prefix_len.v6 > Some(128) {
+            errors.push("compare_prefix_len.v6: must be at most 128".to_string());
+        }
+    }
+
+    errors
+}
+
+fn take_invalid_entries<T>(
+    entries: &mut Vec<T>,
+    entry_name: &str,
+    validate: impl Fn(&T) -> Vec<String>,
+    get_hostname: impl Fn(&T) -> Option<String>,
+) -> Vec<InvalidEntry> {
+    let mut invalid_entries = Vec::<InvalidEntry>::new();
+    let mut index = 0;
+
+    entries.retain(|entry| {
+        let entry_index = index;
+        index += 1;
+
+        let errors = validate(entry);
+        if errors.is_empty() {
+            return true;
+        }
+
+        invalid_entries.push(InvalidEntry {
+            entry: entry_name.to_string() + "[" + &entry_index.to_string() + "]",
+            hostname: get_hostname(entry).filter(|hostname| !hostname.is_empty()),
+            errors,
+        });
+
+        false
+    });
+
+    invalid_entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_are_measured_after_punycode() {
+        // 60 octets as utf-8, 64 octets as xn--aaa...a-xxa
+        let label = "a".repeat(58) + "ü";
+        assert!(label.len() <= 63);
+
+        let hostname = label + ".example";
+        let errors = validate_hostname("hostname", &hostname, false);
+        assert_eq!(errors.len(), 1);
+        assert!(
+            errors[0].starts_with(&("hostname: ".to_string() + &hostname + " (xn--")),
+            "{}",
+            errors[0]
+        );
+        assert!(
+            errors[0].contains("has a label longer than 63 octets"),
+            "{}",
+            errors[0]
+        );
+    }
+
+    #[test]
+    fn hostnames_are_measured_after_punycode() {
+        // 5 labels of 47 octets as utf-8 are 239 octets with dots, and over 253 as punycode
+        let label = "a".repeat(45) + "ü";
+        let hostname = [label.as_str(); 5].join(".");
+        assert!(hostname.len() <= 253);
+
+        let errors = validate_hostname("hostname", &hostname, false);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(
+            errors[0].ends_with("is longer than 253 octets"),
+            "{}",
+            errors[0]
+        );
+    }
+
+    // a config with every required property and the given properties
+    fn config_with_properties(properties: &str) -> Config {
+        let mut config_value: serde_json::Value = serde_json::from_str(
+            r#"{
+                "results_filepath": "results.json",
+                "ip_services": [],
+                "dyndns2": [],
+                "cloudflare": [],
+                "query_get": [],
+                "freedns": []
+            }"#,
+        )
+        .unwrap();
+        let properties: serde_json::Value = serde_json::from_str(properties).unwrap();
+        for (name, value) in properties.as_object().unwrap() {
+            config_value[name] = value.clone();
+        }
+        serde_json::from_value(config_value).unwrap()
+    }
+
+    fn config_with_ip_services(ip_services: &str, ip_services_v6: &str) -> Config {
+        let properties = r#"{"ip_services": IP_SERVICES, "ip_services_v6": IP_SERVICES_V6}"#
+            .replace("IP_SERVICES_V6", ip_services_v6)
+            .replace("IP_SERVICES", ip_services);
+        config_with_properties(&properties)
+    }
+
+    const MIXED_ENTRIES: &str = r#"[
+        ["https://ip.example/", "address_as_body"],
+        ["https://ip.example/xml", "address_as_xml"]
+    ]"#;
+    const ENTRY_ERROR: &str = "ip_services[1]: response type address_as_xml is not supported";
+    const GLOBAL_ERROR: &str = "netns: ../netns is not a network namespace name";
+
+    #[test]
+    fn skipped_entries_leave_only_global_errors() {
+        let properties =
+            r#"{"on_invalid_entry": "skip", "netns": "../netns", "ip_services": IP_SERVICES}"#
+                .replace("IP_SERVICES", MIXED_ENTRIES);
+        let mut config = config_with_properties(&properties);
+
+        assert_eq!(
+            validate_config(&mut config),
+            Err(vec![GLOBAL_ERROR.to_string()])
+        );
+    }
+
+    #[test]
+    fn failed_entries_are_reported_before_global_errors() {
+        let properties = r#"{"netns": "../netns", "ip_services": IP_SERVICES}"#
+            .replace("IP_SERVICES", MIXED_ENTRIES);
+        let mut config = config_with_properties(&properties);
+
+        assert_eq!(
+            validate_config(&mut config),
+            Err(vec![ENTRY_ERROR.to_string(), GLOBAL_ERROR.to_string()])
+        );
+    }
+
+    #[test]
+    fn skipped_entries_are_removed_and_kept() {
+        let properties = r#"{"on_invalid_entry": "skip", "ip_services": IP_SERVICES}"#
+            .replace("IP_SERVICES", MIXED_ENTRIES);
+        let mut config = config_with_properties(&properties);
+
+        assert_eq!(validate_config(&mut config), Ok(()));
+        assert_eq!(config.ip_services.len(), 1);
+        assert_eq!(config.ip_services[0].url, "https://ip.example/");
+        assert_eq!(config.invalid_entries.len(), 1);
+        assert_eq!(config.invalid_entries[0].to_string(), ENTRY_ERROR);
+        assert_eq!(config.invalid_entries[0].hostname, None);
+    }
+
+    #[test]
+    fn skipped_entries_do_not_hide_missing_ip_services() {
+        let properties = r#"{"on_invalid_entry": "skip", "ip_services": [["https://ip.example/", "address_as_xml"]]}"#;
+        let mut config = config_with_properties(properties);
+
+        assert_eq!(
+            validate_config(&mut config),
+            Err(vec!["ip_services: no valid ip services".to_string()])
+        );
+    }
+
+    #[cfg(feature = "query_get")]
+    #[test]
+    fn skipped_domains_keep_their_hostname() {
+        let properties = r#"{
+            "on_invalid_entry": "skip",
+            "ip_services": [["https://ip.example/", "address_as_body"]],
+            "query_get": [
+                {"service_uri": "https://example.com/update", "hostname": "a.example", "ip_param": "ip"},
+                {"service_uri": "not a url", "hostname": "b.example", "ip_param": "ip"}
+            ]
+        }"#;
+        let mut config = config_with_properties(properties);
+
+        assert_eq!(validate_config(&mut config), Ok(()));
+        assert_eq!(config.query_get.len(), 1);
+        assert_eq!(config.invalid_entries.len(), 1);
+        assert_eq!(config.invalid_entries[0].entry, "query_get[1]");
+        assert_eq!(
+            config.invalid_entries[0].hostname.as_deref(),
+            Some("b.example")
+        );
+    }
+
+    #[test]
+    fn run_as_is_invalid_with_netns() {
+        let properties = r#"{
+            "ip_services": [["https://ip.example/", "address_as_body"]],
+            "run_as": {"user": "nobody"},
+            "netns": "vpn"
+        }"#;
+        let mut config = config_with_properties(properties);
+        assert_eq!(
+            validate_config(&mut config),
+            Err(vec![
+                "run_as: can not be used with netns, entering a namespace requires CAP_SYS_ADMIN"
+                    .to_string()
+            ])
+        );
+
+        // either of them alone is valid
+        for properties in [
+            r#"{"ip_services": [["https://ip.example/", "address_as_body"]], "run_as": {"user": "nobody"}}"#,
+            r#"{"ip_services": [["https://ip.example/", "address_as_body"]], "netns": "vpn"}"#,
+        ] {
+            let mut config = config_with_properties(properties);
+            assert_eq!(validate_config(&mut config), Ok(()));
+        }
+    }
+
+    #[test]
+    fn configs_with_only_ip_services_v6_are_valid() {
+        let mut config =
+            config_with_ip_services("[]", r#"[["https://[::1]/", "address_as_body"]]"#);
+        assert_eq!(validate_config(&mut config), Ok(()));
+        assert!(config.ip_services.is_empty());
+    }
+
+    #[test]
+    fn configs_without_ip_services_are_invalid() {
+        let mut config = config_with_ip_services("[]", "[]");
+        assert_eq!(
+            validate_config(&mut config),
+            Err(vec!["ip_services: no valid ip services".to_string()])
+        );
+    }
+
+    #[test]
+    fn internationalized_hostnames_are_valid() {
+        assert!(validate_hostname("hostname", "bücher.example", false).is_empty());
+        assert!(validate_hostname("name", "日本語.jp", false).is_empty());
+        assert!(validate_hostname("hostname", "xn--bcher-kva.example", false).is_empty());
+    }
+}
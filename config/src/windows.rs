@@ -0,0 +1,232 @@
+use serde::{Deserialize, Serialize};
+
+/*
+    Allowed windows limit dns updates to certain days and times in UTC.
+
+    A window without days applies to every day. A window that ends before
+    it starts spans midnight, ie: 22:00 to 02:00, and the hours after
+    midnight belong to the day it started.
+
+    A timezone of "local" reads the offset of the host at each timestamp,
+    so windows follow daylight saving time. A fixed offset, ie: "+05:30",
+    never changes.
+*/
+
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct AllowedWindow {
+    pub days: Option<Vec<String>>,
+    pub start: String,
+    pub end: String,
+    // utc when missing
+    pub timezone: Option<String>,
+}
+
+impl AllowedWindow {
+    // invalid windows are rejected by config validation and never contain a time
+    pub fn contains(&self, timestamp_ms: u128) -> bool {
+        let (start, end) = match (parse_minutes(&self.start), parse_minutes(&self.end)) {
+            (Ok(start), Ok(end)) => (start, end),
+            _ => return false,
+        };
+
+        let utc_seconds = (timestamp_ms / 1000) as i64;
+        let offset_seconds = match get_offset_seconds(&self.timezone, utc_seconds) {
+            Ok(offset_seconds) => offset_seconds,
+            _ => return false,
+        };
+        let seconds = match u128::try_from(utc_seconds + offset_seconds) {
+            Ok(seconds) => seconds,
+            _ => return false,
+        };
+        let minute_of_day = ((seconds % 86400) / 60) as u32;
+        // 1970-01-01 was a thursday
+        let weekday = ((seconds / 86400 + 3) % 7) as usize;
+        let prev_weekday = (weekday + 6) % 7;
+
+        if start <= end {
+            return self.applies_to(weekday) && start <= minute_of_day && minute_of_day < end;
+        }
+
+        (self.applies_to(weekday) && start <= minute_of_day)
+            || (self.applies_to(prev_weekday) && minute_of_day < end)
+    }
+
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::<String>::new();
+
+        if let Err(e) = parse_minutes(&self.start) {
+            errors.push("allowed_windows.start: ".to_string() + &e);
+        }
+        if let Err(e) = parse_minutes(&self.end) {
+            errors.push("allowed_windows.end: ".to_string() + &e);
+        }
+        if let Err(e) = get_offset_seconds(&self.timezone, 0) {
+            errors.push("allowed_windows.timezone: ".to_string() + &e);
+        }
+        if self.start == self.end {
+            errors.push("allowed_windows: start and end must differ".to_string());
+        }
+        if let Some(days) = &self.days {
+            for day in days {
+                if !WEEKDAYS.contains(&day.to_lowercase().as_str()) {
+                    errors.push("allowed_windows.days: ".to_string() + day + " is not a weekday");
+                }
+            }
+        }
+
+        errors
+    }
+
+    fn applies_to(&self, weekday: usize) -> bool {
+        match &self.days {
+            Some(days) => days
+                .iter()
+                .any(|day| day.to_lowercase() == WEEKDAYS[weekday]),
+            _ => true,
+        }
+    }
+}
+
+// minutes since midnight of a time like 09:30, 24:00 is the end of a day
+fn parse_minutes(time: &str) -> Result<u32, String> {
+    let (hours, minutes) = match time.split_once(':') {
+        Some((hours, minutes)) => (hours.parse::<u32>(), minutes.parse::<u32>()),
+        _ => return Err(time.to_string() + " is not HH:MM"),
+    };
+
+    match (hours, minutes) {
+        (Ok(24), Ok(0)) => Ok(24 * 60),
+        (Ok(hours), Ok(minutes)) if hours < 24 && minutes < 60 => Ok(hours * 60 + minutes),
+        _ => Err(time.to_string() + " is not HH:MM"),
+    }
+}
+
+// seconds east of utc at a timestamp in seconds
+fn get_offset_seconds(timezone: &Option<String>, utc_seconds: i64) -> Result<i64, String> {
+    let timezone = match timezone {
+        Some(timezone) => timezone,
+        _ => return Ok(0),
+    };
+
+    match timezone.to_lowercase().as_str() {
+        "utc" => Ok(0),
+        "local" => get_local_offset_seconds(utc_seconds),
+        _ => parse_offset_seconds(timezone),
+    }
+}
+
+// tm_gmtoff includes daylight saving time at the timestamp
+fn get_local_offset_seconds(utc_seconds: i64) -> Result<i64, String> {
+    let time = utc_seconds as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    match unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        true => Err("local time could not be read".to_string()),
+        false => Ok(tm.tm_gmtoff as i64),
+    }
+}
+
+// a fixed offset like +05:30 or -08:00
+fn parse_offset_seconds(offset: &str) -> Result<i64, String> {
+    let error = offset.to_string() + " is not utc, local, or +HH:MM";
+    let (sign, time) = match (offset.strip_prefix('+'), offset.strip_prefix('-')) {
+        (Some(time), _) => (1, time),
+        (_, Some(time)) => (-1, time),
+        _ => return Err(error),
+    };
+
+    match parse_minutes(time) {
+        Ok(minutes) if minutes < 24 * 60 => Ok(sign * minutes as i64 * 60),
+        _ => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-01-01 was a monday
+    const MONDAY_MS: u128 = 1_704_067_200_000;
+    const HOUR_MS: u128 = 3_600_000;
+
+    fn get_window(
+        days: Option<&[&str]>,
+        start: &str,
+        end: &str,
+        timezone: Option<&str>,
+    ) -> AllowedWindow {
+        AllowedWindow {
+            days: days.map(|days| days.iter().map(|day| day.to_string()).collect()),
+            start: start.to_string(),
+            end: end.to_string(),
+            timezone: timezone.map(|timezone| timezone.to_string()),
+        }
+    }
+
+    #[test]
+    fn windows_without_a_timezone_are_utc() {
+        let window = get_window(None, "09:00", "17:00", None);
+        assert!(!window.contains(MONDAY_MS + 8 * HOUR_MS));
+        assert!(window.contains(MONDAY_MS + 9 * HOUR_MS));
+        assert!(!window.contains(MONDAY_MS + 17 * HOUR_MS));
+
+        let utc_window = get_window(None, "09:00", "17:00", Some("UTC"));
+        assert!(utc_window.contains(MONDAY_MS + 9 * HOUR_MS));
+    }
+
+    #[test]
+    fn fixed_offsets_shift_windows() {
+        // 09:00 at +05:30 is 03:30 utc
+        let window = get_window(None, "09:00", "17:00", Some("+05:30"));
+        assert!(!window.contains(MONDAY_MS + 3 * HOUR_MS));
+        assert!(window.contains(MONDAY_MS + 3 * HOUR_MS + HOUR_MS / 2));
+        assert!(!window.contains(MONDAY_MS + 11 * HOUR_MS + HOUR_MS / 2));
+    }
+
+    #[test]
+    fn negative_offsets_shift_weekdays() {
+        // monday 02:00 utc is sunday 18:00 at -08:00
+        let window = get_window(Some(&["sun"]), "17:00", "19:00", Some("-08:00"));
+        assert!(window.contains(MONDAY_MS + 2 * HOUR_MS));
+
+        let window = get_window(Some(&["mon"]), "17:00", "19:00", Some("-08:00"));
+        assert!(!window.contains(MONDAY_MS + 2 * HOUR_MS));
+    }
+
+    #[test]
+    fn windows_spanning_midnight_use_the_local_day() {
+        // saturday 22:00 to sunday 02:00 at +02:00 is saturday 20:00 to midnight utc
+        let window = get_window(Some(&["sat"]), "22:00", "02:00", Some("+02:00"));
+        let saturday_ms = MONDAY_MS + 5 * 24 * HOUR_MS;
+        assert!(window.contains(saturday_ms + 20 * HOUR_MS));
+        assert!(window.contains(saturday_ms + 23 * HOUR_MS));
+        assert!(!window.contains(saturday_ms + 24 * HOUR_MS));
+    }
+
+    #[test]
+    fn local_offsets_are_read_from_the_host() {
+        let offset_seconds = get_offset_seconds(&Some("local".to_string()), 0).unwrap();
+        assert!(offset_seconds.abs() <= 14 * 3600);
+        assert!(get_window(None, "00:00", "24:00", Some("local")).contains(MONDAY_MS));
+    }
+
+    #[test]
+    fn timezones_are_validated() {
+        for timezone in ["utc", "local", "+00:00", "-12:00", "+14:00", "+05:45"] {
+            let window = get_window(None, "09:00", "17:00", Some(timezone));
+            assert!(window.validate().is_empty(), "{}", timezone);
+        }
+        for timezone in ["", "05:30", "+5", "+24:00", "+05:60", "Europe/Berlin"] {
+            let window = get_window(None, "09:00", "17:00", Some(timezone));
+            assert_eq!(
+                window.validate(),
+                vec![
+                    "allowed_windows.timezone: ".to_string()
+                        + timezone
+                        + " is not utc, local, or +HH:MM"
+                ],
+            );
+        }
+    }
+}
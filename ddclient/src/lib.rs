@@ -0,0 +1,527 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path;
+use tokio::fs;
+
+use cloudflare::Cloudflare;
+use dyndns2::Dyndns2;
+use freedns::FreeDns;
+use ip_services::{IpService, IpServices};
+use query_get::QueryGet;
+
+/*
+    Translates a ddclient.conf into an update_ip config.
+    https://ddclient.net/usage.html
+
+    Settings on a line without hostnames are global and apply to every
+    following host. Settings on a line with hostnames only apply to
+    those hosts and override global settings.
+
+    Anything that cannot be translated is listed in the import notes.
+*/
+
+const DEFAULT_RESULTS_FILEPATH: &str = "./update_ip.results.json";
+const DEFAULT_DYNDNS2_SERVER: &str = "members.dyndns.org";
+const DEFAULT_DYNDNS2_SCRIPT: &str = "/nic/update";
+const DEFAULT_DUCKDNS_SERVER: &str = "www.duckdns.org";
+const CLOUDFLARE_LOOKUP_TIMEOUT_MS: u64 = 10_000;
+
+// ddclient settings without an update_ip equivalent that are safe to drop
+const IGNORED_SETTINGS: [&str; 6] = ["daemon", "syslog", "pid", "ssl", "quiet", "verbose"];
+
+#[derive(Clone, Serialize, Debug)]
+pub struct ImportedConfig {
+    pub results_filepath: String,
+    pub ip_services: IpServices,
+    pub dyndns2: Vec<Dyndns2>,
+    pub cloudflare: Vec<Cloudflare>,
+    pub query_get: Vec<QueryGet>,
+    pub freedns: Vec<FreeDns>,
+}
+
+// cloudflare_zones holds the ddclient zone of each cloudflare domain, in order
+#[derive(Clone, Debug)]
+pub struct DdclientImport {
+    pub config: ImportedConfig,
+    pub notes: Vec<String>,
+    pub cloudflare_zones: Vec<String>,
+}
+
+type Settings = HashMap<String, String>;
+
+struct Statement {
+    settings: Settings,
+    hosts: Vec<String>,
+}
+
+pub async fn from_path(file_path: &path::Path) -> Result<DdclientImport, String> {
+    let mut ddclient_import = match fs::read_to_string(file_path).await {
+        Ok(conf) => import_ddclient(&conf),
+        Err(e) => return Err(e.to_string()),
+    };
+
+    lookup_cloudflare_ids(&mut ddclient_import).await;
+
+    Ok(ddclient_import)
+}
+
+/*
+    ddclient only knows the zone of a cloudflare host. The zone_id and
+    dns_record_id are looked up with the api token of the host, a failed
+    lookup leaves them empty and is listed in the import notes.
+*/
+pub async fn lookup_cloudflare_ids(ddclient_import: &mut DdclientImport) {
+    let zones = &ddclient_import.cloudflare_zones;
+    for (domain, zone) in ddclient_import.config.cloudflare.iter_mut().zip(zones) {
+        if zone.is_empty() {
+            push_note(
+                &mut ddclient_import.notes,
+                domain.name.clone() + ": cloudflare zone is not set, zone_id and dns_record_id must be filled in by hand",
+            );
+            continue;
+        }
+
+        match cloudflare::lookup_record_ids(domain, zone, Some(CLOUDFLARE_LOOKUP_TIMEOUT_MS)).await
+        {
+            Ok((zone_id, dns_record_id)) => {
+                domain.zone_id = zone_id;
+                domain.dns_record_id = dns_record_id;
+            }
+            Err(e) => push_note(
+                &mut ddclient_import.notes,
+                domain.name.clone()
+                    + ": cloudflare zone_id and dns_record_id could not be looked up for zone "
+                    + zone
+                    + ": "
+                    + &e,
+            ),
+        }
+    }
+}
+
+pub fn import_ddclient(conf: &str) -> DdclientImport {
+    let mut config = ImportedConfig {
+        results_filepath: DEFAULT_RESULTS_FILEPATH.to_string(),
+        ip_services: Vec::new(),
+        dyndns2: Vec::new(),
+        cloudflare: Vec::new(),
+        query_get: Vec::new(),
+        freedns: Vec::new(),
+    };
+    let mut notes = Vec::<String>::new();
+    let mut cloudflare_zones = Vec::<String>::new();
+
+    let mut globals = Settings::new();
+    for line in join_lines(conf) {
+        let statement = parse_statement(&line);
+        if statement.hosts.is_empty() {
+            globals.extend(statement.settings);
+            continue;
+        }
+
+        let mut settings = globals.clone();
+        settings.extend(statement.settings);
+
+        add_ip_service(&mut config, &mut notes, &settings);
+        for host in &statement.hosts {
+            add_host(
+                &mut config,
+                &mut notes,
+                &mut cloudflare_zones,
+                &settings,
+                host,
+            );
+        }
+        note_untranslated_settings(&mut notes, &settings);
+    }
+
+    if config.ip_services.is_empty() {
+        add_ip_service(&mut config, &mut notes, &globals);
+    }
+    if config.ip_services.is_empty() {
+        config.ip_services = get_default_ip_services();
+        notes.push("no web ip service found, using default ip services".to_string());
+    }
+
+    DdclientImport {
+        config,
+        notes,
+        cloudflare_zones,
+    }
+}
+
+// removes comments and joins lines ending in a backslash
+fn join_lines(conf: &str) -> Vec<String> {
+    let mut lines = Vec::<String>::new();
+    let mut continued = String::new();
+
+    for line in conf.lines() {
+        let line = match line.find('#') {
+            Some(index) => &line[..index],
+            _ => line,
+        };
+        let line = line.trim_end();
+
+        if let Some(partial) = line.strip_suffix('\\') {
+            continued += partial;
+            continued += " ";
+            continue;
+        }
+
+        continued += line;
+        if !continued.trim().is_empty() {
+            lines.push(continued.clone());
+        }
+        continued.clear();
+    }
+
+    if !continued.trim().is_empty() {
+        lines.push(continued);
+    }
+
+    lines
+}
+
+// a statement is a list of key=value settings followed by hostnames
+fn parse_statement(line: &str) -> Statement {
+    let mut settings = Settings::new();
+    let mut rest = line.trim_start();
+
+    while let Some((key, value, remaining)) = parse_setting(rest) {
+        settings.insert(key, value);
+        rest = remaining.trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+    }
+
+    let hosts = rest
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|host| !host.is_empty())
+        .map(|host| host.to_string())
+        .collect();
+
+    Statement { settings, hosts }
+}
+
+fn parse_setting(line: &str) -> Option<(String, String, &str)> {
+    let key_length = line
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+        .unwrap_or(line.len());
+    if key_length == 0 {
+        return None;
+    }
+
+    let (key, rest) = line.split_at(key_length);
+    let rest = rest.trim_start().strip_prefix('=')?.trim_start();
+
+    // values are optionally wrapped in single quotes
+    if let Some(quoted) = rest.strip_prefix('\'') {
+        let end = quoted.find('\'')?;
+        return Some((
+            key.to_lowercase(),
+            quoted[..end].to_string(),
+            &quoted[end + 1..],
+        ));
+    }
+
+    let end = rest
+        .find(|c: char| c == ',' || c.is_whitespace())
+        .unwrap_or(rest.len());
+
+    Some((key.to_lowercase(), rest[..end].to_string(), &rest[end..]))
+}
+
+fn add_ip_service(config: &mut ImportedConfig, notes: &mut Vec<String>, settings: &Settings) {
+    let use_method = match settings.get("use") {
+        Some(use_method) => use_method,
+        _ => return,
+    };
+
+    if use_method != "web" {
+        push_note(notes, "use=".to_string() + use_method + " is not supported");
+        return;
+    }
+
+    let web = match settings.get("web") {
+        Some(web) => web,
+        _ => return,
+    };
+
+    // ddclient presets like "dyndns" or "googledomains" are not urls
+    if !web.contains("://") {
+        push_note(notes, "web=".to_string() + web + " is not a url");
+        return;
+    }
+
+    if !config
+        .ip_services
+        .iter()
+        .any(|ip_service| &ip_service.url == web)
+    {
+        config
+            .ip_services
+            .push(IpService::new(web, "address_as_body"));
+    }
+}
+
+fn add_host(
+    config: &mut ImportedConfig,
+    notes: &mut Vec<String>,
+    cloudflare_zones: &mut Vec<String>,
+    settings: &Settings,
+    host: &str,
+) {
+    let protocol = match settings.get("protocol") {
+        Some(protocol) => protocol.as_str(),
+        _ => "dyndns2",
+    };
+
+    match protocol {
+        "dyndns2" => config.dyndns2.push(get_dyndns2(settings, host)),
+        "cloudflare" => {
+            config.cloudflare.push(get_cloudflare(settings, host));
+            cloudflare_zones.push(get_setting(settings, "zone"));
+        }
+        "duckdns" => config.query_get.push(get_duckdns(settings, host)),
+        _ => notes.push(host.to_string() + ": protocol " + protocol + " is not supported"),
+    }
+}
+
+fn get_dyndns2(settings: &Settings, host: &str) -> Dyndns2 {
+    let server = match settings.get("server") {
+        Some(server) => server.as_str(),
+        _ => DEFAULT_DYNDNS2_SERVER,
+    };
+    let script = match settings.get("script") {
+        Some(script) => script.as_str(),
+        _ => DEFAULT_DYNDNS2_SCRIPT,
+    };
+
+    Dyndns2 {
+        service_uri: "https://".to_string() + server + script,
+        hostname: host.to_string(),
+        record_type: None,
+        username: get_setting(settings, "login"),
+        password: get_setting(settings, "password"),
+        condition: None,
+        priority: None,
+        min_update_interval_ms: None,
+        compare_prefix_len: None,
+        check_response_shape: None,
+    }
+}
+
+// duckdns takes the token as a query parameter and answers OK or KO, verbose answers end in UPDATED or NOCHANGE
+fn get_duckdns(settings: &Settings, host: &str) -> QueryGet {
+    let server = match settings.get("server") {
+        Some(server) => server.as_str(),
+        _ => DEFAULT_DUCKDNS_SERVER,
+    };
+
+    QueryGet {
+        service_uri: "https://".to_string() + server + "/update",
+        hostname: host.to_string(),
+        record_type: None,
+        ip_param: "ip".to_string(),
+        host_param: Some("domains".to_string()),
+        params: Some(vec![
+            ("token".to_string(), get_setting(settings, "password")),
+            ("verbose".to_string(), "true".to_string()),
+        ]),
+        username: None,
+        password: None,
+        auth_header: None,
+        success_pattern: Some("OK".to_string()),
+        unchanged_pattern: Some("NOCHANGE".to_string()),
+        condition: None,
+        priority: None,
+        min_update_interval_ms: None,
+        compare_prefix_len: None,
+        check_response_shape: None,
+        success_predicate: None,
+    }
+}
+
+fn get_cloudflare(settings: &Settings, host: &str) -> Cloudflare {
+    // ddclient uses the login "token" for api tokens without an email
+    let email = match settings.get("login") {
+        Some(login) if login != "token" => login.clone(),
+        _ => String::new(),
+    };
+    let ttl = match settings.get("ttl") {
+        Some(ttl) => ttl.parse::<usize>().ok(),
+        _ => None,
+    };
+
+    Cloudflare {
+        email,
+        zone_id: String::new(),
+        dns_record_id: String::new(),
+        api_token: get_setting(settings, "password"),
+        name: host.to_string(),
+        r#type: "A".to_string(),
+        proxied: None,
+        comment: None,
+        tags: None,
+        ttl,
+        condition: None,
+        priority: None,
+        min_update_interval_ms: None,
+        compare_prefix_len: None,
+        check_response_shape: None,
+        success_predicate: None,
+    }
+}
+
+fn note_untranslated_settings(notes: &mut Vec<String>, settings: &Settings) {
+    let translated = [
+        "protocol", "server", "script", "login", "password", "use", "web", "zone", "ttl",
+    ];
+
+    let mut keys: Vec<&String> = settings.keys().collect();
+    keys.sort();
+    for key in keys {
+        if translated.contains(&key.as_str()) || IGNORED_SETTINGS.contains(&key.as_str()) {
+            continue;
+        }
+        // values are left out as they might be secrets
+        push_note(notes, "setting ".to_string() + key + " was not translated");
+    }
+}
+
+fn get_setting(settings: &Settings, key: &str) -> String {
+    match settings.get(key) {
+        Some(value) => value.clone(),
+        _ => String::new(),
+    }
+}
+
+fn push_note(notes: &mut Vec<String>, note: String) {
+    if !notes.contains(&note) {
+        notes.push(note);
+    }
+}
+
+fn get_default_ip_services() -> IpServices {
+    vec![
+        IpService::new("https://checkip.amazonaws.com/", "address_as_body"),
+        IpService::new("https://api.ipify.org", "address_as_body"),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn continuation_lines_are_joined() {
+        let conf = "protocol=dyndns2, \\\n  login=alice, password='hunter 2' \\\n  home.example.com # comment\n";
+        let lines = join_lines(conf);
+        assert_eq!(lines.len(), 1);
+
+        let ddclient_import = import_ddclient(conf);
+        let dyndns2 = &ddclient_import.config.dyndns2;
+        assert_eq!(dyndns2.len(), 1);
+        assert_eq!(dyndns2[0].hostname, "home.example.com");
+        assert_eq!(dyndns2[0].username, "alice");
+        assert_eq!(dyndns2[0].password, "hunter 2");
+    }
+
+    #[test]
+    fn globals_apply_to_following_hosts() {
+        let conf = "
+            use=web, web=https://checkip.example/
+            protocol=dyndns2
+            server=dyn.example
+            login=alice
+            password=secret
+            a.example.com
+            b.example.com
+        ";
+        let ddclient_import = import_ddclient(conf);
+        let config = &ddclient_import.config;
+
+        assert_eq!(config.ip_services.len(), 1);
+        assert_eq!(config.ip_services[0].url, "https://checkip.example/");
+        assert_eq!(config.dyndns2.len(), 2);
+        for domain in &config.dyndns2 {
+            assert_eq!(domain.service_uri, "https://dyn.example/nic/update");
+            assert_eq!(domain.username, "alice");
+            assert_eq!(domain.password, "secret");
+        }
+    }
+
+    #[test]
+    fn host_settings_override_globals() {
+        let conf = "
+            protocol=dyndns2
+            login=alice
+            password=secret
+            login=bob, password=other a.example.com
+            b.example.com
+        ";
+        let ddclient_import = import_ddclient(conf);
+        let dyndns2 = &ddclient_import.config.dyndns2;
+
+        assert_eq!(dyndns2.len(), 2);
+        assert_eq!(dyndns2[0].hostname, "a.example.com");
+        assert_eq!(dyndns2[0].username, "bob");
+        assert_eq!(dyndns2[0].password, "other");
+        // overrides only apply to the hosts on their line
+        assert_eq!(dyndns2[1].hostname, "b.example.com");
+        assert_eq!(dyndns2[1].username, "alice");
+        assert_eq!(dyndns2[1].password, "secret");
+    }
+
+    #[test]
+    fn duckdns_hosts_are_query_get_domains() {
+        let conf = "protocol=duckdns, password=token-1 home.duckdns.org";
+        let ddclient_import = import_ddclient(conf);
+        let query_get = &ddclient_import.config.query_get;
+
+        assert_eq!(query_get.len(), 1);
+        assert_eq!(query_get[0].service_uri, "https://www.duckdns.org/update");
+        assert_eq!(query_get[0].hostname, "home.duckdns.org");
+        assert_eq!(query_get[0].host_param.as_deref(), Some("domains"));
+        assert_eq!(query_get[0].ip_param, "ip");
+        assert_eq!(
+            query_get[0].params,
+            Some(vec![
+                ("token".to_string(), "token-1".to_string()),
+                ("verbose".to_string(), "true".to_string()),
+            ])
+        );
+        assert_eq!(query_get[0].unchanged_pattern.as_deref(), Some("NOCHANGE"));
+        assert!(query_get::validate(&query_get[0]).is_empty());
+    }
+
+    #[test]
+    fn cloudflare_zones_are_kept_for_lookups() {
+        let conf = "
+            protocol=cloudflare, zone=example.com, login=token, password=api-token
+            home.example.com
+            protocol=cloudflare, zone=example.net, login=token, password=api-token other.example.net
+        ";
+        let ddclient_import = import_ddclient(conf);
+
+        assert_eq!(ddclient_import.config.cloudflare.len(), 2);
+        assert_eq!(
+            ddclient_import.cloudflare_zones,
+            vec!["example.com", "example.net"]
+        );
+        assert_eq!(ddclient_import.config.cloudflare[0].api_token, "api-token");
+        assert_eq!(ddclient_import.config.cloudflare[0].email, "");
+    }
+
+    #[test]
+    fn unsupported_settings_are_noted() {
+        let conf = "protocol=namecheap, mx=mail.example.com a.example.com";
+        let ddclient_import = import_ddclient(conf);
+
+        assert!(ddclient_import
+            .notes
+            .contains(&"a.example.com: protocol namecheap is not supported".to_string()));
+        assert!(ddclient_import
+            .notes
+            .contains(&"setting mx was not translated".to_string()));
+    }
+}
@@ -0,0 +1,730 @@
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time;
+
+use results::{Address, AddressFamily};
+
+mod message;
+
+use message::{
+    Message, RecordData, RCODE_NAME_ERROR, RCODE_NO_ERROR, TYPE_A, TYPE_AAAA, TYPE_NS, TYPE_SOA,
+};
+
+/*
+    Reads the address records of a hostname back from its authoritative
+    nameservers, for dns services without an api to read a record.
+
+    The nameservers of the zone are found through the system resolvers by
+    walking up the hostname until an NS answer, ie: home.example.com then
+    example.com. Each nameserver is then asked without recursion so a
+    cached answer from a resolver is never read back.
+*/
+
+const RESOLV_CONF_FILEPATH: &str = "/etc/resolv.conf";
+const DNS_PORT: u16 = 53;
+const DEFAULT_TIMEOUT_MS: u64 = 5_000;
+const MAX_UDP_RESPONSE_LEN: usize = 4096;
+
+pub trait DnsTransport {
+    // sends one dns message to a server and returns the response bytes
+    fn exchange(
+        &self,
+        server: SocketAddr,
+        query: &[u8],
+        timeout_ms: u64,
+    ) -> impl Future<Output = Result<Vec<u8>, String>> + Send;
+}
+
+// udp with a tcp retry for truncated responses
+pub struct NetworkTransport;
+
+impl DnsTransport for NetworkTransport {
+    async fn exchange(
+        &self,
+        server: SocketAddr,
+        query: &[u8],
+        timeout_ms: u64,
+    ) -> Result<Vec<u8>, String> {
+        let exchange = async {
+            let response = exchange_udp(server, query).await?;
+            match message::decode_message(&response) {
+                Ok(message) if message.truncated => exchange_tcp(server, query).await,
+                _ => Ok(response),
+            }
+        };
+
+        match time::timeout(Duration::from_millis(timeout_ms), exchange).await {
+            Ok(response) => response,
+            Err(_) => {
+                Err(server.to_string() + ": timed out after " + &timeout_ms.to_string() + "ms")
+            }
+        }
+    }
+}
+
+async fn exchange_udp(server: SocketAddr, query: &[u8]) -> Result<Vec<u8>, String> {
+    let bind_addr = match server {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = match UdpSocket::bind(bind_addr).await {
+        Ok(socket) => socket,
+        Err(e) => return Err(e.to_string()),
+    };
+    if let Err(e) = socket.connect(server).await {
+        return Err(server.to_string() + ": " + &e.to_string());
+    }
+    if let Err(e) = socket.send(query).await {
+        return Err(server.to_string() + ": " + &e.to_string());
+    }
+
+    let mut response = vec![0u8; MAX_UDP_RESPONSE_LEN];
+    match socket.recv(&mut response).await {
+        Ok(len) => {
+            response.truncate(len);
+            Ok(response)
+        }
+        Err(e) => Err(server.to_string() + ": " + &e.to_string()),
+    }
+}
+
+// tcp messages are prefixed with their length
+async fn exchange_tcp(server: SocketAddr, query: &[u8]) -> Result<Vec<u8>, String> {
+    let mut stream = match TcpStream::connect(server).await {
+        Ok(stream) => stream,
+        Err(e) => return Err(server.to_string() + ": " + &e.to_string()),
+    };
+
+    let mut request = (query.len() as u16).to_be_bytes().to_vec();
+    request.extend_from_slice(query);
+    if let Err(e) = stream.write_all(&request).await {
+        return Err(server.to_string() + ": " + &e.to_string());
+    }
+
+    let mut len = [0u8; 2];
+    if let Err(e) = stream.read_exact(&mut len).await {
+        return Err(server.to_string() + ": " + &e.to_string());
+    }
+    let mut response = vec![0u8; u16::from_be_bytes(len) as usize];
+    match stream.read_exact(&mut response).await {
+        Ok(_) => Ok(response),
+        Err(e) => Err(server.to_string() + ": " + &e.to_string()),
+    }
+}
+
+pub fn get_system_resolvers() -> Result<Vec<SocketAddr>, String> {
+    match std::fs::read_to_string(RESOLV_CONF_FILEPATH) {
+        Ok(resolv_conf) => parse_resolv_conf(&resolv_conf),
+        Err(e) => Err(RESOLV_CONF_FILEPATH.to_string() + ": " + &e.to_string()),
+    }
+}
+
+// nameserver lines, ie: nameserver 127.0.0.53
+fn parse_resolv_conf(resolv_conf: &str) -> Result<Vec<SocketAddr>, String> {
+    let mut resolvers = Vec::<SocketAddr>::new();
+    for line in resolv_conf.lines() {
+        let mut fields = line.split_whitespace();
+        if fields.next() != Some("nameserver") {
+            continue;
+        }
+
+        // link local resolvers can carry a zone, ie: fe80::1%eth0
+        let resolver = fields.next().unwrap_or("");
+        let resolver = resolver.split('%').next().unwrap_or(resolver);
+        if let Ok(ip_address) = resolver.parse::<IpAddr>() {
+            resolvers.push(SocketAddr::new(ip_address, DNS_PORT));
+        }
+    }
+
+    match resolvers.is_empty() {
+        true => Err(RESOLV_CONF_FILEPATH.to_string() + ": no nameservers"),
+        false => Ok(resolvers),
+    }
+}
+
+// verifies an update with the system resolvers, every query is bound by timeout_ms
+pub async fn verify_address(
+    hostname: &str,
+    ip_address: &Address,
+    timeout_ms: Option<u64>,
+) -> Result<(), String> {
+    let resolvers = get_system_resolvers()?;
+    verify_address_with_transport(
+        &NetworkTransport,
+        &resolvers,
+        hostname,
+        ip_address,
+        timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS),
+    )
+    .await
+}
+
+pub async fn verify_address_with_transport<T: DnsTransport>(
+    transport: &T,
+    resolvers: &[SocketAddr],
+    hostname: &str,
+    ip_address: &Address,
+    timeout_ms: u64,
+) -> Result<(), String> {
+    let family = ip_address.family();
+    let addresses = read_back_addresses(transport, resolvers, hostname, family, timeout_ms).await?;
+    if addresses.contains(ip_address) {
+        return Ok(());
+    }
+
+    let held = match addresses.is_empty() {
+        true => "no ".to_string() + get_record_type_name(family) + " record",
+        false => addresses
+            .iter()
+            .map(|address| address.to_string())
+            .collect::<Vec<String>>()
+            .join(", "),
+    };
+
+    Err("verify failed: sent ".to_string()
+        + &ip_address.to_string()
+        + " but the authoritative nameserver holds "
+        + &held)
+}
+
+// the A or AAAA records held by the first authoritative nameserver that answers
+pub async fn read_back_addresses<T: DnsTransport>(
+    transport: &T,
+    resolvers: &[SocketAddr],
+    hostname: &str,
+    family: AddressFamily,
+    timeout_ms: u64,
+) -> Result<Vec<Address>, String> {
+    let hostname = hostname.strip_suffix('.').unwrap_or(hostname);
+    let nameservers = find_nameservers(transport, resolvers, hostname, timeout_ms).await?;
+    let record_type = match family {
+        AddressFamily::V4 => TYPE_A,
+        AddressFamily::V6 => TYPE_AAAA,
+    };
+
+    let mut errors = Vec::<String>::new();
+    for nameserver in nameservers {
+        let message = match query(
+            transport,
+            nameserver,
+            hostname,
+            record_type,
+            false,
+            timeout_ms,
+        )
+        .await
+        {
+            Ok(message) => message,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+
+        match message.rcode {
+            RCODE_NO_ERROR => return Ok(get_addresses(&message, record_type)),
+            RCODE_NAME_ERROR => return Ok(Vec::new()),
+            rcode => errors.push(nameserver.to_string() + ": rcode " + &rcode.to_string()),
+        }
+    }
+
+    Err("no authoritative nameserver of ".to_string()
+        + hostname
+        + " answered: "
+        + &errors.join(", "))
+}
+
+// the addresses of the nameservers of the closest zone holding a hostname
+async fn find_nameservers<T: DnsTransport>(
+    transport: &T,
+    resolvers: &[SocketAddr],
+    hostname: &str,
+    timeout_ms: u64,
+) -> Result<Vec<SocketAddr>, String> {
+    let labels: Vec<&str> = hostname.split('.').collect();
+    let mut index = 0;
+    while index < labels.len() {
+        let zone = labels[index..].join(".").to_lowercase();
+        let message = query_resolvers(transport, resolvers, &zone, TYPE_NS, timeout_ms).await?;
+
+        let nameserver_names: Vec<&String> = message
+            .answers
+            .iter()
+            .filter(|record| record.r#type == TYPE_NS && record.name == zone)
+            .filter_map(|record| match &record.data {
+                RecordData::Name(name) => Some(name),
+                _ => None,
+            })
+            .collect();
+        if !nameserver_names.is_empty() {
+            return resolve_nameservers(transport, resolvers, &nameserver_names, timeout_ms).await;
+        }
+
+        // the soa of an empty answer names the zone, ie: example.com for home.example.com
+        let soa_index = message
+            .authorities
+            .iter()
+            .filter(|record| record.r#type == TYPE_SOA)
+            .find_map(|record| {
+                (index + 1..labels.len()).find(|&i| labels[i..].join(".") == record.name)
+            });
+        index = soa_index.unwrap_or(index + 1);
+    }
+
+    Err("no nameservers found for ".to_string() + hostname)
+}
+
+async fn resolve_nameservers<T: DnsTransport>(
+    transport: &T,
+    resolvers: &[SocketAddr],
+    nameserver_names: &[&String],
+    timeout_ms: u64,
+) -> Result<Vec<SocketAddr>, String> {
+    let mut nameservers = Vec::<SocketAddr>::new();
+    let mut errors = Vec::<String>::new();
+    for nameserver_name in nameserver_names {
+        for record_type in [TYPE_A, TYPE_AAAA] {
+            match query_resolvers(
+                transport,
+                resolvers,
+                nameserver_name,
+                record_type,
+                timeout_ms,
+            )
+            .await
+            {
+                Ok(message) => nameservers.extend(
+                    get_addresses(&message, record_type)
+                        .iter()
+                        .map(|address| SocketAddr::new(address.ip_addr(), DNS_PORT)),
+                ),
+                Err(e) => errors.push(e),
+            }
+        }
+    }
+
+    match nameservers.is_empty() {
+        true => Err("nameservers could not be resolved: ".to_string() + &errors.join(", ")),
+        false => Ok(nameservers),
+    }
+}
+
+// the first answer of a resolver, resolvers are asked in order
+async fn query_resolvers<T: DnsTransport>(
+    transport: &T,
+    resolvers: &[SocketAddr],
+    name: &str,
+    record_type: u16,
+    timeout_ms: u64,
+) -> Result<Message, String> {
+    let mut errors = Vec::<String>::new();
+    for resolver in resolvers {
+        match query(transport, *resolver, name, record_type, true, timeout_ms).await {
+            Ok(message) if message.rcode == RCODE_NO_ERROR || message.rcode == RCODE_NAME_ERROR => {
+                return Ok(message)
+            }
+            Ok(message) => {
+                errors.push(resolver.to_string() + ": rcode " + &message.rcode.to_string())
+            }
+            Err(e) => errors.push(e),
+        }
+    }
+
+    Err("no resolver answered for ".to_string() + name + ": " + &errors.join(", "))
+}
+
+async fn query<T: DnsTransport>(
+    transport: &T,
+    server: SocketAddr,
+    name: &str,
+    record_type: u16,
+    recursion_desired: bool,
+    timeout_ms: u64,
+) -> Result<Message, String> {
+    let id: u16 = rand::random();
+    let query = message::encode_query(id, name, record_type, recursion_desired);
+    let response = transport.exchange(server, &query, timeout_ms).await?;
+
+    let message = match message::decode_message(&response) {
+        Ok(message) => message,
+        Err(e) => return Err(server.to_string() + ": " + &e),
+    };
+    if message.id != id {
+        return Err(server.to_string() + ": response does not match the query");
+    }
+
+    Ok(message)
+}
+
+// answers can hold a cname chain, only addresses of the asked type are kept
+fn get_addresses(message: &Message, record_type: u16) -> Vec<Address> {
+    message
+        .answers
+        .iter()
+        .filter(|record| record.r#type == record_type)
+        .filter_map(|record| match record.data {
+            RecordData::Address(ip_address) => Some(Address::new(ip_address)),
+            _ => None,
+        })
+        .collect()
+}
+
+fn get_record_type_name(family: AddressFamily) -> &'static str {
+    match family {
+        AddressFamily::V4 => "A",
+        AddressFamily::V6 => "AAAA",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    const RESOLVER: &str = "10.0.0.53:53";
+    const NS1: &str = "192.0.2.53:53";
+    const NS2: &str = "198.51.100.53:53";
+
+    #[derive(Clone)]
+    enum Answer {
+        Records(u8, Vec<(u16, Vec<u8>)>),
+        Soa(String),
+        Fail,
+    }
+
+    // answers queries by server, name, and type, and records every query
+    #[derive(Default)]
+    struct MockTransport {
+        answers: HashMap<(String, String, u16), Answer>,
+        queries: Mutex<Vec<(String, String, u16, bool)>>,
+    }
+
+    impl MockTransport {
+        fn answer(mut self, server: &str, name: &str, r#type: u16, answer: Answer) -> Self {
+            self.answers
+                .insert((server.to_string(), name.to_string(), r#type), answer);
+            self
+        }
+
+        // a resolver that delegates example.com to both nameservers
+        fn with_delegation() -> Self {
+            MockTransport::default()
+                .answer(
+                    RESOLVER,
+                    "home.example.com",
+                    TYPE_NS,
+                    Answer::Soa("example.com".to_string()),
+                )
+                .answer(
+                    RESOLVER,
+                    "example.com",
+                    TYPE_NS,
+                    Answer::Records(
+                        0,
+                        vec![
+                            (TYPE_NS, encode_name("ns1.example.com")),
+                            (TYPE_NS, encode_name("ns2.example.net")),
+                        ],
+                    ),
+                )
+                .answer(
+                    RESOLVER,
+                    "ns1.example.com",
+                    TYPE_A,
+                    Answer::Records(0, vec![(TYPE_A, vec![192, 0, 2, 53])]),
+                )
+                .answer(
+                    RESOLVER,
+                    "ns2.example.net",
+                    TYPE_A,
+                    Answer::Records(0, vec![(TYPE_A, vec![198, 51, 100, 53])]),
+                )
+        }
+    }
+
+    impl DnsTransport for MockTransport {
+        async fn exchange(
+            &self,
+            server: SocketAddr,
+            query: &[u8],
+            _timeout_ms: u64,
+        ) -> Result<Vec<u8>, String> {
+            let (name, index) = decode_query_name(query);
+            let r#type = u16::from_be_bytes([query[index], query[index + 1]]);
+            let recursion_desired = query[2] & 0x01 != 0;
+            self.queries.lock().unwrap().push((
+                server.to_string(),
+                name.clone(),
+                r#type,
+                recursion_desired,
+            ));
+
+            let answer = self
+                .answers
+                .get(&(server.to_string(), name.clone(), r#type))
+                .cloned()
+                .unwrap_or(Answer::Records(0, Vec::new()));
+
+            let (rcode, answers, authorities) = match answer {
+                Answer::Records(rcode, answers) => (rcode, answers, Vec::new()),
+                Answer::Soa(zone) => (0, Vec::new(), vec![(zone, encode_name("ns1.example.com"))]),
+                Answer::Fail => return Err(server.to_string() + ": connection refused"),
+            };
+
+            // the question is copied, answers are owned by the asked name
+            let mut response = query[..2].to_vec();
+            response.extend_from_slice(&[0x84, rcode]);
+            response.extend_from_slice(&[0, 1]);
+            response.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+            response.extend_from_slice(&(authorities.len() as u16).to_be_bytes());
+            response.extend_from_slice(&[0, 0]);
+            response.extend_from_slice(&query[12..index + 4]);
+            for (r#type, data) in answers {
+                response.extend_from_slice(&[0xc0, 12]);
+                push_record(&mut response, r#type, &data);
+            }
+            for (zone, data) in authorities {
+                response.extend_from_slice(&encode_name(&zone));
+                push_record(&mut response, TYPE_SOA, &data);
+            }
+
+            Ok(response)
+        }
+    }
+
+    fn push_record(response: &mut Vec<u8>, r#type: u16, data: &[u8]) {
+        response.extend_from_slice(&r#type.to_be_bytes());
+        response.extend_from_slice(&[0, 1, 0, 0, 0x0e, 0x10]);
+        response.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        response.extend_from_slice(data);
+    }
+
+    fn encode_name(name: &str) -> Vec<u8> {
+        let mut encoded = Vec::<u8>::new();
+        message::encode_name(&mut encoded, name);
+        encoded
+    }
+
+    fn decode_query_name(query: &[u8]) -> (String, usize) {
+        let mut labels = Vec::<String>::new();
+        let mut index = 12;
+        while query[index] != 0 {
+            let len = query[index] as usize;
+            labels.push(String::from_utf8_lossy(&query[index + 1..index + 1 + len]).to_string());
+            index += 1 + len;
+        }
+        (labels.join("."), index + 1)
+    }
+
+    fn resolvers() -> Vec<SocketAddr> {
+        vec![RESOLVER.parse().unwrap()]
+    }
+
+    fn address(ip_address: &str) -> Address {
+        ip_address.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn matching_records_are_verified() {
+        let transport = MockTransport::with_delegation().answer(
+            NS1,
+            "home.example.com",
+            TYPE_A,
+            Answer::Records(0, vec![(TYPE_A, vec![203, 0, 113, 4])]),
+        );
+
+        let verified = verify_address_with_transport(
+            &transport,
+            &resolvers(),
+            "home.example.com",
+            &address("203.0.113.4"),
+            100,
+        )
+        .await;
+        assert_eq!(verified, Ok(()));
+
+        // authoritative nameservers are never asked to recurse
+        let queries = transport.queries.lock().unwrap();
+        let last_query = queries.last().unwrap();
+        assert_eq!(
+            last_query,
+            &(
+                NS1.to_string(),
+                "home.example.com".to_string(),
+                TYPE_A,
+                false
+            )
+        );
+        // the soa skips straight to the zone
+        assert_eq!(
+            queries
+                .iter()
+                .filter(|query| query.0 == RESOLVER && query.2 == TYPE_NS)
+                .count(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn mismatches_include_both_addresses() {
+        let transport = MockTransport::with_delegation().answer(
+            NS1,
+            "home.example.com",
+            TYPE_AAAA,
+            Answer::Records(
+                0,
+                vec![(
+                    TYPE_AAAA,
+                    [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1].to_vec(),
+                )],
+            ),
+        );
+
+        let verified = verify_address_with_transport(
+            &transport,
+            &resolvers(),
+            "home.example.com",
+            &address("2001:db8::2"),
+            100,
+        )
+        .await;
+        assert_eq!(
+            verified,
+            Err(
+                "verify failed: sent 2001:db8::2 but the authoritative nameserver holds 2001:db8::1"
+                    .to_string()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_records_are_mismatches() {
+        let transport = MockTransport::with_delegation().answer(
+            NS1,
+            "home.example.com",
+            TYPE_A,
+            Answer::Records(RCODE_NAME_ERROR, Vec::new()),
+        );
+
+        let verified = verify_address_with_transport(
+            &transport,
+            &resolvers(),
+            "home.example.com",
+            &address("203.0.113.4"),
+            100,
+        )
+        .await;
+        assert_eq!(
+            verified,
+            Err(
+                "verify failed: sent 203.0.113.4 but the authoritative nameserver holds no A record"
+                    .to_string()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn unreachable_nameservers_are_skipped() {
+        let transport = MockTransport::with_delegation()
+            .answer(NS1, "home.example.com", TYPE_A, Answer::Fail)
+            .answer(
+                NS2,
+                "home.example.com",
+                TYPE_A,
+                Answer::Records(0, vec![(TYPE_A, vec![203, 0, 113, 4])]),
+            );
+
+        let addresses = read_back_addresses(
+            &transport,
+            &resolvers(),
+            "home.example.com.",
+            AddressFamily::V4,
+            100,
+        )
+        .await;
+        assert_eq!(addresses, Ok(vec![address("203.0.113.4")]));
+    }
+
+    #[tokio::test]
+    async fn failures_are_errors_not_mismatches() {
+        let transport = MockTransport::with_delegation()
+            .answer(NS1, "home.example.com", TYPE_A, Answer::Fail)
+            .answer(
+                NS2,
+                "home.example.com",
+                TYPE_A,
+                Answer::Records(2, Vec::new()),
+            );
+
+        let addresses = read_back_addresses(
+            &transport,
+            &resolvers(),
+            "home.example.com",
+            AddressFamily::V4,
+            100,
+        )
+        .await;
+        assert_eq!(
+            addresses,
+            Err("no authoritative nameserver of home.example.com answered: 192.0.2.53:53: connection refused, 198.51.100.53:53: rcode 2".to_string())
+        );
+
+        // without a delegation no nameserver is asked
+        let addresses = read_back_addresses(
+            &MockTransport::default(),
+            &resolvers(),
+            "home.example.com",
+            AddressFamily::V4,
+            100,
+        )
+        .await;
+        assert_eq!(
+            addresses,
+            Err("no nameservers found for home.example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn resolvers_are_read_from_resolv_conf() {
+        let resolv_conf = "# generated\nnameserver 127.0.0.53\noptions edns0\nnameserver fe80::1%eth0\nsearch lan\n";
+        assert_eq!(
+            parse_resolv_conf(resolv_conf),
+            Ok(vec![
+                "127.0.0.53:53".parse().unwrap(),
+                "[fe80::1]:53".parse().unwrap()
+            ])
+        );
+        assert!(parse_resolv_conf("search lan\n").is_err());
+    }
+
+    // the network transport against a local udp server
+    #[tokio::test]
+    async fn network_transport_exchanges_udp_messages() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut query = vec![0u8; 512];
+            let (len, client) = server.recv_from(&mut query).await.unwrap();
+            let mut response = query[..len].to_vec();
+            response[2] |= 0x80;
+            server.send_to(&response, client).await.unwrap();
+        });
+
+        let message = query(
+            &NetworkTransport,
+            server_addr,
+            "example.com",
+            TYPE_A,
+            true,
+            1000,
+        )
+        .await
+        .unwrap();
+        assert_eq!(message.rcode, RCODE_NO_ERROR);
+        assert!(message.answers.is_empty());
+    }
+}
@@ -0,0 +1,257 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/*
+    A minimal dns message codec (RFC 1035), only what a read back needs:
+    one question out, and A, AAAA, NS, and SOA records in.
+*/
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_NS: u16 = 2;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_AAAA: u16 = 28;
+
+pub const RCODE_NO_ERROR: u8 = 0;
+pub const RCODE_NAME_ERROR: u8 = 3;
+
+const CLASS_IN: u16 = 1;
+const HEADER_LEN: usize = 12;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+
+// compression pointers can loop, a name never needs more than 127 labels
+const MAX_POINTERS: usize = 127;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RecordData {
+    Address(IpAddr),
+    Name(String),
+    Other,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub name: String,
+    pub r#type: u16,
+    pub data: RecordData,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub id: u16,
+    pub authoritative: bool,
+    pub truncated: bool,
+    pub rcode: u8,
+    pub answers: Vec<Record>,
+    pub authorities: Vec<Record>,
+}
+
+// recursion is desired from resolvers, never from authoritative servers
+pub fn encode_query(id: u16, name: &str, r#type: u16, recursion_desired: bool) -> Vec<u8> {
+    let flags = match recursion_desired {
+        true => FLAG_RECURSION_DESIRED,
+        false => 0,
+    };
+
+    let mut query = Vec::<u8>::new();
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&flags.to_be_bytes());
+    // one question, no answers, authorities, or additionals
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    encode_name(&mut query, name);
+    query.extend_from_slice(&r#type.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    query
+}
+
+pub fn encode_name(message: &mut Vec<u8>, name: &str) {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+}
+
+pub fn decode_message(bytes: &[u8]) -> Result<Message, String> {
+    if bytes.len() < HEADER_LEN {
+        return Err("dns response is shorter than a header".to_string());
+    }
+
+    let id = read_u16(bytes, 0)?;
+    let flags = read_u16(bytes, 2)?;
+    if flags & FLAG_RESPONSE == 0 {
+        return Err("dns response is a query".to_string());
+    }
+
+    let question_count = read_u16(bytes, 4)?;
+    let answer_count = read_u16(bytes, 6)?;
+    let authority_count = read_u16(bytes, 8)?;
+
+    let mut index = HEADER_LEN;
+    for _ in 0..question_count {
+        let (_name, next_index) = decode_name(bytes, index)?;
+        // type and class
+        index = next_index + 4;
+    }
+
+    let mut answers = Vec::<Record>::new();
+    for _ in 0..answer_count {
+        let (record, next_index) = decode_record(bytes, index)?;
+        answers.push(record);
+        index = next_index;
+    }
+
+    // additional records are not read, nameserver addresses are resolved instead
+    let mut authorities = Vec::<Record>::new();
+    for _ in 0..authority_count {
+        let (record, next_index) = decode_record(bytes, index)?;
+        authorities.push(record);
+        index = next_index;
+    }
+
+    Ok(Message {
+        id,
+        authoritative: flags & FLAG_AUTHORITATIVE != 0,
+        truncated: flags & FLAG_TRUNCATED != 0,
+        rcode: (flags & 0x000f) as u8,
+        answers,
+        authorities,
+    })
+}
+
+fn decode_record(bytes: &[u8], index: usize) -> Result<(Record, usize), String> {
+    let (name, index) = decode_name(bytes, index)?;
+    let r#type = read_u16(bytes, index)?;
+    // class and ttl
+    let data_len = read_u16(bytes, index + 8)? as usize;
+    let data_index = index + 10;
+    let data = match bytes.get(data_index..data_index + data_len) {
+        Some(data) => data,
+        _ => return Err("dns record is truncated".to_string()),
+    };
+
+    let data = match (r#type, data_len) {
+        (TYPE_A, 4) => RecordData::Address(IpAddr::V4(Ipv4Addr::new(
+            data[0], data[1], data[2], data[3],
+        ))),
+        (TYPE_AAAA, 16) => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(data);
+            RecordData::Address(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        // names in rdata can point anywhere in the message
+        (TYPE_NS, _) | (TYPE_SOA, _) => RecordData::Name(decode_name(bytes, data_index)?.0),
+        _ => RecordData::Other,
+    };
+
+    Ok((Record { name, r#type, data }, data_index + data_len))
+}
+
+// a name and the index after it, compression pointers are followed
+fn decode_name(bytes: &[u8], index: usize) -> Result<(String, usize), String> {
+    let mut labels = Vec::<String>::new();
+    let mut index = index;
+    let mut next_index = None;
+    let mut pointers = 0;
+    loop {
+        let len = match bytes.get(index) {
+            Some(len) => *len as usize,
+            _ => return Err("dns name is truncated".to_string()),
+        };
+
+        if len & 0xc0 == 0xc0 {
+            pointers += 1;
+            if pointers > MAX_POINTERS {
+                return Err("dns name has a compression loop".to_string());
+            }
+            let pointer = read_u16(bytes, index)? & 0x3fff;
+            next_index.get_or_insert(index + 2);
+            index = pointer as usize;
+            continue;
+        }
+
+        if len == 0 {
+            let name = labels.join(".");
+            return Ok((name, next_index.unwrap_or(index + 1)));
+        }
+
+        match bytes.get(index + 1..index + 1 + len) {
+            Some(label) => labels.push(String::from_utf8_lossy(label).to_lowercase()),
+            _ => return Err("dns name is truncated".to_string()),
+        }
+        index += 1 + len;
+    }
+}
+
+fn read_u16(bytes: &[u8], index: usize) -> Result<u16, String> {
+    match bytes.get(index..index + 2) {
+        Some(pair) => Ok(u16::from_be_bytes([pair[0], pair[1]])),
+        _ => Err("dns response is truncated".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queries_are_encoded() {
+        let query = encode_query(0x1234, "home.example.com.", TYPE_AAAA, false);
+        assert_eq!(
+            query,
+            [
+                &[0x12, 0x34, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0][..],
+                b"\x04home\x07example\x03com\x00",
+                &[0, 28, 0, 1],
+            ]
+            .concat()
+        );
+
+        let query = encode_query(1, "example.com", TYPE_NS, true);
+        assert_eq!(&query[2..4], &[0x01, 0x00]);
+    }
+
+    #[test]
+    fn compressed_names_are_decoded() {
+        // a response to an NS query with the nameserver name pointing into the question
+        let mut response = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0];
+        response.extend_from_slice(b"\x07example\x03com\x00\x00\x02\x00\x01");
+        response.extend_from_slice(&[0xc0, 12, 0, 2, 0, 1, 0, 0, 0x0e, 0x10, 0, 6]);
+        response.extend_from_slice(b"\x03ns1\xc0\x0c");
+
+        let message = decode_message(&response).unwrap();
+        assert_eq!(message.id, 0x1234);
+        assert_eq!(
+            message.answers,
+            vec![Record {
+                name: "example.com".to_string(),
+                r#type: TYPE_NS,
+                data: RecordData::Name("ns1.example.com".to_string()),
+            }]
+        );
+    }
+
+    #[test]
+    fn compression_loops_are_errors() {
+        let mut response = vec![0, 1, 0x80, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        response.extend_from_slice(&[0xc0, 12]);
+        assert_eq!(
+            decode_message(&response),
+            Err("dns name has a compression loop".to_string())
+        );
+    }
+
+    #[test]
+    fn truncated_messages_are_errors() {
+        let mut response = vec![0, 1, 0x80, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+        response.extend_from_slice(b"\x00\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\x7f");
+        assert_eq!(
+            decode_message(&response),
+            Err("dns record is truncated".to_string())
+        );
+        assert!(decode_message(&[0, 1, 0x80]).is_err());
+    }
+}
@@ -1,63 +1,1778 @@
 use std::collections::HashMap;
 
-use config::Config;
-use results::{DomainResult, IpServiceResult, UpdateIpResults};
+use conditions::{Condition, ConditionResults};
+use config::{Config, DomainService};
+use results::{
+    Address, AddressFamily, AddressRange, ComparePrefixLen, Confidence, Decision, DecisionLog,
+    DomainOutcome, DomainResult, IpServiceResult, NatClassification, PlannedUpdate, ResponseShape,
+    UpdateIpResults, UpdateReason,
+};
 
-#[cfg(feature = "cloudflare")]
-use cloudflare;
-#[cfg(feature = "dyndns2")]
-use dyndns2;
+const DEFAULT_PREFLIGHT_TIMEOUT_MS: u64 = 3000;
 
+// a domain is either skipped with a result or updated for a reason
+pub enum Plan {
+    Skip(DomainResult),
+    Update(UpdateReason),
+}
+
+// the properties of this run shared by every domain plan
+pub struct RunState {
+    pub ip_address: Address,
+    pub address_is_fresh: bool,
+    pub push_on_first_run: bool,
+    pub condition_results: ConditionResults,
+    pub timestamp: u128,
+    pub suppressed_behind_cgnat: bool,
+    pub address_blocklisted: bool,
+    pub address_conflicted: bool,
+    pub outside_allowed_windows: bool,
+}
+
+/*
+    Without ip_services_v6 every domain is planned with the run state of
+    ip_services, whatever the family of its address.
+
+    With ip_services_v6, AAAA records are planned with the run state of
+    ip_services_v6 and every other record with the run state of ip_services.
+    A family without an address only skips the domains of that family.
+    Without ip_services only AAAA records are updated.
+*/
+struct RunStates {
+    v4: Result<RunState, String>,
+    v6: Option<Result<RunState, String>>,
+}
+
+// the properties of a domain used to plan its update
+pub struct DomainEntry<'a> {
+    pub key: String,
+    pub service: &'static str,
+    pub hostname: &'a String,
+    pub condition: &'a Option<Condition>,
+    pub priority: Option<u32>,
+    pub min_update_interval_ms: Option<u64>,
+    pub compare_prefix_len: &'a Option<ComparePrefixLen>,
+    pub check_response_shape: bool,
+    pub address_family: AddressFamily,
+    pub endpoint: &'a str,
+}
+
+/*
+    Domains without a priority are always updated.
+
+    Domains with a priority that share a hostname form a failover chain.
+    They are tried in ascending priority until one of them does not fail.
+*/
+#[cfg_attr(not(feature = "services"), allow(unused_variables, unused_mut))]
 pub async fn update_domains(
     config: &Config,
     prev_results: &Option<UpdateIpResults>,
-    ip_service_result: &Option<IpServiceResult>,
+    ip_service_result: &IpServiceResult,
+    ip_service_result_v6: &Option<IpServiceResult>,
+    decisions: &mut DecisionLog,
 ) -> Result<HashMap<String, DomainResult>, String> {
-    let ip_address = match get_ip_address(prev_results, ip_service_result) {
-        Ok(ip) => ip,
-        Err(e) => return Err(e),
+    let domain_entries = get_domain_entries(config);
+
+    let conditions = domain_entries
+        .iter()
+        .filter_map(|entry| entry.condition.as_ref())
+        .collect();
+    let condition_results = conditions::evaluate_conditions(conditions).await;
+
+    let timestamp = results::get_timestamp_or_zero();
+    let dual_stack = !config.ip_services_v6.is_empty();
+    // a config with only ip_services_v6 does not detect an ipv4 address
+    let v4 = match config.ip_services.is_empty() && ip_service_result.ip_address.is_none() {
+        true => Err("ip_services: ipv4 is not detected".to_string()),
+        false => get_run_state(
+            config,
+            prev_results,
+            Some(ip_service_result),
+            dual_stack.then_some(AddressFamily::V4),
+            &condition_results,
+            timestamp,
+            decisions,
+        ),
+    };
+    let v6 = match dual_stack {
+        true => Some(get_run_state(
+            config,
+            prev_results,
+            ip_service_result_v6.as_ref(),
+            Some(AddressFamily::V6),
+            &condition_results,
+            timestamp,
+            decisions,
+        )),
+        false => None,
     };
 
+    // a run without any address has nothing to update
+    match (&v4, &v6) {
+        (Err(_), Some(Err(e))) if config.ip_services.is_empty() => return Err(e.clone()),
+        (Err(e), None | Some(Err(_))) => return Err(e.clone()),
+        _ => {}
+    }
+    let run_states = RunStates { v4, v6 };
+
     let mut domain_results = HashMap::<String, DomainResult>::new();
 
-    // add more services here
-    #[cfg(feature = "dyndns2")]
-    dyndns2::update_domains(
-        &mut domain_results,
-        prev_results,
-        &ip_address,
-        &config.dyndns2,
-    )
-    .await;
-
-    #[cfg(feature = "cloudflare")]
-    cloudflare::update_domains(
-        &mut domain_results,
-        prev_results,
-        &ip_address,
-        &config.cloudflare,
-    )
-    .await;
+    // domains skipped by config validation are keyed by their config entry
+    for invalid_entry in &config.invalid_entries {
+        if let Some(hostname) = &invalid_entry.hostname {
+            let mut domain_result = DomainResult::new(hostname);
+            domain_result.outcome = Some(DomainOutcome::ConfigInvalid);
+            domain_result.errors = invalid_entry.errors.clone();
+            domain_results.insert(invalid_entry.entry.clone(), domain_result);
+        }
+    }
+
+    // endpoints are preflighted once per run and shared by their domains
+    let mut endpoint_results = HashMap::<String, Result<(), String>>::new();
+
+    let mut priorities: Vec<Option<u32>> =
+        domain_entries.iter().map(|entry| entry.priority).collect();
+    priorities.sort();
+    priorities.dedup();
+
+    for priority in priorities {
+        // plans and sends the updates of every dns service in the registry for this priority
+        macro_rules! update_services {
+            ([$(($feature:literal, $service:ident, $domain:ty)),* $(,)?]) => {$(
+                #[cfg(feature = $feature)]
+                {
+                    let mut planned_updates = plan_updates(
+                        &mut domain_results,
+                        decisions,
+                        prev_results,
+                        &run_states,
+                        stringify!($service),
+                        &config.$service,
+                        priority,
+                    );
+                    if config.preflight_endpoints {
+                        preflight_updates(
+                            &mut endpoint_results,
+                            &mut domain_results,
+                            decisions,
+                            stringify!($service),
+                            &mut planned_updates,
+                            config.dns_update_timeout_ms,
+                        )
+                        .await;
+                    }
+                    $service::update_domains(
+                        &mut domain_results,
+                        &planned_updates,
+                        config.dns_update_timeout_ms,
+                    )
+                    .await;
+                    if config.verify_updates {
+                        $service::verify_domains(
+                            &mut domain_results,
+                            &planned_updates,
+                            config.dns_update_timeout_ms,
+                        )
+                        .await;
+                    }
+                    record_sent_updates(
+                        decisions,
+                        &mut domain_results,
+                        prev_results,
+                        &planned_updates,
+                        timestamp,
+                    );
+                }
+            )*};
+        }
+
+        config::for_each_service!(update_services);
+    }
 
     Ok(domain_results)
 }
 
-// function to get ip address
+#[cfg_attr(not(feature = "services"), allow(dead_code))]
+fn get_entry<'a, T: DomainService>(service: &'static str, domain: &'a T) -> DomainEntry<'a> {
+    DomainEntry {
+        service,
+        key: get_domain_key(
+            service,
+            domain.hostname(),
+            domain.record_type(),
+            domain.priority(),
+        ),
+        hostname: domain.hostname(),
+        condition: domain.condition(),
+        priority: domain.priority(),
+        min_update_interval_ms: domain.min_update_interval_ms(),
+        compare_prefix_len: domain.compare_prefix_len(),
+        check_response_shape: domain.check_response_shape(),
+        address_family: get_address_family(domain.record_type()),
+        endpoint: domain.endpoint(),
+    }
+}
 
-fn get_ip_address(
+/*
+    Domain results are keyed by service, hostname, and record type so
+    entries that share a hostname keep separate results.
+
+    Domains in a failover chain share the result of their hostname.
+*/
+#[cfg_attr(not(feature = "services"), allow(dead_code))]
+fn get_domain_key(
+    service: &str,
+    hostname: &str,
+    record_type: Option<&str>,
+    priority: Option<u32>,
+) -> String {
+    // a failover chain is a hostname and record type, A and AAAA records are separate chains
+    if priority.is_some() {
+        return match get_address_family(record_type) {
+            AddressFamily::V4 => hostname.to_string() + ":A",
+            AddressFamily::V6 => hostname.to_string() + ":AAAA",
+        };
+    }
+
+    let key = service.to_string() + ":" + hostname;
+    match record_type {
+        Some(record_type) => key + ":" + record_type,
+        _ => key,
+    }
+}
+
+// only AAAA records are updated with an ipv6 address
+#[cfg_attr(not(feature = "services"), allow(dead_code))]
+fn get_address_family(record_type: Option<&str>) -> AddressFamily {
+    match record_type {
+        Some("AAAA") => AddressFamily::V6,
+        _ => AddressFamily::V4,
+    }
+}
+
+// the domain entries of every dns service in the registry
+macro_rules! domain_entries {
+    ([$(($feature:literal, $service:ident, $domain:ty)),* $(,)?] $config:ident) => {{
+        let mut domain_entries = Vec::<DomainEntry>::new();
+        $(
+            #[cfg(feature = $feature)]
+            domain_entries.extend(
+                $config
+                    .$service
+                    .iter()
+                    .map(|domain| get_entry(stringify!($service), domain)),
+            );
+        )*
+        domain_entries
+    }};
+}
+
+// keeps the domains of every dns service in the registry with a listed hostname
+macro_rules! retain_domains {
+    ([$(($feature:literal, $service:ident, $domain:ty)),* $(,)?] $config:ident, $hostnames:ident) => {$(
+        #[cfg(feature = $feature)]
+        $config
+            .$service
+            .retain(|domain| $hostnames.contains(domain.hostname()));
+    )*};
+}
+
+#[cfg_attr(not(feature = "services"), allow(unused_variables, unused_mut))]
+fn get_domain_entries(config: &Config) -> Vec<DomainEntry<'_>> {
+    config::for_each_service!(domain_entries, config)
+}
+
+// the keys of every domain result a config can produce
+pub fn get_domain_keys(config: &Config) -> Vec<String> {
+    get_domain_entries(config)
+        .into_iter()
+        .map(|entry| entry.key)
+        .collect()
+}
+
+// removes results of domains that are not in a config and returns their sorted keys
+pub fn remove_unknown_domain_results(
+    config: &Config,
+    domain_results: &mut HashMap<String, DomainResult>,
+) -> Vec<String> {
+    let domain_keys = get_domain_keys(config);
+    let mut unknown_keys: Vec<String> = domain_results
+        .keys()
+        .filter(|key| !domain_keys.contains(key))
+        .cloned()
+        .collect();
+    unknown_keys.sort();
+
+    for key in &unknown_keys {
+        domain_results.remove(key);
+    }
+
+    unknown_keys
+}
+
+/*
+    Keeps the domains of a config with a listed hostname, every entry of a
+    failover chain is kept. A hostname that is not a domain of the config
+    is an error so a typo never turns into a run that updates nothing.
+*/
+#[cfg_attr(not(feature = "services"), allow(unused_mut))]
+pub fn filter_domains(config: &Config, hostnames: &[String]) -> Result<Config, String> {
+    if hostnames.is_empty() {
+        return Err("domains: must not be empty".to_string());
+    }
+
+    // domains are kept in punycode, ie: bücher.example is xn--bcher-kva.example
+    let hostnames: Vec<String> = hostnames
+        .iter()
+        .map(|hostname| config::hostname_to_ascii(hostname).unwrap_or(hostname.clone()))
+        .collect();
+
+    let domain_entries = get_domain_entries(config);
+    let unknown_hostnames: Vec<&str> = hostnames
+        .iter()
+        .filter(|hostname| {
+            !domain_entries
+                .iter()
+                .any(|entry| entry.hostname == *hostname)
+        })
+        .map(|hostname| hostname.as_str())
+        .collect();
+    if !unknown_hostnames.is_empty() {
+        return Err(
+            "domains: not a domain in the config: ".to_string() + &unknown_hostnames.join(", ")
+        );
+    }
+
+    let mut config = config.clone();
+    config::for_each_service!(retain_domains, config, hostnames);
+
+    config
+        .invalid_entries
+        .retain(|invalid_entry| match &invalid_entry.hostname {
+            Some(hostname) => hostnames.contains(hostname),
+            _ => false,
+        });
+
+    Ok(config)
+}
+
+// plans domains of a single priority, skipped domains are added to domain results
+#[cfg_attr(not(feature = "services"), allow(dead_code))]
+fn plan_updates<'a, T: DomainService>(
+    domain_results: &mut HashMap<String, DomainResult>,
+    decisions: &mut DecisionLog,
+    prev_results: &Option<UpdateIpResults>,
+    run_states: &RunStates,
+    service: &'static str,
+    domains: &'a [T],
+    priority: Option<u32>,
+) -> Vec<PlannedUpdate<'a, T>> {
+    let mut planned_updates = Vec::<PlannedUpdate<T>>::new();
+
+    for domain in domains {
+        let entry = get_entry(service, domain);
+        if entry.priority != priority {
+            continue;
+        }
+
+        // a failover domain is only needed when its hostname failed
+        if priority.is_some() {
+            if let Some(domain_result) = domain_results.get(&entry.key) {
+                if !needs_failover(&domain_result.outcome) {
+                    decisions.push(Decision::FailoverNotNeeded { key: entry.key });
+                    continue;
+                }
+            }
+        }
+
+        let prev_domain_result = match prev_results {
+            Some(results) => results.domain_service_results.get(&entry.key),
+            _ => None,
+        };
+
+        let run_state = match (&run_states.v6, entry.address_family) {
+            (Some(run_state), AddressFamily::V6) => run_state,
+            _ => &run_states.v4,
+        };
+
+        // the other family might still have an address
+        let run_state = match run_state {
+            Ok(run_state) => run_state,
+            Err(e) => {
+                let mut domain_result = match prev_domain_result {
+                    Some(domain_result) => domain_result.clone(),
+                    _ => DomainResult::new(entry.hostname),
+                };
+                domain_result.outcome = Some(DomainOutcome::StaleAddress);
+                domain_result.errors = vec![e.clone()];
+                decisions.push(Decision::DomainSkipped {
+                    key: entry.key.clone(),
+                    outcome: domain_result.outcome.clone(),
+                    errors: domain_result.errors.clone(),
+                });
+                domain_results.insert(entry.key, domain_result);
+                continue;
+            }
+        };
+
+        let condition_not_met =
+            conditions::condition_not_met(&run_state.condition_results, entry.condition);
+
+        match plan_domain(&entry, prev_domain_result, run_state, condition_not_met) {
+            Plan::Skip(domain_result) => {
+                decisions.push(Decision::DomainSkipped {
+                    key: entry.key.clone(),
+                    outcome: domain_result.outcome.clone(),
+                    errors: domain_result.errors.clone(),
+                });
+                domain_results.insert(entry.key, domain_result);
+            }
+            Plan::Update(reason) => {
+                decisions.push(Decision::DomainPlanned {
+                    key: entry.key.clone(),
+                    reason,
+                });
+                planned_updates.push(PlannedUpdate {
+                    key: entry.key,
+                    domain,
+                    reason,
+                    ip_address: run_state.ip_address,
+                    check_response_shape: entry.check_response_shape,
+                });
+            }
+        }
+    }
+
+    planned_updates
+}
+
+/*
+    Only a failed update is retried by the next domain of a failover chain.
+
+    A domain skipped by its own checks, ie: min_update_interval_ms or its
+    condition, does not fail over so the next domain never overwrites the
+    result of a hostname that is correct. Skips of the run, ie: a stale
+    address, skip every domain of the chain the same way.
+*/
+fn needs_failover(outcome: &Option<DomainOutcome>) -> bool {
+    match outcome {
+        Some(DomainOutcome::Failed) => true,
+        Some(DomainOutcome::Updated)
+        | Some(DomainOutcome::Unchanged)
+        | Some(DomainOutcome::ConditionNotMet)
+        | Some(DomainOutcome::ConfigInvalid)
+        | Some(DomainOutcome::StaleAddress)
+        | Some(DomainOutcome::Deferred)
+        | Some(DomainOutcome::BehindCgnat)
+        | Some(DomainOutcome::Blocklisted)
+        | Some(DomainOutcome::AddressConflicted) => false,
+        None => true,
+    }
+}
+
+/*
+    Preflight connects to the endpoint of every planned update once per run.
+    Updates to an unreachable endpoint are not sent, their domains fail with
+    the endpoint in their errors and are retried next run.
+*/
+#[cfg_attr(not(feature = "services"), allow(dead_code))]
+async fn preflight_updates<'a, T: DomainService>(
+    endpoint_results: &mut HashMap<String, Result<(), String>>,
+    domain_results: &mut HashMap<String, DomainResult>,
+    decisions: &mut DecisionLog,
+    service: &'static str,
+    planned_updates: &mut Vec<PlannedUpdate<'a, T>>,
+    timeout_ms: Option<u64>,
+) {
+    let mut reachable_updates = Vec::<PlannedUpdate<T>>::new();
+
+    for planned_update in planned_updates.drain(..) {
+        let entry = get_entry(service, planned_update.domain);
+
+        // endpoints are keyed by host and port so tokens in urls are not recorded
+        let endpoint = match requests::get_url_authority(entry.endpoint) {
+            Ok(authority) => authority,
+            _ => {
+                reachable_updates.push(planned_update);
+                continue;
+            }
+        };
+
+        if !endpoint_results.contains_key(&endpoint) {
+            let timeout_ms = match timeout_ms {
+                Some(ms) => ms,
+                _ => DEFAULT_PREFLIGHT_TIMEOUT_MS,
+            };
+            let result = requests::probe_url(entry.endpoint, timeout_ms, None).await;
+            if let Err(e) = &result {
+                decisions.push(Decision::EndpointUnreachable {
+                    endpoint: endpoint.clone(),
+                    error: e.clone(),
+                });
+            }
+            endpoint_results.insert(endpoint.clone(), result);
+        }
+
+        if let Some(Ok(())) = endpoint_results.get(&endpoint) {
+            reachable_updates.push(planned_update);
+            continue;
+        }
+
+        let mut domain_result = DomainResult::new(entry.hostname);
+        domain_result.service = Some(entry.service.to_string());
+        domain_result.outcome = Some(DomainOutcome::Failed);
+        domain_result.reason = Some(planned_update.reason);
+        domain_result.errors = vec!["provider unreachable: ".to_string() + &endpoint];
+        decisions.push(Decision::DomainSkipped {
+            key: entry.key.clone(),
+            outcome: domain_result.outcome.clone(),
+            errors: domain_result.errors.clone(),
+        });
+        domain_results.insert(entry.key, domain_result);
+    }
+
+    *planned_updates = reachable_updates;
+}
+
+// records when updates were sent and what dns services returned
+#[cfg_attr(not(feature = "services"), allow(dead_code))]
+fn record_sent_updates<T>(
+    decisions: &mut DecisionLog,
+    domain_results: &mut HashMap<String, DomainResult>,
     prev_results: &Option<UpdateIpResults>,
-    ip_service_result: &Option<IpServiceResult>,
-) -> Result<String, String> {
+    planned_updates: &[PlannedUpdate<'_, T>],
+    timestamp: u128,
+) {
+    for planned_update in planned_updates {
+        if let Some(domain_result) = domain_results.get_mut(&planned_update.key) {
+            domain_result.sent_at = Some(timestamp);
+
+            let prev_shape = match prev_results {
+                Some(results) => results
+                    .domain_service_results
+                    .get(&planned_update.key)
+                    .and_then(|prev_result| prev_result.response_shape.clone()),
+                _ => None,
+            };
+            check_response_shape(
+                domain_result,
+                prev_shape,
+                planned_update.check_response_shape,
+            );
+            decisions.push(Decision::DomainSent {
+                key: planned_update.key.clone(),
+                service: domain_result.service.clone(),
+                outcome: domain_result.outcome.clone(),
+                errors: domain_result.errors.clone(),
+            });
+        }
+    }
+}
+
+// successful responses that differ from the previous one are only warned about
+#[cfg_attr(not(feature = "services"), allow(dead_code))]
+fn check_response_shape(
+    domain_result: &mut DomainResult,
+    prev_shape: Option<ResponseShape>,
+    enabled: bool,
+) {
+    let prev_shape = match prev_shape {
+        Some(shape) => shape,
+        _ => return,
+    };
+
+    // failed updates keep the shape of the last successful response
+    let shape = match &domain_result.response_shape {
+        Some(shape) => shape,
+        _ => {
+            domain_result.response_shape = Some(prev_shape);
+            return;
+        }
+    };
+
+    if enabled {
+        let mut warnings = results::compare_response_shapes(&prev_shape, shape);
+        domain_result.warnings.append(&mut warnings);
+    }
+}
+
+// the single place that decides if a domain is updated
+pub fn plan_domain(
+    entry: &DomainEntry,
+    prev_domain_result: Option<&DomainResult>,
+    run_state: &RunState,
+    condition_not_met: Option<String>,
+) -> Plan {
+    let mut domain_result = match prev_domain_result {
+        Some(domain_result) => domain_result.clone(),
+        _ => DomainResult::new(entry.hostname),
+    };
+
+    // with push_on_first_run a domain that was never set is pushed whatever its schedule
+    let forced = run_state.push_on_first_run && domain_result.ip_address.is_none();
+
+    if let (Some(reason), false) = (condition_not_met, forced) {
+        domain_result.outcome = Some(DomainOutcome::ConditionNotMet);
+        domain_result.errors = vec![reason];
+        return Plan::Skip(domain_result);
+    }
+
+    let reason = match (prev_domain_result, &domain_result.ip_address) {
+        (None, _) => UpdateReason::FirstUpdate,
+        (_, Some(domain_ip))
+            if domain_ip.matches(&run_state.ip_address, entry.compare_prefix_len) =>
+        {
+            domain_result.outcome = Some(DomainOutcome::Unchanged);
+            return Plan::Skip(domain_result);
+        }
+        (_, Some(_)) => UpdateReason::AddressChanged,
+        _ => UpdateReason::RetryAfterFailure,
+    };
+
+    // only addresses confirmed by a recent detection are sent to dns services
+    if !run_state.address_is_fresh {
+        domain_result.outcome = Some(DomainOutcome::StaleAddress);
+        domain_result.errors = vec!["address was not detected recently".to_string()];
+        return Plan::Skip(domain_result);
+    }
+
+    // blocklisted addresses are never published
+    if run_state.address_blocklisted {
+        domain_result.outcome = Some(DomainOutcome::Blocklisted);
+        domain_result.errors = vec!["blocklisted address".to_string()];
+        return Plan::Skip(domain_result);
+    }
+
+    // sources that disagree on the address might publish the wrong one
+    if run_state.address_conflicted {
+        domain_result.outcome = Some(DomainOutcome::AddressConflicted);
+        domain_result.errors = vec!["address detection conflicted".to_string()];
+        return Plan::Skip(domain_result);
+    }
+
+    // addresses behind carrier grade nat are not reachable from the internet
+    if run_state.suppressed_behind_cgnat {
+        domain_result.outcome = Some(DomainOutcome::BehindCgnat);
+        domain_result.errors = vec!["address is behind carrier grade nat".to_string()];
+        return Plan::Skip(domain_result);
+    }
+
+    // updates outside allowed windows wait for a run inside one
+    if run_state.outside_allowed_windows && !forced {
+        domain_result.outcome = Some(DomainOutcome::Deferred);
+        domain_result.errors = vec!["outside of allowed_windows".to_string()];
+        return Plan::Skip(domain_result);
+    }
+
+    // dns services with update quotas are sent at most once per min_update_interval_ms
+    if let (Some(interval_ms), Some(sent_at)) =
+        (entry.min_update_interval_ms, domain_result.sent_at)
+    {
+        let next_update_at = sent_at + interval_ms as u128;
+        if run_state.timestamp < next_update_at {
+            domain_result.outcome = Some(DomainOutcome::Deferred);
+            domain_result.errors =
+                vec!["next update allowed at ".to_string() + &next_update_at.to_string()];
+            return Plan::Skip(domain_result);
+        }
+    }
+
+    Plan::Update(reason)
+}
+
+// the run state of a family, None is the family of ip_services
+fn get_run_state(
+    config: &Config,
+    prev_results: &Option<UpdateIpResults>,
+    ip_service_result: Option<&IpServiceResult>,
+    family: Option<AddressFamily>,
+    condition_results: &ConditionResults,
+    timestamp: u128,
+    decisions: &mut DecisionLog,
+) -> Result<RunState, String> {
+    let prev_ip_service_result = prev_results
+        .as_ref()
+        .and_then(|results| results.get_ip_service_result(family));
+
+    let ip_address = get_ip_address(prev_ip_service_result, ip_service_result, family)?;
+    let address_is_fresh = address_is_fresh(
+        config,
+        prev_results,
+        prev_ip_service_result,
+        ip_service_result,
+        timestamp,
+    );
+
+    let detected = match ip_service_result {
+        Some(ip_result) => ip_result.ip_address.is_some(),
+        _ => false,
+    };
+    if !detected {
+        decisions.push(Decision::AddressCarriedForward {
+            ip_address,
+            is_fresh: address_is_fresh,
+        });
+    }
+
+    let outside_allowed_windows = match &config.allowed_windows {
+        Some(windows) => !windows.iter().any(|window| window.contains(timestamp)),
+        _ => false,
+    };
+
+    Ok(RunState {
+        ip_address,
+        address_is_fresh,
+        push_on_first_run: config.push_on_first_run,
+        condition_results: condition_results.clone(),
+        timestamp,
+        suppressed_behind_cgnat: config.suppress_updates_behind_cgnat
+            && is_behind_cgnat(ip_service_result),
+        address_blocklisted: is_blocklisted(config, &ip_address),
+        address_conflicted: config.block_updates_on_conflict && is_conflicted(ip_service_result),
+        outside_allowed_windows,
+    })
+}
+
+fn get_ip_address(
+    prev_ip_service_result: Option<&IpServiceResult>,
+    ip_service_result: Option<&IpServiceResult>,
+    family: Option<AddressFamily>,
+) -> Result<Address, String> {
     if let Some(ip_result) = ip_service_result {
         if let Some(ip_addr) = &ip_result.ip_address {
-            return Ok(ip_addr.clone());
+            return Ok(*ip_addr);
+        }
+    }
+    if let Some(prev_ip_result) = prev_ip_service_result {
+        if let Some(ip_addr) = &prev_ip_result.ip_address {
+            return Ok(*ip_addr);
+        }
+    }
+
+    match family {
+        Some(AddressFamily::V4) => Err("there are no ipv4 addresses to update".to_string()),
+        Some(AddressFamily::V6) => Err("there are no ipv6 addresses to update".to_string()),
+        _ => Err("there are no ip addresses to update".to_string()),
+    }
+}
+
+/*
+    An address detected this run is always fresh.
+
+    An address carried forward from previous results is only fresh when
+    it was detected within address_max_staleness_ms of the run timestamp.
+    Results written before detected_at was recorded fall back to the
+    timestamp of the results.
+*/
+fn address_is_fresh(
+    config: &Config,
+    prev_results: &Option<UpdateIpResults>,
+    prev_ip_service_result: Option<&IpServiceResult>,
+    ip_service_result: Option<&IpServiceResult>,
+    timestamp: u128,
+) -> bool {
+    if let Some(ip_result) = ip_service_result {
+        if ip_result.ip_address.is_some() {
+            return true;
+        }
+    }
+
+    let (max_staleness_ms, prev_result, prev_ip_result) = match (
+        config.address_max_staleness_ms,
+        prev_results,
+        prev_ip_service_result,
+    ) {
+        (Some(ms), Some(results), Some(ip_result)) => (ms, results, ip_result),
+        _ => return false,
+    };
+
+    // results are only written after a successful detection
+    let detected_at = match prev_ip_result.detected_at {
+        Some(detected_at) => detected_at,
+        _ => prev_result.timestamp,
+    };
+    timestamp.saturating_sub(detected_at) <= max_staleness_ms as u128
+}
+
+// invalid ranges are rejected by config validation
+fn is_blocklisted(config: &Config, ip_address: &Address) -> bool {
+    config
+        .blocklist
+        .iter()
+        .filter_map(|range| range.parse::<AddressRange>().ok())
+        .any(|range| range.contains(ip_address))
+}
+
+fn is_conflicted(ip_service_result: Option<&IpServiceResult>) -> bool {
+    match ip_service_result {
+        Some(ip_result) => ip_result.confidence == Some(Confidence::Conflicted),
+        _ => false,
+    }
+}
+
+fn is_behind_cgnat(ip_service_result: Option<&IpServiceResult>) -> bool {
+    match ip_service_result {
+        Some(ip_result) => ip_result.nat_classification == Some(NatClassification::Cgnat),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failover_keys_are_separate_per_record_type() {
+        let key_a = get_domain_key("dyndns2", "example.com", None, Some(1));
+        let key_aaaa = get_domain_key("cloudflare", "example.com", Some("AAAA"), Some(1));
+
+        assert_eq!(key_a, "example.com:A");
+        assert_eq!(key_aaaa, "example.com:AAAA");
+        assert_ne!(key_a, key_aaaa);
+    }
+
+    #[test]
+    fn failover_keys_are_shared_across_services() {
+        assert_eq!(
+            get_domain_key("dyndns2", "example.com", None, Some(1)),
+            get_domain_key("cloudflare", "example.com", Some("A"), Some(2))
+        );
+        assert_eq!(
+            get_domain_key("query_get", "example.com", Some("AAAA"), Some(1)),
+            get_domain_key("freedns", "example.com", Some("AAAA"), Some(2))
+        );
+    }
+
+    #[test]
+    fn keys_without_priority_include_the_service() {
+        assert_eq!(
+            get_domain_key("dyndns2", "example.com", None, None),
+            "dyndns2:example.com"
+        );
+        assert_eq!(
+            get_domain_key("cloudflare", "example.com", Some("AAAA"), None),
+            "cloudflare:example.com:AAAA"
+        );
+    }
+
+    const HOSTNAME: &str = "example.com";
+
+    fn get_entry(hostname: &String) -> DomainEntry<'_> {
+        DomainEntry {
+            key: get_domain_key("dyndns2", hostname, None, None),
+            service: "dyndns2",
+            hostname,
+            condition: &None,
+            priority: None,
+            min_update_interval_ms: None,
+            compare_prefix_len: &None,
+            check_response_shape: true,
+            address_family: AddressFamily::V4,
+            endpoint: "https://example.com/nic/update",
+        }
+    }
+
+    fn get_run_state(address: &str) -> RunState {
+        RunState {
+            ip_address: address.parse().unwrap(),
+            address_is_fresh: true,
+            push_on_first_run: false,
+            condition_results: ConditionResults::new(),
+            timestamp: 1_000_000,
+            suppressed_behind_cgnat: false,
+            address_blocklisted: false,
+            address_conflicted: false,
+            outside_allowed_windows: false,
+        }
+    }
+
+    // a check of a run state that fails the plan of a domain
+    type FailCheck = fn(&mut RunState);
+
+    fn get_skipped_result(plan: Plan) -> DomainResult {
+        match plan {
+            Plan::Skip(domain_result) => domain_result,
+            Plan::Update(reason) => panic!("expected a skip but got {:?}", reason),
+        }
+    }
+
+    fn get_prev_result(address: Option<&str>, outcome: DomainOutcome) -> DomainResult {
+        let mut domain_result = DomainResult::new(HOSTNAME);
+        domain_result.ip_address = address.map(|address| address.parse().unwrap());
+        domain_result.outcome = Some(outcome);
+        domain_result
+    }
+
+    fn get_update_reason(plan: Plan) -> UpdateReason {
+        match plan {
+            Plan::Update(reason) => reason,
+            Plan::Skip(domain_result) => {
+                panic!("expected an update but got {:?}", domain_result.outcome)
+            }
+        }
+    }
+
+    #[test]
+    fn domains_without_previous_results_are_first_updates() {
+        let hostname = HOSTNAME.to_string();
+        let plan = plan_domain(
+            &get_entry(&hostname),
+            None,
+            &get_run_state("192.0.2.1"),
+            None,
+        );
+        assert_eq!(get_update_reason(plan), UpdateReason::FirstUpdate);
+    }
+
+    #[test]
+    fn changed_addresses_are_updated() {
+        let hostname = HOSTNAME.to_string();
+        let prev_domain_result = get_prev_result(Some("192.0.2.1"), DomainOutcome::Updated);
+        let plan = plan_domain(
+            &get_entry(&hostname),
+            Some(&prev_domain_result),
+            &get_run_state("192.0.2.2"),
+            None,
+        );
+        assert_eq!(get_update_reason(plan), UpdateReason::AddressChanged);
+    }
+
+    #[test]
+    fn failed_domains_are_retried() {
+        let hostname = HOSTNAME.to_string();
+        let prev_domain_result = get_prev_result(None, DomainOutcome::Failed);
+        let plan = plan_domain(
+            &get_entry(&hostname),
+            Some(&prev_domain_result),
+            &get_run_state("192.0.2.1"),
+            None,
+        );
+        assert_eq!(get_update_reason(plan), UpdateReason::RetryAfterFailure);
+    }
+
+    #[test]
+    fn unchanged_addresses_are_skipped_before_checks() {
+        let hostname = HOSTNAME.to_string();
+        let prev_domain_result = get_prev_result(Some("192.0.2.1"), DomainOutcome::Updated);
+        let mut run_state = get_run_state("192.0.2.1");
+        run_state.address_is_fresh = false;
+        run_state.address_blocklisted = true;
+
+        let plan = plan_domain(
+            &get_entry(&hostname),
+            Some(&prev_domain_result),
+            &run_state,
+            None,
+        );
+        let domain_result = get_skipped_result(plan);
+        assert_eq!(domain_result.outcome, Some(DomainOutcome::Unchanged));
+        assert!(domain_result.errors.is_empty());
+    }
+
+    #[test]
+    fn addresses_within_the_compared_prefix_are_unchanged() {
+        let hostname = HOSTNAME.to_string();
+        let mut entry = get_entry(&hostname);
+        let compare_prefix_len = Some(ComparePrefixLen {
+            v4: Some(24),
+            v6: None,
+        });
+        entry.compare_prefix_len = &compare_prefix_len;
+        let prev_domain_result = get_prev_result(Some("192.0.2.1"), DomainOutcome::Updated);
+
+        let plan = plan_domain(
+            &entry,
+            Some(&prev_domain_result),
+            &get_run_state("192.0.2.200"),
+            None,
+        );
+        assert_eq!(
+            get_skipped_result(plan).outcome,
+            Some(DomainOutcome::Unchanged)
+        );
+
+        let plan = plan_domain(
+            &entry,
+            Some(&prev_domain_result),
+            &get_run_state("192.0.3.1"),
+            None,
+        );
+        assert_eq!(get_update_reason(plan), UpdateReason::AddressChanged);
+    }
+
+    #[test]
+    fn ipv6_suffix_changes_keep_the_published_address() {
+        let hostname = HOSTNAME.to_string();
+        let mut entry = get_entry(&hostname);
+        entry.address_family = AddressFamily::V6;
+        let compare_prefix_len = Some(ComparePrefixLen {
+            v4: None,
+            v6: Some(56),
+        });
+        entry.compare_prefix_len = &compare_prefix_len;
+        let prev_domain_result = get_prev_result(Some("2001:db8:0:100::1"), DomainOutcome::Updated);
+
+        // a rotated suffix is not a change and the published address is kept
+        let plan = plan_domain(
+            &entry,
+            Some(&prev_domain_result),
+            &get_run_state("2001:db8:0:100:a:b:c:d"),
+            None,
+        );
+        let domain_result = get_skipped_result(plan);
+        assert_eq!(domain_result.outcome, Some(DomainOutcome::Unchanged));
+        assert_eq!(domain_result.ip_address, prev_domain_result.ip_address);
+
+        // a new prefix is sent with the full detected address
+        let run_state = get_run_state("2001:db8:0:200:a:b:c:d");
+        let plan = plan_domain(&entry, Some(&prev_domain_result), &run_state, None);
+        assert_eq!(get_update_reason(plan), UpdateReason::AddressChanged);
+        assert_eq!(run_state.ip_address.to_string(), "2001:db8:0:200:a:b:c:d");
+    }
+
+    #[test]
+    fn checks_skip_changed_addresses_in_order() {
+        let hostname = HOSTNAME.to_string();
+        let entry = get_entry(&hostname);
+        let prev_domain_result = get_prev_result(Some("192.0.2.1"), DomainOutcome::Updated);
+
+        // every check fails, each run passes one more check
+        let checks: [(FailCheck, DomainOutcome, &str); 5] = [
+            (
+                |run_state| run_state.address_is_fresh = false,
+                DomainOutcome::StaleAddress,
+                "address was not detected recently",
+            ),
+            (
+                |run_state| run_state.address_blocklisted = true,
+                DomainOutcome::Blocklisted,
+                "blocklisted address",
+            ),
+            (
+                |run_state| run_state.address_conflicted = true,
+                DomainOutcome::AddressConflicted,
+                "address detection conflicted",
+            ),
+            (
+                |run_state| run_state.suppressed_behind_cgnat = true,
+                DomainOutcome::BehindCgnat,
+                "address is behind carrier grade nat",
+            ),
+            (
+                |run_state| run_state.outside_allowed_windows = true,
+                DomainOutcome::Deferred,
+                "outside of allowed_windows",
+            ),
+        ];
+
+        for index in 0..checks.len() {
+            let mut run_state = get_run_state("192.0.2.2");
+            for (fail_check, _, _) in &checks[index..] {
+                fail_check(&mut run_state);
+            }
+
+            let plan = plan_domain(&entry, Some(&prev_domain_result), &run_state, None);
+            let domain_result = get_skipped_result(plan);
+            let (_, outcome, error) = &checks[index];
+            assert_eq!(domain_result.outcome.as_ref(), Some(outcome));
+            assert_eq!(domain_result.errors, vec![error.to_string()]);
+            // the previous address is kept so the update is sent later
+            assert_eq!(domain_result.ip_address, prev_domain_result.ip_address);
+        }
+    }
+
+    #[test]
+    fn updates_wait_for_min_update_interval_ms() {
+        let hostname = HOSTNAME.to_string();
+        let mut entry = get_entry(&hostname);
+        entry.min_update_interval_ms = Some(60_000);
+        let mut prev_domain_result = get_prev_result(Some("192.0.2.1"), DomainOutcome::Updated);
+        let run_state = get_run_state("192.0.2.2");
+
+        prev_domain_result.sent_at = Some(run_state.timestamp - 30_000);
+        let plan = plan_domain(&entry, Some(&prev_domain_result), &run_state, None);
+        let domain_result = get_skipped_result(plan);
+        assert_eq!(domain_result.outcome, Some(DomainOutcome::Deferred));
+        assert_eq!(
+            domain_result.errors,
+            vec!["next update allowed at 1030000".to_string()]
+        );
+
+        prev_domain_result.sent_at = Some(run_state.timestamp - 60_000);
+        let plan = plan_domain(&entry, Some(&prev_domain_result), &run_state, None);
+        assert_eq!(get_update_reason(plan), UpdateReason::AddressChanged);
+    }
+
+    fn get_staleness_config(address_max_staleness_ms: Option<u64>) -> Config {
+        let mut config_value = serde_json::json!({
+            "results_filepath": "results.json",
+            "ip_services": [["https://ip.example/", "address_as_body"]],
+            "address_max_staleness_ms": address_max_staleness_ms,
+        });
+        for name in config::get_service_names() {
+            config_value[name] = serde_json::json!([]);
+        }
+        serde_json::from_value(config_value).unwrap()
+    }
+
+    // previous results of a detection, written at results_timestamp
+    fn get_detected_results(
+        detected_at: Option<u128>,
+        results_timestamp: u128,
+    ) -> Option<UpdateIpResults> {
+        let mut ip_service_result = IpServiceResult::new("https://ip.example/");
+        ip_service_result.ip_address = Some("203.0.113.4".parse().unwrap());
+        ip_service_result.detected_at = detected_at;
+
+        let mut results = UpdateIpResults::from_results(
+            ip_service_result,
+            None,
+            HashMap::new(),
+            DecisionLog::new(),
+            "prev-run",
+            None,
+        );
+        results.timestamp = results_timestamp;
+        Some(results)
+    }
+
+    // whether the previous address is fresh for a run at 1_000_000 that failed to detect
+    fn carried_forward_is_fresh(config: &Config, prev_results: &Option<UpdateIpResults>) -> bool {
+        let prev_ip_service_result = prev_results
+            .as_ref()
+            .and_then(|results| results.get_ip_service_result(None));
+        let failed_result = IpServiceResult::new("https://ip.example/");
+        address_is_fresh(
+            config,
+            prev_results,
+            prev_ip_service_result,
+            Some(&failed_result),
+            1_000_000,
+        )
+    }
+
+    #[test]
+    fn addresses_detected_this_run_are_fresh() {
+        let mut ip_service_result = IpServiceResult::new("https://ip.example/");
+        ip_service_result.ip_address = Some("203.0.113.4".parse().unwrap());
+        assert!(address_is_fresh(
+            &get_staleness_config(None),
+            &None,
+            None,
+            Some(&ip_service_result),
+            1_000_000,
+        ));
+    }
+
+    #[test]
+    fn carried_forward_addresses_are_stale_by_default() {
+        let config = get_staleness_config(None);
+        let prev_results = get_detected_results(Some(1_000_000), 1_000_000);
+        assert!(!carried_forward_is_fresh(&config, &prev_results));
+        assert!(!carried_forward_is_fresh(
+            &get_staleness_config(Some(60_000)),
+            &None
+        ));
+    }
+
+    #[test]
+    fn carried_forward_addresses_are_fresh_within_address_max_staleness_ms() {
+        let config = get_staleness_config(Some(60_000));
+        let cases = [(940_000, true), (939_999, false), (1_000_000, true)];
+        for (detected_at, is_fresh) in cases {
+            let prev_results = get_detected_results(Some(detected_at), 1_000_000);
+            assert_eq!(
+                carried_forward_is_fresh(&config, &prev_results),
+                is_fresh,
+                "{}",
+                detected_at
+            );
+        }
+    }
+
+    #[test]
+    fn missing_detection_times_fall_back_to_the_results_timestamp() {
+        let config = get_staleness_config(Some(60_000));
+        let cases = [(940_000, true), (939_999, false)];
+        for (results_timestamp, is_fresh) in cases {
+            let prev_results = get_detected_results(None, results_timestamp);
+            assert_eq!(
+                carried_forward_is_fresh(&config, &prev_results),
+                is_fresh,
+                "{}",
+                results_timestamp
+            );
+        }
+
+        // a detection time is preferred over the results timestamp
+        let prev_results = get_detected_results(Some(939_999), 1_000_000);
+        assert!(!carried_forward_is_fresh(&config, &prev_results));
+    }
+
+    #[test]
+    fn stale_addresses_are_never_sent() {
+        let hostname = HOSTNAME.to_string();
+        let entry = get_entry(&hostname);
+        let mut run_state = get_run_state("203.0.113.4");
+        run_state.address_is_fresh = false;
+
+        let prev_domain_result = get_prev_result(Some("203.0.113.1"), DomainOutcome::Updated);
+        let plan = plan_domain(&entry, Some(&prev_domain_result), &run_state, None);
+        let domain_result = get_skipped_result(plan);
+        assert_eq!(domain_result.outcome, Some(DomainOutcome::StaleAddress));
+        assert_eq!(
+            domain_result.errors,
+            vec!["address was not detected recently".to_string()]
+        );
+
+        // an unchanged address is not sent, so it is never stale
+        let prev_domain_result = get_prev_result(Some("203.0.113.4"), DomainOutcome::Updated);
+        let plan = plan_domain(&entry, Some(&prev_domain_result), &run_state, None);
+        assert_eq!(
+            get_skipped_result(plan).outcome,
+            Some(DomainOutcome::Unchanged)
+        );
+    }
+
+    #[test]
+    fn first_pushes_ignore_conditions_and_allowed_windows() {
+        let hostname = HOSTNAME.to_string();
+        let entry = get_entry(&hostname);
+        let mut run_state = get_run_state("203.0.113.4");
+        run_state.outside_allowed_windows = true;
+
+        let plan = plan_domain(
+            &entry,
+            None,
+            &run_state,
+            Some("condition not met".to_string()),
+        );
+        assert_eq!(
+            get_skipped_result(plan).outcome,
+            Some(DomainOutcome::ConditionNotMet)
+        );
+        let plan = plan_domain(&entry, None, &run_state, None);
+        assert_eq!(
+            get_skipped_result(plan).outcome,
+            Some(DomainOutcome::Deferred)
+        );
+
+        run_state.push_on_first_run = true;
+        let plan = plan_domain(
+            &entry,
+            None,
+            &run_state,
+            Some("condition not met".to_string()),
+        );
+        assert_eq!(get_update_reason(plan), UpdateReason::FirstUpdate);
+
+        // domains are pushed until an update succeeds
+        let prev_domain_result = get_prev_result(None, DomainOutcome::Failed);
+        let plan = plan_domain(
+            &entry,
+            Some(&prev_domain_result),
+            &run_state,
+            Some("condition not met".to_string()),
+        );
+        assert_eq!(get_update_reason(plan), UpdateReason::RetryAfterFailure);
+
+        // then change detection resumes
+        let prev_domain_result = get_prev_result(Some("203.0.113.1"), DomainOutcome::Updated);
+        let plan = plan_domain(&entry, Some(&prev_domain_result), &run_state, None);
+        assert_eq!(
+            get_skipped_result(plan).outcome,
+            Some(DomainOutcome::Deferred)
+        );
+    }
+
+    #[test]
+    fn first_pushes_never_send_unsafe_addresses() {
+        let hostname = HOSTNAME.to_string();
+        let entry = get_entry(&hostname);
+        let checks: [(FailCheck, DomainOutcome); 4] = [
+            (
+                |run_state| run_state.address_is_fresh = false,
+                DomainOutcome::StaleAddress,
+            ),
+            (
+                |run_state| run_state.address_blocklisted = true,
+                DomainOutcome::Blocklisted,
+            ),
+            (
+                |run_state| run_state.address_conflicted = true,
+                DomainOutcome::AddressConflicted,
+            ),
+            (
+                |run_state| run_state.suppressed_behind_cgnat = true,
+                DomainOutcome::BehindCgnat,
+            ),
+        ];
+
+        for (fail_check, outcome) in checks {
+            let mut run_state = get_run_state("203.0.113.4");
+            run_state.push_on_first_run = true;
+            fail_check(&mut run_state);
+
+            let plan = plan_domain(&entry, None, &run_state, None);
+            let domain_result = get_skipped_result(plan);
+            assert_eq!(domain_result.outcome, Some(outcome));
+            assert_eq!(domain_result.ip_address, None);
+        }
+    }
+
+    #[test]
+    fn textual_forms_across_runs_are_not_updated() {
+        let hostname = HOSTNAME.to_string();
+        let mut entry = get_entry(&hostname);
+        entry.address_family = AddressFamily::V6;
+        let textual_forms = [
+            "2001:db8::1",
+            "2001:DB8::1",
+            "2001:db8:0:0:0:0:0:1",
+            "[2001:0db8::0001]",
+            "2001:db8::1%eth0",
+        ];
+
+        let mut prev_domain_result = DomainResult::new(&hostname);
+        prev_domain_result.ip_address = Some(textual_forms[0].parse().unwrap());
+        prev_domain_result.outcome = Some(DomainOutcome::Updated);
+
+        // an unchanged address is never planned, so no provider is called
+        for textual_form in textual_forms {
+            let run_state = get_run_state(textual_form);
+            let plan = plan_domain(&entry, Some(&prev_domain_result), &run_state, None);
+            let domain_result = get_skipped_result(plan);
+            assert_eq!(domain_result.outcome, Some(DomainOutcome::Unchanged));
+            prev_domain_result = domain_result;
         }
     }
-    if let Some(prev_result) = prev_results {
-        if let Some(ip_addr) = &prev_result.ip_service_result.ip_address {
-            return Ok(ip_addr.clone());
+
+    #[cfg(feature = "dyndns2")]
+    fn get_ip_service_result(address: &str) -> IpServiceResult {
+        let mut ip_service_result = IpServiceResult::new("https://ip.example/");
+        ip_service_result.ip_address = Some(address.parse().unwrap());
+        ip_service_result.detected_at = Some(results::get_timestamp_or_zero());
+        ip_service_result
+    }
+
+    // sends every request to a responder that records it and answers good
+    #[cfg(feature = "dyndns2")]
+    async fn update_dual_stack(
+        config: &Config,
+        prev_results: &Option<UpdateIpResults>,
+        address: &str,
+        address_v6: &str,
+    ) -> (HashMap<String, DomainResult>, Vec<String>) {
+        let uris = std::rc::Rc::new(std::cell::RefCell::new(Vec::<String>::new()));
+        let responder_uris = uris.clone();
+        requests::mock_transport::set_responder(move |request| {
+            responder_uris.borrow_mut().push(request.uri.clone());
+            Ok(results::ResponseJson {
+                status_code: 200,
+                body: "good".to_string(),
+                timestamp: results::get_timestamp_or_zero(),
+                server_timestamp: None,
+                content_type: None,
+                bot_challenge: false,
+                tls: None,
+            })
+        });
+
+        let domain_results = update_domains(
+            config,
+            prev_results,
+            &get_ip_service_result(address),
+            &Some(get_ip_service_result(address_v6)),
+            &mut DecisionLog::new(),
+        )
+        .await;
+        requests::mock_transport::clear_responder();
+
+        let uris = uris.borrow().clone();
+        (domain_results.unwrap(), uris)
+    }
+
+    #[cfg(feature = "dyndns2")]
+    #[tokio::test]
+    async fn dual_entries_keep_separate_state_across_runs() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "results_filepath": "results.json",
+                "ip_services": [["https://ip.example/", "address_as_body"]],
+                "ip_services_v6": [["https://ip6.example/", "address_as_body"]],
+                "cloudflare": [],
+                "query_get": [],
+                "freedns": [],
+                "dyndns2": [
+                    {"service_uri": "https://dyndns.example/nic/update", "hostname": "example.com", "record_type": "A", "username": "a", "password": "b"},
+                    {"service_uri": "https://dyndns.example/nic/update", "hostname": "example.com", "record_type": "AAAA", "username": "a", "password": "b"}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let (key, key_v6) = ("dyndns2:example.com:A", "dyndns2:example.com:AAAA");
+        assert_eq!(get_domain_keys(&config), vec![key, key_v6]);
+
+        // the first run updates both records
+        let (domain_results, uris) =
+            update_dual_stack(&config, &None, "192.0.2.1", "2001:db8::1").await;
+        assert_eq!(uris.len(), 2);
+        assert_eq!(domain_results.len(), 2);
+        assert_eq!(
+            domain_results[key].ip_address,
+            Some("192.0.2.1".parse().unwrap())
+        );
+        assert_eq!(
+            domain_results[key_v6].ip_address,
+            Some("2001:db8::1".parse().unwrap())
+        );
+
+        // the second run only updates the record of the changed family
+        let prev_results = Some(UpdateIpResults::from_results(
+            get_ip_service_result("192.0.2.1"),
+            Some(get_ip_service_result("2001:db8::1")),
+            domain_results,
+            DecisionLog::new(),
+            "run-1",
+            None,
+        ));
+        let (domain_results, uris) =
+            update_dual_stack(&config, &prev_results, "192.0.2.1", "2001:db8::2").await;
+        assert_eq!(uris.len(), 1);
+        assert!(uris[0].contains("myip=2001%3Adb8%3A%3A2"), "{}", uris[0]);
+
+        assert_eq!(domain_results[key].outcome, Some(DomainOutcome::Unchanged));
+        assert_eq!(
+            domain_results[key].ip_address,
+            Some("192.0.2.1".parse().unwrap())
+        );
+        assert_eq!(domain_results[key_v6].outcome, Some(DomainOutcome::Updated));
+        assert_eq!(
+            domain_results[key_v6].ip_address,
+            Some("2001:db8::2".parse().unwrap())
+        );
+    }
+
+    #[cfg(feature = "dyndns2")]
+    #[test]
+    fn imported_state_keeps_only_domains_of_the_config() {
+        let config_with_hostnames = |hostname: &str, hostname_v6: &str| -> Config {
+            let config_str = r#"{
+                "results_filepath": "results.json",
+                "ip_services": [["https://ip.example/", "address_as_body"]],
+                "cloudflare": [],
+                "query_get": [],
+                "freedns": [],
+                "dyndns2": [
+                    {"service_uri": "https://dyndns.example/nic/update", "hostname": "HOSTNAME", "username": "a", "password": "b"},
+                    {"service_uri": "https://dyndns.example/nic/update", "hostname": "HOSTNAME_V6", "record_type": "AAAA", "username": "a", "password": "b"}
+                ]
+            }"#
+            .replace("HOSTNAME_V6", hostname_v6)
+            .replace("HOSTNAME", hostname);
+            serde_json::from_str(&config_str).unwrap()
+        };
+
+        // state exported on the old host
+        let old_config = config_with_hostnames("a.example", "b.example");
+        let mut domain_results = HashMap::new();
+        for key in get_domain_keys(&old_config) {
+            let mut domain_result = DomainResult::new(&key);
+            domain_result.outcome = Some(DomainOutcome::Updated);
+            domain_results.insert(key, domain_result);
         }
+        let results = UpdateIpResults::from_results(
+            IpServiceResult::new("https://ip.example/"),
+            None,
+            domain_results,
+            DecisionLog::new(),
+            "run-1",
+            None,
+        );
+        let json_str = results::export_state(results).unwrap();
+
+        // imported into a config where b.example was renamed
+        let new_config = config_with_hostnames("a.example", "c.example");
+        let mut imported = results::import_state(&json_str).unwrap();
+        let skipped_keys =
+            remove_unknown_domain_results(&new_config, &mut imported.domain_service_results);
+
+        assert_eq!(skipped_keys, vec!["dyndns2:b.example:AAAA"]);
+        let mut imported_keys: Vec<&String> = imported.domain_service_results.keys().collect();
+        imported_keys.sort();
+        assert_eq!(imported_keys, vec!["dyndns2:a.example"]);
+
+        // the renamed domain has no previous result and is a first update
+        let hostname = "c.example".to_string();
+        let prev_domain_result = imported
+            .domain_service_results
+            .get("dyndns2:c.example:AAAA");
+        let plan = plan_domain(
+            &get_entry(&hostname),
+            prev_domain_result,
+            &get_run_state("2001:db8::1"),
+            None,
+        );
+        assert_eq!(get_update_reason(plan), UpdateReason::FirstUpdate);
     }
 
-    Err("there are no ip addresses to update".to_string())
+    #[cfg(feature = "dyndns2")]
+    #[tokio::test]
+    async fn unreachable_endpoints_fail_only_their_domains() {
+        // a listening endpoint is healthy, nothing listens on port 1
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let healthy = "http://".to_string() + &listener.local_addr().unwrap().to_string();
+        let unhealthy = "http://127.0.0.1:1".to_string();
+
+        let domain = |endpoint: &str, hostname: &str, priority: u32| {
+            serde_json::json!({
+                "service_uri": endpoint.to_string() + "/nic/update",
+                "hostname": hostname,
+                "username": "a",
+                "password": "b",
+                "priority": priority,
+            })
+        };
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "results_filepath": "results.json",
+            "ip_services": [["https://ip.example/", "address_as_body"]],
+            "preflight_endpoints": true,
+            "dns_update_timeout_ms": 2000,
+            "cloudflare": [],
+            "query_get": [],
+            "freedns": [],
+            "dyndns2": [
+                domain(&healthy, "a.example", 0),
+                domain(&unhealthy, "b.example", 0),
+                domain(&healthy, "c.example", 0),
+                // the endpoint of a later priority is not probed again
+                domain(&unhealthy, "d.example", 1),
+                domain(&healthy, "e.example", 1),
+            ],
+        }))
+        .unwrap();
+
+        let uris = std::rc::Rc::new(std::cell::RefCell::new(Vec::<String>::new()));
+        let responder_uris = uris.clone();
+        requests::mock_transport::set_responder(move |request| {
+            responder_uris.borrow_mut().push(request.uri.clone());
+            Ok(results::ResponseJson {
+                status_code: 200,
+                body: "good".to_string(),
+                timestamp: results::get_timestamp_or_zero(),
+                server_timestamp: None,
+                content_type: None,
+                bot_challenge: false,
+                tls: None,
+            })
+        });
+        let mut decisions = DecisionLog::new();
+        let domain_results = update_domains(
+            &config,
+            &None,
+            &get_ip_service_result("192.0.2.1"),
+            &None,
+            &mut decisions,
+        )
+        .await
+        .unwrap();
+        requests::mock_transport::clear_responder();
+
+        // prioritised domains are keyed by their failover chain
+        assert_eq!(domain_results.len(), 5);
+
+        // only the domains of the healthy endpoint are sent
+        let uris = uris.borrow().clone();
+        assert_eq!(uris.len(), 3);
+        assert!(uris.iter().all(|uri| uri.starts_with(&healthy)));
+
+        for hostname in ["a.example", "c.example", "e.example"] {
+            let domain_result = &domain_results[&(hostname.to_string() + ":A")];
+            assert_eq!(domain_result.outcome, Some(DomainOutcome::Updated));
+            assert!(domain_result.errors.is_empty());
+        }
+        for hostname in ["b.example", "d.example"] {
+            let domain_result = &domain_results[&(hostname.to_string() + ":A")];
+            assert_eq!(domain_result.hostname, hostname);
+            assert_eq!(domain_result.outcome, Some(DomainOutcome::Failed));
+            assert_eq!(domain_result.service.as_deref(), Some("dyndns2"));
+            assert_eq!(domain_result.reason, Some(UpdateReason::FirstUpdate));
+            assert_eq!(
+                domain_result.errors,
+                vec!["provider unreachable: 127.0.0.1:1".to_string()]
+            );
+            assert_eq!(domain_result.ip_address, None);
+        }
+
+        // each endpoint is probed once and shared by its domains
+        let unreachable: Vec<&String> = decisions
+            .iter()
+            .filter_map(|decision| match decision {
+                Decision::EndpointUnreachable { endpoint, .. } => Some(endpoint),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(unreachable, vec!["127.0.0.1:1"]);
+
+        let skipped: Vec<&String> = decisions
+            .iter()
+            .filter_map(|decision| match decision {
+                Decision::DomainSkipped { key, .. } => Some(key),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(skipped, vec!["b.example:A", "d.example:A"]);
+    }
+
+    #[test]
+    fn only_failed_domains_fail_over() {
+        assert!(needs_failover(&Some(DomainOutcome::Failed)));
+        assert!(needs_failover(&None));
+
+        let outcomes = [
+            DomainOutcome::Updated,
+            DomainOutcome::Unchanged,
+            DomainOutcome::ConditionNotMet,
+            DomainOutcome::Deferred,
+            DomainOutcome::StaleAddress,
+        ];
+        for outcome in outcomes {
+            assert!(!needs_failover(&Some(outcome.clone())), "{:?}", outcome);
+        }
+    }
+
+    // a primary and a secondary dyndns2 domain of one hostname
+    #[cfg(feature = "dyndns2")]
+    fn get_failover_config(min_update_interval_ms: Option<u64>) -> Config {
+        serde_json::from_value(serde_json::json!({
+            "results_filepath": "results.json",
+            "ip_services": [["https://ip.example/", "address_as_body"]],
+            "cloudflare": [],
+            "query_get": [],
+            "freedns": [],
+            "dyndns2": [
+                {"service_uri": "https://primary.example/nic/update", "hostname": "example.com", "username": "a", "password": "b", "priority": 1, "min_update_interval_ms": min_update_interval_ms},
+                {"service_uri": "https://secondary.example/nic/update", "hostname": "example.com", "username": "a", "password": "b", "priority": 2}
+            ]
+        }))
+        .unwrap()
+    }
+
+    // the primary answers with primary_body and the secondary answers good
+    #[cfg(feature = "dyndns2")]
+    async fn update_failover(
+        config: &Config,
+        prev_results: &Option<UpdateIpResults>,
+        primary_body: &'static str,
+    ) -> (DomainResult, Vec<String>) {
+        let uris = std::rc::Rc::new(std::cell::RefCell::new(Vec::<String>::new()));
+        let responder_uris = uris.clone();
+        requests::mock_transport::set_responder(move |request| {
+            responder_uris.borrow_mut().push(request.uri.clone());
+            let body = match request.uri.contains("primary.example") {
+                true => primary_body,
+                false => "good",
+            };
+            Ok(results::ResponseJson {
+                status_code: 200,
+                body: body.to_string(),
+                timestamp: results::get_timestamp_or_zero(),
+                server_timestamp: None,
+                content_type: None,
+                bot_challenge: false,
+                tls: None,
+            })
+        });
+
+        let domain_results = update_domains(
+            config,
+            prev_results,
+            &get_ip_service_result("192.0.2.2"),
+            &None,
+            &mut DecisionLog::new(),
+        )
+        .await;
+        requests::mock_transport::clear_responder();
+
+        let mut domain_results = domain_results.unwrap();
+        assert_eq!(domain_results.len(), 1);
+        let uris = uris.borrow().clone();
+        (domain_results.remove("example.com:A").unwrap(), uris)
+    }
+
+    #[cfg(feature = "dyndns2")]
+    #[tokio::test]
+    async fn unchanged_primaries_do_not_fail_over() {
+        let config = get_failover_config(None);
+
+        let (domain_result, uris) = update_failover(&config, &None, "nochg 192.0.2.2").await;
+        assert_eq!(uris.len(), 1);
+        assert!(uris[0].contains("primary.example"));
+        assert_eq!(domain_result.outcome, Some(DomainOutcome::Unchanged));
+        assert_eq!(domain_result.ip_address, Some("192.0.2.2".parse().unwrap()));
+        assert!(domain_result.errors.is_empty());
+    }
+
+    #[cfg(feature = "dyndns2")]
+    #[tokio::test]
+    async fn failed_primaries_fail_over() {
+        let config = get_failover_config(None);
+
+        let (domain_result, uris) = update_failover(&config, &None, "badauth").await;
+        assert_eq!(uris.len(), 2);
+        assert!(uris[1].contains("secondary.example"));
+        assert_eq!(domain_result.outcome, Some(DomainOutcome::Updated));
+        assert_eq!(domain_result.ip_address, Some("192.0.2.2".parse().unwrap()));
+    }
+
+    #[cfg(feature = "dyndns2")]
+    #[tokio::test]
+    async fn deferred_primaries_do_not_fail_over() {
+        let config = get_failover_config(Some(3_600_000));
+
+        // the primary sent the previous address a moment ago
+        let mut prev_domain_result = get_prev_result(Some("192.0.2.1"), DomainOutcome::Updated);
+        prev_domain_result.sent_at = Some(results::get_timestamp_or_zero());
+        let prev_results = Some(UpdateIpResults::from_results(
+            get_ip_service_result("192.0.2.1"),
+            None,
+            HashMap::from([("example.com:A".to_string(), prev_domain_result)]),
+            DecisionLog::new(),
+            "run-1",
+            None,
+        ));
+
+        let (domain_result, uris) = update_failover(&config, &prev_results, "good").await;
+        assert_eq!(uris.len(), 0);
+        assert_eq!(domain_result.outcome, Some(DomainOutcome::Deferred));
+        assert_eq!(domain_result.ip_address, Some("192.0.2.1".parse().unwrap()));
+    }
+
+    #[cfg(feature = "query_get")]
+    #[test]
+    fn domains_are_filtered_by_punycode_hostname() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "results_filepath": "results.json",
+                "ip_services": [],
+                "dyndns2": [],
+                "cloudflare": [],
+                "freedns": [],
+                "query_get": [
+                    {"service_uri": "https://example.com/update", "hostname": "xn--bcher-kva.example", "ip_param": "ip"},
+                    {"service_uri": "https://example.com/update", "hostname": "example.com", "ip_param": "ip"}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        for hostname in ["bücher.example", "xn--bcher-kva.example"] {
+            let filtered_config = filter_domains(&config, &[hostname.to_string()]).unwrap();
+            assert_eq!(filtered_config.query_get.len(), 1);
+            assert_eq!(
+                filtered_config.query_get[0].hostname,
+                "xn--bcher-kva.example"
+            );
+        }
+    }
 }
@@ -0,0 +1,317 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+
+use config::Config;
+use requests::mock_transport::{self, MockRequest};
+use results::{
+    Address, DecisionLog, DomainOutcome, DomainResult, IpServiceResult, ResponseJson,
+    UpdateIpResults,
+};
+
+/*
+    Each fixture in tests/fixtures/<provider>/ is one update of one domain.
+
+    The requests a provider sends are answered in order by the exchanges of
+    the fixture. A request must match the method, the path and query, the
+    headers, and the body of its exchange. Secrets are never written to a
+    fixture, ie: an authorization header is matched by "^Basic ".
+
+    Patterns are a subset of regular expressions: literals, ".", "*", "+",
+    "?", "^", "$", and "\" escapes.
+*/
+
+#[derive(Deserialize)]
+struct Fixture {
+    // the config property of the domain, ie: query_get for duckdns
+    service: String,
+    domain: Value,
+    address: Address,
+    // a previous result of the domain that holds this address
+    prev_address: Option<Address>,
+    exchanges: VecDeque<Exchange>,
+    outcome: DomainOutcome,
+    // every error is a substring of an error of the domain result
+    #[serde(default)]
+    errors: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Exchange {
+    request: ExpectedRequest,
+    response: CannedResponse,
+}
+
+#[derive(Deserialize)]
+struct ExpectedRequest {
+    method: String,
+    path: String,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    body: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CannedResponse {
+    status: u16,
+    body: String,
+    content_type: Option<String>,
+}
+
+#[cfg_attr(
+    not(any(feature = "dyndns2", feature = "cloudflare", feature = "query_get")),
+    allow(unused_macros)
+)]
+macro_rules! fixture_tests {
+    ($provider:ident: $($case:ident),+) => {
+        mod $provider {
+            $(
+                #[tokio::test]
+                async fn $case() {
+                    super::run_fixture(include_str!(concat!(
+                        "fixtures/",
+                        stringify!($provider),
+                        "/",
+                        stringify!($case),
+                        ".json"
+                    )))
+                    .await;
+                }
+            )+
+        }
+    };
+}
+
+// add more providers here
+#[cfg(feature = "dyndns2")]
+fixture_tests!(dyndns2: success, no_change, auth_failure, rate_limit);
+
+#[cfg(feature = "cloudflare")]
+fixture_tests!(cloudflare: success, no_change, auth_failure, rate_limit);
+
+#[cfg(feature = "query_get")]
+fixture_tests!(duckdns: success, no_change, auth_failure, rate_limit);
+
+#[allow(dead_code)]
+async fn run_fixture(fixture_str: &str) {
+    let mut fixture: Fixture = serde_json::from_str(fixture_str).unwrap();
+
+    let mut config_value = json!({
+        "results_filepath": "results.json",
+        "ip_services": [],
+        "dyndns2": [],
+        "cloudflare": [],
+        "query_get": [],
+        "freedns": [],
+    });
+    config_value[&fixture.service] = json!([fixture.domain]);
+    let config: Config = serde_json::from_value(config_value).unwrap();
+    let keys = domain_services::get_domain_keys(&config);
+    assert_eq!(keys.len(), 1);
+
+    let mut ip_service_result = IpServiceResult::new("fixture");
+    ip_service_result.ip_address = Some(fixture.address);
+    ip_service_result.detected_at = Some(results::get_timestamp_or_zero());
+
+    let prev_results = fixture.prev_address.map(|prev_address| {
+        let mut domain_result = DomainResult::new("fixture");
+        domain_result.ip_address = Some(prev_address);
+        domain_result.outcome = Some(DomainOutcome::Updated);
+        UpdateIpResults::from_results(
+            ip_service_result.clone(),
+            None,
+            HashMap::from([(keys[0].clone(), domain_result)]),
+            DecisionLog::default(),
+            "fixture",
+            None,
+        )
+    });
+
+    let exchanges = Rc::new(RefCell::new(std::mem::take(&mut fixture.exchanges)));
+    let mismatches = Rc::new(RefCell::new(Vec::<String>::new()));
+    let (responder_exchanges, responder_mismatches) = (exchanges.clone(), mismatches.clone());
+    mock_transport::set_responder(move |request| {
+        let exchange = match responder_exchanges.borrow_mut().pop_front() {
+            Some(exchange) => exchange,
+            _ => {
+                let mismatch = "unexpected request: ".to_string() + &request.uri;
+                responder_mismatches.borrow_mut().push(mismatch.clone());
+                return Err(mismatch);
+            }
+        };
+
+        if let Err(mismatch) = match_request(&exchange.request, request) {
+            responder_mismatches.borrow_mut().push(mismatch.clone());
+            return Err(mismatch);
+        }
+
+        Ok(ResponseJson {
+            status_code: exchange.response.status,
+            body: exchange.response.body,
+            timestamp: results::get_timestamp_or_zero(),
+            server_timestamp: None,
+            content_type: exchange.response.content_type,
+            bot_challenge: false,
+            tls: None,
+        })
+    });
+
+    let mut decisions = DecisionLog::default();
+    let domain_results = domain_services::update_domains(
+        &config,
+        &prev_results,
+        &ip_service_result,
+        &None,
+        &mut decisions,
+    )
+    .await;
+    mock_transport::clear_responder();
+
+    assert_eq!(*mismatches.borrow(), Vec::<String>::new());
+    assert!(
+        exchanges.borrow().is_empty(),
+        "{} exchanges were not requested",
+        exchanges.borrow().len()
+    );
+
+    let domain_result = &domain_results.unwrap()[&keys[0]];
+    assert_eq!(
+        domain_result.outcome,
+        Some(fixture.outcome),
+        "{:?}",
+        domain_result.errors
+    );
+    for error in &fixture.errors {
+        assert!(
+            domain_result.errors.iter().any(|e| e.contains(error)),
+            "{} not in {:?}",
+            error,
+            domain_result.errors
+        );
+    }
+}
+
+fn match_request(expected: &ExpectedRequest, request: &MockRequest) -> Result<(), String> {
+    if expected.method != request.method {
+        return Err("expected ".to_string() + &expected.method + " but got " + &request.method);
+    }
+
+    // the path and query of an absolute uri, ie: /nic/update?hostname=example.com
+    let path = match request.uri.split_once("://") {
+        Some((_, rest)) => rest.find('/').map(|index| &rest[index..]).unwrap_or("/"),
+        _ => request.uri.as_str(),
+    };
+    if !is_match(&expected.path, path) {
+        return Err("path ".to_string() + path + " does not match " + &expected.path);
+    }
+
+    for (name, pattern) in &expected.headers {
+        let value = request
+            .headers
+            .iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str());
+        match value {
+            Some(value) if is_match(pattern, value) => {}
+            _ => return Err("header ".to_string() + name + " does not match " + pattern),
+        }
+    }
+
+    if let Some(pattern) = &expected.body {
+        if !is_match(pattern, &request.body) {
+            return Err("body ".to_string() + &request.body + " does not match " + pattern);
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+enum Atom {
+    Any,
+    Char(char),
+}
+
+fn is_match(pattern: &str, text: &str) -> bool {
+    let (anchored_start, pattern) = match pattern.strip_prefix('^') {
+        Some(pattern) => (true, pattern),
+        _ => (false, pattern),
+    };
+    let (anchored_end, pattern) = match pattern.strip_suffix('$') {
+        Some(pattern) if !pattern.ends_with('\\') => (true, pattern),
+        _ => (false, pattern),
+    };
+
+    // atoms and how often they repeat, ie: (min, max)
+    let mut tokens = Vec::<(Atom, usize, usize)>::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        let atom = match c {
+            '.' => Atom::Any,
+            '\\' => Atom::Char(chars.next().unwrap_or('\\')),
+            _ => Atom::Char(c),
+        };
+        tokens.push((atom, 1, 1));
+
+        let repeat = match chars.clone().next() {
+            Some('*') => (0, usize::MAX),
+            Some('+') => (1, usize::MAX),
+            Some('?') => (0, 1),
+            _ => continue,
+        };
+        chars.next();
+        if let Some(token) = tokens.last_mut() {
+            (token.1, token.2) = repeat;
+        }
+    }
+
+    let text: Vec<char> = text.chars().collect();
+    match anchored_start {
+        true => match_here(&tokens, &text, anchored_end),
+        false => (0..=text.len()).any(|index| match_here(&tokens, &text[index..], anchored_end)),
+    }
+}
+
+fn match_here(tokens: &[(Atom, usize, usize)], text: &[char], anchored_end: bool) -> bool {
+    let (atom, min, max) = match tokens.first() {
+        Some(token) => *token,
+        _ => return !anchored_end || text.is_empty(),
+    };
+
+    let mut count = 0;
+    while count < max && count < text.len() {
+        match atom {
+            Atom::Char(c) if text[count] != c => break,
+            _ => count += 1,
+        }
+    }
+
+    // repeats are greedy and give back characters until the rest matches
+    (min..=count)
+        .rev()
+        .any(|count| match_here(&tokens[1..], &text[count..], anchored_end))
+}
+
+#[test]
+fn patterns_match_a_regex_subset() {
+    assert!(is_match(
+        "^/nic/update\\?hostname=.+$",
+        "/nic/update?hostname=a"
+    ));
+    assert!(!is_match(
+        "^/nic/update\\?hostname=.+$",
+        "/nic/update?hostname="
+    ));
+    assert!(is_match("^Basic ", "Basic YWxpY2U6aHVudGVyMg=="));
+    assert!(!is_match("^Basic ", "Bearer token"));
+    assert!(is_match(
+        "\"content\":\"203\\.0\\.113\\.4\"",
+        "{\"content\":\"203.0.113.4\"}"
+    ));
+    assert!(!is_match("203\\.0\\.113\\.4", "203x0x113x4"));
+    assert!(is_match("^a?b*c$", "c"));
+    assert!(is_match("^a?b*c$", "abbbc"));
+    assert!(!is_match("^a?b*c$", "aabc"));
+}
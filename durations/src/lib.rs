@@ -0,0 +1,239 @@
+use serde::de::{Deserializer, Error, Unexpected, Visitor};
+use std::fmt;
+
+/*
+    Durations in the config are milliseconds or humane strings.
+    A string is a list of amounts with units, ie: "500ms", "30s", "5m", "1h30m", "2d".
+
+    Durations are always stored and written as milliseconds.
+*/
+
+const EXPECTED: &str =
+    "milliseconds or a duration like \"500ms\", \"30s\", \"5m\", \"1h30m\", \"2d\"";
+
+pub fn deserialize_ms<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_any(MsVisitor)
+}
+
+// use with #[serde(default)] so a missing field is None
+pub fn deserialize_optional_ms<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_option(OptionalMsVisitor)
+}
+
+pub fn parse_duration_ms(duration: &str) -> Result<u64, String> {
+    let duration = duration.trim();
+    if duration.is_empty() {
+        return Err("duration is empty".to_string());
+    }
+
+    let mut total_ms: u64 = 0;
+    let mut rest = duration;
+    while !rest.is_empty() {
+        let amount_len = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let unit_len = rest[amount_len..]
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len() - amount_len);
+
+        let amount = match rest[..amount_len].parse::<u64>() {
+            Ok(amount) => amount,
+            _ => return Err(duration.to_string() + " is not a duration"),
+        };
+        let unit_ms = match &rest[amount_len..amount_len + unit_len] {
+            "ms" => 1,
+            "s" => 1000,
+            "m" => 60 * 1000,
+            "h" => 60 * 60 * 1000,
+            "d" => 24 * 60 * 60 * 1000,
+            "" => return Err(duration.to_string() + " is missing a unit"),
+            unit => return Err(duration.to_string() + " has an unknown unit " + unit),
+        };
+
+        total_ms = match amount
+            .checked_mul(unit_ms)
+            .and_then(|ms| total_ms.checked_add(ms))
+        {
+            Some(ms) => ms,
+            _ => return Err(duration.to_string() + " is too long"),
+        };
+
+        rest = &rest[amount_len + unit_len..];
+    }
+
+    Ok(total_ms)
+}
+
+struct MsVisitor;
+
+impl<'de> Visitor<'de> for MsVisitor {
+    type Value = u64;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(EXPECTED)
+    }
+
+    fn visit_u64<E: Error>(self, ms: u64) -> Result<u64, E> {
+        Ok(ms)
+    }
+
+    fn visit_i64<E: Error>(self, ms: i64) -> Result<u64, E> {
+        match u64::try_from(ms) {
+            Ok(ms) => Ok(ms),
+            _ => Err(E::invalid_value(Unexpected::Signed(ms), &self)),
+        }
+    }
+
+    fn visit_str<E: Error>(self, duration: &str) -> Result<u64, E> {
+        match parse_duration_ms(duration) {
+            Ok(ms) => Ok(ms),
+            Err(e) => Err(E::custom(e + ", expected " + EXPECTED)),
+        }
+    }
+}
+
+struct OptionalMsVisitor;
+
+impl<'de> Visitor<'de> for OptionalMsVisitor {
+    type Value = Option<u64>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(EXPECTED)
+    }
+
+    fn visit_none<E: Error>(self) -> Result<Option<u64>, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: Error>(self) -> Result<Option<u64>, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D>(self, deserializer: D) -> Result<Option<u64>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserialize_ms(deserializer).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Timeouts {
+        #[serde(deserialize_with = "deserialize_ms")]
+        timeout_ms: u64,
+        #[serde(default, deserialize_with = "deserialize_optional_ms")]
+        interval_ms: Option<u64>,
+    }
+
+    #[test]
+    fn durations_are_parsed_as_milliseconds() {
+        assert_eq!(parse_duration_ms("500ms"), Ok(500));
+        assert_eq!(parse_duration_ms("30s"), Ok(30_000));
+        assert_eq!(parse_duration_ms("5m"), Ok(300_000));
+        assert_eq!(parse_duration_ms("1h30m"), Ok(5_400_000));
+        assert_eq!(parse_duration_ms("2d"), Ok(172_800_000));
+        assert_eq!(parse_duration_ms(" 1m30s500ms "), Ok(90_500));
+        assert_eq!(parse_duration_ms("0s"), Ok(0));
+    }
+
+    #[test]
+    fn invalid_durations_are_errors() {
+        assert_eq!(parse_duration_ms(""), Err("duration is empty".to_string()));
+        assert_eq!(
+            parse_duration_ms("30"),
+            Err("30 is missing a unit".to_string())
+        );
+        assert_eq!(
+            parse_duration_ms("30x"),
+            Err("30x has an unknown unit x".to_string())
+        );
+        assert_eq!(
+            parse_duration_ms("s"),
+            Err("s is not a duration".to_string())
+        );
+        assert_eq!(
+            parse_duration_ms("-5s"),
+            Err("-5s is not a duration".to_string())
+        );
+        assert_eq!(
+            parse_duration_ms("1.5s"),
+            Err("1.5s has an unknown unit .".to_string())
+        );
+        assert_eq!(
+            parse_duration_ms("99999999999999999d"),
+            Err("99999999999999999d is too long".to_string())
+        );
+    }
+
+    #[test]
+    fn numbers_and_strings_deserialize_to_the_same_milliseconds() {
+        let from_numbers: Timeouts =
+            serde_json::from_str(r#"{"timeout_ms": 90000, "interval_ms": 5400000}"#).unwrap();
+        let from_strings: Timeouts =
+            serde_json::from_str(r#"{"timeout_ms": "1m30s", "interval_ms": "1h30m"}"#).unwrap();
+        assert_eq!(from_numbers, from_strings);
+        assert_eq!(
+            from_strings,
+            Timeouts {
+                timeout_ms: 90_000,
+                interval_ms: Some(5_400_000),
+            }
+        );
+    }
+
+    #[test]
+    fn durations_round_trip_as_milliseconds() {
+        for (json, ms) in [
+            (r#"{"timeout_ms": "500ms", "interval_ms": "2d"}"#, 500),
+            (r#"{"timeout_ms": "30s", "interval_ms": null}"#, 30_000),
+            (r#"{"timeout_ms": 1234}"#, 1234),
+        ] {
+            let timeouts: Timeouts = serde_json::from_str(json).unwrap();
+            assert_eq!(timeouts.timeout_ms, ms);
+
+            // written as milliseconds and read back unchanged
+            let written = serde_json::to_string(&timeouts).unwrap();
+            assert!(written.contains(&("\"timeout_ms\":".to_string() + &ms.to_string())));
+            let read_back: Timeouts = serde_json::from_str(&written).unwrap();
+            assert_eq!(read_back, timeouts);
+        }
+    }
+
+    #[test]
+    fn missing_and_null_optional_durations_are_none() {
+        let timeouts: Timeouts = serde_json::from_str(r#"{"timeout_ms": 1}"#).unwrap();
+        assert_eq!(timeouts.interval_ms, None);
+        let timeouts: Timeouts =
+            serde_json::from_str(r#"{"timeout_ms": 1, "interval_ms": null}"#).unwrap();
+        assert_eq!(timeouts.interval_ms, None);
+    }
+
+    #[test]
+    fn deserialize_errors_show_the_accepted_forms() {
+        let error = serde_json::from_str::<Timeouts>(r#"{"timeout_ms": "5 minutes"}"#)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.starts_with("5 minutes has an unknown unit"),
+            "{}",
+            error
+        );
+        assert!(error.contains(EXPECTED), "{}", error);
+
+        let error = serde_json::from_str::<Timeouts>(r#"{"timeout_ms": -1}"#)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains(EXPECTED), "{}", error);
+    }
+}
@@ -5,8 +5,10 @@ use http_body_util::Empty;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-use requests;
-use results::{DomainResult, ResponseJson, UpdateIpResults};
+use conditions::Condition;
+use results::{
+    Address, ComparePrefixLen, DomainOutcome, DomainResult, PlannedUpdate, ResponseJson,
+};
 
 /*
     Implements a subset of the dyndns2 protocol.
@@ -22,53 +24,116 @@ use results::{DomainResult, ResponseJson, UpdateIpResults};
 pub struct Dyndns2 {
     pub service_uri: String,
     pub hostname: String,
+    pub record_type: Option<String>,
     pub username: String,
     pub password: String,
+    pub condition: Option<Condition>,
+    pub priority: Option<u32>,
+    #[serde(default, deserialize_with = "durations::deserialize_optional_ms")]
+    pub min_update_interval_ms: Option<u64>,
+    pub compare_prefix_len: Option<ComparePrefixLen>,
+    pub check_response_shape: Option<bool>,
 }
 
 pub type Dyndns2Domains = Vec<Dyndns2>;
 
+const SERVICE_NAME: &str = "dyndns2";
 const CLIENT_HEADER_VALUE: &str = "hyper/1.0 rust-client";
 
+pub fn validate(domain: &Dyndns2) -> Vec<String> {
+    let mut errors = Vec::<String>::new();
+
+    if let Err(e) = requests::validate_url(&domain.service_uri) {
+        errors.push("service_uri: ".to_string() + &e);
+    }
+    if domain.hostname.is_empty() {
+        errors.push("hostname: must not be empty".to_string());
+    }
+    if domain.username.is_empty() {
+        errors.push("username: must not be empty".to_string());
+    }
+    if let Some(record_type) = &domain.record_type {
+        if record_type != "A" && record_type != "AAAA" {
+            errors.push("record_type: ".to_string() + record_type + " must be A or AAAA");
+        }
+    }
+    if let Some(condition) = &domain.condition {
+        errors.append(&mut conditions::validate(condition));
+    }
+
+    errors
+}
+
 // must return results
 pub async fn update_domains(
     domain_results: &mut HashMap<String, DomainResult>,
-    prev_results: &Option<UpdateIpResults>,
-    ip_address: &str,
-    domains: &Dyndns2Domains,
+    planned_updates: &[PlannedUpdate<'_, Dyndns2>],
+    timeout_ms: Option<u64>,
 ) {
-    for domain in domains {
-        let domain_result = match prev_results {
-            Some(results) => match results.domain_service_results.get(&domain.hostname) {
-                Some(domain) => domain.clone(),
-                _ => DomainResult::new(&domain.hostname),
-            },
-            _ => DomainResult::new(&domain.hostname),
-        };
+    for planned_update in planned_updates {
+        let domain_result = build_domain_result(planned_update, timeout_ms).await;
 
-        let hostname = domain.hostname.clone();
+        // write over previous entry
+        domain_results.insert(planned_update.key.clone(), domain_result);
+    }
+}
 
-        if let Some(domain_ip) = &domain_result.ip_address {
-            if domain_ip == ip_address {
-                domain_results.insert(hostname, domain_result);
-                continue;
-            }
+/*
+    Verified updates are read back from the authoritative nameservers of
+    the hostname. A record that does not hold the sent address is updated
+    and read back once more before the domain fails.
+*/
+pub async fn verify_domains(
+    domain_results: &mut HashMap<String, DomainResult>,
+    planned_updates: &[PlannedUpdate<'_, Dyndns2>],
+    timeout_ms: Option<u64>,
+) {
+    for planned_update in planned_updates {
+        let updated = match domain_results.get(&planned_update.key) {
+            Some(domain_result) => domain_result.outcome == Some(DomainOutcome::Updated),
+            _ => false,
+        };
+        if !updated {
+            continue;
         }
 
-        // build domain result
-        let domain_result = build_domain_result(&domain, ip_address).await;
+        let hostname = &planned_update.domain.hostname;
+        let ip_address = &planned_update.ip_address;
+        let mut verified = dns_read_back::verify_address(hostname, ip_address, timeout_ms).await;
+        if verified.is_err() {
+            let domain_result = build_domain_result(planned_update, timeout_ms).await;
+            if domain_result.outcome == Some(DomainOutcome::Updated) {
+                verified = dns_read_back::verify_address(hostname, ip_address, timeout_ms).await;
+            }
+            domain_results.insert(planned_update.key.clone(), domain_result);
+        }
 
-        // write over previous entry
-        domain_results.insert(hostname, domain_result);
+        // a failed verify is retried next run
+        if let (Err(e), Some(domain_result)) =
+            (verified, domain_results.get_mut(&planned_update.key))
+        {
+            domain_result.outcome = Some(DomainOutcome::Failed);
+            domain_result.ip_address = None;
+            domain_result.errors.push(e);
+        }
     }
 }
 
-async fn build_domain_result(domain: &Dyndns2, ip_address: &str) -> DomainResult {
+async fn build_domain_result(
+    planned_update: &PlannedUpdate<'_, Dyndns2>,
+    timeout_ms: Option<u64>,
+) -> DomainResult {
+    let domain = planned_update.domain;
+    let ip_address = &planned_update.ip_address;
+
     let mut domain_result = DomainResult::new(&domain.hostname);
+    domain_result.service = Some(SERVICE_NAME.to_string());
+    domain_result.reason = Some(planned_update.reason);
 
-    let request = match get_https_dyndns2_req(&domain, &ip_address) {
+    let request = match get_https_dyndns2_req(domain, ip_address) {
         Ok(s) => s,
         Err(e) => {
+            domain_result.outcome = Some(DomainOutcome::Failed);
             domain_result.errors.push(e);
             return domain_result;
         }
@@ -77,24 +142,61 @@ async fn build_domain_result(domain: &Dyndns2, ip_address: &str) -> DomainResult
     // update domain service
     // create json-able struct from response
     // add to domain result
-    match requests::request_http1_tls_response(request).await {
+    let response = requests::request_http1_tls_response(request);
+    match requests::with_timeout(timeout_ms, response).await {
         Ok(r) => {
-            if verify_resposne(&r) {
-                domain_result.ip_address = Some(ip_address.to_string());
+            if let Some(warning) = requests::get_clock_skew_warning(&r) {
+                domain_result.warnings.push(warning);
+            }
+            match verify_resposne(&r) {
+                Ok(outcome) => {
+                    domain_result.outcome = Some(outcome);
+                    domain_result.ip_address = Some(*ip_address);
+                    domain_result.response_shape = Some(results::get_response_shape(&r));
+                }
+                Err(e) => {
+                    domain_result.outcome = Some(DomainOutcome::Failed);
+                    domain_result.errors.push(e);
+                }
             }
         }
-        Err(e) => domain_result.errors.push(e),
+        Err(e) => {
+            domain_result.outcome = Some(DomainOutcome::Failed);
+            domain_result.errors.push(e);
+        }
     }
 
     domain_result
 }
 
-fn verify_resposne(res: &ResponseJson) -> bool {
-    res.status_code >= 200 && res.status_code < 300
+/*
+    Responses start with a return code, ie: "good 203.0.113.4" or "nochg 203.0.113.4".
+    Every other return code fails the update, ie: badauth, nohost, abuse, or 911.
+*/
+fn verify_resposne(res: &ResponseJson) -> Result<DomainOutcome, String> {
+    if res.status_code < 200 || res.status_code >= 300 {
+        return Err("response status ".to_string() + &res.status_code.to_string());
+    }
+
+    let body = res.body.trim();
+    match body.split_whitespace().next() {
+        Some("good") => Ok(DomainOutcome::Updated),
+        Some("nochg") => Ok(DomainOutcome::Unchanged),
+        _ => Err("dyndns2 error: ".to_string() + body),
+    }
 }
 
-fn get_https_dyndns2_req(domain: &Dyndns2, ip_addr: &str) -> Result<Request<Empty<Bytes>>, String> {
-    let uri_str = domain.service_uri.clone() + "?hostname=" + &domain.hostname + "&myip=" + ip_addr;
+fn get_https_dyndns2_req(
+    domain: &Dyndns2,
+    ip_addr: &Address,
+) -> Result<Request<Empty<Bytes>>, String> {
+    let uri_str = requests::append_query_params(
+        &domain.service_uri,
+        &[
+            ("hostname", &domain.hostname),
+            ("myip", &ip_addr.to_string()),
+        ],
+    )?;
     let uri = match uri_str.parse::<http::Uri>() {
         Ok(u) => u,
         Err(e) => return Err(e.to_string()),
@@ -105,7 +207,7 @@ fn get_https_dyndns2_req(domain: &Dyndns2, ip_addr: &str) -> Result<Request<Empt
     };
 
     let auth_str = domain.username.to_string() + ":" + &domain.password;
-    let auth = general_purpose::STANDARD.encode(&auth_str.as_bytes());
+    let auth = general_purpose::STANDARD.encode(auth_str.as_bytes());
     let auth_value = "Basic ".to_string() + &auth;
 
     match Request::builder()
@@ -0,0 +1,205 @@
+use bytes::Bytes;
+use http::Request;
+use http_body_util::Empty;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use conditions::Condition;
+use results::{
+    Address, ComparePrefixLen, DomainOutcome, DomainResult, PlannedUpdate, ResponseJson,
+};
+
+/*
+    Implements the freedns.afraid.org randomized update url.
+    https://freedns.afraid.org/dynamic/v2/
+
+    Every record has its own update url with a secret token in the path.
+    The ip address is sent in the address query parameter.
+*/
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct FreeDns {
+    pub update_url: String,
+    pub hostname: String,
+    pub record_type: Option<String>,
+    pub condition: Option<Condition>,
+    pub priority: Option<u32>,
+    #[serde(default, deserialize_with = "durations::deserialize_optional_ms")]
+    pub min_update_interval_ms: Option<u64>,
+    pub compare_prefix_len: Option<ComparePrefixLen>,
+    pub check_response_shape: Option<bool>,
+}
+
+pub type FreeDnsDomains = Vec<FreeDns>;
+
+const SERVICE_NAME: &str = "freedns";
+const CLIENT_HEADER_VALUE: &str = "hyper/1.0 rust-client";
+
+pub fn validate(domain: &FreeDns) -> Vec<String> {
+    let mut errors = Vec::<String>::new();
+
+    // the url is a secret, only the error is reported
+    if let Err(e) = requests::validate_url(&domain.update_url) {
+        errors.push("update_url: ".to_string() + &e);
+    }
+    if domain.hostname.is_empty() {
+        errors.push("hostname: must not be empty".to_string());
+    }
+    if let Some(record_type) = &domain.record_type {
+        if record_type != "A" && record_type != "AAAA" {
+            errors.push("record_type: ".to_string() + record_type + " must be A or AAAA");
+        }
+    }
+    if let Some(condition) = &domain.condition {
+        errors.append(&mut conditions::validate(condition));
+    }
+
+    errors
+}
+
+pub async fn update_domains(
+    domain_results: &mut HashMap<String, DomainResult>,
+    planned_updates: &[PlannedUpdate<'_, FreeDns>],
+    timeout_ms: Option<u64>,
+) {
+    for planned_update in planned_updates {
+        let domain_result = build_domain_result(planned_update, timeout_ms).await;
+
+        // write over previous entry
+        domain_results.insert(planned_update.key.clone(), domain_result);
+    }
+}
+
+/*
+    Verified updates are read back from the authoritative nameservers of
+    the hostname. A record that does not hold the sent address is updated
+    and read back once more before the domain fails.
+*/
+pub async fn verify_domains(
+    domain_results: &mut HashMap<String, DomainResult>,
+    planned_updates: &[PlannedUpdate<'_, FreeDns>],
+    timeout_ms: Option<u64>,
+) {
+    for planned_update in planned_updates {
+        let updated = match domain_results.get(&planned_update.key) {
+            Some(domain_result) => domain_result.outcome == Some(DomainOutcome::Updated),
+            _ => false,
+        };
+        if !updated {
+            continue;
+        }
+
+        let hostname = &planned_update.domain.hostname;
+        let ip_address = &planned_update.ip_address;
+        let mut verified = dns_read_back::verify_address(hostname, ip_address, timeout_ms).await;
+        if verified.is_err() {
+            let domain_result = build_domain_result(planned_update, timeout_ms).await;
+            if domain_result.outcome == Some(DomainOutcome::Updated) {
+                verified = dns_read_back::verify_address(hostname, ip_address, timeout_ms).await;
+            }
+            domain_results.insert(planned_update.key.clone(), domain_result);
+        }
+
+        // a failed verify is retried next run
+        if let (Err(e), Some(domain_result)) =
+            (verified, domain_results.get_mut(&planned_update.key))
+        {
+            domain_result.outcome = Some(DomainOutcome::Failed);
+            domain_result.ip_address = None;
+            domain_result.errors.push(e);
+        }
+    }
+}
+
+async fn build_domain_result(
+    planned_update: &PlannedUpdate<'_, FreeDns>,
+    timeout_ms: Option<u64>,
+) -> DomainResult {
+    let domain = planned_update.domain;
+    let ip_address = &planned_update.ip_address;
+
+    let mut domain_result = DomainResult::new(&domain.hostname);
+    domain_result.service = Some(SERVICE_NAME.to_string());
+    domain_result.reason = Some(planned_update.reason);
+
+    let request = match get_freedns_req(domain, ip_address) {
+        Ok(s) => s,
+        Err(e) => {
+            domain_result.outcome = Some(DomainOutcome::Failed);
+            domain_result.errors.push(e);
+            return domain_result;
+        }
+    };
+
+    let response = requests::request_http1_tls_response(request);
+    match requests::with_timeout(timeout_ms, response).await {
+        Ok(r) => {
+            if let Some(warning) = requests::get_clock_skew_warning(&r) {
+                domain_result.warnings.push(warning);
+            }
+            match verify_response(&r) {
+                Ok(outcome) => {
+                    domain_result.outcome = Some(outcome);
+                    domain_result.ip_address = Some(*ip_address);
+                    domain_result.response_shape = Some(results::get_response_shape(&r));
+                }
+                Err(e) => {
+                    domain_result.outcome = Some(DomainOutcome::Failed);
+                    domain_result.errors.push(e);
+                }
+            }
+        }
+        Err(e) => {
+            domain_result.outcome = Some(DomainOutcome::Failed);
+            domain_result.errors.push(e);
+        }
+    }
+
+    domain_result
+}
+
+/*
+    Responses are plain text:
+    - "Updated <hostname> to <address> in <time> seconds"
+    - "No IP change detected for <hostname> with IP <address>, skipping update"
+    - "ERROR: Address <address> has not changed."
+*/
+fn verify_response(res: &ResponseJson) -> Result<DomainOutcome, String> {
+    if res.status_code < 200 || res.status_code >= 300 {
+        return Err("response status ".to_string() + &res.status_code.to_string());
+    }
+
+    let body = res.body.trim();
+    if body.starts_with("Updated") {
+        return Ok(DomainOutcome::Updated);
+    }
+    if body.contains("has not changed") || body.starts_with("No IP change detected") {
+        return Ok(DomainOutcome::Unchanged);
+    }
+
+    Err("freedns error: ".to_string() + body)
+}
+
+fn get_freedns_req(domain: &FreeDns, ip_addr: &Address) -> Result<Request<Empty<Bytes>>, String> {
+    let ip_str = ip_addr.to_string();
+    let uri_str = requests::append_query_params(&domain.update_url, &[("address", &ip_str)])?;
+
+    let uri = match uri_str.parse::<http::Uri>() {
+        Ok(u) => u,
+        Err(e) => return Err(e.to_string()),
+    };
+    let host = match uri.host() {
+        Some(u) => u.to_string(),
+        None => return Err("host not found in uri".to_string()),
+    };
+
+    match Request::builder()
+        .uri(uri)
+        .header(hyper::header::HOST, host)
+        .header(hyper::header::USER_AGENT, CLIENT_HEADER_VALUE)
+        .body(Empty::<Bytes>::new())
+    {
+        Ok(req) => Ok(req),
+        Err(e) => Err(e.to_string()),
+    }
+}
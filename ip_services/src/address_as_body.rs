@@ -1,28 +1,26 @@
-use std::net;
+use results::{Address, ResponseJson};
 
-use requests;
+pub const BOT_CHALLENGE_ERROR: &str = "service behind bot challenge";
 
-// request with empty body returns response body with IP Address
-pub async fn request_address_as_response_body(service: &str) -> Result<String, String> {
-    let request = match requests::create_request_with_empty_body(service) {
-        Ok(req) => req,
-        Err(e) => return Err(e),
-    };
+const SNIPPET_LENGTH: usize = 64;
 
-    let response = match requests::request_http1_tls_response(request).await {
-        Ok(res) => res,
-        Err(e) => return Err(e),
-    };
-
-    if response.status_code != 200 {
-        return Err("response was not okay".to_string());
+// response body is the IP Address
+pub fn get_address_as_response_body(response: &ResponseJson) -> Result<Address, String> {
+    // error pages and rate limit messages are rejected, never stored as an address
+    match response.body.parse::<Address>() {
+        Ok(ip) => Ok(ip),
+        _ => Err("ip address could not be parsed from response: ".to_string()
+            + &get_body_snippet(&response.body)),
     }
+}
 
-    // set address if request is successful
-    let ip_address = match response.body.trim().parse::<net::IpAddr>() {
-        Ok(ip) => ip.to_string(),
-        _ => return Err("ip address could not be parsed from response".to_string()),
-    };
+// a quoted and escaped prefix of a response, ie: "<html><head><title>429 Too..."
+pub fn get_body_snippet(body: &str) -> String {
+    let body = body.trim();
+    let mut snippet: String = body.chars().take(SNIPPET_LENGTH).collect();
+    if body.chars().count() > SNIPPET_LENGTH {
+        snippet += "...";
+    }
 
-    Ok(ip_address)
+    format!("{:?}", snippet)
 }
@@ -0,0 +1,42 @@
+use serde_json::Value;
+
+use results::{Address, ResponseJson};
+
+use crate::address_as_body::get_body_snippet;
+
+pub const DEFAULT_FIELD: &str = "ip";
+
+/*
+    The response body is a json object with the address in a field,
+    ie: {"ip": "203.0.113.4"}.
+
+    A field starting with "/" is a json pointer to a nested field,
+    ie: /client/address.
+*/
+pub fn get_address_as_json_field(response: &ResponseJson, field: &str) -> Result<Address, String> {
+    let json: Value = match serde_json::from_str(&response.body) {
+        Ok(json) => json,
+        Err(_) => {
+            return Err("response is not json: ".to_string() + &get_body_snippet(&response.body))
+        }
+    };
+
+    let value = match field.starts_with('/') {
+        true => json.pointer(field),
+        false => json.get(field),
+    };
+
+    let address_str = match value {
+        Some(Value::String(address_str)) => address_str,
+        Some(_) => return Err("field ".to_string() + field + " is not a string"),
+        _ => return Err("field ".to_string() + field + " not found in response"),
+    };
+
+    match address_str.trim().parse::<Address>() {
+        Ok(ip) => Ok(ip),
+        _ => Err("ip address could not be parsed from field ".to_string()
+            + field
+            + ": "
+            + &get_body_snippet(address_str)),
+    }
+}
@@ -1,54 +1,529 @@
 use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
 
-use results::{IpServiceResult, UpdateIpResults};
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::time;
+
+use results::{
+    Address, Decision, DecisionLog, DomainOutcome, IpServiceResult, NatClassification, Observation,
+    ResponseJson, TlsParameters, UpdateIpResults,
+};
 
 mod address_as_body;
+mod address_as_json;
+mod services_from_url;
+mod watch;
+
+pub use results::AddressFamily;
+pub use services_from_url::get_ip_services_from_url;
+pub use watch::{watch, ChangeEvent, ChangeStream, WatchConfig};
 
 // ip services are accounted for by response type
 // beware of potential hydra
-pub type IpServices = Vec<(String, String)>;
+pub type IpServices = Vec<IpService>;
+
+const RESPONSE_TYPES: [&str; 2] = ["address_as_body", "address_as_json"];
+
+pub const DEFAULT_TIMEOUT_MS: u64 = 10_000;
+
+/*
+    An ip service is configured as [url, response_type] or
+    [url, response_type, field]. The field names the json field that
+    holds the address and is only read by address_as_json, it defaults to "ip".
+
+    An ip service can also be configured as an object with an optional
+    expected_content_type, ie: {"url": ..., "response_type": ...,
+    "expected_content_type": "application/json"}. Responses with another
+    Content-Type are warned about.
+*/
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(from = "IpServiceEntry", into = "IpServiceEntry")]
+pub struct IpService {
+    pub url: String,
+    pub response_type: String,
+    pub field: Option<String>,
+    pub expected_content_type: Option<String>,
+}
 
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum IpServiceEntry {
+    WithField(String, String, String),
+    WithoutField(String, String),
+    Object {
+        url: String,
+        response_type: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        field: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expected_content_type: Option<String>,
+    },
+}
+
+impl From<IpServiceEntry> for IpService {
+    fn from(entry: IpServiceEntry) -> Self {
+        match entry {
+            IpServiceEntry::WithField(url, response_type, field) => IpService {
+                url,
+                response_type,
+                field: Some(field),
+                expected_content_type: None,
+            },
+            IpServiceEntry::WithoutField(url, response_type) => IpService {
+                url,
+                response_type,
+                field: None,
+                expected_content_type: None,
+            },
+            IpServiceEntry::Object {
+                url,
+                response_type,
+                field,
+                expected_content_type,
+            } => IpService {
+                url,
+                response_type,
+                field,
+                expected_content_type,
+            },
+        }
+    }
+}
+
+impl From<IpService> for IpServiceEntry {
+    fn from(ip_service: IpService) -> Self {
+        match (ip_service.field, ip_service.expected_content_type) {
+            (field, Some(expected_content_type)) => IpServiceEntry::Object {
+                url: ip_service.url,
+                response_type: ip_service.response_type,
+                field,
+                expected_content_type: Some(expected_content_type),
+            },
+            (Some(field), _) => {
+                IpServiceEntry::WithField(ip_service.url, ip_service.response_type, field)
+            }
+            _ => IpServiceEntry::WithoutField(ip_service.url, ip_service.response_type),
+        }
+    }
+}
+
+impl IpService {
+    pub fn new(url: &str, response_type: &str) -> IpService {
+        IpService {
+            url: url.to_string(),
+            response_type: response_type.to_string(),
+            field: None,
+            expected_content_type: None,
+        }
+    }
+}
+
+pub fn validate(ip_service: &IpService) -> Vec<String> {
+    let mut errors = Vec::<String>::new();
+
+    if let Err(e) = requests::validate_http_or_https_url(&ip_service.url) {
+        errors.push("url: ".to_string() + &e);
+    }
+
+    let response_type = &ip_service.response_type;
+    if !RESPONSE_TYPES.contains(&response_type.as_str()) {
+        errors.push("response type ".to_string() + response_type + " is not supported");
+    }
+
+    match (response_type.as_str(), &ip_service.field) {
+        ("address_as_json", Some(field)) if field.is_empty() => {
+            errors.push("field: must not be empty".to_string())
+        }
+        ("address_as_json", _) => {}
+        (_, Some(field)) => errors
+            .push("field ".to_string() + field + " is only read by response type address_as_json"),
+        _ => {}
+    }
+
+    if let Some(expected_content_type) = &ip_service.expected_content_type {
+        if expected_content_type.trim().is_empty() {
+            errors.push("expected_content_type: must not be empty".to_string());
+        }
+    }
+
+    errors
+}
+
+/*
+    A family is given when ip_services_v6 is configured. Services are then
+    expected to detect an address of that family and the previous service
+    is read from the result of that family.
+
+    A result is always returned. When every service fails, the result has
+    no address and the errors of every service, prefixed with their url.
+*/
 pub async fn get_ip_service_results(
     ip_services: &IpServices,
     prev_results: &Option<UpdateIpResults>,
-) -> Result<IpServiceResult, String> {
-    let service = match prev_results {
-        Some(results) => &results.ip_service_result.service,
+    family: Option<AddressFamily>,
+    netns: Option<&str>,
+    probe_timeout_ms: Option<u64>,
+    timeout_ms: u64,
+    decisions: &mut DecisionLog,
+) -> IpServiceResult {
+    let service = match prev_results
+        .as_ref()
+        .and_then(|results| results.get_ip_service_result(family))
+    {
+        Some(ip_result) => &ip_result.service,
         None => "previous-results-do-not-exist",
     };
 
-    let (ip_service, response_type) = match get_random_ip_service(ip_services, service) {
-        Some(r) => r,
-        _ => return Err("failed to find ip service".to_string()),
+    let start_index = match get_random_ip_service_index(ip_services, service, decisions) {
+        Some(index) => index,
+        _ => {
+            let mut ip_struct = IpServiceResult::new("");
+            ip_struct.errors = vec!["no ip services are configured".to_string()];
+            return ip_struct;
+        }
     };
 
-    let address = match response_type {
-        _ => address_as_body::request_address_as_response_body(&ip_service).await,
+    // the chosen service is kept on failure so the next run starts elsewhere
+    let mut ip_struct = IpServiceResult::new(&ip_services[start_index].url);
+
+    // services are tried in order from the chosen service until one detects an address
+    let mut attempted_services = Vec::<&String>::new();
+    for offset in 0..ip_services.len() {
+        let ip_service = &ip_services[(start_index + offset) % ip_services.len()];
+        if attempted_services.contains(&&ip_service.url) {
+            continue;
+        }
+        if !attempted_services.is_empty() {
+            decisions.push(Decision::IpServiceChosen {
+                service: ip_service.url.clone(),
+                excluded_service: None,
+            });
+        }
+        attempted_services.push(&ip_service.url);
+
+        let request = request_address(
+            ip_service,
+            family,
+            netns,
+            probe_timeout_ms,
+            timeout_ms,
+            &mut ip_struct.warnings,
+        );
+        match request.await {
+            Ok((addr, tls)) => {
+                decisions.push(Decision::AddressDetected {
+                    service: ip_service.url.clone(),
+                    ip_address: addr,
+                });
+                ip_struct.service = ip_service.url.clone();
+                ip_struct.ip_address = Some(addr);
+                ip_struct.nat_classification = classify_nat(&addr);
+                ip_struct.detected_at = Some(results::get_timestamp_or_zero());
+                ip_struct.tls = tls;
+                return ip_struct;
+            }
+            Err(e) => {
+                decisions.push(Decision::IpServiceFailed {
+                    service: ip_service.url.clone(),
+                    error: e.clone(),
+                });
+                ip_struct.errors.push(ip_service.url.clone() + ": " + &e);
+            }
+        }
+    }
+
+    ip_struct
+}
+
+async fn request_address(
+    ip_service: &IpService,
+    family: Option<AddressFamily>,
+    netns: Option<&str>,
+    probe_timeout_ms: Option<u64>,
+    timeout_ms: u64,
+    warnings: &mut Vec<String>,
+) -> Result<(Address, Option<TlsParameters>), String> {
+    // a quick connection probe fails fast on services that are unreachable
+    if let Some(timeout_ms) = probe_timeout_ms {
+        if let Err(e) = requests::probe_url(&ip_service.url, timeout_ms, netns).await {
+            return Err("probe failed: ".to_string() + &e);
+        }
+    }
+
+    // a hung service fails like any other so the next service is tried
+    let request = request_response(&ip_service.url, netns);
+    let response = match time::timeout(Duration::from_millis(timeout_ms), request).await {
+        Ok(response) => response?,
+        Err(_) => return Err("timed out after ".to_string() + &timeout_ms.to_string() + "ms"),
     };
 
-    match address {
-        Ok(addr) => {
-            let mut ip_struct = IpServiceResult::new(&ip_service);
-            ip_struct.ip_address = Some(addr);
-            Ok(ip_struct)
+    // a mismatch is warned about whether or not the address can be parsed
+    if let Some(warning) = check_content_type(ip_service, &response) {
+        warnings.push(ip_service.url.clone() + ": " + &warning);
+    }
+
+    let address = get_address_by_response_type(ip_service, &response)?;
+    match family {
+        Some(family) if address.family() != family => {
+            Err(address.to_string() + " is not an " + family_to_string(family) + " address")
         }
-        Err(e) => Err(e),
+        _ => Ok((address, response.tls)),
+    }
+}
+
+async fn request_response(service: &str, netns: Option<&str>) -> Result<ResponseJson, String> {
+    let request = requests::create_request_with_empty_body(service)?;
+    let response = requests::request_http1_response_in_netns(request, netns).await?;
+
+    // challenge pages are never addresses
+    if response.bot_challenge {
+        return Err(address_as_body::BOT_CHALLENGE_ERROR.to_string());
+    }
+
+    if response.status_code != 200 {
+        return Err("response was not okay".to_string());
+    }
+
+    Ok(response)
+}
+
+fn get_address_by_response_type(
+    ip_service: &IpService,
+    response: &ResponseJson,
+) -> Result<Address, String> {
+    match (ip_service.response_type.as_str(), &ip_service.field) {
+        ("address_as_body", _) => address_as_body::get_address_as_response_body(response),
+        ("address_as_json", field) => {
+            let field = field.as_deref().unwrap_or(address_as_json::DEFAULT_FIELD);
+            address_as_json::get_address_as_json_field(response, field)
+        }
+        (response_type, _) => {
+            Err("response type ".to_string() + response_type + " is not supported")
+        }
+    }
+}
+
+// media types are compared without parameters like charset
+fn check_content_type(ip_service: &IpService, response: &ResponseJson) -> Option<String> {
+    let expected = ip_service
+        .expected_content_type
+        .as_ref()?
+        .trim()
+        .to_lowercase();
+    let content_type = results::get_response_shape(response).content_type;
+    if content_type.as_ref() == Some(&expected) {
+        return None;
+    }
+
+    Some(
+        "expected content type ".to_string()
+            + &expected
+            + " but got "
+            + content_type.as_deref().unwrap_or("none"),
+    )
+}
+
+/*
+    The fast path reads the address of a local interface instead of
+    requesting an ip service. When it is the previous address and every
+    domain was updated or unchanged, there is nothing to do this run.
+
+    Stale previous results are discarded before this, so a run after
+    state_max_age_ms always goes through detection.
+*/
+pub fn fast_path_address_unchanged(
+    interface: &str,
+    prev_results: &Option<UpdateIpResults>,
+) -> bool {
+    interface_addresses_unchanged(prev_results, || {
+        conditions::get_addresses_of_interface(interface)
+    })
+}
+
+// the interface is only read when the previous results could end the run
+fn interface_addresses_unchanged(
+    prev_results: &Option<UpdateIpResults>,
+    get_interface_addresses: impl FnOnce() -> Result<Vec<IpAddr>, String>,
+) -> bool {
+    let prev_results = match prev_results {
+        Some(results) => results,
+        _ => return false,
+    };
+    // a config with only ip_services_v6 has no previous ipv4 address
+    let prev_addresses: Vec<Address> = std::iter::once(&prev_results.ip_service_result)
+        .chain(prev_results.ip_service_result_v6.as_ref())
+        .filter_map(|ip_result| ip_result.ip_address)
+        .collect();
+    if prev_addresses.is_empty() {
+        return false;
+    }
+
+    let domains_settled = prev_results
+        .domain_service_results
+        .values()
+        .all(|domain_result| {
+            matches!(
+                domain_result.outcome,
+                Some(DomainOutcome::Updated) | Some(DomainOutcome::Unchanged)
+            )
+        });
+    if !domains_settled {
+        return false;
+    }
+
+    // every previously detected family must still be assigned
+    match get_interface_addresses() {
+        Ok(addresses) => prev_addresses.iter().all(|prev_address| {
+            addresses
+                .iter()
+                .any(|address| Address::new(*address) == *prev_address)
+        }),
+        _ => false,
+    }
+}
+
+fn family_to_string(family: AddressFamily) -> &'static str {
+    match family {
+        AddressFamily::V4 => "ipv4",
+        AddressFamily::V6 => "ipv6",
+    }
+}
+
+/*
+    The fast_path interface is a second source when a run goes through
+    detection. It observes the detected address when it holds it, otherwise
+    its first public address of the same family.
+
+    An interface without a public address of the family, ie: the lan
+    address of a host behind nat, can not confirm or contradict the
+    detected address and is not observed.
+*/
+pub fn cross_check_interface(ip_service_result: &mut IpServiceResult, interface: &str) {
+    if let Ok(interface_addresses) = conditions::get_addresses_of_interface(interface) {
+        cross_check_interface_addresses(ip_service_result, interface, &interface_addresses);
+    }
+}
+
+fn cross_check_interface_addresses(
+    ip_service_result: &mut IpServiceResult,
+    interface: &str,
+    interface_addresses: &[IpAddr],
+) {
+    let ip_address = match ip_service_result.ip_address {
+        Some(address) => address,
+        _ => return,
+    };
+
+    let candidates: Vec<Address> = interface_addresses
+        .iter()
+        .map(|address| Address::new(*address))
+        .filter(|address| address.family() == ip_address.family())
+        .filter(is_public_address)
+        .collect();
+    let observed_address = match candidates.iter().find(|address| **address == ip_address) {
+        Some(address) => *address,
+        _ => match candidates.first() {
+            Some(address) => *address,
+            _ => return,
+        },
+    };
+
+    ip_service_result.observations = vec![
+        Observation {
+            source: ip_service_result.service.clone(),
+            ip_address,
+        },
+        Observation {
+            source: "interface ".to_string() + interface,
+            ip_address: observed_address,
+        },
+    ];
+    ip_service_result.confidence = results::classify_observations(&ip_service_result.observations);
+}
+
+// private, shared, loopback, link-local, and unique local addresses are not public
+fn is_public_address(address: &Address) -> bool {
+    if is_shared_address(address) {
+        return false;
+    }
+
+    let ip_addr = address.ip_addr();
+    if is_link_local(&ip_addr) || ip_addr.is_loopback() || ip_addr.is_unspecified() {
+        return false;
+    }
+    match ip_addr {
+        IpAddr::V4(ipv4) => !ipv4.is_private(),
+        IpAddr::V6(ipv6) => ipv6.segments()[0] & 0xfe00 != 0xfc00,
+    }
+}
+
+fn is_link_local(address: &IpAddr) -> bool {
+    match address {
+        IpAddr::V4(ipv4) => ipv4.is_link_local(),
+        IpAddr::V6(ipv6) => ipv6.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+/*
+    Only the detected address is classified. Interface addresses in
+    100.64.0.0/10, like those of a tailscale interface, say nothing about
+    the route of the detected address. Interfaces that cannot be read
+    leave any other address unclassified.
+*/
+fn classify_nat(address: &Address) -> Option<NatClassification> {
+    if is_shared_address(address) {
+        return Some(NatClassification::Cgnat);
+    }
+
+    let interface_addresses = conditions::get_interface_addresses().ok()?;
+    Some(classify_nat_with_interfaces(address, &interface_addresses))
+}
+
+fn classify_nat_with_interfaces(
+    address: &Address,
+    interface_addresses: &[IpAddr],
+) -> NatClassification {
+    if is_shared_address(address) {
+        return NatClassification::Cgnat;
+    }
+
+    match interface_addresses.contains(&address.ip_addr()) {
+        true => NatClassification::NoNat,
+        false => NatClassification::Nat,
+    }
+}
+
+// 100.64.0.0/10 is shared address space for carrier-grade nat
+fn is_shared_address(address: &Address) -> bool {
+    match address.ip_addr() {
+        IpAddr::V4(ipv4) => ipv4.octets()[0] == 100 && ipv4.octets()[1] & 0b1100_0000 == 64,
+        _ => false,
     }
 }
 
-fn get_random_ip_service(ip_services: &IpServices, prev_service: &str) -> Option<(String, String)> {
-    if ip_services.len() == 0 {
+// services are never filtered, so None means no services are configured
+fn get_random_ip_service_index(
+    ip_services: &IpServices,
+    prev_service: &str,
+    decisions: &mut DecisionLog,
+) -> Option<usize> {
+    if ip_services.is_empty() {
         return None;
     }
 
     if ip_services.len() == 1 {
-        return Some(ip_services[0].clone());
+        decisions.push(Decision::IpServiceChosen {
+            service: ip_services[0].url.clone(),
+            excluded_service: None,
+        });
+        return Some(0);
     }
 
     // get previous service index
     let mut prev_index = None;
-    for (index, (url, _ip_service_type)) in ip_services.iter().enumerate() {
-        if url == &prev_service {
+    for (index, ip_service) in ip_services.iter().enumerate() {
+        if ip_service.url == prev_service {
             prev_index = Some(index);
             break;
         };
@@ -68,5 +543,541 @@ fn get_random_ip_service(ip_services: &IpServices, prev_service: &str) -> Option
         }
     }
 
-    return Some(ip_services[random_index].clone());
+    decisions.push(Decision::IpServiceChosen {
+        service: ip_services[random_index].url.clone(),
+        excluded_service: prev_index.map(|_| prev_service.to_string()),
+    });
+
+    Some(random_index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // serves every request with the same response until the test ends
+    async fn serve_http(status: &'static str, body: &'static str) -> String {
+        serve_http_with_content_type(status, None, body).await
+    }
+
+    async fn serve_http_with_content_type(
+        status: &'static str,
+        content_type: Option<&'static str>,
+        body: &'static str,
+    ) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = "http://".to_string() + &listener.local_addr().unwrap().to_string() + "/";
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let content_type_header = match content_type {
+                    Some(content_type) => "\r\ncontent-type: ".to_string() + content_type,
+                    _ => String::new(),
+                };
+                let response = "HTTP/1.1 ".to_string()
+                    + status
+                    + &content_type_header
+                    + "\r\ncontent-length: "
+                    + &body.len().to_string()
+                    + "\r\nconnection: close\r\n\r\n"
+                    + body;
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        url
+    }
+
+    fn prev_results_from(ip_service_result: IpServiceResult) -> Option<UpdateIpResults> {
+        Some(UpdateIpResults::from_results(
+            ip_service_result,
+            None,
+            HashMap::new(),
+            DecisionLog::new(),
+            "prev-run",
+            None,
+        ))
+    }
+
+    #[tokio::test]
+    async fn all_services_fail() {
+        let first = serve_http("500 Internal Server Error", "").await;
+        let second = serve_http("503 Service Unavailable", "").await;
+        let ip_services = vec![
+            IpService::new(&first, "address_as_body"),
+            IpService::new(&second, "address_as_body"),
+        ];
+
+        let mut prev_ip_result = IpServiceResult::new(&first);
+        prev_ip_result.ip_address = Some("203.0.113.9".parse().unwrap());
+        let prev_results = prev_results_from(prev_ip_result.clone());
+
+        let mut decisions = DecisionLog::new();
+        let mut ip_result = get_ip_service_results(
+            &ip_services,
+            &prev_results,
+            None,
+            None,
+            None,
+            DEFAULT_TIMEOUT_MS,
+            &mut decisions,
+        )
+        .await;
+
+        assert_eq!(ip_result.ip_address, None);
+        assert_eq!(ip_result.errors.len(), 2);
+        assert!(ip_result.errors.iter().any(|e| e.starts_with(&first)));
+        assert!(ip_result.errors.iter().any(|e| e.starts_with(&second)));
+
+        // the previous address is kept so the next run does not see a change
+        ip_result.keep_prev_address(Some(&prev_ip_result));
+        assert_eq!(ip_result.ip_address, prev_ip_result.ip_address);
+        assert_eq!(ip_result.errors.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn second_service_succeeds() {
+        let failing = serve_http("500 Internal Server Error", "").await;
+        let working = serve_http("200 OK", "203.0.113.9").await;
+        let ip_services = vec![
+            IpService::new(&failing, "address_as_body"),
+            IpService::new(&working, "address_as_body"),
+        ];
+
+        // the previous service is excluded from the first attempt
+        let prev_results = prev_results_from(IpServiceResult::new(&working));
+
+        let mut decisions = DecisionLog::new();
+        let ip_result = get_ip_service_results(
+            &ip_services,
+            &prev_results,
+            None,
+            None,
+            None,
+            DEFAULT_TIMEOUT_MS,
+            &mut decisions,
+        )
+        .await;
+
+        assert_eq!(ip_result.ip_address, Some("203.0.113.9".parse().unwrap()));
+        assert_eq!(ip_result.service, working);
+        assert_eq!(ip_result.errors.len(), 1);
+        assert!(ip_result.errors[0].starts_with(&failing));
+    }
+
+    fn get_content_type_service(url: &str, response_type: &str, expected: &str) -> IpService {
+        let mut ip_service = IpService::new(url, response_type);
+        ip_service.expected_content_type = Some(expected.to_string());
+        ip_service
+    }
+
+    async fn detect(ip_services: &IpServices) -> IpServiceResult {
+        get_ip_service_results(
+            ip_services,
+            &None,
+            None,
+            None,
+            None,
+            DEFAULT_TIMEOUT_MS,
+            &mut DecisionLog::new(),
+        )
+        .await
+    }
+
+    #[test]
+    fn ip_services_are_tuples_or_objects() {
+        let ip_services: IpServices = serde_json::from_value(serde_json::json!([
+            ["https://ip.example/", "address_as_body"],
+            ["https://ip.example/json", "address_as_json", "address"],
+            {
+                "url": "https://ip.example/json",
+                "response_type": "address_as_json",
+                "expected_content_type": "application/json",
+            },
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            ip_services[0],
+            IpService::new("https://ip.example/", "address_as_body")
+        );
+        assert_eq!(ip_services[1].field, Some("address".to_string()));
+        assert_eq!(
+            ip_services[2],
+            get_content_type_service(
+                "https://ip.example/json",
+                "address_as_json",
+                "application/json"
+            )
+        );
+
+        // services without an expected content type keep their tuple form
+        let serialized = serde_json::to_value(&ip_services).unwrap();
+        assert!(serialized[0].is_array());
+        assert!(serialized[1].is_array());
+        assert_eq!(
+            serialized[2]["expected_content_type"],
+            serde_json::json!("application/json")
+        );
+        assert_eq!(
+            serde_json::from_value::<IpServices>(serialized).unwrap(),
+            ip_services
+        );
+    }
+
+    #[test]
+    fn expected_content_types_must_not_be_empty() {
+        let ip_service = get_content_type_service("https://ip.example/", "address_as_body", " ");
+        assert_eq!(
+            validate(&ip_service),
+            vec!["expected_content_type: must not be empty".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn expected_content_types_are_not_warned_about() {
+        let url = serve_http_with_content_type(
+            "200 OK",
+            Some("Application/JSON; charset=utf-8"),
+            "{\"ip\":\"203.0.113.9\"}",
+        )
+        .await;
+        let ip_services = vec![get_content_type_service(
+            &url,
+            "address_as_json",
+            "application/json",
+        )];
+
+        let ip_result = detect(&ip_services).await;
+        assert_eq!(ip_result.ip_address, Some("203.0.113.9".parse().unwrap()));
+        assert!(ip_result.warnings.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unexpected_content_types_are_warned_about() {
+        let url = serve_http_with_content_type("200 OK", Some("text/plain"), "203.0.113.9").await;
+
+        // the address is still detected
+        let ip_services = vec![get_content_type_service(
+            &url,
+            "address_as_body",
+            "application/json",
+        )];
+        let ip_result = detect(&ip_services).await;
+        assert_eq!(ip_result.ip_address, Some("203.0.113.9".parse().unwrap()));
+        assert_eq!(
+            ip_result.warnings,
+            vec![url.clone() + ": expected content type application/json but got text/plain"]
+        );
+
+        // the warning explains a failed parse
+        let ip_services = vec![get_content_type_service(
+            &url,
+            "address_as_json",
+            "application/json",
+        )];
+        let ip_result = detect(&ip_services).await;
+        assert_eq!(ip_result.ip_address, None);
+        assert_eq!(
+            ip_result.errors,
+            vec![url.clone() + ": response is not json: \"203.0.113.9\""]
+        );
+        assert_eq!(ip_result.warnings.len(), 1);
+
+        // responses without a content type are a mismatch
+        let url = serve_http("200 OK", "203.0.113.9").await;
+        let ip_services = vec![get_content_type_service(
+            &url,
+            "address_as_body",
+            "text/plain",
+        )];
+        let ip_result = detect(&ip_services).await;
+        assert_eq!(
+            ip_result.warnings,
+            vec![url + ": expected content type text/plain but got none"]
+        );
+    }
+
+    // previous results of a run that left every domain in an outcome
+    fn settled_results(
+        address: Option<&str>,
+        address_v6: Option<&str>,
+        outcome: DomainOutcome,
+    ) -> Option<UpdateIpResults> {
+        let get_ip_service_result = |address: Option<&str>| {
+            let mut ip_service_result = IpServiceResult::new("https://ip.example/");
+            ip_service_result.ip_address = address.map(|address| address.parse().unwrap());
+            ip_service_result
+        };
+        let mut domain_result = results::DomainResult::new("example.com");
+        domain_result.outcome = Some(outcome);
+
+        Some(UpdateIpResults::from_results(
+            get_ip_service_result(address),
+            address_v6.map(|address_v6| get_ip_service_result(Some(address_v6))),
+            HashMap::from([("dyndns2:example.com".to_string(), domain_result)]),
+            DecisionLog::new(),
+            "prev-run",
+            None,
+        ))
+    }
+
+    fn interface(addresses: &[&str]) -> impl FnOnce() -> Result<Vec<IpAddr>, String> {
+        let addresses: Vec<IpAddr> = addresses
+            .iter()
+            .map(|address| address.parse().unwrap())
+            .collect();
+        move || Ok(addresses)
+    }
+
+    #[test]
+    fn fast_path_ends_runs_with_the_previous_address() {
+        let prev_results = settled_results(Some("203.0.113.9"), None, DomainOutcome::Updated);
+        assert!(interface_addresses_unchanged(
+            &prev_results,
+            interface(&["fe80::1", "203.0.113.9"])
+        ));
+
+        let prev_results = settled_results(Some("203.0.113.9"), None, DomainOutcome::Unchanged);
+        assert!(interface_addresses_unchanged(
+            &prev_results,
+            interface(&["203.0.113.9"])
+        ));
+    }
+
+    #[test]
+    fn fast_path_continues_when_the_address_differs() {
+        let prev_results = settled_results(Some("203.0.113.9"), None, DomainOutcome::Updated);
+        assert!(!interface_addresses_unchanged(
+            &prev_results,
+            interface(&["203.0.113.10"])
+        ));
+        assert!(!interface_addresses_unchanged(
+            &prev_results,
+            interface(&[])
+        ));
+        assert!(!interface_addresses_unchanged(&prev_results, || Err(
+            "interface not found".to_string()
+        )));
+    }
+
+    #[test]
+    fn fast_path_continues_without_a_previous_address() {
+        assert!(!interface_addresses_unchanged(
+            &None,
+            interface(&["203.0.113.9"])
+        ));
+        let prev_results = settled_results(None, None, DomainOutcome::Updated);
+        assert!(!interface_addresses_unchanged(
+            &prev_results,
+            interface(&["203.0.113.9"])
+        ));
+    }
+
+    #[test]
+    fn fast_path_continues_until_every_domain_is_settled() {
+        for outcome in [
+            DomainOutcome::Failed,
+            DomainOutcome::StaleAddress,
+            DomainOutcome::Deferred,
+            DomainOutcome::ConditionNotMet,
+        ] {
+            let prev_results = settled_results(Some("203.0.113.9"), None, outcome);
+            assert!(!interface_addresses_unchanged(
+                &prev_results,
+                interface(&["203.0.113.9"])
+            ));
+        }
+    }
+
+    #[test]
+    fn fast_path_requires_every_previous_family() {
+        let prev_results = settled_results(
+            Some("203.0.113.9"),
+            Some("2001:db8::9"),
+            DomainOutcome::Updated,
+        );
+        assert!(interface_addresses_unchanged(
+            &prev_results,
+            interface(&["203.0.113.9", "2001:db8::9"])
+        ));
+        assert!(!interface_addresses_unchanged(
+            &prev_results,
+            interface(&["203.0.113.9"])
+        ));
+
+        // a config with only ip_services_v6 compares only ipv6
+        let prev_results = settled_results(None, Some("2001:db8::9"), DomainOutcome::Updated);
+        assert!(interface_addresses_unchanged(
+            &prev_results,
+            interface(&["2001:db8::9"])
+        ));
+    }
+
+    #[test]
+    fn fast_path_skips_results_older_than_state_max_age_ms() {
+        // a forced refresh after state_max_age_ms always goes through detection
+        let mut prev_results = settled_results(Some("127.0.0.1"), None, DomainOutcome::Updated);
+        if let Some(results) = &mut prev_results {
+            results.timestamp -= 2 * 86_400_000;
+        }
+        let prev_results = results::discard_stale_results(prev_results, 86_400_000);
+        assert!(!fast_path_address_unchanged("lo", &prev_results));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn fast_path_reads_the_interface() {
+        let prev_results = settled_results(Some("127.0.0.1"), None, DomainOutcome::Updated);
+        assert!(fast_path_address_unchanged("lo", &prev_results));
+        assert!(!fast_path_address_unchanged(
+            "not-an-interface",
+            &prev_results
+        ));
+    }
+
+    fn get_detected_result(address: &str) -> IpServiceResult {
+        let mut ip_service_result = IpServiceResult::new("https://ip.example/");
+        ip_service_result.ip_address = Some(address.parse().unwrap());
+        ip_service_result
+    }
+
+    fn get_addresses(addresses: &[&str]) -> Vec<IpAddr> {
+        addresses
+            .iter()
+            .map(|address| address.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn interfaces_holding_the_detected_address_agree() {
+        let mut ip_service_result = get_detected_result("203.0.113.9");
+        let interface_addresses = get_addresses(&["192.168.1.10", "203.0.113.9", "fe80::1"]);
+        cross_check_interface_addresses(&mut ip_service_result, "ppp0", &interface_addresses);
+
+        assert_eq!(ip_service_result.observations.len(), 2);
+        assert_eq!(ip_service_result.observations[1].source, "interface ppp0");
+        assert_eq!(
+            ip_service_result.confidence,
+            Some(results::Confidence::Unanimous)
+        );
+    }
+
+    #[test]
+    fn interfaces_with_another_public_address_conflict() {
+        let mut ip_service_result = get_detected_result("203.0.113.9");
+        let interface_addresses = get_addresses(&["10.0.0.2", "198.51.100.4"]);
+        cross_check_interface_addresses(&mut ip_service_result, "ppp0", &interface_addresses);
+
+        assert_eq!(
+            ip_service_result.observations[1].ip_address,
+            "198.51.100.4".parse().unwrap()
+        );
+        assert_eq!(
+            ip_service_result.confidence,
+            Some(results::Confidence::Conflicted)
+        );
+    }
+
+    #[test]
+    fn interfaces_behind_nat_are_not_observed() {
+        // the lan, tailscale, loopback, and link-local addresses of a host behind a home router
+        let interface_addresses = get_addresses(&[
+            "192.168.1.10",
+            "172.16.0.3",
+            "10.0.0.2",
+            "100.100.7.1",
+            "127.0.0.1",
+            "169.254.0.5",
+            "fd00::10",
+            "fe80::1",
+            "::1",
+        ]);
+        for detected in ["203.0.113.9", "2001:db8::1"] {
+            let mut ip_service_result = get_detected_result(detected);
+            cross_check_interface_addresses(&mut ip_service_result, "eth0", &interface_addresses);
+
+            assert!(ip_service_result.observations.is_empty(), "{}", detected);
+            assert_eq!(ip_service_result.confidence, None);
+        }
+    }
+
+    #[test]
+    fn interfaces_are_only_compared_within_a_family() {
+        let mut ip_service_result = get_detected_result("2001:db8::1");
+        let interface_addresses = get_addresses(&["203.0.113.9", "fd00::10"]);
+        cross_check_interface_addresses(&mut ip_service_result, "ppp0", &interface_addresses);
+        assert!(ip_service_result.observations.is_empty());
+
+        let interface_addresses = get_addresses(&["203.0.113.9", "2001:db8::2"]);
+        cross_check_interface_addresses(&mut ip_service_result, "ppp0", &interface_addresses);
+        assert_eq!(
+            ip_service_result.confidence,
+            Some(results::Confidence::Conflicted)
+        );
+    }
+
+    #[test]
+    fn shared_interface_addresses_do_not_classify_as_cgnat() {
+        // a tailscale interface next to a public address behind a home router
+        let interface_addresses: Vec<IpAddr> = vec![
+            "192.168.1.10".parse().unwrap(),
+            "100.100.7.1".parse().unwrap(),
+        ];
+
+        let address: Address = "203.0.113.9".parse().unwrap();
+        assert_eq!(
+            classify_nat_with_interfaces(&address, &interface_addresses),
+            NatClassification::Nat
+        );
+    }
+
+    #[test]
+    fn detected_addresses_are_classified() {
+        let interface_addresses: Vec<IpAddr> = vec!["203.0.113.9".parse().unwrap()];
+
+        let assigned: Address = "203.0.113.9".parse().unwrap();
+        assert_eq!(
+            classify_nat_with_interfaces(&assigned, &interface_addresses),
+            NatClassification::NoNat
+        );
+
+        for shared in ["100.64.0.1", "100.127.255.254"] {
+            let shared: Address = shared.parse().unwrap();
+            assert_eq!(
+                classify_nat_with_interfaces(&shared, &interface_addresses),
+                NatClassification::Cgnat
+            );
+        }
+
+        for outside in ["100.63.255.255", "100.128.0.1", "2001:db8::1"] {
+            let outside: Address = outside.parse().unwrap();
+            assert_eq!(
+                classify_nat_with_interfaces(&outside, &interface_addresses),
+                NatClassification::Nat
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn no_services_configured() {
+        let mut decisions = DecisionLog::new();
+        let ip_result = get_ip_service_results(
+            &Vec::new(),
+            &None,
+            None,
+            None,
+            None,
+            DEFAULT_TIMEOUT_MS,
+            &mut decisions,
+        )
+        .await;
+
+        assert_eq!(ip_result.ip_address, None);
+        assert_eq!(ip_result.errors, vec!["no ip services are configured"]);
+    }
 }
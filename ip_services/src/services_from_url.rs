@@ -0,0 +1,85 @@
+use std::path::Path;
+use tokio::fs;
+
+use crate::IpServices;
+
+/*
+    Ip services can be managed centrally and fetched from a url at startup.
+    The url responds with the same json as the ip_services property.
+
+    The last fetched ip services are cached so an unreachable url
+    does not leave a run without ip services.
+*/
+pub async fn get_ip_services_from_url(
+    url: &str,
+    auth_header: Option<&str>,
+    cache_filepath: &Path,
+) -> Result<IpServices, String> {
+    let fetch_error = match fetch_ip_services(url, auth_header).await {
+        Ok(ip_services) => {
+            write_cache(&ip_services, cache_filepath).await?;
+            return Ok(ip_services);
+        }
+        Err(e) => e,
+    };
+
+    match read_cache(cache_filepath).await {
+        Ok(ip_services) => Ok(ip_services),
+        Err(e) => Err(fetch_error + ", cache error: " + &e),
+    }
+}
+
+async fn fetch_ip_services(url: &str, auth_header: Option<&str>) -> Result<IpServices, String> {
+    let mut request = requests::create_request_with_empty_body(url)?;
+    if let Some(auth_header) = auth_header {
+        match http::HeaderValue::try_from(auth_header) {
+            Ok(value) => request
+                .headers_mut()
+                .insert(http::header::AUTHORIZATION, value),
+            Err(e) => return Err(e.to_string()),
+        };
+    }
+
+    let response = requests::request_http1_tls_response(request).await?;
+    if response.status_code != 200 {
+        return Err("response status ".to_string() + &response.status_code.to_string());
+    }
+
+    parse_ip_services(&response.body)
+}
+
+// a list with any invalid ip service is rejected as a whole
+fn parse_ip_services(json_str: &str) -> Result<IpServices, String> {
+    let ip_services: IpServices = match serde_json::from_str(json_str) {
+        Ok(s) => s,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    for ip_service in &ip_services {
+        let errors = crate::validate(ip_service);
+        if !errors.is_empty() {
+            return Err(ip_service.url.clone() + ": " + &errors.join(", "));
+        }
+    }
+
+    Ok(ip_services)
+}
+
+async fn write_cache(ip_services: &IpServices, cache_filepath: &Path) -> Result<(), String> {
+    let json_str = match serde_json::to_string_pretty(ip_services) {
+        Ok(s) => s,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    match fs::write(cache_filepath, json_str).await {
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+async fn read_cache(cache_filepath: &Path) -> Result<IpServices, String> {
+    match fs::read_to_string(cache_filepath).await {
+        Ok(json_str) => parse_ip_services(&json_str),
+        Err(e) => Err(e.to_string()),
+    }
+}
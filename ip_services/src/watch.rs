@@ -0,0 +1,262 @@
+use futures_core::Stream;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time;
+
+use results::{Address, AddressFamily, DecisionLog, IpServiceResult, UpdateIpResults};
+
+use crate::{get_ip_service_results, IpServices, DEFAULT_TIMEOUT_MS};
+
+/*
+    Detects the address of each family every interval_ms and sends an event
+    only when the address of a family changes. The first detected address of
+    a family is sent with no old address.
+
+    ip_services detect an IPv4 address and ip_services_v6 an IPv6 address,
+    like a run with ip_services_v6. A family without ip services is not
+    watched, so an address never changes to an address of the other family.
+
+    Failed detections are ignored. Watching stops when the stream is dropped.
+*/
+
+#[derive(Clone, Debug)]
+pub struct ChangeEvent {
+    pub old: Option<Address>,
+    pub new: Address,
+    pub family: AddressFamily,
+    pub timestamp: u128,
+}
+
+/*
+    The properties of a config that are watched.
+
+    The config crate depends on ip_services, so a config::Config can not be
+    passed here. Its properties of the same names are copied instead.
+*/
+#[derive(Clone, Debug)]
+pub struct WatchConfig {
+    pub ip_services: IpServices,
+    pub ip_services_v6: IpServices,
+    pub interval_ms: u64,
+    pub netns: Option<String>,
+}
+
+// change events of every watched family
+pub struct ChangeStream {
+    receiver: mpsc::Receiver<ChangeEvent>,
+}
+
+impl ChangeStream {
+    pub async fn recv(&mut self) -> Option<ChangeEvent> {
+        self.receiver.recv().await
+    }
+}
+
+impl Stream for ChangeStream {
+    type Item = ChangeEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ChangeEvent>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+// a named stream, so callers can store it or call recv without StreamExt
+pub fn watch(config: WatchConfig) -> ChangeStream {
+    let (sender, receiver) = mpsc::channel(1);
+
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_millis(config.interval_ms));
+        let mut prev_results = get_watch_results();
+
+        let families = [
+            (&config.ip_services, AddressFamily::V4),
+            (&config.ip_services_v6, AddressFamily::V6),
+        ];
+
+        loop {
+            interval.tick().await;
+            if sender.is_closed() {
+                return;
+            }
+
+            for (ip_services, family) in families {
+                if ip_services.is_empty() {
+                    continue;
+                }
+
+                let mut decisions = DecisionLog::new();
+                let ip_service_result = get_ip_service_results(
+                    ip_services,
+                    &Some(prev_results.clone()),
+                    Some(family),
+                    config.netns.as_deref(),
+                    None,
+                    DEFAULT_TIMEOUT_MS,
+                    &mut decisions,
+                )
+                .await;
+
+                let change_event = get_change_event(&mut prev_results, ip_service_result, family);
+                if let Some(change_event) = change_event {
+                    if sender.send(change_event).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+
+    ChangeStream { receiver }
+}
+
+// the previous ip service of each family is remembered so services are rotated like in a run
+fn get_watch_results() -> UpdateIpResults {
+    UpdateIpResults {
+        timestamp: results::get_timestamp_or_zero(),
+        ip_service_result: IpServiceResult::new(""),
+        ip_service_result_v6: Some(IpServiceResult::new("")),
+        domain_service_results: Default::default(),
+        decisions: DecisionLog::new(),
+        run_id: None,
+        config_fingerprint: None,
+    }
+}
+
+// an address is only compared with the previous address of its own family
+fn get_change_event(
+    prev_results: &mut UpdateIpResults,
+    mut ip_service_result: IpServiceResult,
+    family: AddressFamily,
+) -> Option<ChangeEvent> {
+    let prev_ip_result = match family {
+        AddressFamily::V4 => &mut prev_results.ip_service_result,
+        AddressFamily::V6 => prev_results
+            .ip_service_result_v6
+            .get_or_insert_with(|| IpServiceResult::new("")),
+    };
+    let old = prev_ip_result.ip_address;
+
+    let new = ip_service_result
+        .ip_address
+        .filter(|address| address.family() == family);
+    ip_service_result.ip_address = new;
+    ip_service_result.keep_prev_address(Some(&*prev_ip_result));
+    *prev_ip_result = ip_service_result;
+
+    let new = new?;
+    if old == Some(new) {
+        return None;
+    }
+
+    Some(ChangeEvent {
+        old,
+        new,
+        family,
+        timestamp: results::get_timestamp_or_zero(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detected(service: &str, address: Option<&str>) -> IpServiceResult {
+        let mut ip_service_result = IpServiceResult::new(service);
+        ip_service_result.ip_address = address.map(get_address);
+        ip_service_result
+    }
+
+    fn get_address(address: &str) -> Address {
+        Address::new(address.parse().unwrap())
+    }
+
+    #[test]
+    fn first_addresses_have_no_old_address() {
+        let mut prev_results = get_watch_results();
+
+        let v4 = detected("a", Some("203.0.113.4"));
+        let change_event = get_change_event(&mut prev_results, v4, AddressFamily::V4).unwrap();
+        assert_eq!(change_event.old, None);
+        assert_eq!(change_event.new, get_address("203.0.113.4"));
+        assert_eq!(change_event.family, AddressFamily::V4);
+
+        let v6 = detected("b", Some("2001:db8::1"));
+        let change_event = get_change_event(&mut prev_results, v6, AddressFamily::V6).unwrap();
+        assert_eq!(change_event.old, None);
+        assert_eq!(change_event.family, AddressFamily::V6);
+    }
+
+    #[test]
+    fn families_do_not_change_into_each_other() {
+        let mut prev_results = get_watch_results();
+        for _ in 0..3 {
+            let v4 = detected("a", Some("203.0.113.4"));
+            get_change_event(&mut prev_results, v4, AddressFamily::V4);
+            let v6 = detected("b", Some("2001:db8::1"));
+            get_change_event(&mut prev_results, v6, AddressFamily::V6);
+        }
+
+        // the same addresses on every poll are not changes
+        let v4 = detected("a", Some("203.0.113.4"));
+        assert!(get_change_event(&mut prev_results, v4, AddressFamily::V4).is_none());
+        let v6 = detected("b", Some("2001:db8::1"));
+        assert!(get_change_event(&mut prev_results, v6, AddressFamily::V6).is_none());
+
+        // an address of the other family is ignored
+        let v6_as_v4 = detected("a", Some("2001:db8::2"));
+        assert!(get_change_event(&mut prev_results, v6_as_v4, AddressFamily::V4).is_none());
+        assert_eq!(
+            prev_results.ip_service_result.ip_address,
+            Some(get_address("203.0.113.4"))
+        );
+    }
+
+    #[test]
+    fn changes_carry_the_old_address_of_their_family() {
+        let mut prev_results = get_watch_results();
+        let v4 = detected("a", Some("203.0.113.4"));
+        get_change_event(&mut prev_results, v4, AddressFamily::V4);
+        let v6 = detected("b", Some("2001:db8::1"));
+        get_change_event(&mut prev_results, v6, AddressFamily::V6);
+
+        let v6 = detected("b", Some("2001:db8::2"));
+        let change_event = get_change_event(&mut prev_results, v6, AddressFamily::V6).unwrap();
+        assert_eq!(change_event.old, Some(get_address("2001:db8::1")));
+        assert_eq!(change_event.new, get_address("2001:db8::2"));
+    }
+
+    #[test]
+    fn failed_detections_keep_the_previous_address() {
+        let mut prev_results = get_watch_results();
+        let v4 = detected("a", Some("203.0.113.4"));
+        get_change_event(&mut prev_results, v4, AddressFamily::V4);
+
+        let failed = detected("b", None);
+        assert!(get_change_event(&mut prev_results, failed, AddressFamily::V4).is_none());
+        assert_eq!(prev_results.ip_service_result.service, "b");
+
+        let v4 = detected("c", Some("203.0.113.4"));
+        assert!(get_change_event(&mut prev_results, v4, AddressFamily::V4).is_none());
+    }
+
+    #[tokio::test]
+    async fn change_streams_end_when_watching_stops() {
+        let (sender, receiver) = mpsc::channel(1);
+        let mut change_stream = ChangeStream { receiver };
+
+        let change_event = ChangeEvent {
+            old: None,
+            new: get_address("203.0.113.4"),
+            family: AddressFamily::V4,
+            timestamp: 0,
+        };
+        sender.send(change_event).await.unwrap();
+        drop(sender);
+
+        let received = std::future::poll_fn(|cx| Pin::new(&mut change_stream).poll_next(cx)).await;
+        assert_eq!(received.unwrap().new, get_address("203.0.113.4"));
+        assert!(change_stream.recv().await.is_none());
+    }
+}
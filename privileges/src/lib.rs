@@ -0,0 +1,191 @@
+/*
+    Drops root privileges to a user and group before any requests are sent.
+
+    Files the user has to write are handed over with chown_paths before
+    the drop. Supplementary groups are cleared, then the group and user
+    are set. The drop is verified, a process that can become root again
+    is an error.
+*/
+
+use std::path::Path;
+
+// the uid and gid of a user, the group defaults to the primary group of the user
+#[cfg(unix)]
+pub fn get_ids(user: &str, group: Option<&str>) -> Result<(u32, u32), String> {
+    use std::ffi::CString;
+
+    let user_cstr = match CString::new(user) {
+        Ok(s) => s,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    // getpwnam and getgrnam return static memory, values are copied immediately
+    let passwd = unsafe { libc::getpwnam(user_cstr.as_ptr()) };
+    if passwd.is_null() {
+        return Err("user ".to_string() + user + " was not found");
+    }
+    let (uid, user_gid) = unsafe { ((*passwd).pw_uid, (*passwd).pw_gid) };
+
+    let gid = match group {
+        Some(group) => {
+            let group_cstr = match CString::new(group) {
+                Ok(s) => s,
+                Err(e) => return Err(e.to_string()),
+            };
+            let group_entry = unsafe { libc::getgrnam(group_cstr.as_ptr()) };
+            if group_entry.is_null() {
+                return Err("group ".to_string() + group + " was not found");
+            }
+            unsafe { (*group_entry).gr_gid }
+        }
+        _ => user_gid,
+    };
+
+    Ok((uid, gid))
+}
+
+// files that do not exist yet are skipped
+#[cfg(unix)]
+pub fn chown_paths(paths: &[&Path], uid: u32, gid: u32) -> Result<(), String> {
+    for path in paths {
+        if !path.exists() {
+            continue;
+        }
+        if let Err(e) = std::os::unix::fs::chown(path, Some(uid), Some(gid)) {
+            return Err(path.display().to_string() + ": " + &e.to_string());
+        }
+    }
+
+    Ok(())
+}
+
+// checked with the real uid and gid, so after the drop it is the access of the user
+#[cfg(unix)]
+pub fn check_writable(path: &Path) -> Result<(), String> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path_cstr = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(s) => s,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    match unsafe { libc::access(path_cstr.as_ptr(), libc::W_OK) } {
+        0 => Ok(()),
+        _ => Err(path.display().to_string() + ": " + &std::io::Error::last_os_error().to_string()),
+    }
+}
+
+#[cfg(unix)]
+pub fn drop_privileges(uid: u32, gid: u32) -> Result<(), String> {
+    if unsafe { libc::setgroups(1, &gid) } != 0 {
+        return Err("setgroups: ".to_string() + &std::io::Error::last_os_error().to_string());
+    }
+    if unsafe { libc::setgid(gid) } != 0 {
+        return Err("setgid: ".to_string() + &std::io::Error::last_os_error().to_string());
+    }
+    if unsafe { libc::setuid(uid) } != 0 {
+        return Err("setuid: ".to_string() + &std::io::Error::last_os_error().to_string());
+    }
+
+    let dropped = unsafe {
+        libc::getuid() == uid
+            && libc::geteuid() == uid
+            && libc::getgid() == gid
+            && libc::getegid() == gid
+    };
+    if !dropped {
+        return Err("privileges were not dropped".to_string());
+    }
+    if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err("privileges could be regained after the drop".to_string());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn get_ids(_user: &str, _group: Option<&str>) -> Result<(u32, u32), String> {
+    Err("run_as is only supported on unix".to_string())
+}
+
+#[cfg(not(unix))]
+pub fn chown_paths(_paths: &[&Path], _uid: u32, _gid: u32) -> Result<(), String> {
+    Err("run_as is only supported on unix".to_string())
+}
+
+#[cfg(not(unix))]
+pub fn check_writable(_path: &Path) -> Result<(), String> {
+    Err("run_as is only supported on unix".to_string())
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges(_uid: u32, _gid: u32) -> Result<(), String> {
+    Err("run_as is only supported on unix".to_string())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::PathBuf;
+
+    fn get_temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(
+            "update_ip_privileges_test_".to_string() + name + "_" + &std::process::id().to_string(),
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn ids_of_users_and_groups() {
+        assert_eq!(get_ids("root", None), Ok((0, 0)));
+        assert_eq!(get_ids("root", Some("root")), Ok((0, 0)));
+
+        assert_eq!(
+            get_ids("update_ip_no_such_user", None),
+            Err("user update_ip_no_such_user was not found".to_string())
+        );
+        assert_eq!(
+            get_ids("root", Some("update_ip_no_such_group")),
+            Err("group update_ip_no_such_group was not found".to_string())
+        );
+        assert!(get_ids("ro\0ot", None).is_err());
+    }
+
+    #[test]
+    fn paths_are_handed_to_the_current_user() {
+        let dir = get_temp_dir("chown");
+        let (uid, gid) = (unsafe { libc::getuid() }, unsafe { libc::getgid() });
+
+        let existing = dir.join("results.json");
+        let missing = dir.join("results.json.1");
+        std::fs::write(&existing, "{}").unwrap();
+
+        assert_eq!(chown_paths(&[&existing, &missing], uid, gid), Ok(()));
+        let metadata = std::fs::metadata(&existing).unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), (uid, gid));
+        // missing files are not created
+        assert!(!missing.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn writable_paths_are_checked_with_the_real_ids() {
+        let dir = get_temp_dir("writable");
+        assert_eq!(check_writable(&dir), Ok(()));
+
+        let missing = dir.join("missing");
+        let e = check_writable(&missing).unwrap_err();
+        assert!(
+            e.starts_with(&(missing.display().to_string() + ": ")),
+            "{}",
+            e
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,262 @@
+use base64::{engine::general_purpose, Engine as _};
+use bytes::Bytes;
+use http::Request;
+use http_body_util::Empty;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use conditions::Condition;
+use results::{
+    Address, ComparePrefixLen, DomainOutcome, DomainResult, PlannedUpdate, ResponseJson,
+};
+
+/*
+    Implements a generic authenticated GET with the ip address in the query.
+
+    Covers dns services that resemble dyndns2 but differ in
+    parameter names, authentication, and response bodies.
+*/
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct QueryGet {
+    pub service_uri: String,
+    pub hostname: String,
+    pub record_type: Option<String>,
+    pub ip_param: String,
+    pub host_param: Option<String>,
+    pub params: Option<Vec<(String, String)>>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub auth_header: Option<String>,
+    pub success_pattern: Option<String>,
+    pub unchanged_pattern: Option<String>,
+    pub condition: Option<Condition>,
+    pub priority: Option<u32>,
+    #[serde(default, deserialize_with = "durations::deserialize_optional_ms")]
+    pub min_update_interval_ms: Option<u64>,
+    pub compare_prefix_len: Option<ComparePrefixLen>,
+    pub check_response_shape: Option<bool>,
+    pub success_predicate: Option<String>,
+}
+
+pub type QueryGetDomains = Vec<QueryGet>;
+
+const SERVICE_NAME: &str = "query_get";
+const CLIENT_HEADER_VALUE: &str = "hyper/1.0 rust-client";
+
+pub fn validate(domain: &QueryGet) -> Vec<String> {
+    let mut errors = Vec::<String>::new();
+
+    if let Err(e) = requests::validate_url(&domain.service_uri) {
+        errors.push("service_uri: ".to_string() + &e);
+    }
+    if domain.hostname.is_empty() {
+        errors.push("hostname: must not be empty".to_string());
+    }
+    if domain.ip_param.is_empty() {
+        errors.push("ip_param: must not be empty".to_string());
+    }
+    if domain.password.is_some() && domain.username.is_none() {
+        errors.push("username: required with password".to_string());
+    }
+    if domain.username.is_some() && domain.auth_header.is_some() {
+        errors.push("auth_header: cannot be combined with username".to_string());
+    }
+    if let Some(params) = &domain.params {
+        for (key, value) in params {
+            if key.chars().any(char::is_control) || value.chars().any(char::is_control) {
+                errors.push("params: ".to_string() + key + " contains control characters");
+            }
+        }
+    }
+    if let Some(success_predicate) = &domain.success_predicate {
+        if let Err(e) = requests::validate_json_predicate(success_predicate) {
+            errors.push("success_predicate: ".to_string() + &e);
+        }
+    }
+    if let Some(record_type) = &domain.record_type {
+        if record_type != "A" && record_type != "AAAA" {
+            errors.push("record_type: ".to_string() + record_type + " must be A or AAAA");
+        }
+    }
+    if let Some(condition) = &domain.condition {
+        errors.append(&mut conditions::validate(condition));
+    }
+
+    errors
+}
+
+pub async fn update_domains(
+    domain_results: &mut HashMap<String, DomainResult>,
+    planned_updates: &[PlannedUpdate<'_, QueryGet>],
+    timeout_ms: Option<u64>,
+) {
+    for planned_update in planned_updates {
+        let domain_result = build_domain_result(planned_update, timeout_ms).await;
+
+        // write over previous entry
+        domain_results.insert(planned_update.key.clone(), domain_result);
+    }
+}
+
+/*
+    Verified updates are read back from the authoritative nameservers of
+    the hostname. A record that does not hold the sent address is updated
+    and read back once more before the domain fails.
+*/
+pub async fn verify_domains(
+    domain_results: &mut HashMap<String, DomainResult>,
+    planned_updates: &[PlannedUpdate<'_, QueryGet>],
+    timeout_ms: Option<u64>,
+) {
+    for planned_update in planned_updates {
+        let updated = match domain_results.get(&planned_update.key) {
+            Some(domain_result) => domain_result.outcome == Some(DomainOutcome::Updated),
+            _ => false,
+        };
+        if !updated {
+            continue;
+        }
+
+        let hostname = &planned_update.domain.hostname;
+        let ip_address = &planned_update.ip_address;
+        let mut verified = dns_read_back::verify_address(hostname, ip_address, timeout_ms).await;
+        if verified.is_err() {
+            let domain_result = build_domain_result(planned_update, timeout_ms).await;
+            if domain_result.outcome == Some(DomainOutcome::Updated) {
+                verified = dns_read_back::verify_address(hostname, ip_address, timeout_ms).await;
+            }
+            domain_results.insert(planned_update.key.clone(), domain_result);
+        }
+
+        // a failed verify is retried next run
+        if let (Err(e), Some(domain_result)) =
+            (verified, domain_results.get_mut(&planned_update.key))
+        {
+            domain_result.outcome = Some(DomainOutcome::Failed);
+            domain_result.ip_address = None;
+            domain_result.errors.push(e);
+        }
+    }
+}
+
+async fn build_domain_result(
+    planned_update: &PlannedUpdate<'_, QueryGet>,
+    timeout_ms: Option<u64>,
+) -> DomainResult {
+    let domain = planned_update.domain;
+    let ip_address = &planned_update.ip_address;
+
+    let mut domain_result = DomainResult::new(&domain.hostname);
+    domain_result.service = Some(SERVICE_NAME.to_string());
+    domain_result.reason = Some(planned_update.reason);
+
+    let request = match get_query_get_req(domain, ip_address) {
+        Ok(s) => s,
+        Err(e) => {
+            domain_result.outcome = Some(DomainOutcome::Failed);
+            domain_result.errors.push(e);
+            return domain_result;
+        }
+    };
+
+    let response = requests::request_http1_tls_response(request);
+    match requests::with_timeout(timeout_ms, response).await {
+        Ok(r) => {
+            if let Some(warning) = requests::get_clock_skew_warning(&r) {
+                domain_result.warnings.push(warning);
+            }
+            match verify_response(domain, &r) {
+                Ok(outcome) => {
+                    domain_result.outcome = Some(outcome);
+                    domain_result.ip_address = Some(*ip_address);
+                    domain_result.response_shape = Some(results::get_response_shape(&r));
+                }
+                Err(e) => {
+                    domain_result.outcome = Some(DomainOutcome::Failed);
+                    domain_result.errors.push(e);
+                }
+            }
+        }
+        Err(e) => {
+            domain_result.outcome = Some(DomainOutcome::Failed);
+            domain_result.errors.push(e);
+        }
+    }
+
+    domain_result
+}
+
+// a response matching the unchanged_pattern is unchanged, ie: NOCHANGE
+fn verify_response(domain: &QueryGet, res: &ResponseJson) -> Result<DomainOutcome, String> {
+    if res.status_code < 200 || res.status_code >= 300 {
+        return Err("response status ".to_string() + &res.status_code.to_string());
+    }
+
+    if let Some(pattern) = &domain.success_pattern {
+        if !res.body.contains(pattern) {
+            return Err("response body did not match success_pattern: ".to_string() + &res.body);
+        }
+    }
+
+    if let Some(success_predicate) = &domain.success_predicate {
+        requests::check_json_predicate(&res.body, success_predicate)?;
+    }
+
+    match &domain.unchanged_pattern {
+        Some(pattern) if res.body.contains(pattern) => Ok(DomainOutcome::Unchanged),
+        _ => Ok(DomainOutcome::Updated),
+    }
+}
+
+fn get_query_get_req(
+    domain: &QueryGet,
+    ip_addr: &Address,
+) -> Result<Request<Empty<Bytes>>, String> {
+    let ip_str = ip_addr.to_string();
+    let mut params = Vec::<(&str, &str)>::new();
+    if let Some(host_param) = &domain.host_param {
+        params.push((host_param, &domain.hostname));
+    }
+    params.push((&domain.ip_param, &ip_str));
+    if let Some(extra_params) = &domain.params {
+        for (key, value) in extra_params {
+            params.push((key, value));
+        }
+    }
+
+    let uri_str = requests::append_query_params(&domain.service_uri, &params)?;
+
+    let uri = match uri_str.parse::<http::Uri>() {
+        Ok(u) => u,
+        Err(e) => return Err(e.to_string()),
+    };
+    let host = match uri.host() {
+        Some(u) => u.to_string(),
+        None => return Err("host not found in uri".to_string()),
+    };
+
+    let mut builder = Request::builder()
+        .uri(uri)
+        .header(hyper::header::HOST, host)
+        .header(hyper::header::USER_AGENT, CLIENT_HEADER_VALUE);
+
+    if let Some(username) = &domain.username {
+        let password = match &domain.password {
+            Some(p) => p.as_str(),
+            _ => "",
+        };
+        let auth_str = username.to_string() + ":" + password;
+        let auth = general_purpose::STANDARD.encode(auth_str.as_bytes());
+        builder = builder.header(hyper::header::AUTHORIZATION, "Basic ".to_string() + &auth);
+    }
+
+    if let Some(auth_header) = &domain.auth_header {
+        builder = builder.header(hyper::header::AUTHORIZATION, auth_header);
+    }
+
+    match builder.body(Empty::<Bytes>::new()) {
+        Ok(req) => Ok(req),
+        Err(e) => Err(e.to_string()),
+    }
+}
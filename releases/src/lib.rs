@@ -0,0 +1,191 @@
+use bytes::Bytes;
+use http::Request;
+use http_body_util::Empty;
+use serde::Deserialize;
+
+use results::ResponseJson;
+
+/*
+    Checks the latest release of update_ip on github.
+    https://docs.github.com/en/rest/releases/releases#get-the-latest-release
+
+    Releases are only reported, they are never installed.
+*/
+
+const LATEST_RELEASE_URL: &str =
+    "https://api.github.com/repos/wolfpup-software/update_ip/releases/latest";
+const CLIENT_HEADER_VALUE: &str = "hyper/1.0 rust-client";
+const RELEASE_TIMEOUT_MS: u64 = 10000;
+
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReleaseCheck {
+    pub current_version: String,
+    pub latest_version: String,
+    pub is_outdated: bool,
+}
+
+pub async fn check_latest_release(current_version: &str) -> Result<ReleaseCheck, String> {
+    let request = match get_latest_release_request() {
+        Ok(req) => req,
+        Err(e) => return Err(e),
+    };
+
+    let response = match requests::with_timeout(
+        Some(RELEASE_TIMEOUT_MS),
+        requests::request_http1_tls_response(request),
+    )
+    .await
+    {
+        Ok(res) => res,
+        Err(e) => return Err(e),
+    };
+
+    get_release_check(current_version, &response)
+}
+
+// reads the latest release from a response of the releases api
+fn get_release_check(
+    current_version: &str,
+    response: &ResponseJson,
+) -> Result<ReleaseCheck, String> {
+    if response.status_code != 200 {
+        return Err("response status ".to_string() + &response.status_code.to_string());
+    }
+
+    let release: Release = match serde_json::from_str(&response.body) {
+        Ok(r) => r,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let latest_version = release.tag_name.trim_start_matches('v').to_string();
+    let is_outdated = match (
+        parse_version(current_version),
+        parse_version(&latest_version),
+    ) {
+        (Some(current), Some(latest)) => current < latest,
+        _ => return Err("version ".to_string() + &latest_version + " could not be compared"),
+    };
+
+    Ok(ReleaseCheck {
+        current_version: current_version.to_string(),
+        latest_version,
+        is_outdated,
+    })
+}
+
+fn get_latest_release_request() -> Result<Request<Empty<Bytes>>, String> {
+    let uri = match LATEST_RELEASE_URL.parse::<http::Uri>() {
+        Ok(u) => u,
+        Err(e) => return Err(e.to_string()),
+    };
+    let host = match uri.host() {
+        Some(u) => u.to_string(),
+        None => return Err("host not found in uri".to_string()),
+    };
+
+    // github rejects requests without a user agent
+    match Request::builder()
+        .uri(uri)
+        .header(hyper::header::HOST, host)
+        .header(hyper::header::USER_AGENT, CLIENT_HEADER_VALUE)
+        .header(hyper::header::ACCEPT, "application/vnd.github+json")
+        .body(Empty::<Bytes>::new())
+    {
+        Ok(req) => Ok(req),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// versions are compared by major, minor, and patch, pre-release labels are ignored
+fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let core = version.split(['-', '+']).next()?;
+    let mut parts = core.split('.').map(|part| part.parse::<u64>().ok());
+
+    let major = parts.next()??;
+    let minor = parts.next().unwrap_or(Some(0))?;
+    let patch = parts.next().unwrap_or(Some(0))?;
+
+    Some((major, minor, patch))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_response(status_code: u16, body: &str) -> ResponseJson {
+        ResponseJson {
+            status_code,
+            body: body.to_string(),
+            timestamp: 0,
+            server_timestamp: None,
+            content_type: Some("application/json".to_string()),
+            bot_challenge: false,
+            tls: None,
+        }
+    }
+
+    #[test]
+    fn versions_are_compared_by_number() {
+        assert!(parse_version("0.9.0") < parse_version("0.10.0"));
+        assert!(parse_version("1.2.3") < parse_version("1.2.10"));
+        assert!(parse_version("1.10.0") > parse_version("1.9.9"));
+        assert!(parse_version("2.0.0") > parse_version("1.99.99"));
+        assert_eq!(parse_version("1.2"), Some((1, 2, 0)));
+        assert_eq!(parse_version("1"), Some((1, 0, 0)));
+    }
+
+    #[test]
+    fn pre_release_labels_are_ignored() {
+        assert_eq!(parse_version("1.2.3-beta.1"), Some((1, 2, 3)));
+        assert_eq!(parse_version("1.2.3+build.5"), Some((1, 2, 3)));
+        assert_eq!(parse_version("1.2.3-rc.1"), parse_version("1.2.3"));
+    }
+
+    #[test]
+    fn invalid_versions_are_not_parsed() {
+        assert_eq!(parse_version(""), None);
+        assert_eq!(parse_version("latest"), None);
+        assert_eq!(parse_version("1.x.0"), None);
+        assert_eq!(parse_version("-1.0.0"), None);
+    }
+
+    #[test]
+    fn releases_are_read_from_the_response() {
+        let body = r#"{"url":"https://api.github.com/repos/wolfpup-software/update_ip/releases/1","tag_name":"v0.2.0","name":"0.2.0","prerelease":false}"#;
+        let release_check = get_release_check("0.1.0", &create_response(200, body)).unwrap();
+        assert_eq!(release_check.latest_version, "0.2.0");
+        assert!(release_check.is_outdated);
+
+        let release_check = get_release_check("0.2.0", &create_response(200, body)).unwrap();
+        assert!(!release_check.is_outdated);
+
+        let release_check = get_release_check("0.3.0", &create_response(200, body)).unwrap();
+        assert!(!release_check.is_outdated);
+    }
+
+    #[test]
+    fn release_errors_are_reported() {
+        let not_found = create_response(404, r#"{"message":"Not Found"}"#);
+        assert_eq!(
+            get_release_check("0.1.0", &not_found).unwrap_err(),
+            "response status 404"
+        );
+
+        let without_tag = create_response(200, r#"{"name":"0.2.0"}"#);
+        assert!(get_release_check("0.1.0", &without_tag).is_err());
+
+        let not_json = create_response(200, "<html></html>");
+        assert!(get_release_check("0.1.0", &not_json).is_err());
+
+        let unknown_tag = create_response(200, r#"{"tag_name":"nightly"}"#);
+        assert_eq!(
+            get_release_check("0.1.0", &unknown_tag).unwrap_err(),
+            "version nightly could not be compared"
+        );
+    }
+}
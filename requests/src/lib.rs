@@ -6,12 +6,193 @@ use http_body_util::{BodyExt, Empty, Full};
 use hyper::body::Incoming;
 use hyper::client::conn::http1;
 use hyper_util::rt::TokioIo;
-use native_tls::TlsConnector;
+use native_tls::{Protocol, TlsConnector};
+use std::future::Future;
 use std::io;
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{OnceLock, RwLock};
+use std::time::Duration;
 use tokio::net::TcpStream;
+use tokio::time;
 
-use results::ResponseJson;
+use results::{ResponseJson, TlsParameters};
+
+#[cfg(feature = "mock_transport")]
+pub mod mock_transport;
+mod tls_parameters;
+
+use tls_parameters::{get_tls13_rejection, HandshakeObserver, ObservedStream};
+
+type TlsIo = TokioIo<tokio_native_tls::TlsStream<ObservedStream<TcpStream>>>;
+
+// named network namespaces created by iproute2
+#[cfg(target_os = "linux")]
+const NETNS_DIR: &str = "/run/netns/";
+
+// server clocks further off than 5 minutes suggest a broken local clock
+const CLOCK_SKEW_WARNING_MS: u128 = 5 * 60 * 1000;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// a header added to every request of a run, ie: X-Request-Id
+static RUN_HEADER: RwLock<Option<(String, String)>> = RwLock::new(None);
+
+// hosts requests may connect to, ie: api.cloudflare.com or *.cloudflare.com
+static ALLOWED_HOSTS: OnceLock<Vec<String>> = OnceLock::new();
+
+// connections that negotiate a version below tls 1.3 are rejected
+static REQUIRE_TLS13: AtomicBool = AtomicBool::new(false);
+
+pub fn set_require_tls13(require_tls13: bool) {
+    REQUIRE_TLS13.store(require_tls13, Ordering::Relaxed);
+}
+
+// allowed hosts can only be set once
+pub fn set_allowed_hosts(allowed_hosts: &[String]) -> Result<(), String> {
+    let allowed_hosts = allowed_hosts
+        .iter()
+        .map(|host| host.to_lowercase())
+        .collect();
+
+    match ALLOWED_HOSTS.set(allowed_hosts) {
+        Ok(_) => Ok(()),
+        Err(_) => Err("allowed hosts are already set".to_string()),
+    }
+}
+
+// every host is allowed until allowed hosts are set
+fn check_allowed_host(host: &str) -> Result<(), String> {
+    let allowed_hosts = match ALLOWED_HOSTS.get() {
+        Some(hosts) => hosts,
+        _ => return Ok(()),
+    };
+
+    let host = host.to_lowercase();
+    for allowed_host in allowed_hosts {
+        let is_allowed = match allowed_host.strip_prefix("*.") {
+            Some(suffix) => host.ends_with(&(".".to_string() + suffix)),
+            _ => &host == allowed_host,
+        };
+        if is_allowed {
+            return Ok(());
+        }
+    }
+
+    Err("host ".to_string() + &host + " is not in allowed_hosts")
+}
+
+// every run replaces the run header of the previous run
+pub fn set_run_header(name: &str, value: &str) -> Result<(), String> {
+    if let Err(e) = http::HeaderName::try_from(name) {
+        return Err(e.to_string());
+    }
+    if let Err(e) = http::HeaderValue::try_from(value) {
+        return Err(e.to_string());
+    }
+
+    match RUN_HEADER.write() {
+        Ok(mut run_header) => {
+            *run_header = Some((name.to_string(), value.to_string()));
+            Ok(())
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn add_run_header<T>(req: &mut Request<T>) {
+    let run_header = match RUN_HEADER.read() {
+        Ok(run_header) => run_header,
+        _ => return,
+    };
+
+    if let Some((name, value)) = run_header.as_ref() {
+        if let (Ok(name), Ok(value)) = (
+            http::HeaderName::try_from(name),
+            http::HeaderValue::try_from(value),
+        ) {
+            req.headers_mut().insert(name, value);
+        }
+    }
+}
+
+pub fn validate_url(url_string: &str) -> Result<(), String> {
+    validate_url_with_schemes(url_string, &["https"])
+}
+
+// ip services can be requested over plain http, ie: an ip echo service on a lan
+pub fn validate_http_or_https_url(url_string: &str) -> Result<(), String> {
+    validate_url_with_schemes(url_string, &["http", "https"])
+}
+
+fn validate_url_with_schemes(url_string: &str, schemes: &[&str]) -> Result<(), String> {
+    let uri = match http::Uri::try_from(url_string) {
+        Ok(u) => u,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    if let Some(scheme) = uri.scheme_str() {
+        if !schemes.contains(&scheme) {
+            return Err("scheme ".to_string() + scheme + " is not supported");
+        }
+    }
+
+    match get_host_and_authority(&uri) {
+        Some(_) => Ok(()),
+        _ => Err("host not found in url".to_string()),
+    }
+}
+
+// the host:port a url connects to, ie: example.com:443
+pub fn get_url_authority(url_string: &str) -> Result<String, String> {
+    let uri = match http::Uri::try_from(url_string) {
+        Ok(u) => u,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    match get_host_and_authority(&uri) {
+        Some((_host, authority)) => Ok(authority),
+        _ => Err("host not found in url".to_string()),
+    }
+}
+
+/*
+    Appends query parameters to a url. Keys and values are percent encoded
+    per RFC 3986, only unreserved characters are left as is.
+    Raw control characters are rejected.
+*/
+pub fn append_query_params(url_string: &str, params: &[(&str, &str)]) -> Result<String, String> {
+    let mut url = url_string.to_string();
+
+    for (index, (key, value)) in params.iter().enumerate() {
+        if key.chars().any(char::is_control) || value.chars().any(char::is_control) {
+            return Err("query parameter ".to_string() + key + " contains control characters");
+        }
+
+        let separator = match (index, url_string.contains('?')) {
+            (0, false) => "?",
+            _ => "&",
+        };
+        url = url + separator + &percent_encode(key) + "=" + &percent_encode(value);
+    }
+
+    Ok(url)
+}
+
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded += &format!("%{:02X}", byte),
+        }
+    }
+
+    encoded
+}
 
 pub fn create_request_with_empty_body(url_string: &str) -> Result<Request<Empty<Bytes>>, String> {
     let uri = match http::Uri::try_from(url_string) {
@@ -39,12 +220,78 @@ pub fn create_request_with_empty_body(url_string: &str) -> Result<Request<Empty<
 pub async fn request_http1_tls_response(
     req: Request<Empty<Bytes>>,
 ) -> Result<ResponseJson, String> {
-    let (host, authority) = match get_host_and_authority(&req.uri()) {
+    request_http1_tls_response_in_netns(req, None).await
+}
+
+// http urls are requested without tls, https and schemeless urls with tls
+pub async fn request_http1_response_in_netns(
+    req: Request<Empty<Bytes>>,
+    netns: Option<&str>,
+) -> Result<ResponseJson, String> {
+    match req.uri().scheme_str() {
+        Some("http") => request_http1_plain_response_in_netns(req, netns).await,
+        _ => request_http1_tls_response_in_netns(req, netns).await,
+    }
+}
+
+async fn request_http1_plain_response_in_netns(
+    mut req: Request<Empty<Bytes>>,
+    netns: Option<&str>,
+) -> Result<ResponseJson, String> {
+    add_run_header(&mut req);
+
+    #[cfg(feature = "mock_transport")]
+    if let Some(response) = mock_transport::respond(&req).await {
+        return response;
+    }
+
+    let (host, authority) = match get_host_and_authority(req.uri()) {
+        Some(stream) => stream,
+        _ => return Err("failed to get authority from uri".to_string()),
+    };
+
+    check_allowed_host(host)?;
+
+    let io = match connect_tcp(&authority, netns).await {
+        Ok(stream) => TokioIo::new(stream),
+        Err(e) => return Err(e),
+    };
+
+    let (mut sender, conn) = match http1::handshake(io).await {
+        Ok(handshake) => handshake,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    tokio::task::spawn(async move {
+        if let Err(_err) = conn.await { /* log connection error */ }
+    });
+
+    let res = match sender.send_request(req).await {
+        Ok(res) => res,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    convert_response_to_json_struct(res, None).await
+}
+
+// connects from within a named linux network namespace when one is given
+pub async fn request_http1_tls_response_in_netns(
+    mut req: Request<Empty<Bytes>>,
+    netns: Option<&str>,
+) -> Result<ResponseJson, String> {
+    add_run_header(&mut req);
+
+    #[cfg(feature = "mock_transport")]
+    if let Some(response) = mock_transport::respond(&req).await {
+        return response;
+    }
+
+    let (host, authority) = match get_host_and_authority(req.uri()) {
         Some(stream) => stream,
         _ => return Err("failed to get authority from uri".to_string()),
     };
 
-    let io = match create_tls_stream(&host, &authority).await {
+    let (io, tls) = match create_tls_stream(host, &authority, netns).await {
         Ok(stream) => stream,
         Err(e) => return Err(e),
     };
@@ -63,18 +310,25 @@ pub async fn request_http1_tls_response(
         Err(e) => return Err(e.to_string()),
     };
 
-    convert_response_to_json_struct(res).await
+    convert_response_to_json_struct(res, tls).await
 }
 
 pub async fn boxed_request_http1_tls_response(
-    req: Request<Full<Bytes>>,
+    mut req: Request<Full<Bytes>>,
 ) -> Result<ResponseJson, String> {
-    let (host, authority) = match get_host_and_authority(&req.uri()) {
+    add_run_header(&mut req);
+
+    #[cfg(feature = "mock_transport")]
+    if let Some(response) = mock_transport::respond(&req).await {
+        return response;
+    }
+
+    let (host, authority) = match get_host_and_authority(req.uri()) {
         Some(stream) => stream,
         _ => return Err("failed to get authority from uri".to_string()),
     };
 
-    let io = match create_tls_stream(&host, &authority).await {
+    let (io, tls) = match create_tls_stream(host, &authority, None).await {
         Ok(stream) => stream,
         Err(e) => return Err(e),
     };
@@ -93,7 +347,58 @@ pub async fn boxed_request_http1_tls_response(
         Err(e) => return Err(e.to_string()),
     };
 
-    convert_response_to_json_struct(res).await
+    convert_response_to_json_struct(res, tls).await
+}
+
+// connects to the host of a url within timeout_ms without sending a request
+pub async fn probe_url(
+    url_string: &str,
+    timeout_ms: u64,
+    netns: Option<&str>,
+) -> Result<(), String> {
+    let uri = match http::Uri::try_from(url_string) {
+        Ok(u) => u,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let (host, authority) = match get_host_and_authority(&uri) {
+        Some(u) => u,
+        _ => return Err("authority not found in url".to_string()),
+    };
+
+    check_allowed_host(host)?;
+
+    let connect = async {
+        match netns {
+            Some(netns) => connect_in_netns(netns, &authority).await,
+            _ => match TcpStream::connect(&authority).await {
+                Ok(s) => Ok(s),
+                Err(e) => Err(e.to_string()),
+            },
+        }
+    };
+
+    match time::timeout(Duration::from_millis(timeout_ms), connect).await {
+        Ok(Ok(_stream)) => Ok(()),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err("timed out after ".to_string() + &timeout_ms.to_string() + "ms"),
+    }
+}
+
+// bound a request by an optional timeout in milliseconds
+pub async fn with_timeout(
+    timeout_ms: Option<u64>,
+    request: impl Future<Output = Result<ResponseJson, String>>,
+) -> Result<ResponseJson, String> {
+    let ms = match timeout_ms {
+        Some(ms) => ms,
+        _ => return request.await,
+    };
+
+    match time::timeout(Duration::from_millis(ms), request).await {
+        Ok(res) => res,
+        Err(_) => Err("request timed out after ".to_string() + &ms.to_string() + "ms"),
+    }
 }
 
 fn get_host_and_authority(uri: &Uri) -> Option<(&str, String)> {
@@ -123,34 +428,133 @@ fn get_host_and_authority(uri: &Uri) -> Option<(&str, String)> {
 async fn create_tls_stream(
     host: &str,
     addr: &str,
-) -> Result<TokioIo<tokio_native_tls::TlsStream<TcpStream>>, String> {
-    let tls_connector = match TlsConnector::new() {
+    netns: Option<&str>,
+) -> Result<(TlsIo, Option<TlsParameters>), String> {
+    check_allowed_host(host)?;
+
+    let require_tls13 = REQUIRE_TLS13.load(Ordering::Relaxed);
+    let min_protocol_version = match require_tls13 {
+        true => Some(Protocol::Tlsv13),
+        false => None,
+    };
+
+    let tls_connector = match TlsConnector::builder()
+        .min_protocol_version(min_protocol_version)
+        .build()
+    {
         Ok(cx) => tokio_native_tls::TlsConnector::from(cx),
         Err(e) => return Err(e.to_string()),
     };
 
-    let client_stream = match TcpStream::connect(addr).await {
-        Ok(s) => s,
-        Err(e) => {
-            return Err(e.to_string());
-        }
+    // a failed handshake is retried once on a fresh connection, connect errors are not
+    let observer = HandshakeObserver::default();
+    let client_stream = ObservedStream::new(connect_tcp(addr, netns).await?, observer.clone());
+    let e = match tls_connector.connect(host, client_stream).await {
+        Ok(s) => return Ok((TokioIo::new(s), observer.tls_parameters())),
+        Err(e) => e,
     };
 
-    let tls_stream = match tls_connector.connect(host, client_stream).await {
-        Ok(s) => TokioIo::new(s),
+    // a server without tls 1.3 rejects every retry the same way
+    if require_tls13 {
+        if let Some(reason) = get_tls13_rejection(observer.server_reply()) {
+            return Err("tls 1.3 is required, ".to_string() + &reason + ": " + &e.to_string());
+        }
+    }
+
+    let observer = HandshakeObserver::default();
+    let client_stream = ObservedStream::new(connect_tcp(addr, netns).await?, observer.clone());
+    match tls_connector.connect(host, client_stream).await {
+        Ok(s) => Ok((TokioIo::new(s), observer.tls_parameters())),
+        Err(e) if require_tls13 => {
+            Err("tls handshake failed twice, tls 1.3 is required: ".to_string() + &e.to_string())
+        }
+        Err(e) => Err("tls handshake failed twice: ".to_string() + &e.to_string()),
+    }
+}
+
+async fn connect_tcp(addr: &str, netns: Option<&str>) -> Result<TcpStream, String> {
+    match netns {
+        Some(netns) => connect_in_netns(netns, addr).await,
+        _ => match TcpStream::connect(addr).await {
+            Ok(s) => Ok(s),
+            Err(e) => Err(e.to_string()),
+        },
+    }
+}
+
+/*
+    setns only moves the calling thread into a network namespace.
+    The socket is connected from a short lived thread so no runtime
+    thread is left in the namespace. Sockets keep their namespace
+    after the thread exits.
+*/
+#[cfg(target_os = "linux")]
+async fn connect_in_netns(netns: &str, addr: &str) -> Result<TcpStream, String> {
+    let netns_path = NETNS_DIR.to_string() + netns;
+    let addr = addr.to_string();
+
+    let (sender, receiver) = tokio::sync::oneshot::channel();
+    std::thread::spawn(move || {
+        let _ = sender.send(connect_from_netns_thread(&netns_path, &addr));
+    });
+
+    let std_stream = match receiver.await {
+        Ok(stream) => stream?,
         Err(e) => return Err(e.to_string()),
     };
 
-    Ok(tls_stream)
+    match TcpStream::from_std(std_stream) {
+        Ok(s) => Ok(s),
+        Err(e) => Err(e.to_string()),
+    }
 }
 
-async fn convert_response_to_json_struct(res: Response<Incoming>) -> Result<ResponseJson, String> {
-    let timestamp = match get_timestamp() {
-        Ok(n) => n,
-        Err(e) => return Err(e),
+#[cfg(target_os = "linux")]
+fn connect_from_netns_thread(netns_path: &str, addr: &str) -> Result<std::net::TcpStream, String> {
+    use std::os::fd::AsRawFd;
+
+    let netns_file = match std::fs::File::open(netns_path) {
+        Ok(f) => f,
+        Err(e) => return Err(netns_path.to_string() + ": " + &e.to_string()),
+    };
+
+    if unsafe { libc::setns(netns_file.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+        return Err("setns: ".to_string() + &io::Error::last_os_error().to_string());
+    }
+
+    let stream = match std::net::TcpStream::connect(addr) {
+        Ok(s) => s,
+        Err(e) => return Err(e.to_string()),
     };
 
+    match stream.set_nonblocking(true) {
+        Ok(_) => Ok(stream),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn connect_in_netns(_netns: &str, _addr: &str) -> Result<TcpStream, String> {
+    Err("network namespaces are only supported on linux".to_string())
+}
+
+async fn convert_response_to_json_struct(
+    res: Response<Incoming>,
+    tls: Option<TlsParameters>,
+) -> Result<ResponseJson, String> {
+    let timestamp = results::get_timestamp_or_zero();
+
     let status = res.status().as_u16();
+    let server_timestamp = match res.headers().get(http::header::DATE) {
+        Some(date) => date.to_str().ok().and_then(parse_http_date),
+        _ => None,
+    };
+    let content_type = match res.headers().get(http::header::CONTENT_TYPE) {
+        Some(content_type) => content_type.to_str().ok().map(|c| c.to_string()),
+        _ => None,
+    };
+
+    let bot_challenge = is_bot_challenge(&res);
 
     let body_str = match response_body_to_string(res).await {
         Ok(r) => r,
@@ -160,10 +564,147 @@ async fn convert_response_to_json_struct(res: Response<Incoming>) -> Result<Resp
     Ok(ResponseJson {
         status_code: status,
         body: body_str,
-        timestamp: timestamp,
+        timestamp,
+        server_timestamp,
+        content_type,
+        bot_challenge,
+        tls,
     })
 }
 
+/*
+    Anti-bot proxies answer automated clients with a 403 or 503 html page
+    that expects javascript, ie: cloudflare sets a cf-mitigated header or
+    a cloudflare server header on its challenge pages.
+*/
+fn is_bot_challenge(res: &Response<Incoming>) -> bool {
+    let status = res.status().as_u16();
+    if status != 403 && status != 503 {
+        return false;
+    }
+
+    let header_value = |name| match res.headers().get(name) {
+        Some(value) => value.to_str().unwrap_or("").to_lowercase(),
+        _ => String::new(),
+    };
+
+    let is_html = header_value(http::header::CONTENT_TYPE).starts_with("text/html");
+    let is_mitigated = res.headers().contains_key("cf-mitigated");
+    let is_cloudflare = header_value(http::header::SERVER) == "cloudflare";
+
+    is_html && (is_mitigated || is_cloudflare)
+}
+
+pub fn get_clock_skew_warning(res: &ResponseJson) -> Option<String> {
+    let server_timestamp = res.server_timestamp?;
+    if res.timestamp == 0 || res.timestamp.abs_diff(server_timestamp) <= CLOCK_SKEW_WARNING_MS {
+        return None;
+    }
+
+    let direction = match res.timestamp > server_timestamp {
+        true => " ahead of ",
+        false => " behind ",
+    };
+
+    Some(
+        "local clock is ".to_string()
+            + &(res.timestamp.abs_diff(server_timestamp) / 1000).to_string()
+            + "s"
+            + direction
+            + "the server clock, check ntp",
+    )
+}
+
+/*
+    A json predicate compares the value at a json pointer of a response
+    body to a json value, ie: /success == true or /result/content == "192.0.2.1"
+    https://www.rfc-editor.org/rfc/rfc6901
+*/
+pub fn validate_json_predicate(predicate: &str) -> Result<(), String> {
+    parse_json_predicate(predicate).map(|_| ())
+}
+
+pub fn check_json_predicate(body: &str, predicate: &str) -> Result<(), String> {
+    let (pointer, expected) = parse_json_predicate(predicate)?;
+
+    let json: serde_json::Value = match serde_json::from_str(body) {
+        Ok(j) => j,
+        Err(e) => return Err("response body is not json: ".to_string() + &e.to_string()),
+    };
+
+    match json.pointer(pointer) {
+        Some(value) if value == &expected => Ok(()),
+        Some(value) => Err(pointer.to_string() + " was " + &value.to_string()),
+        _ => Err(pointer.to_string() + " was not found in the response body"),
+    }
+}
+
+fn parse_json_predicate(predicate: &str) -> Result<(&str, serde_json::Value), String> {
+    let (pointer, value) = match predicate.split_once("==") {
+        Some((pointer, value)) => (pointer.trim(), value.trim()),
+        _ => return Err(predicate.to_string() + " is not <json pointer> == <json value>"),
+    };
+
+    if !pointer.is_empty() && !pointer.starts_with('/') {
+        return Err(pointer.to_string() + " is not a json pointer");
+    }
+
+    match serde_json::from_str(value) {
+        Ok(value) => Ok((pointer, value)),
+        Err(_) => Err(value.to_string() + " is not a json value"),
+    }
+}
+
+/*
+    Parses an IMF-fixdate, ie: Sun, 06 Nov 1994 08:49:37 GMT
+    https://www.rfc-editor.org/rfc/rfc9110#name-date-time-formats
+
+    Obsolete date formats are not parsed.
+*/
+fn parse_http_date(date: &str) -> Option<u128> {
+    let parts: Vec<&str> = date.split_whitespace().collect();
+    let (day, month, year, time) = match parts.as_slice() {
+        [_, day, month, year, time, "GMT"] => (day, month, year, time),
+        _ => return None,
+    };
+
+    let day = day.parse::<u64>().ok()?;
+    let month = MONTHS.iter().position(|m| m == month)? as u64 + 1;
+    let year = year.parse::<u64>().ok()?;
+    let mut time_parts = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (hours, minutes, seconds) = match (
+        time_parts.next(),
+        time_parts.next(),
+        time_parts.next(),
+        time_parts.next(),
+    ) {
+        (Some(Some(h)), Some(Some(m)), Some(Some(s)), None) => (h, m, s),
+        _ => return None,
+    };
+
+    if year < 1970 || !(1..=31).contains(&day) || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+
+    let days = days_since_epoch(year, month, day);
+    let seconds = days * 86400 + hours * 3600 + minutes * 60 + seconds;
+
+    Some(seconds as u128 * 1000)
+}
+
+// days between 1970-01-01 and a date in the gregorian calendar
+fn days_since_epoch(year: u64, month: u64, day: u64) -> u64 {
+    // years start in march so leap days are the last day of a year
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
 async fn response_body_to_string(response: Response<Incoming>) -> Result<String, String> {
     // asynchronously aggregate the chunks of the body
     let body = match response.collect().await {
@@ -179,9 +720,287 @@ async fn response_body_to_string(response: Response<Incoming>) -> Result<String,
     Ok(ip_str.to_string())
 }
 
-fn get_timestamp() -> Result<u128, String> {
-    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-        Ok(n) => Ok(n.as_millis()),
-        Err(e) => Err(e.to_string()),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // how a server splits and decodes a query string
+    fn parse_query(url: &str) -> Vec<(String, String)> {
+        let query = match url.split_once('?') {
+            Some((_, query)) => query,
+            _ => return Vec::new(),
+        };
+
+        query
+            .split('&')
+            .map(|param| match param.split_once('=') {
+                Some((key, value)) => (percent_decode(key), percent_decode(value)),
+                _ => (percent_decode(param), String::new()),
+            })
+            .collect()
+    }
+
+    fn percent_decode(value: &str) -> String {
+        let bytes = value.as_bytes();
+        let mut decoded = Vec::<u8>::new();
+        let mut index = 0;
+        while index < bytes.len() {
+            let hex = bytes
+                .get(index + 1..index + 3)
+                .map(|hex| String::from_utf8_lossy(hex));
+            match (
+                bytes[index],
+                hex.and_then(|hex| u8::from_str_radix(&hex, 16).ok()),
+            ) {
+                (b'%', Some(byte)) => {
+                    decoded.push(byte);
+                    index += 3;
+                }
+                (byte, _) => {
+                    decoded.push(byte);
+                    index += 1;
+                }
+            }
+        }
+
+        String::from_utf8(decoded).unwrap()
+    }
+
+    const ADVERSARIAL_PARAMS: [(&str, &str); 5] = [
+        ("hostname", "my host.example"),
+        ("password", "hunter2&myip=1.1.1.1"),
+        ("token", "100%real%2Fsecret"),
+        ("fragment", "a#b?c=d"),
+        ("unicode", "bücher+€/;,"),
+    ];
+
+    #[test]
+    fn special_characters_are_percent_encoded() {
+        let url = append_query_params("https://example.com/update", &ADVERSARIAL_PARAMS).unwrap();
+        assert_eq!(
+            url,
+            "https://example.com/update?hostname=my%20host.example\
+             &password=hunter2%26myip%3D1.1.1.1\
+             &token=100%25real%252Fsecret\
+             &fragment=a%23b%3Fc%3Dd\
+             &unicode=b%C3%BCcher%2B%E2%82%AC%2F%3B%2C"
+        );
+        assert_eq!(validate_url(&url), Ok(()));
+    }
+
+    #[test]
+    fn encoded_params_decode_to_themselves() {
+        let url = append_query_params("https://example.com/update", &ADVERSARIAL_PARAMS).unwrap();
+        let expected: Vec<(String, String)> = ADVERSARIAL_PARAMS
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        assert_eq!(parse_query(&url), expected);
+    }
+
+    #[test]
+    fn params_are_appended_to_an_existing_query() {
+        let url = append_query_params("https://example.com/update?a=1", &[("b", "2&c=3")]).unwrap();
+        assert_eq!(url, "https://example.com/update?a=1&b=2%26c%3D3");
+    }
+
+    #[test]
+    fn control_characters_are_rejected() {
+        for value in [
+            "line\nbreak",
+            "carriage\rreturn",
+            "null\0byte",
+            "tab\tbed",
+            "del\u{7f}",
+        ] {
+            assert_eq!(
+                append_query_params("https://example.com/update", &[("password", value)]),
+                Err("query parameter password contains control characters".to_string()),
+                "{:?}",
+                value
+            );
+        }
+        assert!(append_query_params("https://example.com/update", &[("pass\nword", "")]).is_err());
+    }
+
+    // answers one request with its request target as the body
+    async fn echo_request_target(listener: TcpListener) {
+        echo_request_target_with_headers(listener, "").await
+    }
+
+    async fn echo_request_target_with_headers(listener: TcpListener, headers: &str) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::<u8>::new();
+        let mut buffer = [0u8; 1024];
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            let length = stream.read(&mut buffer).await.unwrap();
+            if length == 0 {
+                break;
+            }
+            request.extend_from_slice(&buffer[..length]);
+        }
+
+        let request = String::from_utf8_lossy(&request).to_string();
+        let target = request.split(' ').nth(1).unwrap_or("").to_string();
+        let response = "HTTP/1.1 200 OK\r\n".to_string()
+            + headers
+            + "content-length: "
+            + &target.len().to_string()
+            + "\r\nconnection: close\r\n\r\n"
+            + &target;
+        stream.write_all(response.as_bytes()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn servers_receive_only_the_sent_params() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = "http://".to_string() + &listener.local_addr().unwrap().to_string() + "/update";
+        let server = tokio::spawn(echo_request_target(listener));
+
+        let url = append_query_params(&url, &ADVERSARIAL_PARAMS).unwrap();
+        let req = create_request_with_empty_body(&url).unwrap();
+        let response = request_http1_response_in_netns(req, None).await.unwrap();
+        server.await.unwrap();
+
+        // nothing leaks into another parameter, ie: no myip from the password
+        let expected: Vec<(String, String)> = ADVERSARIAL_PARAMS
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let path = response.body.split_once('?').map(|(path, _)| path);
+        assert!(
+            path.is_some_and(|path| path.ends_with("/update")),
+            "{}",
+            response.body
+        );
+        assert_eq!(parse_query(&response.body), expected);
+    }
+
+    // Sun, 06 Nov 1994 08:49:37 GMT
+    const RFC_EXAMPLE_MS: u128 = 784_111_777_000;
+
+    fn get_response(timestamp: u128, server_timestamp: Option<u128>) -> ResponseJson {
+        ResponseJson {
+            status_code: 200,
+            body: String::new(),
+            timestamp,
+            server_timestamp,
+            content_type: None,
+            bot_challenge: false,
+            tls: None,
+        }
+    }
+
+    #[test]
+    fn days_are_counted_from_the_epoch() {
+        assert_eq!(days_since_epoch(1970, 1, 1), 0);
+        assert_eq!(days_since_epoch(1970, 3, 1), 59);
+        assert_eq!(days_since_epoch(2000, 2, 29), 11_016);
+        assert_eq!(days_since_epoch(2000, 3, 1), 11_017);
+        assert_eq!(days_since_epoch(2023, 12, 31), 19_722);
+        assert_eq!(days_since_epoch(2024, 1, 1), 19_723);
+        // 2100 is not a leap year
+        assert_eq!(
+            days_since_epoch(2100, 3, 1) - days_since_epoch(2100, 2, 28),
+            1
+        );
+    }
+
+    #[test]
+    fn imf_fixdates_are_parsed() {
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(RFC_EXAMPLE_MS)
+        );
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(
+            parse_http_date("Tue, 29 Feb 2000 23:59:59 GMT"),
+            Some((11_016 * 86_400 + 86_399) * 1000)
+        );
+        // a leap second is not rejected
+        assert_eq!(
+            parse_http_date("Sat, 31 Dec 2016 23:59:60 GMT"),
+            Some((17_166 * 86_400 + 86_400) * 1000)
+        );
+    }
+
+    #[test]
+    fn other_date_formats_are_not_parsed() {
+        for date in [
+            "",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Sun, 06 Nov 1994 08:49:37 +0000",
+            "Sun, 06 nov 1994 08:49:37 GMT",
+            "Sun, 32 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 06 Nov 1994 08:60:00 GMT",
+            "Sun, 06 Nov 1994 08:49 GMT",
+            "Sun, 06 Nov 1994 08:49:37:00 GMT",
+            "Wed, 31 Dec 1969 23:59:59 GMT",
+        ] {
+            assert_eq!(parse_http_date(date), None, "{}", date);
+        }
+    }
+
+    #[test]
+    fn small_clock_skews_are_not_warned() {
+        let timestamp = RFC_EXAMPLE_MS;
+        assert_eq!(get_clock_skew_warning(&get_response(timestamp, None)), None);
+        for server_timestamp in [
+            timestamp,
+            timestamp + CLOCK_SKEW_WARNING_MS,
+            timestamp - CLOCK_SKEW_WARNING_MS,
+        ] {
+            let response = get_response(timestamp, Some(server_timestamp));
+            assert_eq!(get_clock_skew_warning(&response), None);
+        }
+
+        // a local clock that could not be read is not compared
+        assert_eq!(
+            get_clock_skew_warning(&get_response(0, Some(RFC_EXAMPLE_MS))),
+            None
+        );
+    }
+
+    #[test]
+    fn large_clock_skews_are_warned_with_their_direction() {
+        let timestamp = RFC_EXAMPLE_MS;
+        let response = get_response(timestamp, Some(timestamp - CLOCK_SKEW_WARNING_MS - 1000));
+        assert_eq!(
+            get_clock_skew_warning(&response).as_deref(),
+            Some("local clock is 301s ahead of the server clock, check ntp")
+        );
+
+        let response = get_response(timestamp, Some(timestamp + 3_600_000));
+        assert_eq!(
+            get_clock_skew_warning(&response).as_deref(),
+            Some("local clock is 3600s behind the server clock, check ntp")
+        );
+    }
+
+    #[tokio::test]
+    async fn date_headers_are_recorded_as_server_timestamps() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = "http://".to_string() + &listener.local_addr().unwrap().to_string() + "/";
+        let server = tokio::spawn(echo_request_target_with_headers(
+            listener,
+            "date: Sun, 06 Nov 1994 08:49:37 GMT\r\n",
+        ));
+
+        let req = create_request_with_empty_body(&url).unwrap();
+        let response = request_http1_response_in_netns(req, None).await.unwrap();
+        server.await.unwrap();
+
+        assert_eq!(response.server_timestamp, Some(RFC_EXAMPLE_MS));
+        let warning = get_clock_skew_warning(&response).unwrap();
+        assert!(
+            warning.ends_with("s ahead of the server clock, check ntp"),
+            "{}",
+            warning
+        );
     }
 }
@@ -0,0 +1,76 @@
+use bytes::Bytes;
+use http::Request;
+use http_body_util::BodyExt;
+use hyper::body::Body;
+use std::cell::RefCell;
+use std::fmt::Display;
+
+use results::ResponseJson;
+
+/*
+    Answers requests in tests instead of connecting to a host.
+
+    A responder is installed per thread, the current thread runtime of a
+    test sends every request from the thread of the test. Requests are
+    answered by the responder until it is cleared.
+*/
+
+#[derive(Clone, Debug)]
+pub struct MockRequest {
+    pub method: String,
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+type Responder = Box<dyn FnMut(&MockRequest) -> Result<ResponseJson, String>>;
+
+thread_local! {
+    static RESPONDER: RefCell<Option<Responder>> = RefCell::new(None);
+}
+
+pub fn set_responder(
+    responder: impl FnMut(&MockRequest) -> Result<ResponseJson, String> + 'static,
+) {
+    RESPONDER.with(|cell| *cell.borrow_mut() = Some(Box::new(responder)));
+}
+
+pub fn clear_responder() {
+    RESPONDER.with(|cell| *cell.borrow_mut() = None);
+}
+
+// none when no responder is installed and the request is sent
+pub(crate) async fn respond<B>(req: &Request<B>) -> Option<Result<ResponseJson, String>>
+where
+    B: Body<Data = Bytes> + Clone,
+    B::Error: Display,
+{
+    let installed = RESPONDER.with(|cell| cell.borrow().is_some());
+    if !installed {
+        return None;
+    }
+
+    let body = match req.body().clone().collect().await {
+        Ok(body) => String::from_utf8_lossy(&body.to_bytes()).to_string(),
+        Err(e) => return Some(Err(e.to_string())),
+    };
+    let mock_request = MockRequest {
+        method: req.method().to_string(),
+        uri: req.uri().to_string(),
+        headers: req
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                let value = String::from_utf8_lossy(value.as_bytes()).to_string();
+                (name.to_string(), value)
+            })
+            .collect(),
+        body,
+    };
+
+    RESPONDER.with(|cell| {
+        cell.borrow_mut()
+            .as_mut()
+            .map(|responder| responder(&mock_request))
+    })
+}
@@ -0,0 +1,407 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use results::TlsParameters;
+
+/*
+    native_tls does not report what a handshake negotiated. The server
+    answers a client hello in plaintext with a server hello or an alert,
+    so the first bytes read from the server are kept until one of them
+    can be parsed.
+
+    The protocol version is read from the supported_versions extension
+    of the server hello and falls back to its legacy_version, the cipher
+    suite is read from the server hello itself.
+*/
+
+// a server hello fits well within a single record
+const MAX_OBSERVED_BYTES: usize = 16 * 1024;
+
+const RECORD_HEADER_LEN: usize = 5;
+const CONTENT_TYPE_ALERT: u8 = 0x15;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_TYPE_SERVER_HELLO: u8 = 0x02;
+const EXTENSION_SUPPORTED_VERSIONS: u16 = 0x002b;
+
+const TLS13_VERSION: u16 = 0x0304;
+const ALERT_PROTOCOL_VERSION: u8 = 70;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ServerReply {
+    ServerHello { version: u16, cipher_suite: u16 },
+    Alert { description: u8 },
+}
+
+#[derive(Default)]
+struct Observation {
+    bytes: Vec<u8>,
+    server_reply: Option<ServerReply>,
+}
+
+// shared with the stream, the stream is dropped when a handshake fails
+#[derive(Clone, Default)]
+pub struct HandshakeObserver(Arc<Mutex<Observation>>);
+
+impl HandshakeObserver {
+    pub fn server_reply(&self) -> Option<ServerReply> {
+        match self.0.lock() {
+            Ok(observation) => observation.server_reply,
+            _ => None,
+        }
+    }
+
+    pub fn tls_parameters(&self) -> Option<TlsParameters> {
+        match self.server_reply()? {
+            ServerReply::ServerHello {
+                version,
+                cipher_suite,
+            } => Some(TlsParameters {
+                protocol_version: get_protocol_version_name(version),
+                cipher_suite: get_cipher_suite_name(cipher_suite),
+            }),
+            _ => None,
+        }
+    }
+
+    fn observe(&self, bytes: &[u8]) {
+        let mut observation = match self.0.lock() {
+            Ok(observation) => observation,
+            _ => return,
+        };
+        if observation.server_reply.is_some() || observation.bytes.len() >= MAX_OBSERVED_BYTES {
+            return;
+        }
+
+        observation.bytes.extend_from_slice(bytes);
+        observation.server_reply = parse_server_reply(&observation.bytes);
+        if observation.server_reply.is_some() {
+            observation.bytes = Vec::new();
+        }
+    }
+}
+
+pub struct ObservedStream<S> {
+    inner: S,
+    observer: HandshakeObserver,
+}
+
+impl<S> ObservedStream<S> {
+    pub fn new(inner: S, observer: HandshakeObserver) -> ObservedStream<S> {
+        ObservedStream { inner, observer }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ObservedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled_len = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            self.observer.observe(&buf.filled()[filled_len..]);
+        }
+
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ObservedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// none until a whole server hello or alert was read
+pub fn parse_server_reply(bytes: &[u8]) -> Option<ServerReply> {
+    // a handshake message can span several records
+    let mut handshake = Vec::<u8>::new();
+    let mut rest = bytes;
+    while rest.len() >= RECORD_HEADER_LEN {
+        let content_type = rest[0];
+        let record_len = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+        let fragment = rest.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + record_len)?;
+        rest = &rest[RECORD_HEADER_LEN + record_len..];
+
+        match content_type {
+            CONTENT_TYPE_ALERT if fragment.len() >= 2 => {
+                return Some(ServerReply::Alert {
+                    description: fragment[1],
+                })
+            }
+            CONTENT_TYPE_HANDSHAKE => {
+                handshake.extend_from_slice(fragment);
+                if let Some(server_reply) = parse_server_hello(&handshake) {
+                    return Some(server_reply);
+                }
+            }
+            _ => return None,
+        }
+    }
+
+    None
+}
+
+fn parse_server_hello(handshake: &[u8]) -> Option<ServerReply> {
+    if *handshake.first()? != HANDSHAKE_TYPE_SERVER_HELLO {
+        return None;
+    }
+    let message_len = u32::from_be_bytes([0, *handshake.get(1)?, handshake[2], handshake[3]]);
+    let body = handshake.get(4..4 + message_len as usize)?;
+
+    let legacy_version = u16::from_be_bytes([*body.first()?, *body.get(1)?]);
+    // legacy_version and random
+    let mut index = 2 + 32;
+    let session_id_len = *body.get(index)? as usize;
+    index += 1 + session_id_len;
+    let cipher_suite = u16::from_be_bytes([*body.get(index)?, *body.get(index + 1)?]);
+    // cipher_suite and compression_method
+    index += 3;
+
+    let mut version = legacy_version;
+    if let Some(extensions_len) = body.get(index..index + 2) {
+        let extensions_len = u16::from_be_bytes([extensions_len[0], extensions_len[1]]) as usize;
+        let mut extensions = body.get(index + 2..index + 2 + extensions_len)?;
+        while extensions.len() >= 4 {
+            let extension_type = u16::from_be_bytes([extensions[0], extensions[1]]);
+            let extension_len = u16::from_be_bytes([extensions[2], extensions[3]]) as usize;
+            let data = extensions.get(4..4 + extension_len)?;
+            if extension_type == EXTENSION_SUPPORTED_VERSIONS && data.len() == 2 {
+                version = u16::from_be_bytes([data[0], data[1]]);
+            }
+            extensions = &extensions[4 + extension_len..];
+        }
+    }
+
+    Some(ServerReply::ServerHello {
+        version,
+        cipher_suite,
+    })
+}
+
+// why a server refused tls 1.3, none when the handshake failed for another reason
+pub fn get_tls13_rejection(server_reply: Option<ServerReply>) -> Option<String> {
+    match server_reply? {
+        ServerReply::ServerHello { version, .. } if version < TLS13_VERSION => {
+            Some("the server negotiated ".to_string() + &get_protocol_version_name(version))
+        }
+        ServerReply::Alert { description } if description == ALERT_PROTOCOL_VERSION => {
+            Some("the server does not support it".to_string())
+        }
+        _ => None,
+    }
+}
+
+fn get_protocol_version_name(version: u16) -> String {
+    match version {
+        0x0300 => "SSLv3".to_string(),
+        0x0301 => "TLSv1".to_string(),
+        0x0302 => "TLSv1.1".to_string(),
+        0x0303 => "TLSv1.2".to_string(),
+        0x0304 => "TLSv1.3".to_string(),
+        _ => format!("0x{:04x}", version),
+    }
+}
+
+// iana names of the suites offered by common clients, others are shown by number
+fn get_cipher_suite_name(cipher_suite: u16) -> String {
+    let name = match cipher_suite {
+        0x1301 => "TLS_AES_128_GCM_SHA256",
+        0x1302 => "TLS_AES_256_GCM_SHA384",
+        0x1303 => "TLS_CHACHA20_POLY1305_SHA256",
+        0xc02b => "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256",
+        0xc02c => "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384",
+        0xc02f => "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+        0xc030 => "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384",
+        0xcca8 => "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256",
+        0xcca9 => "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256",
+        0xc013 => "TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA",
+        0xc014 => "TLS_ECDHE_RSA_WITH_AES_256_CBC_SHA",
+        0x009c => "TLS_RSA_WITH_AES_128_GCM_SHA256",
+        0x009d => "TLS_RSA_WITH_AES_256_GCM_SHA384",
+        0x002f => "TLS_RSA_WITH_AES_128_CBC_SHA",
+        0x0035 => "TLS_RSA_WITH_AES_256_CBC_SHA",
+        _ => return format!("0x{:04x}", cipher_suite),
+    };
+
+    name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a server hello record with a session id and optional extensions
+    fn create_server_hello(legacy_version: u16, cipher_suite: u16, extensions: &[u8]) -> Vec<u8> {
+        let mut body = legacy_version.to_be_bytes().to_vec();
+        body.extend_from_slice(&[0xab; 32]);
+        body.push(32);
+        body.extend_from_slice(&[0xcd; 32]);
+        body.extend_from_slice(&cipher_suite.to_be_bytes());
+        body.push(0);
+        if !extensions.is_empty() {
+            body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+            body.extend_from_slice(extensions);
+        }
+
+        let mut handshake = vec![HANDSHAKE_TYPE_SERVER_HELLO];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x03];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn tls13_is_read_from_supported_versions() {
+        // key_share then supported_versions
+        let extensions = [
+            0x00, 0x33, 0x00, 0x02, 0x00, 0x1d, 0x00, 0x2b, 0x00, 0x02, 0x03, 0x04,
+        ];
+        let record = create_server_hello(0x0303, 0x1302, &extensions);
+
+        assert_eq!(
+            parse_server_reply(&record),
+            Some(ServerReply::ServerHello {
+                version: TLS13_VERSION,
+                cipher_suite: 0x1302
+            })
+        );
+    }
+
+    #[test]
+    fn tls12_is_read_from_legacy_version() {
+        let extensions = [0xff, 0x01, 0x00, 0x01, 0x00];
+        let record = create_server_hello(0x0303, 0xc02f, &extensions);
+        assert_eq!(
+            parse_server_reply(&record),
+            Some(ServerReply::ServerHello {
+                version: 0x0303,
+                cipher_suite: 0xc02f
+            })
+        );
+
+        let record = create_server_hello(0x0303, 0x009c, &[]);
+        assert_eq!(
+            parse_server_reply(&record),
+            Some(ServerReply::ServerHello {
+                version: 0x0303,
+                cipher_suite: 0x009c
+            })
+        );
+    }
+
+    #[test]
+    fn partial_records_are_not_parsed() {
+        let record = create_server_hello(0x0303, 0x1301, &[]);
+        for len in [0, 3, RECORD_HEADER_LEN, record.len() - 1] {
+            assert_eq!(parse_server_reply(&record[..len]), None);
+        }
+    }
+
+    #[test]
+    fn server_hellos_are_read_across_records() {
+        let record = create_server_hello(0x0303, 0x1301, &[]);
+        let handshake = &record[RECORD_HEADER_LEN..];
+        let (first, second) = handshake.split_at(10);
+
+        let mut bytes = Vec::<u8>::new();
+        for fragment in [first, second] {
+            bytes.extend_from_slice(&[CONTENT_TYPE_HANDSHAKE, 0x03, 0x03]);
+            bytes.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(fragment);
+        }
+
+        assert_eq!(
+            parse_server_reply(&bytes),
+            Some(ServerReply::ServerHello {
+                version: 0x0303,
+                cipher_suite: 0x1301
+            })
+        );
+    }
+
+    #[test]
+    fn alerts_are_read() {
+        let alert = [
+            CONTENT_TYPE_ALERT,
+            0x03,
+            0x03,
+            0x00,
+            0x02,
+            0x02,
+            ALERT_PROTOCOL_VERSION,
+        ];
+        assert_eq!(
+            parse_server_reply(&alert),
+            Some(ServerReply::Alert {
+                description: ALERT_PROTOCOL_VERSION
+            })
+        );
+    }
+
+    #[test]
+    fn only_version_rejections_skip_the_retry() {
+        let tls12 = ServerReply::ServerHello {
+            version: 0x0303,
+            cipher_suite: 0xc02f,
+        };
+        let tls13 = ServerReply::ServerHello {
+            version: TLS13_VERSION,
+            cipher_suite: 0x1301,
+        };
+        // handshake_failure
+        let other_alert = ServerReply::Alert { description: 40 };
+        let version_alert = ServerReply::Alert {
+            description: ALERT_PROTOCOL_VERSION,
+        };
+
+        assert_eq!(
+            get_tls13_rejection(Some(tls12)),
+            Some("the server negotiated TLSv1.2".to_string())
+        );
+        assert_eq!(
+            get_tls13_rejection(Some(version_alert)),
+            Some("the server does not support it".to_string())
+        );
+        // certificate errors and resets are retried
+        assert_eq!(get_tls13_rejection(Some(tls13)), None);
+        assert_eq!(get_tls13_rejection(Some(other_alert)), None);
+        assert_eq!(get_tls13_rejection(None), None);
+    }
+
+    #[test]
+    fn observed_bytes_are_parsed_in_pieces() {
+        let observer = HandshakeObserver::default();
+        let record = create_server_hello(0x0303, 0x1303, &[0x00, 0x2b, 0x00, 0x02, 0x03, 0x04]);
+        for chunk in record.chunks(7) {
+            observer.observe(chunk);
+        }
+
+        assert_eq!(
+            observer.tls_parameters(),
+            Some(TlsParameters {
+                protocol_version: "TLSv1.3".to_string(),
+                cipher_suite: "TLS_CHACHA20_POLY1305_SHA256".to_string(),
+            })
+        );
+    }
+}
@@ -0,0 +1,344 @@
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+/*
+    A config fingerprint is the effective config flattened into keys
+    like dyndns2[0].hostname, in a stable order.
+
+    Every value is replaced by an HMAC-SHA256 keyed with a random key that
+    is kept in its own file next to the results, never in the results file.
+    A changed field is reported by its key. Only booleans and numbers of
+    fields that are not secret are kept as values so their changes can be
+    shown, strings like usernames and zone ids never reach the results file.
+*/
+
+pub const FINGERPRINT_ALGORITHM: &str = "hmac-sha256";
+
+const FINGERPRINT_KEY_LEN: usize = 32;
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ConfigFingerprint {
+    // fingerprints of an older algorithm are not compared
+    #[serde(default)]
+    pub algorithm: String,
+    pub hash: String,
+    pub fields: BTreeMap<String, String>,
+    #[serde(default)]
+    pub values: BTreeMap<String, String>,
+}
+
+pub fn create_config_fingerprint(
+    config: &Value,
+    secret_keys: &[&str],
+    key: &[u8],
+) -> Result<ConfigFingerprint, String> {
+    let mut fields = BTreeMap::<String, String>::new();
+    let mut values = BTreeMap::<String, String>::new();
+    flatten_value(
+        &mut fields,
+        &mut values,
+        secret_keys,
+        key,
+        String::new(),
+        config,
+    )?;
+
+    // maps are ordered so equal configs hash equally
+    let fields_str = match serde_json::to_string(&fields) {
+        Ok(fields_str) => fields_str,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    Ok(ConfigFingerprint {
+        algorithm: FINGERPRINT_ALGORITHM.to_string(),
+        hash: hmac_sha256(key, &fields_str)?,
+        fields,
+        values,
+    })
+}
+
+// keys added, removed, or modified since the previous fingerprint, ie: netns modified
+pub fn diff_config_fingerprints(
+    prev_fingerprint: &ConfigFingerprint,
+    fingerprint: &ConfigFingerprint,
+) -> Vec<String> {
+    if prev_fingerprint.algorithm != fingerprint.algorithm
+        || prev_fingerprint.hash == fingerprint.hash
+    {
+        return Vec::new();
+    }
+
+    let mut changes = Vec::<String>::new();
+    for (key, field) in &fingerprint.fields {
+        let value = fingerprint.values.get(key);
+        let change = match (prev_fingerprint.fields.get(key), value) {
+            (None, Some(value)) => key.clone() + " added: " + value,
+            (None, None) => key.clone() + " added",
+            (Some(prev_field), _) if prev_field == field => continue,
+            (Some(_), Some(value)) => match prev_fingerprint.values.get(key) {
+                Some(prev_value) => key.clone() + " modified: " + prev_value + " -> " + value,
+                _ => key.clone() + " modified",
+            },
+            (Some(_), None) => key.clone() + " modified",
+        };
+        changes.push(change);
+    }
+
+    for key in prev_fingerprint.fields.keys() {
+        if !fingerprint.fields.contains_key(key) {
+            changes.push(key.clone() + " removed");
+        }
+    }
+    changes.sort();
+
+    changes
+}
+
+// the key file sits next to the results file, ie: results.json.fingerprint_key
+pub fn get_fingerprint_key_filepath(results_filepath: &Path) -> PathBuf {
+    let mut key_filepath = results_filepath.as_os_str().to_owned();
+    key_filepath.push(".fingerprint_key");
+    PathBuf::from(key_filepath)
+}
+
+// reads the fingerprint key or creates it, readable only by its owner
+pub async fn load_fingerprint_key(key_filepath: &Path) -> Result<Vec<u8>, String> {
+    match fs::read_to_string(key_filepath).await {
+        Ok(key_str) => return decode_hex(key_str.trim()),
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.to_string()),
+        _ => {}
+    }
+
+    let key: [u8; FINGERPRINT_KEY_LEN] = rand::random();
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut key_file = match options.open(key_filepath).await {
+        Ok(key_file) => key_file,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    if let Err(e) = key_file.write_all(encode_hex(&key).as_bytes()).await {
+        return Err(e.to_string());
+    }
+
+    Ok(key.to_vec())
+}
+
+// unset fields are left out so setting one reads as added
+fn flatten_value(
+    fields: &mut BTreeMap<String, String>,
+    values: &mut BTreeMap<String, String>,
+    secret_keys: &[&str],
+    hmac_key: &[u8],
+    key: String,
+    value: &Value,
+) -> Result<(), String> {
+    match value {
+        Value::Null => {}
+        Value::Object(map) => {
+            for (name, value) in map {
+                let field_key = match key.is_empty() {
+                    true => name.clone(),
+                    false => key.clone() + "." + name,
+                };
+
+                // secrets like params are hashed whole
+                if secret_keys.contains(&name.as_str()) {
+                    if !value.is_null() {
+                        fields.insert(field_key, hmac_sha256(hmac_key, &value.to_string())?);
+                    }
+                    continue;
+                }
+
+                flatten_value(fields, values, secret_keys, hmac_key, field_key, value)?;
+            }
+        }
+        Value::Array(array) => {
+            for (index, value) in array.iter().enumerate() {
+                let field_key = key.clone() + "[" + &index.to_string() + "]";
+                flatten_value(fields, values, secret_keys, hmac_key, field_key, value)?;
+            }
+        }
+        Value::Bool(_) | Value::Number(_) => {
+            fields.insert(key.clone(), hmac_sha256(hmac_key, &value.to_string())?);
+            values.insert(key, value.to_string());
+        }
+        Value::String(_) => {
+            fields.insert(key, hmac_sha256(hmac_key, &value.to_string())?);
+        }
+    }
+
+    Ok(())
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Result<String, String> {
+    let pkey = match PKey::hmac(key) {
+        Ok(pkey) => pkey,
+        Err(e) => return Err(e.to_string()),
+    };
+    let mut signer = match Signer::new(MessageDigest::sha256(), &pkey) {
+        Ok(signer) => signer,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    match signer.sign_oneshot_to_vec(data.as_bytes()) {
+        Ok(hmac) => Ok(encode_hex(&hmac)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.is_ascii() || hex.len() != FINGERPRINT_KEY_LEN * 2 {
+        return Err("fingerprint key is not ".to_string()
+            + &FINGERPRINT_KEY_LEN.to_string()
+            + " hex encoded bytes");
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(
+            |index| match u8::from_str_radix(&hex[index..index + 2], 16) {
+                Ok(byte) => Ok(byte),
+                Err(e) => Err("fingerprint key is not hex: ".to_string() + &e.to_string()),
+            },
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const SECRET_KEYS: [&str; 2] = ["password", "params"];
+    const KEY: [u8; FINGERPRINT_KEY_LEN] = [7; FINGERPRINT_KEY_LEN];
+
+    // RFC 4231 test case 2
+    #[test]
+    fn hmac_sha256_matches_rfc_4231() {
+        assert_eq!(
+            hmac_sha256(b"Jefe", "what do ya want for nothing?").unwrap(),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn strings_are_never_kept() {
+        let config = json!({
+            "netns": "wan",
+            "dyndns2": [{"username": "alice", "password": "hunter2", "priority": 1}],
+            "cloudflare": [{"zone_id": "023e105f4ecef8ad9ca31a8372d0c353"}],
+            "push_on_first_run": true,
+        });
+        let fingerprint = create_config_fingerprint(&config, &SECRET_KEYS, &KEY).unwrap();
+        let fingerprint_str = serde_json::to_string(&fingerprint).unwrap();
+
+        for value in [
+            "wan",
+            "alice",
+            "hunter2",
+            "023e105f4ecef8ad9ca31a8372d0c353",
+        ] {
+            assert!(!fingerprint_str.contains(value), "{}", value);
+        }
+        assert_eq!(fingerprint.values["dyndns2[0].priority"], "1");
+        assert_eq!(fingerprint.values["push_on_first_run"], "true");
+        assert!(fingerprint.fields.contains_key("dyndns2[0].password"));
+    }
+
+    #[test]
+    fn fingerprints_depend_on_the_key() {
+        let config = json!({"netns": "wan"});
+        let fingerprint = create_config_fingerprint(&config, &SECRET_KEYS, &KEY).unwrap();
+        let other_fingerprint =
+            create_config_fingerprint(&config, &SECRET_KEYS, &[8; FINGERPRINT_KEY_LEN]).unwrap();
+
+        assert_eq!(
+            fingerprint,
+            create_config_fingerprint(&config, &SECRET_KEYS, &KEY).unwrap()
+        );
+        assert_ne!(fingerprint.hash, other_fingerprint.hash);
+        assert_eq!(fingerprint.hash.len(), 64);
+    }
+
+    #[test]
+    fn diff_reports_keys_and_plain_values() {
+        let prev_config = json!({
+            "netns": "wan",
+            "dyndns2": [{"password": "hunter2", "priority": 1}],
+            "verify_updates": false,
+        });
+        let config = json!({
+            "netns": "lan",
+            "dyndns2": [{"password": "hunter3", "priority": 2}],
+            "statsd_addr": "127.0.0.1:8125",
+        });
+        let prev_fingerprint = create_config_fingerprint(&prev_config, &SECRET_KEYS, &KEY).unwrap();
+        let fingerprint = create_config_fingerprint(&config, &SECRET_KEYS, &KEY).unwrap();
+
+        assert_eq!(
+            diff_config_fingerprints(&prev_fingerprint, &fingerprint),
+            vec![
+                "dyndns2[0].password modified",
+                "dyndns2[0].priority modified: 1 -> 2",
+                "netns modified",
+                "statsd_addr added",
+                "verify_updates removed",
+            ]
+        );
+        assert!(diff_config_fingerprints(&fingerprint, &fingerprint).is_empty());
+    }
+
+    #[test]
+    fn older_fingerprints_are_not_compared() {
+        let prev_fingerprint: ConfigFingerprint = serde_json::from_value(json!({
+            "salt": "00",
+            "hash": "0000000000000000",
+            "fields": {"netns": "\"wan\""},
+            "secrets": {},
+        }))
+        .unwrap();
+        let fingerprint =
+            create_config_fingerprint(&json!({"netns": "lan"}), &SECRET_KEYS, &KEY).unwrap();
+
+        assert!(diff_config_fingerprints(&prev_fingerprint, &fingerprint).is_empty());
+    }
+
+    #[tokio::test]
+    async fn fingerprint_key_is_created_once() {
+        let dir = std::env::temp_dir()
+            .join("update_ip_fingerprint_key_".to_string() + &std::process::id().to_string());
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let key_filepath = get_fingerprint_key_filepath(&dir.join("results.json"));
+
+        let key = load_fingerprint_key(&key_filepath).await.unwrap();
+        assert_eq!(key.len(), FINGERPRINT_KEY_LEN);
+        assert_eq!(load_fingerprint_key(&key_filepath).await.unwrap(), key);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&key_filepath)
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}
@@ -1,32 +1,488 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
 use tokio::fs;
+use tokio::fs::OpenOptions;
+
+mod config_fingerprint;
+mod observations;
+mod response_shape;
+
+pub use config_fingerprint::{
+    create_config_fingerprint, diff_config_fingerprints, get_fingerprint_key_filepath,
+    load_fingerprint_key, ConfigFingerprint,
+};
+pub use observations::{classify_observations, Confidence, Observation};
+pub use response_shape::{compare_response_shapes, get_response_shape, ResponseShape};
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct ResponseJson {
     pub status_code: u16,
     pub body: String,
     pub timestamp: u128,
+    // milliseconds since the unix epoch from the Date header of the response
+    #[serde(default)]
+    pub server_timestamp: Option<u128>,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    // a 403 or 503 html challenge page from an anti-bot proxy
+    #[serde(default)]
+    pub bot_challenge: bool,
+    // none for plain http
+    #[serde(default)]
+    pub tls: Option<TlsParameters>,
+}
+
+// negotiated in the tls handshake, ie: TLSv1.3 and TLS_AES_256_GCM_SHA384
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct TlsParameters {
+    pub protocol_version: String,
+    pub cipher_suite: String,
+}
+
+// ip addresses are normalized on construction so they can be compared structurally
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub struct Address(IpAddr);
+
+// addresses are compared by their first v4 or v6 bits, ie: a delegated ipv6 prefix
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct ComparePrefixLen {
+    pub v4: Option<u8>,
+    pub v6: Option<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddressFamily {
+    V4,
+    V6,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct IpServiceResult {
     pub service: String,
-    pub ip_address: Option<String>,
+    pub ip_address: Option<Address>,
+    #[serde(default)]
+    pub nat_classification: Option<NatClassification>,
+    // milliseconds since the unix epoch of the detection, kept when a result is carried forward
+    #[serde(default)]
+    pub detected_at: Option<u128>,
+    // every address reported this run when more than one source was asked
+    #[serde(default)]
+    pub observations: Vec<Observation>,
+    #[serde(default)]
+    pub confidence: Option<Confidence>,
+    // services that failed before this service detected the address
+    #[serde(default)]
+    pub errors: Vec<String>,
+    // the tls handshake of the request that detected the address
+    #[serde(default)]
+    pub tls: Option<TlsParameters>,
+    // responses of services that did not have their expected content type
+    #[serde(default)]
+    pub warnings: Vec<String>,
+}
+
+/*
+    NoNat: the detected address is assigned to a local interface
+    Cgnat: the detected address is in 100.64.0.0/10
+    Nat: any other detected address
+*/
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum NatClassification {
+    NoNat,
+    Nat,
+    Cgnat,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub enum DomainOutcome {
+    Updated,
+    Unchanged,
+    Failed,
+    ConditionNotMet,
+    ConfigInvalid,
+    StaleAddress,
+    Deferred,
+    BehindCgnat,
+    Blocklisted,
+    AddressConflicted,
 }
 
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum UpdateReason {
+    FirstUpdate,
+    AddressChanged,
+    RetryAfterFailure,
+}
+
+// reason is kept from the most recent update attempt
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct DomainResult {
     pub hostname: String,
-    pub ip_address: Option<String>,
+    pub ip_address: Option<Address>,
+    pub service: Option<String>,
+    pub outcome: Option<DomainOutcome>,
+    pub reason: Option<UpdateReason>,
+    // milliseconds since the unix epoch of the most recent update sent
+    #[serde(default)]
+    pub sent_at: Option<u128>,
     pub errors: Vec<String>,
+    #[serde(default)]
+    pub warnings: Vec<String>,
+    // the shape of the most recent successful response
+    #[serde(default)]
+    pub response_shape: Option<ResponseShape>,
+}
+
+// a domain update decided by domain services and sent by a dns service
+#[derive(Clone, Debug)]
+pub struct PlannedUpdate<'a, T> {
+    pub key: String,
+    pub domain: &'a T,
+    pub reason: UpdateReason,
+    pub ip_address: Address,
+    pub check_response_shape: bool,
 }
 
+// decisions are recorded by the code that makes them, in the order they are made
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum Decision {
+    ConfigChanged {
+        changes: Vec<String>,
+    },
+    IpServiceChosen {
+        service: String,
+        excluded_service: Option<String>,
+    },
+    AddressDetected {
+        service: String,
+        ip_address: Address,
+    },
+    IpServiceFailed {
+        service: String,
+        error: String,
+    },
+    AddressLost {
+        ip_address: Address,
+    },
+    AddressCarriedForward {
+        ip_address: Address,
+        is_fresh: bool,
+    },
+    FailoverNotNeeded {
+        key: String,
+    },
+    DomainSkipped {
+        key: String,
+        outcome: Option<DomainOutcome>,
+        errors: Vec<String>,
+    },
+    DomainPlanned {
+        key: String,
+        reason: UpdateReason,
+    },
+    EndpointUnreachable {
+        endpoint: String,
+        error: String,
+    },
+    DomainSent {
+        key: String,
+        service: Option<String>,
+        outcome: Option<DomainOutcome>,
+        errors: Vec<String>,
+    },
+}
+
+pub type DecisionLog = Vec<Decision>;
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct UpdateIpResults {
+    // results written before timestamps were recorded default to 0
+    #[serde(default)]
+    pub timestamp: u128,
     pub ip_service_result: IpServiceResult,
+    // only detected when ip_services_v6 is configured
+    #[serde(default)]
+    pub ip_service_result_v6: Option<IpServiceResult>,
     pub domain_service_results: HashMap<String, DomainResult>,
+    #[serde(default)]
+    pub decisions: DecisionLog,
+    #[serde(default)]
+    pub run_id: Option<String>,
+    #[serde(default)]
+    pub config_fingerprint: Option<ConfigFingerprint>,
+}
+
+// bump when exported state can no longer be read by older versions
+pub const STATE_VERSION: u32 = 1;
+
+// unchanged results are rewritten once an hour
+const RESULTS_REFRESH_MS: u128 = 60 * 60 * 1000;
+
+// results moved between hosts with state export and state import
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct StateExport {
+    pub state_version: u32,
+    pub results: UpdateIpResults,
+}
+
+pub fn export_state(results: UpdateIpResults) -> Result<String, String> {
+    let state_export = StateExport {
+        state_version: STATE_VERSION,
+        results,
+    };
+
+    match serde_json::to_string_pretty(&state_export) {
+        Ok(json_str) => Ok(json_str),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+pub fn import_state(json_str: &str) -> Result<UpdateIpResults, String> {
+    let state_export: StateExport = match serde_json::from_str(json_str) {
+        Ok(s) => s,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    if state_export.state_version > STATE_VERSION {
+        return Err("state_version ".to_string()
+            + &state_export.state_version.to_string()
+            + " is newer than the supported state_version "
+            + &STATE_VERSION.to_string());
+    }
+
+    Ok(state_export.results)
+}
+
+/*
+    Addresses arrive in many textual forms, ie: 2001:DB8::1, [2001:db8::1],
+    2001:db8:0:0:0:0:0:1%eth0, or ::ffff:192.0.2.1
+
+    Zone ids are local to a host and are removed. IPv4-mapped IPv6 addresses
+    become IPv4 addresses. Addresses are displayed in canonical form
+    (lowercase and compressed IPv6) when sent to dns services or written to results.
+*/
+impl Address {
+    pub fn new(ip_address: IpAddr) -> Address {
+        Address(ip_address.to_canonical())
+    }
+
+    pub fn ip_addr(&self) -> IpAddr {
+        self.0
+    }
+
+    pub fn family(&self) -> AddressFamily {
+        match self.0 {
+            IpAddr::V4(_) => AddressFamily::V4,
+            IpAddr::V6(_) => AddressFamily::V6,
+        }
+    }
+
+    // addresses of different families never match
+    pub fn matches(&self, other: &Address, compare_prefix_len: &Option<ComparePrefixLen>) -> bool {
+        let compare_prefix_len = match compare_prefix_len {
+            Some(c) => c,
+            _ => return self == other,
+        };
+
+        match (
+            self.0,
+            other.0,
+            compare_prefix_len.v4,
+            compare_prefix_len.v6,
+        ) {
+            (IpAddr::V4(ip), IpAddr::V4(other_ip), Some(prefix_len), _) => {
+                let mask = u32::MAX
+                    .checked_shl(32_u32.saturating_sub(prefix_len as u32))
+                    .unwrap_or(0);
+                u32::from(ip) & mask == u32::from(other_ip) & mask
+            }
+            (IpAddr::V6(ip), IpAddr::V6(other_ip), _, Some(prefix_len)) => {
+                let mask = u128::MAX
+                    .checked_shl(128_u32.saturating_sub(prefix_len as u32))
+                    .unwrap_or(0);
+                u128::from(ip) & mask == u128::from(other_ip) & mask
+            }
+            _ => self == other,
+        }
+    }
+}
+
+// an address or a cidr range, ie: 192.0.2.1 or 2001:db8::/32
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AddressRange {
+    address: Address,
+    prefix_len: u8,
+}
+
+impl AddressRange {
+    pub fn contains(&self, address: &Address) -> bool {
+        let compare_prefix_len = ComparePrefixLen {
+            v4: Some(self.prefix_len),
+            v6: Some(self.prefix_len),
+        };
+        self.address.matches(address, &Some(compare_prefix_len))
+    }
+}
+
+impl FromStr for AddressRange {
+    type Err = String;
+
+    fn from_str(range: &str) -> Result<AddressRange, String> {
+        let (address, prefix_len) = match range.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            _ => (range, None),
+        };
+
+        let address: Address = address.parse()?;
+        let max_prefix_len = match address.ip_addr() {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => match prefix_len.trim().parse::<u8>() {
+                Ok(len) if len <= max_prefix_len => len,
+                _ => return Err("invalid prefix length ".to_string() + prefix_len),
+            },
+            _ => max_prefix_len,
+        };
+
+        Ok(AddressRange {
+            address,
+            prefix_len,
+        })
+    }
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(address: &str) -> Result<Address, String> {
+        let address = address.trim();
+        let address = match address.strip_prefix('[') {
+            Some(bracketed) => bracketed.strip_suffix(']').unwrap_or(bracketed),
+            _ => address,
+        };
+        let address = match address.split_once('%') {
+            Some((ip, _zone_id)) => ip,
+            _ => address,
+        };
+
+        match address.parse::<IpAddr>() {
+            Ok(ip) => Ok(Address::new(ip)),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+impl TryFrom<String> for Address {
+    type Error = String;
+
+    fn try_from(address: String) -> Result<Address, String> {
+        address.parse()
+    }
+}
+
+impl From<Address> for String {
+    fn from(address: Address) -> String {
+        address.to_string()
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Decision::ConfigChanged { changes } => {
+                write!(f, "config changed: {}", changes.join(", "))
+            }
+            Decision::IpServiceChosen {
+                service,
+                excluded_service: Some(excluded_service),
+            } => write!(
+                f,
+                "ip service {} chosen, excluding previous service {}",
+                service, excluded_service
+            ),
+            Decision::IpServiceChosen { service, .. } => write!(f, "ip service {} chosen", service),
+            Decision::AddressDetected {
+                service,
+                ip_address,
+            } => write!(f, "ip service {} detected {}", service, ip_address),
+            Decision::IpServiceFailed { service, error } => {
+                write!(f, "ip service {} failed: {}", service, error)
+            }
+            Decision::AddressLost { ip_address } => {
+                write!(f, "previous address {} was lost", ip_address)
+            }
+            Decision::AddressCarriedForward {
+                ip_address,
+                is_fresh,
+            } => write!(
+                f,
+                "previous address {} carried forward, fresh: {}",
+                ip_address, is_fresh
+            ),
+            Decision::FailoverNotNeeded { key } => {
+                write!(f, "{} skipped, hostname already updated", key)
+            }
+            Decision::DomainSkipped {
+                key,
+                outcome,
+                errors,
+            } => write!(
+                f,
+                "{} skipped: {}{}",
+                key,
+                outcome_to_string(outcome),
+                errors_to_string(errors)
+            ),
+            Decision::DomainPlanned { key, reason } => {
+                write!(f, "{} planned: {:?}", key, reason)
+            }
+            Decision::EndpointUnreachable { endpoint, error } => {
+                write!(f, "endpoint {} unreachable: {}", endpoint, error)
+            }
+            Decision::DomainSent {
+                key,
+                service,
+                outcome,
+                errors,
+            } => write!(
+                f,
+                "{} sent to {}: {}{}",
+                key,
+                service.as_deref().unwrap_or("unknown service"),
+                outcome_to_string(outcome),
+                errors_to_string(errors)
+            ),
+        }
+    }
+}
+
+fn outcome_to_string(outcome: &Option<DomainOutcome>) -> String {
+    match outcome {
+        Some(outcome) => format!("{:?}", outcome),
+        _ => "no outcome".to_string(),
+    }
+}
+
+fn errors_to_string(errors: &[String]) -> String {
+    match errors.is_empty() {
+        true => String::new(),
+        false => ", ".to_string() + &errors.join(", "),
+    }
 }
 
 impl IpServiceResult {
@@ -34,6 +490,27 @@ impl IpServiceResult {
         IpServiceResult {
             service: service.to_string(),
             ip_address: None,
+            nat_classification: None,
+            detected_at: None,
+            observations: Vec::<Observation>::new(),
+            confidence: None,
+            errors: Vec::<String>::new(),
+            tls: None,
+            warnings: Vec::<String>::new(),
+        }
+    }
+
+    // a failed detection keeps the previous address so the next run compares against it
+    pub fn keep_prev_address(&mut self, prev_ip_service_result: Option<&IpServiceResult>) {
+        if self.ip_address.is_some() {
+            return;
+        }
+
+        if let Some(prev_ip_result) = prev_ip_service_result {
+            self.ip_address = prev_ip_result.ip_address;
+            self.nat_classification = prev_ip_result.nat_classification;
+            self.detected_at = prev_ip_result.detected_at;
+            self.tls = prev_ip_result.tls.clone();
         }
     }
 }
@@ -43,25 +520,98 @@ impl DomainResult {
         DomainResult {
             hostname: hostname.to_string(),
             ip_address: None,
+            service: None,
+            outcome: None,
+            reason: None,
+            sent_at: None,
             errors: Vec::<String>::new(),
+            warnings: Vec::<String>::new(),
+            response_shape: None,
         }
     }
 }
 
 impl UpdateIpResults {
-    pub fn try_from_results(
-        ip_service_result: Option<IpServiceResult>,
-        domain_service_results: Option<HashMap<String, DomainResult>>,
-    ) -> Result<UpdateIpResults, String> {
-        if let (Some(ip_result), Some(domain_results)) = (ip_service_result, domain_service_results)
-        {
-            return Ok(UpdateIpResults {
-                ip_service_result: ip_result,
-                domain_service_results: domain_results,
-            });
+    // the result of a family, None is the result of ip_services
+    pub fn get_ip_service_result(&self, family: Option<AddressFamily>) -> Option<&IpServiceResult> {
+        match family {
+            Some(AddressFamily::V6) => self.ip_service_result_v6.as_ref(),
+            _ => Some(&self.ip_service_result),
         }
+    }
+
+    pub fn from_results(
+        ip_service_result: IpServiceResult,
+        ip_service_result_v6: Option<IpServiceResult>,
+        domain_service_results: HashMap<String, DomainResult>,
+        decisions: DecisionLog,
+        run_id: &str,
+        config_fingerprint: Option<ConfigFingerprint>,
+    ) -> UpdateIpResults {
+        UpdateIpResults {
+            timestamp: get_timestamp_or_zero(),
+            ip_service_result,
+            ip_service_result_v6,
+            domain_service_results,
+            decisions,
+            run_id: Some(run_id.to_string()),
+            config_fingerprint,
+        }
+    }
+}
+
+pub fn get_timestamp() -> Result<u128, String> {
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(n) => Ok(n.as_millis()),
+        Err(e) => Err(e.to_string()),
+    }
+}
 
-        Err("couldn't get resutls".to_string())
+// a random uuid v4 that identifies a run in results and request headers
+pub fn create_run_id() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    hex[0..8].to_string()
+        + "-"
+        + &hex[8..12]
+        + "-"
+        + &hex[12..16]
+        + "-"
+        + &hex[16..20]
+        + "-"
+        + &hex[20..32]
+}
+
+// clocks set before 1970, ie: boards that boot before ntp, fall back to 0
+pub fn get_timestamp_or_zero() -> u128 {
+    get_timestamp().unwrap_or(0)
+}
+
+/*
+    Results older than max_age_ms are discarded and the run is treated as a first run.
+
+    Discarding results resets:
+    - the previous ip address, so a failed detection cannot fall back to it
+    - the previous ip service, so any ip service can be chosen
+    - every previous domain result, so every domain is updated again
+*/
+pub fn discard_stale_results(
+    results: Option<UpdateIpResults>,
+    max_age_ms: u64,
+) -> Option<UpdateIpResults> {
+    let results = results?;
+
+    let now = match get_timestamp() {
+        Ok(n) => n,
+        _ => return None,
+    };
+
+    match now.saturating_sub(results.timestamp) > max_age_ms as u128 {
+        true => None,
+        false => Some(results),
     }
 }
 
@@ -77,18 +627,647 @@ pub async fn load_results_from_disk(results_filepath: &PathBuf) -> Result<Update
     }
 }
 
+/*
+    An existing results file is opened for writing without truncating it.
+    A missing results file is created to check its directory and removed
+    again, so a check never leaves an empty results file behind.
+*/
+pub async fn check_results_writable(results_filepath: &PathBuf) -> Result<(), String> {
+    match OpenOptions::new().write(true).open(&results_filepath).await {
+        Ok(_) => return Ok(()),
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.to_string()),
+        _ => {}
+    }
+
+    match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&results_filepath)
+        .await
+    {
+        Ok(_) => match fs::remove_file(&results_filepath).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e.to_string()),
+        },
+        // written by another run in the meantime
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/*
+    Previous results are kept as results.json.1, results.json.2, and so on
+    up to backups.
+
+    Results are only written when their addresses, domain outcomes, errors
+    or config fingerprint changed, so a run that confirms the previous
+    results neither rewrites the file nor pushes out older backups.
+    Unchanged results are still written without a backup once they are
+    older than RESULTS_REFRESH_MS, so state_max_age_ms and detected_at keep
+    measuring detections.
+*/
 pub async fn write_results_to_disk(
     results: UpdateIpResults,
     results_filepath: &PathBuf,
+    backups: usize,
 ) -> Result<UpdateIpResults, String> {
+    let prev_results = load_results_from_disk(results_filepath).await.ok();
+    let changed = match &prev_results {
+        Some(prev_results) => results_changed(prev_results, &results),
+        _ => true,
+    };
+    if !changed && !results_need_refresh(&prev_results, &results) {
+        return Ok(results);
+    }
+
     let json_str = match serde_json::to_string_pretty(&results) {
         Ok(f) => f,
         Err(e) => return Err(e.to_string()),
     };
 
+    // unreadable results are not worth a backup
+    if backups > 0 && changed && prev_results.is_some() {
+        rotate_backups(results_filepath, backups).await?;
+    }
+
     if let Err(e) = fs::write(&results_filepath, json_str).await {
         return Err(e.to_string());
     };
 
     Ok(results)
 }
+
+// falls back to the most recent readable backup
+pub async fn load_results_with_backups(
+    results_filepath: &PathBuf,
+    backups: usize,
+) -> Result<UpdateIpResults, String> {
+    let load_error = match load_results_from_disk(results_filepath).await {
+        Ok(results) => return Ok(results),
+        Err(e) => e,
+    };
+
+    for index in 1..=backups {
+        let backup_filepath = get_backup_filepath(results_filepath, index);
+        if let Ok(results) = load_results_from_disk(&backup_filepath).await {
+            return Ok(results);
+        }
+    }
+
+    Err(load_error)
+}
+
+// removes results and their backups
+pub async fn remove_results_from_disk(
+    results_filepath: &PathBuf,
+    backups: usize,
+) -> Result<(), String> {
+    remove_if_exists(results_filepath).await?;
+    for index in 1..=backups {
+        remove_if_exists(&get_backup_filepath(results_filepath, index)).await?;
+    }
+
+    Ok(())
+}
+
+/*
+    Only addresses, domain outcomes, their errors and warnings and the
+    config fingerprint are compared. The timestamp, run_id, decisions and detected_at change
+    every run.
+*/
+fn results_changed(prev_results: &UpdateIpResults, results: &UpdateIpResults) -> bool {
+    let ip_result_changed = |prev: Option<&IpServiceResult>,
+                             ip_result: Option<&IpServiceResult>| {
+        match (prev, ip_result) {
+            (Some(prev), Some(ip_result)) => {
+                prev.ip_address != ip_result.ip_address
+                    || prev.errors != ip_result.errors
+                    || prev.warnings != ip_result.warnings
+            }
+            (None, None) => false,
+            _ => true,
+        }
+    };
+    if ip_result_changed(
+        Some(&prev_results.ip_service_result),
+        Some(&results.ip_service_result),
+    ) || ip_result_changed(
+        prev_results.ip_service_result_v6.as_ref(),
+        results.ip_service_result_v6.as_ref(),
+    ) || prev_results.config_fingerprint != results.config_fingerprint
+    {
+        return true;
+    }
+
+    if prev_results.domain_service_results.len() != results.domain_service_results.len() {
+        return true;
+    }
+    results
+        .domain_service_results
+        .iter()
+        .any(
+            |(key, domain_result)| match prev_results.domain_service_results.get(key) {
+                Some(prev_domain_result) => {
+                    prev_domain_result.ip_address != domain_result.ip_address
+                        || prev_domain_result.outcome != domain_result.outcome
+                        || prev_domain_result.sent_at != domain_result.sent_at
+                        || prev_domain_result.errors != domain_result.errors
+                        || prev_domain_result.warnings != domain_result.warnings
+                }
+                _ => true,
+            },
+        )
+}
+
+fn results_need_refresh(prev_results: &Option<UpdateIpResults>, results: &UpdateIpResults) -> bool {
+    match prev_results {
+        Some(prev_results) => {
+            results.timestamp.saturating_sub(prev_results.timestamp) >= RESULTS_REFRESH_MS
+        }
+        _ => true,
+    }
+}
+
+// renames results.json.1 to results.json.2 and so on, oldest first
+async fn rotate_backups(results_filepath: &PathBuf, backups: usize) -> Result<(), String> {
+    for index in (1..backups).rev() {
+        rename_if_exists(
+            &get_backup_filepath(results_filepath, index),
+            &get_backup_filepath(results_filepath, index + 1),
+        )
+        .await?;
+    }
+
+    rename_if_exists(results_filepath, &get_backup_filepath(results_filepath, 1)).await
+}
+
+async fn rename_if_exists(from: &PathBuf, to: &PathBuf) -> Result<(), String> {
+    match fs::rename(from, to).await {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+async fn remove_if_exists(filepath: &PathBuf) -> Result<(), String> {
+    match fs::remove_file(filepath).await {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+pub fn get_backup_filepath(results_filepath: &Path, index: usize) -> PathBuf {
+    let mut backup_filepath = results_filepath.as_os_str().to_owned();
+    backup_filepath.push(".");
+    backup_filepath.push(index.to_string());
+    PathBuf::from(backup_filepath)
+}
+
+// writes results as a single line of json to a listening unix socket
+#[cfg(unix)]
+pub async fn write_results_to_socket(
+    results: &UpdateIpResults,
+    socket_path: &PathBuf,
+) -> Result<(), String> {
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::UnixStream;
+
+    let json_str = match serde_json::to_string(results) {
+        Ok(f) => f + "\n",
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let mut stream = match UnixStream::connect(socket_path).await {
+        Ok(stream) => stream,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    if let Err(e) = stream.write_all(json_str.as_bytes()).await {
+        return Err(e.to_string());
+    };
+
+    if let Err(e) = stream.shutdown().await {
+        return Err(e.to_string());
+    };
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn write_results_to_socket(
+    _results: &UpdateIpResults,
+    _socket_path: &PathBuf,
+) -> Result<(), String> {
+    Err("unix sockets are not supported on this platform".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_results(ip_address: &str, run_id: &str) -> UpdateIpResults {
+        let mut ip_service_result = IpServiceResult::new("https://ip.example/");
+        ip_service_result.ip_address = Some(ip_address.parse().unwrap());
+        ip_service_result.detected_at = Some(get_timestamp_or_zero());
+
+        let mut domain_result = DomainResult::new("example.com");
+        domain_result.ip_address = ip_service_result.ip_address;
+        domain_result.outcome = Some(DomainOutcome::Unchanged);
+
+        let decisions = vec![Decision::AddressDetected {
+            service: ip_service_result.service.clone(),
+            ip_address: ip_service_result.ip_address.unwrap(),
+        }];
+
+        UpdateIpResults::from_results(
+            ip_service_result,
+            None,
+            HashMap::from([("dyndns2:example.com".to_string(), domain_result)]),
+            decisions,
+            run_id,
+            None,
+        )
+    }
+
+    fn create_results_filepath(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join("update_ip_results_".to_string() + name + "_" + &std::process::id().to_string());
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("results.json")
+    }
+
+    const TEXTUAL_FORMS: [&str; 5] = [
+        "2001:db8::1",
+        "2001:DB8::1",
+        "2001:db8:0:0:0:0:0:1",
+        "[2001:0db8::0001]",
+        "2001:db8::1%eth0",
+    ];
+
+    #[test]
+    fn textual_forms_are_the_same_address() {
+        let address: Address = "2001:db8::1".parse().unwrap();
+        for textual_form in TEXTUAL_FORMS {
+            assert_eq!(
+                textual_form.parse::<Address>(),
+                Ok(address),
+                "{}",
+                textual_form
+            );
+        }
+    }
+
+    #[test]
+    fn addresses_are_displayed_in_canonical_form() {
+        for textual_form in TEXTUAL_FORMS {
+            let address: Address = textual_form.parse().unwrap();
+            assert_eq!(address.to_string(), "2001:db8::1");
+
+            let json = "\"".to_string() + textual_form + "\"";
+            let address: Address = serde_json::from_str(&json).unwrap();
+            assert_eq!(serde_json::to_string(&address).unwrap(), "\"2001:db8::1\"");
+        }
+
+        let mapped: Address = "::ffff:192.0.2.1".parse().unwrap();
+        assert_eq!(mapped.family(), AddressFamily::V4);
+        assert_eq!(mapped.to_string(), "192.0.2.1");
+    }
+
+    #[test]
+    fn textual_forms_across_runs_are_not_changes() {
+        let mut prev_results = create_results(TEXTUAL_FORMS[0], "run-0");
+        for (index, textual_form) in TEXTUAL_FORMS.iter().enumerate().skip(1) {
+            let results = create_results(textual_form, &("run-".to_string() + &index.to_string()));
+            assert!(
+                !results_changed(&prev_results, &results),
+                "{}",
+                textual_form
+            );
+            prev_results = results;
+        }
+    }
+
+    const DAY_MS: u64 = 86_400_000;
+
+    #[test]
+    fn recent_results_are_kept() {
+        let results = create_results("192.0.2.1", "run-1");
+        let kept = discard_stale_results(Some(results), 7 * DAY_MS).unwrap();
+        assert_eq!(kept.run_id.as_deref(), Some("run-1"));
+        assert_eq!(
+            kept.ip_service_result.ip_address,
+            Some("192.0.2.1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn stale_results_are_discarded() {
+        let mut results = create_results("192.0.2.1", "run-1");
+        results.timestamp -= 8 * DAY_MS as u128;
+        assert!(discard_stale_results(Some(results), 7 * DAY_MS).is_none());
+
+        let mut results = create_results("192.0.2.1", "run-1");
+        results.timestamp -= 2 * DAY_MS as u128;
+        assert!(discard_stale_results(Some(results.clone()), 7 * DAY_MS).is_some());
+        assert!(discard_stale_results(Some(results), DAY_MS).is_none());
+    }
+
+    #[test]
+    fn results_without_a_timestamp_are_stale() {
+        let json = r#"{
+            "ip_service_result": {"service": "https://ip.example/", "ip_address": "192.0.2.1", "errors": []},
+            "domain_service_results": {}
+        }"#;
+        let results: UpdateIpResults = serde_json::from_str(json).unwrap();
+        assert_eq!(results.timestamp, 0);
+        assert!(discard_stale_results(Some(results), 7 * DAY_MS).is_none());
+    }
+
+    #[test]
+    fn results_from_the_future_are_kept() {
+        // a clock that was set back is not a reason to forget every domain
+        let mut results = create_results("192.0.2.1", "run-1");
+        results.timestamp += DAY_MS as u128;
+        assert!(discard_stale_results(Some(results), 7 * DAY_MS).is_some());
+    }
+
+    #[test]
+    fn missing_results_stay_missing() {
+        assert!(discard_stale_results(None, 7 * DAY_MS).is_none());
+    }
+
+    fn get_address(address: &str) -> Address {
+        address.parse().unwrap()
+    }
+
+    fn compare_prefix_len(v4: Option<u8>, v6: Option<u8>) -> Option<ComparePrefixLen> {
+        Some(ComparePrefixLen { v4, v6 })
+    }
+
+    #[test]
+    fn ipv4_addresses_are_masked_to_their_prefix() {
+        let address = get_address("192.0.2.1");
+        let prefix_len = compare_prefix_len(Some(24), None);
+        assert!(address.matches(&get_address("192.0.2.254"), &prefix_len));
+        assert!(!address.matches(&get_address("192.0.3.1"), &prefix_len));
+
+        let prefix_len = compare_prefix_len(Some(20), None);
+        assert!(address.matches(&get_address("192.0.15.1"), &prefix_len));
+        assert!(!address.matches(&get_address("192.0.16.1"), &prefix_len));
+    }
+
+    #[test]
+    fn ipv6_addresses_are_masked_to_their_prefix() {
+        let address = get_address("2001:db8:0:100::1");
+        let prefix_len = compare_prefix_len(None, Some(56));
+        assert!(address.matches(&get_address("2001:db8:0:1ff:abcd::9"), &prefix_len));
+        assert!(!address.matches(&get_address("2001:db8:0:200::1"), &prefix_len));
+
+        let prefix_len = compare_prefix_len(None, Some(64));
+        assert!(address.matches(&get_address("2001:db8:0:100:ffff::1"), &prefix_len));
+        assert!(!address.matches(&get_address("2001:db8:0:101::1"), &prefix_len));
+    }
+
+    #[test]
+    fn full_and_empty_prefixes_are_exact_and_any() {
+        let (address, address_v6) = (get_address("192.0.2.1"), get_address("2001:db8::1"));
+
+        let full = compare_prefix_len(Some(32), Some(128));
+        assert!(address.matches(&get_address("192.0.2.1"), &full));
+        assert!(!address.matches(&get_address("192.0.2.2"), &full));
+        assert!(address_v6.matches(&get_address("2001:db8::1"), &full));
+        assert!(!address_v6.matches(&get_address("2001:db8::2"), &full));
+
+        let empty = compare_prefix_len(Some(0), Some(0));
+        assert!(address.matches(&get_address("203.0.113.4"), &empty));
+        assert!(address_v6.matches(&get_address("fd00::1"), &empty));
+    }
+
+    #[test]
+    fn prefixes_only_apply_to_their_family() {
+        // a v4 prefix leaves ipv6 addresses compared exactly and the other way around
+        let prefix_len = compare_prefix_len(Some(24), None);
+        let address_v6 = get_address("2001:db8::1");
+        assert!(!address_v6.matches(&get_address("2001:db8::2"), &prefix_len));
+
+        let prefix_len = compare_prefix_len(None, Some(56));
+        let address = get_address("192.0.2.1");
+        assert!(!address.matches(&get_address("192.0.2.2"), &prefix_len));
+        assert!(address.matches(&get_address("192.0.2.1"), &prefix_len));
+    }
+
+    #[test]
+    fn addresses_of_different_families_never_match() {
+        let prefix_len = compare_prefix_len(Some(0), Some(0));
+        let address = get_address("192.0.2.1");
+        assert!(!address.matches(&get_address("64:ff9b::c000:201"), &prefix_len));
+        assert!(!address.matches(&get_address("2001:db8::1"), &prefix_len));
+        assert!(!address.matches(&get_address("2001:db8::1"), &None));
+    }
+
+    #[test]
+    fn exported_state_imports_the_same_results() {
+        let mut results = create_results("2001:db8::1", "run-1");
+        if let Some(domain_result) = results
+            .domain_service_results
+            .get_mut("dyndns2:example.com")
+        {
+            domain_result.sent_at = Some(results.timestamp);
+            domain_result.reason = Some(UpdateReason::AddressChanged);
+        }
+
+        let json_str = export_state(results.clone()).unwrap();
+        let state_export: serde_json::Value = serde_json::from_str(&json_str).unwrap();
+        assert_eq!(state_export["state_version"], STATE_VERSION);
+
+        let imported = import_state(&json_str).unwrap();
+        assert_eq!(
+            serde_json::to_value(&imported).unwrap(),
+            serde_json::to_value(&results).unwrap()
+        );
+        assert!(!results_changed(&results, &imported));
+
+        // an import exports to the same state
+        assert_eq!(export_state(imported).unwrap(), json_str);
+    }
+
+    #[test]
+    fn older_state_versions_are_imported() {
+        let results = create_results("192.0.2.1", "run-1");
+        let mut state_export: serde_json::Value =
+            serde_json::from_str(&export_state(results).unwrap()).unwrap();
+        state_export["state_version"] = serde_json::json!(0);
+
+        let imported = import_state(&state_export.to_string()).unwrap();
+        assert_eq!(imported.run_id.as_deref(), Some("run-1"));
+    }
+
+    #[test]
+    fn newer_state_versions_are_rejected() {
+        let results = create_results("192.0.2.1", "run-1");
+        let mut state_export: serde_json::Value =
+            serde_json::from_str(&export_state(results).unwrap()).unwrap();
+        state_export["state_version"] = serde_json::json!(STATE_VERSION + 1);
+
+        assert_eq!(
+            import_state(&state_export.to_string()).unwrap_err(),
+            "state_version ".to_string()
+                + &(STATE_VERSION + 1).to_string()
+                + " is newer than the supported state_version "
+                + &STATE_VERSION.to_string()
+        );
+    }
+
+    #[test]
+    fn results_without_a_state_version_are_not_imported() {
+        let results = create_results("192.0.2.1", "run-1");
+        let json_str = serde_json::to_string(&results).unwrap();
+        let error = import_state(&json_str).unwrap_err();
+        assert!(error.contains("state_version"), "{}", error);
+    }
+
+    #[test]
+    fn results_changed_ignores_run_fields() {
+        let prev_results = create_results("192.0.2.1", "run-1");
+        let mut results = create_results("192.0.2.1", "run-2");
+        results.timestamp += 60_000;
+        results.decisions = DecisionLog::new();
+        results.ip_service_result.detected_at = Some(results.timestamp);
+
+        assert!(!results_changed(&prev_results, &results));
+    }
+
+    #[test]
+    fn results_changed_compares_addresses_and_outcomes() {
+        let prev_results = create_results("192.0.2.1", "run-1");
+
+        let results = create_results("192.0.2.2", "run-2");
+        assert!(results_changed(&prev_results, &results));
+
+        let mut results = create_results("192.0.2.1", "run-2");
+        if let Some(domain_result) = results
+            .domain_service_results
+            .get_mut("dyndns2:example.com")
+        {
+            domain_result.outcome = Some(DomainOutcome::Failed);
+            domain_result.errors = vec!["response was not okay".to_string()];
+        }
+        assert!(results_changed(&prev_results, &results));
+
+        let mut results = create_results("192.0.2.1", "run-2");
+        results.ip_service_result.errors = vec!["timed out after 10000ms".to_string()];
+        assert!(results_changed(&prev_results, &results));
+
+        let mut results = create_results("192.0.2.1", "run-2");
+        results.domain_service_results.clear();
+        assert!(results_changed(&prev_results, &results));
+    }
+
+    #[test]
+    fn results_changed_compares_warnings() {
+        let prev_results = create_results("192.0.2.1", "run-1");
+
+        let mut results = create_results("192.0.2.1", "run-2");
+        if let Some(domain_result) = results
+            .domain_service_results
+            .get_mut("dyndns2:example.com")
+        {
+            domain_result.warnings =
+                vec!["response content type changed from text/plain to text/html".to_string()];
+        }
+        assert!(results_changed(&prev_results, &results));
+        assert!(!results_changed(&results.clone(), &results));
+
+        let mut results = create_results("192.0.2.1", "run-2");
+        results.ip_service_result.warnings = vec![
+            "https://ip.example/: expected content type application/json but got text/plain"
+                .to_string(),
+        ];
+        assert!(results_changed(&prev_results, &results));
+    }
+
+    #[tokio::test]
+    async fn unchanged_results_are_not_written() {
+        let results_filepath = create_results_filepath("unchanged");
+
+        write_results_to_disk(create_results("192.0.2.1", "run-1"), &results_filepath, 2)
+            .await
+            .unwrap();
+        write_results_to_disk(create_results("192.0.2.1", "run-2"), &results_filepath, 2)
+            .await
+            .unwrap();
+
+        let results = load_results_from_disk(&results_filepath).await.unwrap();
+        assert_eq!(results.run_id.as_deref(), Some("run-1"));
+        assert!(!get_backup_filepath(&results_filepath, 1).exists());
+    }
+
+    #[tokio::test]
+    async fn changed_results_rotate_backups() {
+        let results_filepath = create_results_filepath("changed");
+
+        write_results_to_disk(create_results("192.0.2.1", "run-1"), &results_filepath, 2)
+            .await
+            .unwrap();
+        write_results_to_disk(create_results("192.0.2.2", "run-2"), &results_filepath, 2)
+            .await
+            .unwrap();
+
+        let results = load_results_from_disk(&results_filepath).await.unwrap();
+        assert_eq!(results.run_id.as_deref(), Some("run-2"));
+        let backup = load_results_from_disk(&get_backup_filepath(&results_filepath, 1))
+            .await
+            .unwrap();
+        assert_eq!(backup.run_id.as_deref(), Some("run-1"));
+    }
+
+    #[tokio::test]
+    async fn unchanged_results_are_refreshed_without_backup() {
+        let results_filepath = create_results_filepath("refreshed");
+
+        write_results_to_disk(create_results("192.0.2.1", "run-1"), &results_filepath, 2)
+            .await
+            .unwrap();
+        let mut results = create_results("192.0.2.1", "run-2");
+        results.timestamp += RESULTS_REFRESH_MS;
+        write_results_to_disk(results, &results_filepath, 2)
+            .await
+            .unwrap();
+
+        let results = load_results_from_disk(&results_filepath).await.unwrap();
+        assert_eq!(results.run_id.as_deref(), Some("run-2"));
+        assert!(!get_backup_filepath(&results_filepath, 1).exists());
+    }
+
+    #[tokio::test]
+    async fn writable_checks_leave_no_results_behind() {
+        let results_filepath = create_results_filepath("check_missing");
+        check_results_writable(&results_filepath).await.unwrap();
+        assert!(!results_filepath.exists());
+    }
+
+    #[tokio::test]
+    async fn writable_checks_keep_existing_results() {
+        let results_filepath = create_results_filepath("check_existing");
+        write_results_to_disk(create_results("192.0.2.1", "run-1"), &results_filepath, 2)
+            .await
+            .unwrap();
+
+        check_results_writable(&results_filepath).await.unwrap();
+        let results = load_results_from_disk(&results_filepath).await.unwrap();
+        assert_eq!(results.run_id.as_deref(), Some("run-1"));
+    }
+
+    #[tokio::test]
+    async fn missing_results_directories_are_not_writable() {
+        let results_filepath = create_results_filepath("check_no_dir")
+            .parent()
+            .unwrap()
+            .join("missing")
+            .join("results.json");
+        assert!(check_results_writable(&results_filepath).await.is_err());
+        assert!(!results_filepath.exists());
+    }
+}
@@ -0,0 +1,222 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{Address, AddressFamily};
+
+/*
+    An observation is an address reported by a single source of a run,
+    ie: an ip service or the fast_path interface.
+
+    Observations are grouped by family, addresses of different families
+    never agree or conflict with each other. The confidence of a run is
+    the lowest confidence of its families:
+    - Unanimous: every source of a family reported the same address
+    - Majority: more than half of the sources of a family agree
+    - Conflicted: no address is reported by more than half of the sources
+*/
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Observation {
+    pub source: String,
+    pub ip_address: Address,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, PartialOrd)]
+pub enum Confidence {
+    Conflicted,
+    Majority,
+    Unanimous,
+}
+
+// None when there is nothing to compare
+pub fn classify_observations(observations: &[Observation]) -> Option<Confidence> {
+    let mut confidence = None;
+
+    for family in [AddressFamily::V4, AddressFamily::V6] {
+        let addresses: Vec<&Address> = observations
+            .iter()
+            .map(|observation| &observation.ip_address)
+            .filter(|address| address.family() == family)
+            .collect();
+        if addresses.len() < 2 {
+            continue;
+        }
+
+        let top_count = addresses
+            .iter()
+            .map(|address| addresses.iter().filter(|other| other == &address).count())
+            .max()
+            .unwrap_or(0);
+
+        let family_confidence = match top_count {
+            count if count == addresses.len() => Confidence::Unanimous,
+            count if count * 2 > addresses.len() => Confidence::Majority,
+            _ => Confidence::Conflicted,
+        };
+
+        confidence = match confidence {
+            Some(c) if c < family_confidence => Some(c),
+            _ => Some(family_confidence),
+        };
+    }
+
+    confidence
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observe(addresses: &[&str]) -> Vec<Observation> {
+        addresses
+            .iter()
+            .enumerate()
+            .map(|(index, address)| Observation {
+                source: "source_".to_string() + &index.to_string(),
+                ip_address: Address::new(address.parse().unwrap()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn nothing_to_compare_is_none() {
+        assert_eq!(classify_observations(&[]), None);
+        assert_eq!(classify_observations(&observe(&["203.0.113.4"])), None);
+        assert_eq!(classify_observations(&observe(&["2001:db8::1"])), None);
+    }
+
+    #[test]
+    fn single_family_confidence() {
+        let cases: [(&[&str], Confidence); 8] = [
+            (&["203.0.113.4", "203.0.113.4"], Confidence::Unanimous),
+            (&["203.0.113.4"; 5], Confidence::Unanimous),
+            (&["203.0.113.4", "203.0.113.5"], Confidence::Conflicted),
+            (
+                &["203.0.113.4", "203.0.113.4", "203.0.113.5"],
+                Confidence::Majority,
+            ),
+            (
+                &["203.0.113.4", "203.0.113.4", "203.0.113.5", "203.0.113.6"],
+                Confidence::Conflicted,
+            ),
+            (
+                &["203.0.113.4", "203.0.113.5", "203.0.113.6"],
+                Confidence::Conflicted,
+            ),
+            (
+                &["2001:db8::1", "2001:db8::1", "2001:db8::1", "2001:db8::2"],
+                Confidence::Majority,
+            ),
+            // textual forms of one address agree
+            (&["2001:db8::1", "2001:0db8:0:0::1"], Confidence::Unanimous),
+        ];
+
+        for (addresses, confidence) in cases {
+            assert_eq!(
+                classify_observations(&observe(addresses)),
+                Some(confidence),
+                "{:?}",
+                addresses
+            );
+        }
+    }
+
+    #[test]
+    fn mixed_families_are_never_compared() {
+        // one address of each family has nothing to compare
+        assert_eq!(
+            classify_observations(&observe(&["203.0.113.4", "2001:db8::1"])),
+            None
+        );
+
+        // an address of the other family is not a dissenting source
+        let observations = observe(&["203.0.113.4", "203.0.113.4", "2001:db8::1"]);
+        assert_eq!(
+            classify_observations(&observations),
+            Some(Confidence::Unanimous)
+        );
+
+        let observations = observe(&[
+            "2001:db8::1",
+            "203.0.113.4",
+            "2001:db8::1",
+            "203.0.113.4",
+            "2001:db8::1",
+        ]);
+        assert_eq!(
+            classify_observations(&observations),
+            Some(Confidence::Unanimous)
+        );
+    }
+
+    #[test]
+    fn mapped_ipv4_addresses_are_ipv4_observations() {
+        let observations = observe(&["::ffff:203.0.113.4", "203.0.113.4"]);
+        assert_eq!(
+            classify_observations(&observations),
+            Some(Confidence::Unanimous)
+        );
+    }
+
+    #[test]
+    fn mixed_families_take_the_lowest_confidence() {
+        let cases: [(&[&str], Confidence); 4] = [
+            (
+                &["203.0.113.4", "203.0.113.4", "2001:db8::1", "2001:db8::2"],
+                Confidence::Conflicted,
+            ),
+            (
+                &["203.0.113.4", "203.0.113.5", "2001:db8::1", "2001:db8::1"],
+                Confidence::Conflicted,
+            ),
+            (
+                &[
+                    "203.0.113.4",
+                    "203.0.113.4",
+                    "203.0.113.5",
+                    "2001:db8::1",
+                    "2001:db8::1",
+                ],
+                Confidence::Majority,
+            ),
+            (
+                &[
+                    "203.0.113.4",
+                    "203.0.113.4",
+                    "203.0.113.5",
+                    "2001:db8::1",
+                    "2001:db8::2",
+                ],
+                Confidence::Conflicted,
+            ),
+        ];
+
+        for (addresses, confidence) in cases {
+            assert_eq!(
+                classify_observations(&observe(addresses)),
+                Some(confidence),
+                "{:?}",
+                addresses
+            );
+        }
+    }
+
+    #[test]
+    fn order_of_observations_does_not_matter() {
+        let mut addresses = vec!["203.0.113.4", "2001:db8::1", "203.0.113.5", "203.0.113.4"];
+        let confidence = classify_observations(&observe(&addresses));
+        assert_eq!(confidence, Some(Confidence::Majority));
+
+        for _ in 0..addresses.len() {
+            addresses.rotate_left(1);
+            assert_eq!(classify_observations(&observe(&addresses)), confidence);
+        }
+        addresses.reverse();
+        assert_eq!(classify_observations(&observe(&addresses)), confidence);
+    }
+
+    #[test]
+    fn confidences_are_ordered() {
+        assert!(Confidence::Conflicted < Confidence::Majority);
+        assert!(Confidence::Majority < Confidence::Unanimous);
+    }
+}
@@ -0,0 +1,147 @@
+use serde::{Deserialize, Serialize};
+
+use crate::ResponseJson;
+
+/*
+    The shape of a successful dns service response is its media type and
+    body length. A maintenance page returned with a 2xx status usually
+    differs in both from the responses a dns service normally sends.
+
+    Shapes only produce warnings, they never change an outcome.
+*/
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ResponseShape {
+    pub content_type: Option<String>,
+    pub body_len: usize,
+}
+
+// body lengths that differ by this factor are an order of magnitude apart
+const BODY_LEN_FACTOR: usize = 10;
+
+pub fn get_response_shape(res: &ResponseJson) -> ResponseShape {
+    // parameters like charset are not part of the shape
+    let content_type = res.content_type.as_ref().map(|content_type| {
+        let media_type = match content_type.split_once(';') {
+            Some((media_type, _)) => media_type,
+            _ => content_type,
+        };
+        media_type.trim().to_lowercase()
+    });
+
+    ResponseShape {
+        content_type,
+        body_len: res.body.len(),
+    }
+}
+
+pub fn compare_response_shapes(prev: &ResponseShape, current: &ResponseShape) -> Vec<String> {
+    let mut warnings = Vec::<String>::new();
+
+    if prev.content_type != current.content_type {
+        warnings.push(
+            "response content type changed from ".to_string()
+                + &content_type_to_string(&prev.content_type)
+                + " to "
+                + &content_type_to_string(&current.content_type),
+        );
+    }
+
+    // empty bodies are compared as a single byte
+    let prev_len = prev.body_len.max(1);
+    let current_len = current.body_len.max(1);
+    if current_len >= prev_len * BODY_LEN_FACTOR || prev_len >= current_len * BODY_LEN_FACTOR {
+        warnings.push(
+            "response body length changed from ".to_string()
+                + &prev.body_len.to_string()
+                + " to "
+                + &current.body_len.to_string()
+                + " bytes",
+        );
+    }
+
+    warnings
+}
+
+fn content_type_to_string(content_type: &Option<String>) -> String {
+    match content_type {
+        Some(content_type) => content_type.clone(),
+        _ => "none".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_shape(content_type: Option<&str>, body: &str) -> ResponseShape {
+        get_response_shape(&ResponseJson {
+            status_code: 200,
+            body: body.to_string(),
+            timestamp: 0,
+            server_timestamp: None,
+            content_type: content_type.map(|content_type| content_type.to_string()),
+            bot_challenge: false,
+            tls: None,
+        })
+    }
+
+    #[test]
+    fn json_responses_are_their_media_type() {
+        let shape = get_shape(
+            Some("Application/JSON; charset=utf-8"),
+            "{\"success\":true}",
+        );
+        assert_eq!(
+            shape,
+            ResponseShape {
+                content_type: Some("application/json".to_string()),
+                body_len: 16,
+            }
+        );
+        assert!(
+            compare_response_shapes(&shape, &get_shape(Some("application/json"), "{}")).is_empty()
+        );
+    }
+
+    #[test]
+    fn plain_text_responses_are_their_media_type() {
+        let shape = get_shape(Some("text/plain"), "good 192.0.2.1");
+        assert_eq!(shape.content_type, Some("text/plain".to_string()));
+        assert_eq!(shape.body_len, 14);
+        assert!(
+            compare_response_shapes(&shape, &get_shape(Some("text/plain"), "nochg 192.0.2.1"))
+                .is_empty()
+        );
+
+        // responses without a content type are compared as none
+        let shape = get_shape(None, "good 192.0.2.1");
+        assert_eq!(shape.content_type, None);
+        assert!(compare_response_shapes(&shape, &get_shape(None, "good")).is_empty());
+    }
+
+    #[test]
+    fn empty_bodies_are_compared_as_a_single_byte() {
+        let empty = get_shape(Some("text/plain"), "");
+        assert_eq!(empty.body_len, 0);
+        assert!(compare_response_shapes(&empty, &get_shape(Some("text/plain"), "good")).is_empty());
+        assert_eq!(
+            compare_response_shapes(&empty, &get_shape(Some("text/plain"), "good 192.0.2.1")),
+            vec!["response body length changed from 0 to 14 bytes".to_string()]
+        );
+    }
+
+    #[test]
+    fn mismatched_content_types_are_warnings() {
+        let json = get_shape(Some("application/json"), "{\"success\":true}");
+        let html = get_shape(Some("text/html"), "<html>maintenance</html>");
+        assert_eq!(
+            compare_response_shapes(&json, &html),
+            vec!["response content type changed from application/json to text/html".to_string()]
+        );
+        assert_eq!(
+            compare_response_shapes(&json, &get_shape(None, "{\"success\":true}")),
+            vec!["response content type changed from application/json to none".to_string()]
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use tokio::net::{lookup_host, UdpSocket};
+
+use results::{DomainOutcome, DomainResult, IpServiceResult, UpdateIpResults};
+
+/*
+    Sends run metrics to a statsd endpoint using the DogStatsD format.
+    https://docs.datadoghq.com/developers/dogstatsd/datagram_shell/
+
+    All metrics are sent as a single multi-metric datagram.
+*/
+
+pub struct RunTimings {
+    pub ip_service_ms: u128,
+    pub domain_services_ms: u128,
+}
+
+pub async fn send_run_metrics(
+    statsd_addr: &str,
+    prev_results: &Option<UpdateIpResults>,
+    ip_service_result: &IpServiceResult,
+    domain_service_results: &Option<HashMap<String, DomainResult>>,
+    timings: &RunTimings,
+) -> Result<(), String> {
+    let metrics = get_metrics(
+        prev_results,
+        ip_service_result,
+        domain_service_results,
+        timings,
+    );
+
+    send_datagram(statsd_addr, &metrics.join("\n")).await
+}
+
+fn get_metrics(
+    prev_results: &Option<UpdateIpResults>,
+    ip_service_result: &IpServiceResult,
+    domain_service_results: &Option<HashMap<String, DomainResult>>,
+    timings: &RunTimings,
+) -> Vec<String> {
+    let mut metrics = vec!["update_ip.runs:1|c".to_string()];
+
+    match ip_service_result.ip_address {
+        Some(_) => {
+            let ip_result = ip_service_result;
+            metrics.push(
+                "update_ip.ip_service.latency:".to_string()
+                    + &timings.ip_service_ms.to_string()
+                    + "|ms|#service:"
+                    + &ip_result.service,
+            );
+
+            // the previous address of either family
+            let prev_addresses = match prev_results {
+                Some(results) => std::iter::once(&results.ip_service_result)
+                    .chain(results.ip_service_result_v6.as_ref())
+                    .map(|ip_result| ip_result.ip_address)
+                    .collect(),
+                _ => Vec::new(),
+            };
+            if !prev_addresses.contains(&ip_result.ip_address) {
+                metrics.push("update_ip.address.changes:1|c".to_string());
+            }
+        }
+        _ => metrics.push("update_ip.ip_service.failures:1|c".to_string()),
+    }
+
+    if let Some(domain_results) = domain_service_results {
+        metrics.push(
+            "update_ip.domain_services.latency:".to_string()
+                + &timings.domain_services_ms.to_string()
+                + "|ms",
+        );
+
+        for domain_result in domain_results.values() {
+            let name = match domain_result.outcome {
+                Some(DomainOutcome::Updated) => "update_ip.domain.updates",
+                Some(DomainOutcome::Failed) => "update_ip.domain.failures",
+                _ => continue,
+            };
+
+            // domains are tagged by their dns service and hostname, not their results key
+            let mut tags = Vec::<String>::new();
+            if let Some(service) = &domain_result.service {
+                tags.push("service:".to_string() + service);
+            }
+            tags.push("hostname:".to_string() + &domain_result.hostname);
+            metrics.push(name.to_string() + ":1|c|#" + &tags.join(","));
+        }
+    }
+
+    metrics
+}
+
+async fn send_datagram(statsd_addr: &str, datagram: &str) -> Result<(), String> {
+    let addr = match lookup_host(statsd_addr).await {
+        Ok(mut addrs) => match addrs.next() {
+            Some(addr) => addr,
+            _ => return Err("statsd address could not be resolved".to_string()),
+        },
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let local_addr = match addr.is_ipv4() {
+        true => "0.0.0.0:0",
+        false => "[::]:0",
+    };
+
+    let socket = match UdpSocket::bind(local_addr).await {
+        Ok(s) => s,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    if let Err(e) = socket.send_to(datagram.as_bytes(), addr).await {
+        return Err(e.to_string());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use results::Address;
+
+    const TIMINGS: RunTimings = RunTimings {
+        ip_service_ms: 12,
+        domain_services_ms: 34,
+    };
+
+    fn get_ip_service_result(address: &str) -> IpServiceResult {
+        let mut ip_service_result = IpServiceResult::new("https://ip.example/");
+        ip_service_result.ip_address = Some(Address::new(address.parse().unwrap()));
+        ip_service_result
+    }
+
+    fn get_domain_result(
+        service: Option<&str>,
+        hostname: &str,
+        outcome: DomainOutcome,
+    ) -> DomainResult {
+        let mut domain_result = DomainResult::new(hostname);
+        domain_result.service = service.map(|service| service.to_string());
+        domain_result.outcome = Some(outcome);
+        domain_result
+    }
+
+    #[test]
+    fn domains_are_tagged_by_service_and_hostname() {
+        let domain_results = HashMap::from([
+            (
+                "dyndns2:a.example".to_string(),
+                get_domain_result(Some("dyndns2"), "a.example", DomainOutcome::Failed),
+            ),
+            (
+                "a.example:AAAA".to_string(),
+                get_domain_result(Some("cloudflare"), "a.example", DomainOutcome::Updated),
+            ),
+        ]);
+        let metrics = get_metrics(
+            &None,
+            &get_ip_service_result("203.0.113.4"),
+            &Some(domain_results),
+            &TIMINGS,
+        );
+
+        assert!(metrics.contains(
+            &"update_ip.domain.failures:1|c|#service:dyndns2,hostname:a.example".to_string()
+        ));
+        assert!(metrics.contains(
+            &"update_ip.domain.updates:1|c|#service:cloudflare,hostname:a.example".to_string()
+        ));
+        assert!(!metrics
+            .iter()
+            .any(|metric| metric.contains("a.example:AAAA")));
+    }
+
+    #[test]
+    fn only_updates_and_failures_are_counted() {
+        let domain_results = HashMap::from([
+            (
+                "query_get:a.example".to_string(),
+                get_domain_result(Some("query_get"), "a.example", DomainOutcome::Unchanged),
+            ),
+            (
+                "query_get:b.example".to_string(),
+                get_domain_result(None, "b.example", DomainOutcome::StaleAddress),
+            ),
+        ]);
+        let metrics = get_metrics(
+            &None,
+            &get_ip_service_result("203.0.113.4"),
+            &Some(domain_results),
+            &TIMINGS,
+        );
+
+        assert!(!metrics
+            .iter()
+            .any(|metric| metric.starts_with("update_ip.domain.")));
+    }
+
+    #[test]
+    fn runs_without_domain_results_count_no_domains() {
+        let metrics = get_metrics(
+            &None,
+            &get_ip_service_result("203.0.113.4"),
+            &None,
+            &TIMINGS,
+        );
+
+        assert_eq!(
+            metrics,
+            vec![
+                "update_ip.runs:1|c".to_string(),
+                "update_ip.ip_service.latency:12|ms|#service:https://ip.example/".to_string(),
+                "update_ip.address.changes:1|c".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn addresses_of_either_previous_family_are_not_changes() {
+        let mut prev_results = UpdateIpResults::from_results(
+            IpServiceResult::new(""),
+            Some(get_ip_service_result("2001:db8::1")),
+            HashMap::new(),
+            Default::default(),
+            "prev",
+            None,
+        );
+        let metrics = get_metrics(
+            &Some(prev_results.clone()),
+            &get_ip_service_result("2001:db8::1"),
+            &None,
+            &TIMINGS,
+        );
+        assert!(!metrics.contains(&"update_ip.address.changes:1|c".to_string()));
+
+        prev_results.ip_service_result_v6 = None;
+        let metrics = get_metrics(
+            &Some(prev_results),
+            &get_ip_service_result("2001:db8::1"),
+            &None,
+            &TIMINGS,
+        );
+        assert!(metrics.contains(&"update_ip.address.changes:1|c".to_string()));
+    }
+}
@@ -1,10 +1,17 @@
 use std::env;
+use std::io;
+use std::io::Read;
 use std::path;
+use std::time::Instant;
 
-use config;
-use domain_services;
-use ip_services;
-use results;
+mod serve;
+
+const RESULTS_WARNING: &str = "WARNING: results cannot be written to disk.
+Every run will consider domains out of date and send updates again.
+Set skip_updates_on_results_error to pause domain updates until results can be written.";
+
+const CLOCK_WARNING: &str = "WARNING: the system clock is set before 1970.
+Timestamps are recorded as 0 and previous results are considered stale.";
 
 #[tokio::main]
 async fn main() {
@@ -13,40 +20,913 @@ async fn main() {
         None => return println!("argument error:\nconfig file not found."),
     };
 
+    if args == "import-ddclient" {
+        return import_ddclient().await;
+    }
+
+    if args == "state" {
+        return state().await;
+    }
+
+    if args == "version" {
+        return version().await;
+    }
+
+    if args == "serve" {
+        return serve().await;
+    }
+
+    let verbose = env::args().skip(2).any(|arg| arg == "--verbose");
+    let verify = env::args().skip(2).any(|arg| arg == "--verify");
+
     let config_path = path::Path::new(&args);
-    let config = match config::from_path(config_path).await {
+    let mut config = match config::from_path(config_path).await {
         Ok(c) => c,
         Err(e) => return println!("configuration error:\n{}", e),
     };
+    if verify {
+        config.verify_updates = true;
+    }
+
+    if let Err(e) = drop_privileges(&config).await {
+        return println!("privileges error:\n{}", e);
+    }
+
+    if let Err(e) = results::get_timestamp() {
+        println!("{}\nclock error:\n{}", CLOCK_WARNING, e);
+    }
+
+    for invalid_entry in &config.invalid_entries {
+        println!("WARNING: skipping invalid config entry\n{}", invalid_entry);
+    }
+
+    if let Some(allowed_hosts) = &config.allowed_hosts {
+        if let Err(e) = requests::set_allowed_hosts(allowed_hosts) {
+            return println!("allowed hosts error:\n{}", e);
+        }
+    }
+    requests::set_require_tls13(config.require_tls13);
+
+    if let Err(e) = add_ip_services_from_url(&mut config).await {
+        println!(
+            "WARNING: ip services could not be fetched\nservices error:\n{}",
+            e
+        );
+    }
+    if config.ip_services.is_empty() && config.ip_services_v6.is_empty() {
+        return println!("configuration error:\nno ip services are available.");
+    }
+
+    let prev_results = load_prev_results(&config).await;
+
+    // the address of a local interface can end a run before any request
+    if let Some(interface) = &config.fast_path {
+        if ip_services::fast_path_address_unchanged(interface, &prev_results) {
+            if verbose {
+                println!("fast path: {} has the previous address", interface);
+            }
+            return;
+        }
+    }
+
+    let mut warnings = Vec::<String>::new();
+    let results = run_update(&config, &prev_results, None, None, &mut warnings).await;
+    for warning in &warnings {
+        println!("{}", warning);
+    }
+
+    let results = match results {
+        Ok(r) => r,
+        Err(e) => return println!("{}", e),
+    };
+
+    if verbose {
+        if let Some(run_id) = &results.run_id {
+            println!("run {}", run_id);
+        }
+        for decision in &results.decisions {
+            println!("{}", decision);
+        }
+    }
+
+    print_summary(&prev_results, &results, verbose);
+}
+
+/*
+    Results, their backups, the fingerprint key, and the ip services cache
+    are created or handed to the run_as user before privileges are dropped.
+    A missing results file is created and backups are renamed within the
+    results directory, so a directory the user cannot write fails here
+    instead of at the end of the run.
+*/
+async fn drop_privileges(config: &config::Config) -> Result<(), String> {
+    let run_as = match &config.run_as {
+        Some(run_as) => run_as,
+        _ => return Ok(()),
+    };
+    let (uid, gid) = privileges::get_ids(&run_as.user, run_as.group.as_deref())?;
+
+    let results_filepath = &config.results_filepath;
+    if let Err(e) = results::check_results_writable(results_filepath).await {
+        return Err(results_filepath.display().to_string() + ": " + &e);
+    }
+    let results_missing = !results_filepath.exists();
+    let key_filepath = results::get_fingerprint_key_filepath(results_filepath);
+    results::load_fingerprint_key(&key_filepath).await?;
+
+    let filepaths = get_run_as_filepaths(config);
+    let filepaths: Vec<&path::Path> = filepaths
+        .iter()
+        .map(|filepath| filepath.as_path())
+        .collect();
+    privileges::chown_paths(&filepaths, uid, gid)?;
+
+    privileges::drop_privileges(uid, gid)?;
+
+    if config.results_backups > 0 || results_missing {
+        let results_dir = match results_filepath.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => path::Path::new("."),
+        };
+        let action = match results_missing {
+            true => " cannot create results",
+            false => " cannot rotate results backups",
+        };
+        if let Err(e) = privileges::check_writable(results_dir) {
+            return Err("user ".to_string()
+                + &run_as.user
+                + action
+                + ", the results directory is not writable\n"
+                + &e);
+        }
+    }
+
+    Ok(())
+}
+
+// the files a run writes: results, the fingerprint key, the ip services cache, and backups
+fn get_run_as_filepaths(config: &config::Config) -> Vec<path::PathBuf> {
+    let results_filepath = &config.results_filepath;
+    let mut filepaths = vec![
+        results_filepath.clone(),
+        results::get_fingerprint_key_filepath(results_filepath),
+        get_ip_services_cache_filepath(config),
+    ];
+    for index in 1..=config.results_backups {
+        filepaths.push(results::get_backup_filepath(results_filepath, index));
+    }
+
+    filepaths
+}
+
+fn get_ip_services_cache_filepath(config: &config::Config) -> path::PathBuf {
+    let mut cache_filepath = config.results_filepath.as_os_str().to_owned();
+    cache_filepath.push(".ip_services");
+    path::PathBuf::from(cache_filepath)
+}
+
+// adds ip services fetched from services_from_url to the config
+async fn add_ip_services_from_url(config: &mut config::Config) -> Result<(), String> {
+    let services_from_url = match &config.services_from_url {
+        Some(url) => url,
+        _ => return Ok(()),
+    };
+
+    let ip_services = ip_services::get_ip_services_from_url(
+        services_from_url,
+        config.services_from_url_auth_header.as_deref(),
+        &get_ip_services_cache_filepath(config),
+    )
+    .await?;
+
+    for ip_service in ip_services {
+        if !config.ip_services.contains(&ip_service) {
+            config.ip_services.push(ip_service);
+        }
+    }
+
+    Ok(())
+}
+
+// "copy" results from disk
+async fn load_prev_results(config: &config::Config) -> Option<results::UpdateIpResults> {
+    let prev_results =
+        results::load_results_with_backups(&config.results_filepath, config.results_backups)
+            .await
+            .ok();
 
-    // "copy" results from disk
-    let prev_results = match results::load_results_from_disk(&config.results_filepath).await {
-        Ok(results) => Some(results),
+    results::discard_stale_results(prev_results, config.state_max_age_ms)
+}
+
+// prints a line per domain to stderr, ie: update_ip: example.com 192.0.2.1 -> 192.0.2.2 (updated via dyndns2)
+fn print_summary(
+    prev_results: &Option<results::UpdateIpResults>,
+    results: &results::UpdateIpResults,
+    verbose: bool,
+) {
+    let ip_service_results = [
+        Some(&results.ip_service_result),
+        results.ip_service_result_v6.as_ref(),
+    ];
+    for ip_result in ip_service_results.into_iter().flatten() {
+        if ip_result.confidence == Some(results::Confidence::Conflicted) {
+            let observations: Vec<String> = ip_result
+                .observations
+                .iter()
+                .map(|observation| {
+                    observation.source.clone() + " " + &observation.ip_address.to_string()
+                })
+                .collect();
+            eprintln!(
+                "update_ip: WARNING address detection conflicted: {}",
+                observations.join(", ")
+            );
+        }
+    }
+
+    for summary in get_domain_summaries(prev_results, results, verbose) {
+        eprintln!("update_ip: {}", summary);
+    }
+}
+
+// unchanged domains are only summarized when verbose
+fn get_domain_summaries(
+    prev_results: &Option<results::UpdateIpResults>,
+    results: &results::UpdateIpResults,
+    verbose: bool,
+) -> Vec<String> {
+    let mut keys: Vec<&String> = results.domain_service_results.keys().collect();
+    keys.sort();
+
+    let mut summaries = Vec::<String>::new();
+    for key in keys {
+        let domain_result = &results.domain_service_results[key];
+        let prev_address = prev_results
+            .as_ref()
+            .and_then(|results| results.domain_service_results.get(key))
+            .and_then(|domain_result| domain_result.ip_address);
+
+        let summary = match (&domain_result.outcome, domain_result.ip_address) {
+            (Some(results::DomainOutcome::Updated), Some(ip_address)) => {
+                let service = match &domain_result.service {
+                    Some(service) => service.as_str(),
+                    _ => "unknown service",
+                };
+                address_to_string(prev_address)
+                    + " -> "
+                    + &ip_address.to_string()
+                    + " (updated via "
+                    + service
+                    + ")"
+            }
+            (Some(results::DomainOutcome::Unchanged), _) if !verbose => continue,
+            (Some(results::DomainOutcome::Unchanged), ip_address) => {
+                address_to_string(ip_address) + " no change"
+            }
+            (Some(outcome), _) => {
+                format!("{:?}", outcome) + ": " + &domain_result.errors.join(", ")
+            }
+            _ => "not updated".to_string(),
+        };
+
+        summaries.push(domain_result.hostname.clone() + " " + &summary);
+    }
+
+    summaries
+}
+
+fn address_to_string(address: Option<results::Address>) -> String {
+    match address {
+        Some(address) => address.to_string(),
+        _ => "none".to_string(),
+    }
+}
+
+/*
+    Detects the address, updates domains, and writes results.
+
+    An ip_service_result replaces address detection. Domains limits the
+    update to the domains with those hostnames, every other domain keeps
+    its previous result. Problems that do not stop the run are added to
+    warnings so callers decide where they are shown.
+*/
+async fn run_update(
+    config: &config::Config,
+    prev_results: &Option<results::UpdateIpResults>,
+    ip_service_result: Option<results::IpServiceResult>,
+    domains: Option<&[String]>,
+    warnings: &mut Vec<String>,
+) -> Result<results::UpdateIpResults, String> {
+    let domain_config = match domains {
+        Some(hostnames) => Some(domain_services::filter_domains(config, hostnames)?),
         _ => None,
     };
 
+    // runs that cannot persist results would update domains every run
+    let results_writable = match results::check_results_writable(&config.results_filepath).await {
+        Ok(_) => true,
+        Err(e) => {
+            warnings.push(RESULTS_WARNING.to_string() + "\nfile error:\n" + &e);
+            false
+        }
+    };
+
     // update results
-    let ip_service_result =
-        match ip_services::get_ip_service_results(&config.ip_services, &prev_results).await {
-            Ok(results) => Some(results),
-            _ => None,
+    let run_id = results::create_run_id();
+    if let Some(run_id_header) = &config.run_id_header {
+        if let Err(e) = requests::set_run_header(run_id_header, &run_id) {
+            warnings.push("run id error:\n".to_string() + &e);
+        }
+    }
+
+    let mut decisions = results::DecisionLog::new();
+
+    // a changed config is recorded before anything it changes
+    let prev_fingerprint = prev_results
+        .as_ref()
+        .and_then(|results| results.config_fingerprint.clone());
+    let key_filepath = results::get_fingerprint_key_filepath(&config.results_filepath);
+    let config_fingerprint = match results::load_fingerprint_key(&key_filepath)
+        .await
+        .and_then(|key| config::get_config_fingerprint(config, &key))
+    {
+        Ok(fingerprint) => Some(fingerprint),
+        Err(e) => {
+            warnings.push("config fingerprint error:\n".to_string() + &e);
+            None
+        }
+    };
+    if let (Some(prev_fingerprint), Some(fingerprint)) = (&prev_fingerprint, &config_fingerprint) {
+        let changes = results::diff_config_fingerprints(prev_fingerprint, fingerprint);
+        if !changes.is_empty() {
+            decisions.push(results::Decision::ConfigChanged { changes });
+        }
+    }
+
+    // with ip_services_v6 each family is detected independently
+    // and a config with only ip_services_v6 does not detect ipv4
+    let detects_v4 = !config.ip_services.is_empty();
+    let (family, family_v6) = match config.ip_services_v6.is_empty() {
+        true => (None, None),
+        false => (
+            Some(results::AddressFamily::V4),
+            Some(results::AddressFamily::V6),
+        ),
+    };
+    let (ip_service_result, ip_service_result_v6) = match ip_service_result {
+        Some(ip_result) if family_v6.is_some() && ip_result_family(&ip_result) == family_v6 => {
+            (None, Some(ip_result))
+        }
+        ip_result => (ip_result, None),
+    };
+
+    let ip_service_start = Instant::now();
+    let mut ip_service_result = match (detects_v4, ip_service_result) {
+        (false, None) => results::IpServiceResult::new(""),
+        (_, ip_service_result) => {
+            detect_address(
+                config,
+                prev_results,
+                &config.ip_services,
+                family,
+                ip_service_result,
+                &mut decisions,
+            )
+            .await
+        }
+    };
+    let mut ip_service_result_v6 = match family_v6 {
+        Some(_) => Some(
+            detect_address(
+                config,
+                prev_results,
+                &config.ip_services_v6,
+                family_v6,
+                ip_service_result_v6,
+                &mut decisions,
+            )
+            .await,
+        ),
+        _ => None,
+    };
+
+    // the fast_path interface cross-checks addresses detected by ip services
+    if let Some(interface) = &config.fast_path {
+        for ip_result in
+            std::iter::once(&mut ip_service_result).chain(ip_service_result_v6.as_mut())
+        {
+            ip_services::cross_check_interface(ip_result, interface);
+        }
+    }
+
+    let ip_service_ms = ip_service_start.elapsed().as_millis();
+
+    // errors of a family without an address are also written to results
+    let detected_results = std::iter::once(&ip_service_result)
+        .filter(|_| detects_v4)
+        .chain(ip_service_result_v6.as_ref());
+    for ip_result in detected_results {
+        if ip_result.ip_address.is_none() {
+            warnings.push("ip service error:\n".to_string() + &ip_result.errors.join("\n"));
+        }
+    }
+
+    // responses without their expected content type are warned about whatever the outcome
+    for ip_result in std::iter::once(&ip_service_result).chain(ip_service_result_v6.as_ref()) {
+        if !ip_result.warnings.is_empty() {
+            warnings.push("ip service warning:\n".to_string() + &ip_result.warnings.join("\n"));
+        }
+    }
+
+    // a lost address is reported but does not trigger updates
+    if detects_v4 && config.treat_loss_as_change && ip_service_result.ip_address.is_none() {
+        if let Some(ip_address) = prev_results
+            .as_ref()
+            .and_then(|results| results.ip_service_result.ip_address)
+        {
+            decisions.push(results::Decision::AddressLost { ip_address });
+            warnings.push(
+                "WARNING: the address ".to_string()
+                    + &ip_address.to_string()
+                    + " could not be detected again, dns records may be stale.",
+            );
+        }
+    }
+
+    let domain_services_start = Instant::now();
+    let updates_paused = !results_writable && config.skip_updates_on_results_error;
+    let domain_service_results = match updates_paused {
+        true => prev_results
+            .as_ref()
+            .map(|results| results.domain_service_results.clone()),
+        false => match domain_services::update_domains(
+            domain_config.as_ref().unwrap_or(config),
+            prev_results,
+            &ip_service_result,
+            &ip_service_result_v6,
+            &mut decisions,
+        )
+        .await
+        {
+            Ok(domain_results) => Some(domain_results),
+            Err(e) => {
+                warnings.push("domain error:\n".to_string() + &e);
+                None
+            }
+        },
+    };
+
+    let domain_services_ms = domain_services_start.elapsed().as_millis();
+
+    if let Some(statsd_addr) = &config.statsd_addr {
+        let timings = statsd::RunTimings {
+            ip_service_ms,
+            domain_services_ms,
+        };
+        // a config with only ip_services_v6 reports its ipv6 detection
+        let reported_result = match (detects_v4, &ip_service_result_v6) {
+            (false, Some(ip_result_v6)) => ip_result_v6,
+            _ => &ip_service_result,
         };
+        // outcomes carried over while updates are paused were counted by an earlier run
+        let run_domain_results = match updates_paused {
+            true => &None,
+            false => &domain_service_results,
+        };
+        if let Err(e) = statsd::send_run_metrics(
+            statsd_addr,
+            prev_results,
+            reported_result,
+            run_domain_results,
+            &timings,
+        )
+        .await
+        {
+            warnings.push("statsd error:\n".to_string() + &e);
+        };
+    }
+
+    // a family that was not detected keeps its previous address next to its errors
+    if detects_v4 {
+        ip_service_result.keep_prev_address(
+            prev_results
+                .as_ref()
+                .and_then(|results| results.get_ip_service_result(family)),
+        );
+    }
+    if let Some(ip_result_v6) = &mut ip_service_result_v6 {
+        ip_result_v6.keep_prev_address(
+            prev_results
+                .as_ref()
+                .and_then(|results| results.get_ip_service_result(family_v6)),
+        );
+    }
+
+    // domains that were not updated keep their previous results
+    let prev_domain_results = prev_results
+        .as_ref()
+        .map(|results| results.domain_service_results.clone())
+        .unwrap_or_default();
+    let domain_service_results = match (domain_service_results, &domain_config) {
+        (Some(mut domain_results), Some(_)) => {
+            let domain_keys = domain_services::get_domain_keys(config);
+            for (key, domain_result) in prev_domain_results {
+                if domain_keys.contains(&key) && !domain_results.contains_key(&key) {
+                    domain_results.insert(key, domain_result);
+                }
+            }
+            domain_results
+        }
+        (Some(domain_results), _) => domain_results,
+        _ => prev_domain_results,
+    };
+
+    let results = results::UpdateIpResults::from_results(
+        ip_service_result,
+        ip_service_result_v6,
+        domain_service_results,
+        decisions,
+        &run_id,
+        config_fingerprint,
+    );
 
-    let domain_service_results =
-        match domain_services::update_domains(&config, &prev_results, &ip_service_result).await {
-            Ok(results) => Some(results),
-            _ => None,
+    // send updated results to a supervising process
+    if let Some(results_socket) = &config.results_socket {
+        if let Err(e) = results::write_results_to_socket(&results, results_socket).await {
+            warnings.push("socket error:\n".to_string() + &e);
         };
+    }
+
+    // write updated results to disk
+    match results::write_results_to_disk(results, &config.results_filepath, config.results_backups)
+        .await
+    {
+        Ok(r) => Ok(r),
+        Err(e) => Err("file error:\n".to_string() + &e),
+    }
+}
+
+// an ip_service_result replaces the detection of its family
+async fn detect_address(
+    config: &config::Config,
+    prev_results: &Option<results::UpdateIpResults>,
+    ip_services: &ip_services::IpServices,
+    family: Option<results::AddressFamily>,
+    ip_service_result: Option<results::IpServiceResult>,
+    decisions: &mut results::DecisionLog,
+) -> results::IpServiceResult {
+    if let Some(ip_service_result) = ip_service_result {
+        if let Some(ip_address) = ip_service_result.ip_address {
+            decisions.push(results::Decision::AddressDetected {
+                service: ip_service_result.service.clone(),
+                ip_address,
+            });
+        }
+        return ip_service_result;
+    }
+
+    ip_services::get_ip_service_results(
+        ip_services,
+        prev_results,
+        family,
+        config.netns.as_deref(),
+        config.ip_service_probe_timeout_ms,
+        config.ip_service_timeout_ms,
+        decisions,
+    )
+    .await
+}
+
+fn ip_result_family(
+    ip_service_result: &results::IpServiceResult,
+) -> Option<results::AddressFamily> {
+    ip_service_result
+        .ip_address
+        .map(|ip_address| ip_address.family())
+}
+
+// answers json requests on stdin until stdin closes
+async fn serve() {
+    let args = match (env::args().nth(2), env::args().nth(3).as_deref()) {
+        (Some(a), Some("--stdio")) => a,
+        _ => return println!("argument error:\nexpected serve <path_to_json_config> --stdio."),
+    };
+
+    let mut config = match config::from_path(path::Path::new(&args)).await {
+        Ok(c) => c,
+        Err(e) => return println!("configuration error:\n{}", e),
+    };
+
+    if let Err(e) = drop_privileges(&config).await {
+        return println!("privileges error:\n{}", e);
+    }
+
+    if let Some(allowed_hosts) = &config.allowed_hosts {
+        if let Err(e) = requests::set_allowed_hosts(allowed_hosts) {
+            return println!("allowed hosts error:\n{}", e);
+        }
+    }
+    requests::set_require_tls13(config.require_tls13);
 
+    if let Err(e) = add_ip_services_from_url(&mut config).await {
+        eprintln!(
+            "WARNING: ip services could not be fetched\nservices error:\n{}",
+            e
+        );
+    }
+
+    serve::serve_stdio(&config).await;
+}
+
+// prints an update_ip config to stdout and untranslated settings to stderr
+async fn import_ddclient() {
+    let args = match env::args().nth(2) {
+        Some(a) => a,
+        None => return println!("argument error:\nddclient config file not found."),
+    };
+
+    let ddclient_import = match ddclient::from_path(path::Path::new(&args)).await {
+        Ok(i) => i,
+        Err(e) => return println!("ddclient error:\n{}", e),
+    };
+
+    match serde_json::to_string_pretty(&ddclient_import.config) {
+        Ok(json_str) => println!("{}", json_str),
+        Err(e) => return println!("ddclient error:\n{}", e),
+    };
+
+    for note in ddclient_import.notes {
+        eprintln!("# {}", note);
+    }
+}
+
+// shows or clears results without any network activity
+async fn state() {
+    let (command, args) = match (env::args().nth(2), env::args().nth(3)) {
+        (Some(c), Some(a)) => (c, a),
+        _ => {
+            return println!(
+                "argument error:\nexpected state show|clear|export|import <path_to_json_config>."
+            )
+        }
+    };
+
+    let config = match config::from_path(path::Path::new(&args)).await {
+        Ok(c) => c,
+        Err(e) => return println!("configuration error:\n{}", e),
+    };
+
+    match command.as_str() {
+        "show" => state_show(&config).await,
+        "clear" => state_clear(&config).await,
+        "export" => state_export(&config).await,
+        "import" => state_import(&config).await,
+        _ => println!(
+            "argument error:\nexpected state show|clear|export|import <path_to_json_config>."
+        ),
+    }
+}
+
+async fn state_show(config: &config::Config) {
     let results =
-        match results::UpdateIpResults::try_from_results(ip_service_result, domain_service_results)
+        match results::load_results_with_backups(&config.results_filepath, config.results_backups)
+            .await
         {
-            Ok(c) => c,
-            Err(e) => return println!("{}", e),
+            Ok(r) => r,
+            Err(e) => return println!("file error:\n{}", e),
         };
 
-    // write updated results to disk
-    if let Err(e) = results::write_results_to_disk(results, &config.results_filepath).await {
-        return println!("file error:\n{}", e);
+    match serde_json::to_string_pretty(&results) {
+        Ok(json_str) => println!("{}", json_str),
+        Err(e) => println!("file error:\n{}", e),
     };
 }
+
+async fn state_clear(config: &config::Config) {
+    if env::args().nth(4).as_deref() != Some("--yes") {
+        println!(
+            "clear results and backups at {}? [y/N]",
+            config.results_filepath.display()
+        );
+
+        let mut answer = String::new();
+        if let Err(e) = io::stdin().read_line(&mut answer) {
+            return println!("argument error:\n{}", e);
+        }
+        if answer.trim() != "y" {
+            return println!("results were not cleared");
+        }
+    }
+
+    match results::remove_results_from_disk(&config.results_filepath, config.results_backups).await
+    {
+        Ok(_) => println!("results cleared"),
+        Err(e) => println!("file error:\n{}", e),
+    };
+}
+
+// prints results with a state_version so they can be imported on another host
+async fn state_export(config: &config::Config) {
+    let results =
+        match results::load_results_with_backups(&config.results_filepath, config.results_backups)
+            .await
+        {
+            Ok(r) => r,
+            Err(e) => return eprintln!("file error:\n{}", e),
+        };
+
+    match results::export_state(results) {
+        Ok(json_str) => println!("{}", json_str),
+        Err(e) => eprintln!("file error:\n{}", e),
+    };
+}
+
+// reads exported state from a file or stdin and replaces the current results
+async fn state_import(config: &config::Config) {
+    let json_str = match env::args().nth(4) {
+        Some(state_path) => match std::fs::read_to_string(&state_path) {
+            Ok(s) => s,
+            Err(e) => return println!("file error:\n{}", e),
+        },
+        _ => {
+            let mut json_str = String::new();
+            if let Err(e) = io::stdin().read_to_string(&mut json_str) {
+                return println!("argument error:\n{}", e);
+            }
+            json_str
+        }
+    };
+
+    let mut results = match results::import_state(&json_str) {
+        Ok(r) => r,
+        Err(e) => return println!("state error:\n{}", e),
+    };
+
+    // domains that are not in this config would never be read
+    let skipped_keys =
+        domain_services::remove_unknown_domain_results(config, &mut results.domain_service_results);
+    for key in &skipped_keys {
+        println!("skipped {}: not a domain in the config", key);
+    }
+
+    match results::write_results_to_disk(results, &config.results_filepath, config.results_backups)
+        .await
+    {
+        Ok(r) => println!(
+            "results imported: {} domains, {} skipped",
+            r.domain_service_results.len(),
+            skipped_keys.len()
+        ),
+        Err(e) => println!("file error:\n{}", e),
+    };
+}
+
+// prints the version of update_ip and optionally the latest release
+async fn version() {
+    let current_version = env!("CARGO_PKG_VERSION");
+    println!("update_ip {}", current_version);
+
+    if !env::args().skip(2).any(|arg| arg == "--check") {
+        return;
+    }
+    let verbose = env::args().skip(2).any(|arg| arg == "--verbose");
+
+    // a failed check is only reported at the verbose level
+    match releases::check_latest_release(current_version).await {
+        Ok(release_check) if release_check.is_outdated => {
+            println!("update_ip {} is available", release_check.latest_version)
+        }
+        Ok(_) => println!("update_ip is up to date"),
+        Err(e) if verbose => println!("release error:\n{}", e),
+        Err(_) => {}
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_config(results_filepath: &path::Path, results_backups: usize) -> config::Config {
+        serde_json::from_value(serde_json::json!({
+            "results_filepath": results_filepath,
+            "results_backups": results_backups,
+            "ip_services": [["https://ip.example/", "address_as_body"]],
+            "run_as": {"user": "nobody"},
+            "dyndns2": [],
+            "cloudflare": [],
+            "query_get": [],
+            "freedns": [],
+        }))
+        .unwrap()
+    }
+
+    fn get_temp_dir(name: &str) -> path::PathBuf {
+        let dir = env::temp_dir().join(
+            "update_ip_run_as_test_".to_string() + name + "_" + &std::process::id().to_string(),
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn run_as_files_are_every_file_a_run_writes() {
+        let results_filepath = path::Path::new("/var/lib/update_ip/results.json");
+        let filepaths = get_run_as_filepaths(&get_config(results_filepath, 2));
+
+        let expected = [
+            "/var/lib/update_ip/results.json",
+            "/var/lib/update_ip/results.json.fingerprint_key",
+            "/var/lib/update_ip/results.json.ip_services",
+            "/var/lib/update_ip/results.json.1",
+            "/var/lib/update_ip/results.json.2",
+        ];
+        let expected: Vec<path::PathBuf> = expected.iter().map(path::PathBuf::from).collect();
+        assert_eq!(filepaths, expected);
+
+        // without backups only the files of the run are handed over
+        let filepaths = get_run_as_filepaths(&get_config(results_filepath, 0));
+        assert_eq!(filepaths, expected[..3]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_as_files_are_handed_to_the_user() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = get_temp_dir("chown");
+        let config = get_config(&dir.join("results.json"), 2);
+        let filepaths = get_run_as_filepaths(&config);
+
+        // the second backup does not exist yet and is skipped
+        for filepath in &filepaths[..4] {
+            std::fs::write(filepath, "{}").unwrap();
+        }
+        let dir_metadata = std::fs::metadata(&dir).unwrap();
+        let (uid, gid) = match dir_metadata.uid() {
+            // root hands the files to nobody like a run_as user
+            0 => (65534, 65534),
+            uid => (uid, dir_metadata.gid()),
+        };
+
+        let paths: Vec<&path::Path> = filepaths.iter().map(|p| p.as_path()).collect();
+        assert_eq!(privileges::chown_paths(&paths, uid, gid), Ok(()));
+        for filepath in &filepaths[..4] {
+            let metadata = std::fs::metadata(filepath).unwrap();
+            assert_eq!((metadata.uid(), metadata.gid()), (uid, gid));
+        }
+        assert!(!filepaths[4].exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn get_domain_result(
+        hostname: &str,
+        address: &str,
+        outcome: results::DomainOutcome,
+    ) -> results::DomainResult {
+        let mut domain_result = results::DomainResult::new(hostname);
+        domain_result.ip_address = Some(address.parse().unwrap());
+        domain_result.service = Some("dyndns2".to_string());
+        domain_result.outcome = Some(outcome);
+        domain_result
+    }
+
+    #[test]
+    fn unchanged_domains_are_only_summarized_when_verbose() {
+        let domain_results = std::collections::HashMap::from([
+            (
+                "dyndns2:example.com".to_string(),
+                get_domain_result("example.com", "192.0.2.2", results::DomainOutcome::Updated),
+            ),
+            (
+                "dyndns2:example.net".to_string(),
+                get_domain_result(
+                    "example.net",
+                    "192.0.2.2",
+                    results::DomainOutcome::Unchanged,
+                ),
+            ),
+        ]);
+        let results = results::UpdateIpResults::from_results(
+            results::IpServiceResult::new("https://ip.example/"),
+            None,
+            domain_results,
+            results::DecisionLog::new(),
+            "run-1",
+            None,
+        );
+
+        assert_eq!(
+            get_domain_summaries(&None, &results, false),
+            vec!["example.com none -> 192.0.2.2 (updated via dyndns2)"]
+        );
+        assert_eq!(
+            get_domain_summaries(&None, &results, true),
+            vec![
+                "example.com none -> 192.0.2.2 (updated via dyndns2)",
+                "example.net 192.0.2.2 no change",
+            ]
+        );
+    }
+}
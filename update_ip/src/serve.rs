@@ -0,0 +1,195 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{stdin, AsyncBufReadExt, BufReader};
+
+use results::{Address, AddressFamily, DecisionLog, IpServiceResult};
+
+use crate::{load_prev_results, run_update};
+
+/*
+    Reads one json request per line from stdin and writes one json
+    response per line to stdout. Requests are handled one at a time
+    in the order they are received.
+
+    {"cmd":"detect"} detects the address without updating domains.
+    {"cmd":"update","address":"192.0.2.1","domains":["example.com"]} runs an update,
+    the address and domains are optional. Domains limits the update to the
+    domains with those hostnames.
+    {"cmd":"status"} returns the current results.
+
+    Responses are {"ok":true,...} or {"ok":false,"error":"..."}.
+*/
+
+const SERVE_SERVICE_NAME: &str = "serve";
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "cmd", rename_all = "snake_case", deny_unknown_fields)]
+enum ServeRequest {
+    // unit variants would ignore unknown fields
+    Detect {},
+    Update {
+        address: Option<String>,
+        domains: Option<Vec<String>>,
+    },
+    Status {},
+}
+
+pub async fn serve_stdio(config: &config::Config) {
+    let mut lines = BufReader::new(stdin()).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => return,
+            Err(e) => return println!("{}", error_response(e.to_string())),
+        };
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<ServeRequest>(&line) {
+            Ok(request) => handle_request(config, request).await,
+            Err(e) => error_response("request error: ".to_string() + &e.to_string()),
+        };
+
+        println!("{}", response);
+    }
+}
+
+async fn handle_request(config: &config::Config, request: ServeRequest) -> Value {
+    match request {
+        ServeRequest::Detect {} => detect(config).await,
+        ServeRequest::Update { address, domains } => update(config, address, domains).await,
+        ServeRequest::Status {} => status(config).await,
+    }
+}
+
+async fn detect(config: &config::Config) -> Value {
+    let prev_results = load_prev_results(config).await;
+
+    let (family, family_v6) = match config.ip_services_v6.is_empty() {
+        true => (None, None),
+        false => (Some(AddressFamily::V4), Some(AddressFamily::V6)),
+    };
+
+    // a config with only ip_services_v6 does not detect ipv4
+    let mut decisions = DecisionLog::new();
+    let ip_service_result = match config.ip_services.is_empty() {
+        true => IpServiceResult::new(""),
+        false => {
+            ip_services::get_ip_service_results(
+                &config.ip_services,
+                &prev_results,
+                family,
+                config.netns.as_deref(),
+                config.ip_service_probe_timeout_ms,
+                config.ip_service_timeout_ms,
+                &mut decisions,
+            )
+            .await
+        }
+    };
+
+    let ip_service_result_v6 = match family_v6 {
+        Some(_) => Some(
+            ip_services::get_ip_service_results(
+                &config.ip_services_v6,
+                &prev_results,
+                family_v6,
+                config.netns.as_deref(),
+                config.ip_service_probe_timeout_ms,
+                config.ip_service_timeout_ms,
+                &mut decisions,
+            )
+            .await,
+        ),
+        _ => None,
+    };
+
+    // families are detected independently, a family without an address reports its errors
+    let detected = ip_service_result.ip_address.is_some()
+        || ip_service_result_v6
+            .as_ref()
+            .is_some_and(|ip_result| ip_result.ip_address.is_some());
+    if !detected {
+        let mut errors = ip_service_result.errors;
+        if let Some(ip_service_result_v6) = ip_service_result_v6 {
+            errors.extend(ip_service_result_v6.errors);
+        }
+        return error_response(errors.join(", "));
+    }
+
+    match ip_service_result_v6 {
+        Some(ip_service_result_v6) => json!({
+            "ok": true,
+            "ip_service_result": ip_service_result,
+            "ip_service_result_v6": ip_service_result_v6,
+            "decisions": decisions,
+        }),
+        _ => json!({
+            "ok": true,
+            "ip_service_result": ip_service_result,
+            "decisions": decisions,
+        }),
+    }
+}
+
+async fn update(
+    config: &config::Config,
+    address: Option<String>,
+    domains: Option<Vec<String>>,
+) -> Value {
+    let ip_service_result = match address {
+        Some(address) => match address.parse::<Address>() {
+            Ok(ip_address) => {
+                let mut ip_service_result = IpServiceResult::new(SERVE_SERVICE_NAME);
+                ip_service_result.ip_address = Some(ip_address);
+                Some(ip_service_result)
+            }
+            Err(e) => return error_response("address error: ".to_string() + &e),
+        },
+        _ => None,
+    };
+
+    let prev_results = load_prev_results(config).await;
+
+    let mut warnings = Vec::<String>::new();
+    match run_update(
+        config,
+        &prev_results,
+        ip_service_result,
+        domains.as_deref(),
+        &mut warnings,
+    )
+    .await
+    {
+        Ok(results) => json!({
+            "ok": true,
+            "results": results,
+            "warnings": warnings,
+        }),
+        Err(e) => json!({
+            "ok": false,
+            "error": e,
+            "warnings": warnings,
+        }),
+    }
+}
+
+async fn status(config: &config::Config) -> Value {
+    match results::load_results_with_backups(&config.results_filepath, config.results_backups).await
+    {
+        Ok(results) => json!({
+            "ok": true,
+            "results": results,
+        }),
+        Err(e) => error_response("file error: ".to_string() + &e),
+    }
+}
+
+fn error_response(error: String) -> Value {
+    json!({
+        "ok": false,
+        "error": error,
+    })
+}
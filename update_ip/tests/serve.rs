@@ -0,0 +1,162 @@
+use serde_json::Value;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+// writes a config to its own directory so results never collide between tests
+fn write_config(name: &str, config: &str) -> PathBuf {
+    let dir = std::env::temp_dir()
+        .join("update_ip_serve_".to_string() + name + "_" + &std::process::id().to_string());
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let results_filepath = dir.join("results.json");
+    let config = config.replace("RESULTS_FILEPATH", results_filepath.to_str().unwrap());
+    let config_filepath = dir.join("config.json");
+    std::fs::write(&config_filepath, config).unwrap();
+
+    config_filepath
+}
+
+// sends one request per line to serve --stdio and reads one response per line
+fn serve(config_filepath: &PathBuf, requests: &[&str]) -> Vec<Value> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_update_ip"))
+        .arg("serve")
+        .arg(config_filepath)
+        .arg("--stdio")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut stdin = child.stdin.take().unwrap();
+    for request in requests {
+        writeln!(stdin, "{}", request).unwrap();
+    }
+    drop(stdin);
+
+    let output = child.wait_with_output().unwrap();
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+const CONFIG: &str = r#"{
+    "results_filepath": "RESULTS_FILEPATH",
+    "ip_services": [["http://127.0.0.1:1/", "address_as_body"]],
+    "dyndns2": [],
+    "cloudflare": [],
+    "query_get": [],
+    "freedns": []
+}"#;
+
+#[test]
+fn unknown_fields_are_rejected() {
+    let config_filepath = write_config("unknown_fields", CONFIG);
+    let responses = serve(
+        &config_filepath,
+        &[
+            r#"{"cmd":"update","address":"192.0.2.1","domain":["example.com"]}"#,
+            r#"{"cmd":"status","verbose":true}"#,
+        ],
+    );
+
+    assert_eq!(responses.len(), 2);
+    for response in responses {
+        assert_eq!(response["ok"], false);
+        let error = response["error"].as_str().unwrap();
+        assert!(error.contains("unknown field"), "{}", error);
+    }
+}
+
+#[test]
+fn unknown_domains_are_rejected() {
+    let config_filepath = write_config("unknown_domains", CONFIG);
+    let responses = serve(
+        &config_filepath,
+        &[
+            r#"{"cmd":"update","address":"192.0.2.1","domains":["missing.example"]}"#,
+            r#"{"cmd":"update","address":"192.0.2.1","domains":[]}"#,
+        ],
+    );
+
+    assert_eq!(responses.len(), 2);
+    assert_eq!(responses[0]["ok"], false);
+    assert_eq!(
+        responses[0]["error"],
+        "domains: not a domain in the config: missing.example"
+    );
+    assert_eq!(responses[1]["ok"], false);
+    assert_eq!(responses[1]["error"], "domains: must not be empty");
+}
+
+#[test]
+fn detect_without_an_address_is_an_error() {
+    let config_filepath = write_config("detect_fails", CONFIG);
+    let responses = serve(&config_filepath, &[r#"{"cmd":"detect"}"#]);
+
+    assert_eq!(responses.len(), 1);
+    assert_eq!(responses[0]["ok"], false);
+    let error = responses[0]["error"].as_str().unwrap();
+    assert!(error.starts_with("http://127.0.0.1:1/: "), "{}", error);
+}
+
+#[test]
+fn detect_with_only_ip_services_v6_detects_only_ipv6() {
+    let config = CONFIG.replace(
+        r#""ip_services": [["http://127.0.0.1:1/", "address_as_body"]]"#,
+        r#""ip_services": [],
+    "ip_services_v6": [["http://[::1]:1/", "address_as_body"]]"#,
+    );
+    let config_filepath = write_config("detect_v6_only", &config);
+    let responses = serve(&config_filepath, &[r#"{"cmd":"detect"}"#]);
+
+    // only the ipv6 service is requested
+    assert_eq!(responses.len(), 1);
+    assert_eq!(responses[0]["ok"], false);
+    let error = responses[0]["error"].as_str().unwrap();
+    assert!(error.starts_with("http://[::1]:1/: "), "{}", error);
+    assert!(!error.contains("127.0.0.1"), "{}", error);
+}
+
+#[cfg(feature = "query_get")]
+#[test]
+fn domains_filter_updates_only_listed_domains() {
+    let config = CONFIG.replace(
+        r#""query_get": []"#,
+        r#""query_get": [
+            {"service_uri": "https://127.0.0.1:1/update", "hostname": "a.example", "ip_param": "ip"},
+            {"service_uri": "https://127.0.0.1:1/update", "hostname": "b.example", "ip_param": "ip"}
+        ]"#,
+    );
+    let config_filepath = write_config("domains_filter", &config);
+    let responses = serve(
+        &config_filepath,
+        &[
+            r#"{"cmd":"update","address":"192.0.2.1","domains":["a.example"]}"#,
+            r#"{"cmd":"update","address":"192.0.2.1","domains":["b.example"]}"#,
+        ],
+    );
+
+    assert_eq!(responses.len(), 2);
+
+    // only the listed domain is updated
+    let domain_results = responses[0]["results"]["domain_service_results"]
+        .as_object()
+        .unwrap();
+    assert!(domain_results.contains_key("query_get:a.example"));
+    assert!(!domain_results.contains_key("query_get:b.example"));
+
+    // domains that are not listed keep their previous result
+    let domain_results = responses[1]["results"]["domain_service_results"]
+        .as_object()
+        .unwrap();
+    assert_eq!(
+        domain_results["query_get:a.example"],
+        responses[0]["results"]["domain_service_results"]["query_get:a.example"]
+    );
+    assert!(domain_results.contains_key("query_get:b.example"));
+}